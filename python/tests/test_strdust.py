@@ -0,0 +1,34 @@
+import pytest
+import strdust
+
+FASTA = "test_data/chr7.fa.gz"
+BAM = "test_data/small-test-phased.bam"
+
+
+def test_genotype_region():
+    loci = strdust.genotype_region(
+        BAM, FASTA, "chr7:154654404-154654432", {"unphased": True, "support": 1}
+    )
+    assert len(loci) == 1
+    locus = loci[0]
+    assert (locus["chrom"], locus["start"], locus["end"]) == ("chr7", 154654404, 154654432)
+    assert len(locus["alleles"]) == 2
+    assert all(allele["support"] is None or allele["support"] > 0 for allele in locus["alleles"])
+
+
+def test_invalid_options():
+    with pytest.raises(ValueError):
+        strdust.genotype_region(BAM, FASTA, "chr7:154654404-154654432", {"no_such_option": 1})
+
+
+@pytest.mark.parametrize(
+    "bam, region",
+    [
+        (BAM, "chr7:154654404"),
+        (BAM, "chrUnknown:100-200"),
+        ("test_data/missing.bam", "chr7:154654404-154654432"),
+    ],
+)
+def test_invalid_region_or_input(bam, region):
+    with pytest.raises(ValueError):
+        strdust.genotype_region(bam, FASTA, region, {"unphased": True})
@@ -1,28 +1,158 @@
-use crate::repeats::RepeatIntervalIterator;
+use crate::checkpoint::Checkpoint;
+use crate::consensus_fasta::ConsensusFasta;
+use crate::locus_log::{log_locus, Stage};
+use crate::registry::AlleleRegistry;
+use crate::spill::SortedRecords;
+use crate::repeats::{LowConfidenceRegions, RepeatInterval, RepeatIntervalIterator};
+use crate::stats::STATS;
+use crate::utils::Anonymizer;
 use indicatif::ParallelProgressIterator;
+use indicatif::ProgressBar;
 use indicatif::ProgressIterator;
-use log::{debug, error};
+use log::{debug, error, warn, Level};
 use rayon::prelude::*;
 use std::io::Write;
-use std::{io, sync::Mutex};
+use std::time::Instant;
+use std::io;
 
-use crate::{genotype, parse_bam, Cli};
+use crate::vcf::VCFRecord;
+use crate::{genotype, parse_bam, Cli, OutputFormat};
+
+// the maximal number of repeats for which an unindexed bam file is scanned automatically
+const MAX_SCAN_INTERVALS: usize = 100;
 
 pub fn genotype_repeats(args: Cli) {
+    if args.find_outliers && !args.unphased {
+        warn!("--find-outliers is only effective with --unphased");
+    }
+    crate::repeats::set_contig_reconciliation(!args.no_contig_reconciliation);
     debug!("Genotyping STRs in {}", args.bam);
+    let run_start = Instant::now();
     let repeats = get_targets(&args);
-    crate::vcf::write_vcf_header(&args.fasta, &args.bam, &args.sample);
+    parse_bam::check_contigs(&args.bam, &args.fasta, &repeats);
+    let targets_time = run_start.elapsed();
+    // with --anonymize, the same key is used for all identifiers of the run
+    let anonymizer = args.anonymizer();
+    if args.format == OutputFormat::Vcf {
+        crate::vcf::write_vcf_header(&args.fasta, &args.bam, &args.sample, anonymizer.as_ref());
+    }
+    if let Some(dir) = &args.gfa {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|err| panic!("Failed creating GFA directory {dir}: {err}"));
+    }
+    if let Some(dir) = &args.plot {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|err| panic!("Failed creating plot directory {dir}: {err}"));
+    }
+    if let Some(dir) = &args.dotplot {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|err| panic!("Failed creating dotplot directory {dir}: {err}"));
+    }
+    // with --checkpoint, loci genotyped in a previous run are not genotyped again
+    let checkpoint = args.checkpoint.as_ref().map(|path| Checkpoint::open(path, &args));
+    // repeats overlapping low confidence regions get the LOWCONF_FLANK filter
+    let lowconf = args
+        .lowconf_regions
+        .as_ref()
+        .map(|bed| LowConfidenceRegions::from_bed(bed));
+    // with --allele-registry, every observed allele is kept with its stable identifier
+    let registry = args
+        .allele_registry
+        .as_ref()
+        .map(|path| AlleleRegistry::open(path));
+    // with --consensus-fasta, the consensus of every allele is also written to a fasta file
+    let consensus_fasta = args.consensus_fasta.as_ref().map(|path| {
+        let sample = crate::vcf::sample_name(&args.bam, &args.sample, anonymizer.as_ref());
+        ConsensusFasta::create(path, &sample)
+    });
+    let inputs = genotype::RunInputs {
+        record_stats: true,
+        ..genotype::RunInputs::from_args(&args)
+    };
     let stdout = io::stdout(); // get the global stdout entity
     let mut handle = io::BufWriter::new(stdout); // wrap that handle in a buffer
-    if args.threads == 1 {
+    let indexed = parse_bam::has_index(&args.bam);
+    let genotyping_start = Instant::now();
+    if args.no_index_scan || (!indexed && repeats.num_intervals <= MAX_SCAN_INTERVALS) {
+        // Without an index the reads for all repeats are collected in a single pass over the bam file
+        // This keeps all reads in memory, so is only used automatically for a small number of repeats
+        // Output is returned in the same order as the bed
+        let repeats = repeats.collect::<Vec<RepeatInterval>>();
+        let unphased = |repeat: &RepeatInterval| genotype::reads_are_unphased(repeat, &args, &inputs);
+        let reading_start = Instant::now();
+        // with --padding, the reads are collected for the padded intervals
+        let padded = repeats
+            .iter()
+            .map(|repeat| repeat.padded(args.padding))
+            .collect::<Vec<RepeatInterval>>();
+        let reads = parse_bam::scan_overlapping_reads(
+            &args.bam,
+            &args.fasta,
+            &padded,
+            &unphased,
+            args.snv_phasing,
+            &args.read_filter(),
+        );
+        STATS.add_reading_time(reading_start.elapsed());
+        for (repeat, reads) in repeats.iter().zip(reads) {
+            if let Some(line) = checkpoint.as_ref().and_then(|c| c.get(repeat)) {
+                STATS.add_checkpointed();
+                writeln!(handle, "{line}").expect("Failed writing the result.");
+                continue;
+            }
+            let start = Instant::now();
+            let output = genotype::genotype_repeat_from_reads(repeat, &args, reads, &inputs);
+            STATS.add_genotyping_time(start.elapsed());
+            match output {
+                Ok(mut output) => {
+                    let line = finish_record(
+                        &mut output,
+                        repeat,
+                        &args,
+                        &lowconf,
+                        &registry,
+                        &consensus_fasta,
+                        &checkpoint,
+                        &anonymizer,
+                    );
+                    writeln!(handle, "{line}").expect("Failed writing the result.");
+                }
+                Err(err) => genotyping_failed(repeat, &err),
+            }
+        }
+    } else if !indexed {
+        error!(
+            "No index found for {}, index the bam file or use --no-index-scan",
+            args.bam
+        );
+        std::process::exit(1);
+    } else if args.threads == 1 {
         // When running single threaded things become easier and the tool will require less memory
         // Output is returned in the same order as the bed, and therefore not sorted before writing immediately to stdout
         // The indexedreader is created once and passed on to the function
         let num_intervals = repeats.num_intervals;
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        for repeat in repeats.progress_count(num_intervals as u64) {
-            if let Ok(output) = genotype::genotype_repeat_singlethreaded(&repeat, &args, &mut bam) {
-                writeln!(handle, "{output}").expect("Failed writing the result.");
+        for repeat in repeats.progress_with(progress_bar(num_intervals)) {
+            if let Some(line) = checkpoint.as_ref().and_then(|c| c.get(&repeat)) {
+                STATS.add_checkpointed();
+                writeln!(handle, "{line}").expect("Failed writing the result.");
+                continue;
+            }
+            match genotype::genotype_repeat_singlethreaded(&repeat, &args, &mut bam, &inputs) {
+                Ok(mut output) => {
+                    let line = finish_record(
+                        &mut output,
+                        &repeat,
+                        &args,
+                        &lowconf,
+                        &registry,
+                        &consensus_fasta,
+                        &checkpoint,
+                        &anonymizer,
+                    );
+                    writeln!(handle, "{line}").expect("Failed writing the result.");
+                }
+                Err(err) => genotyping_failed(&repeat, &err),
             }
         }
     } else {
@@ -30,32 +160,168 @@ pub fn genotype_repeats(args: Cli) {
             .num_threads(args.threads)
             .build()
             .expect("Failed to create threadpool");
-        // genotypes contains the output of the genotyping, a struct instance
-        let genotypes = Mutex::new(Vec::new());
-        // par_bridge does not guarantee that results are returned in order
-        let num_intervals = repeats.num_intervals;
-        repeats
-            .par_bridge()
-            .progress_count(num_intervals as u64)
-            .for_each(|repeat| {
-                if let Ok(output) = genotype::genotype_repeat_multithreaded(&repeat, &args) {
-                    let mut geno = genotypes.lock().expect("Unable to lock genotypes mutex");
-                    geno.push(output);
-                } else {
-                    error!("Problem processing {repeat}");
+        // genotypes contains the formatted output of the genotyping, with the chromosome and start for sorting
+        // with --max-mem, the genotypes are spilled to temporary files rather than kept in memory
+        let max_bytes = args.max_mem.map(|megabytes| megabytes * 1024 * 1024);
+        let genotypes = SortedRecords::new(max_bytes);
+        let genotype_locus = |repeat: RepeatInterval| {
+            if let Some(line) = checkpoint.as_ref().and_then(|c| c.get(&repeat)) {
+                STATS.add_checkpointed();
+                genotypes.push(repeat.chrom.clone(), repeat.start, line.clone());
+            } else {
+                match genotype::genotype_repeat_multithreaded(&repeat, &args, &inputs) {
+                    Ok(mut output) => {
+                        let line = finish_record(
+                            &mut output,
+                            &repeat,
+                            &args,
+                            &lowconf,
+                            &registry,
+                            &consensus_fasta,
+                            &checkpoint,
+                            &anonymizer,
+                        );
+                        // sorted by the locus rather than the record, which may have been normalized,
+                        // such that checkpointed and genotyped loci are in the same order
+                        genotypes.push(repeat.chrom.clone(), repeat.start, line);
+                    }
+                    Err(err) => genotyping_failed(&repeat, &err),
                 }
-            });
-        let mut genotypes_vec = genotypes.lock().unwrap();
-        // The final output is sorted by chrom, start and end
-        genotypes_vec.sort_unstable();
-        for g in &mut *genotypes_vec {
-            writeln!(handle, "{g}").expect("Failed writing the result.");
+            }
+        };
+        let num_intervals = repeats.num_intervals;
+        if let Some(chunk_size) = args.chunk_size {
+            // every thread genotypes a chunk of consecutive loci with its own bam reader,
+            // such that an unsorted bed file does not lead to random seeks in the bam file
+            let progress = progress_bar(num_intervals);
+            repeats
+                .into_chunks(chunk_size)
+                .into_par_iter()
+                .for_each(|chunk| {
+                    for repeat in chunk {
+                        genotype_locus(repeat);
+                        progress.inc(1);
+                    }
+                });
+            progress.finish();
+        } else {
+            // par_bridge does not guarantee that results are returned in order
+            repeats
+                .par_bridge()
+                .progress_with(progress_bar(num_intervals))
+                .for_each(genotype_locus);
         }
+        // The final output is sorted by chrom and start
+        genotypes.write_sorted(&mut handle);
+    }
+    handle.flush().expect("Failed writing the result.");
+    if let Some(path) = &args.stats {
+        crate::stats::write_stats(
+            path,
+            &[
+                ("targets", targets_time),
+                ("genotyping", genotyping_start.elapsed()),
+                ("total", run_start.elapsed()),
+            ],
+        );
     }
 }
 
+fn genotyping_failed(repeat: &RepeatInterval, err: &str) {
+    STATS.add_failure();
+    log_locus(Level::Error, repeat, Stage::Genotyping, err);
+}
+
+// the progress bar is hidden with --quiet, which turns off logging
+fn progress_bar(num_intervals: usize) -> ProgressBar {
+    if log::max_level() == log::LevelFilter::Off {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(num_intervals as u64)
+    }
+}
+
+// format a genotyped repeat, and keep it in the checkpoint file if one is used
+pub(crate) fn finish_record(
+    record: &mut VCFRecord,
+    repeat: &RepeatInterval,
+    args: &Cli,
+    lowconf: &Option<LowConfidenceRegions>,
+    registry: &Option<AlleleRegistry>,
+    consensus_fasta: &Option<ConsensusFasta>,
+    checkpoint: &Option<Checkpoint>,
+    anonymizer: &Option<Anonymizer>,
+) -> String {
+    STATS.add_record(record, args.support);
+    if let Some(anonymizer) = anonymizer {
+        record.anonymize_read_groups(anonymizer);
+    }
+    if lowconf.as_ref().is_some_and(|regions| regions.overlaps(repeat))
+        && !record.filters.contains(&"LOWCONF_FLANK".to_string())
+    {
+        record.filters.push("LOWCONF_FLANK".to_string());
+    }
+    if args.allele_ids || registry.is_some() {
+        record.allele_ids = Some(crate::registry::allele_ids(record));
+    }
+    if let Some(registry) = registry {
+        registry.add(record);
+    }
+    if let Some(consensus_fasta) = consensus_fasta {
+        consensus_fasta.add(record);
+    }
+    if let Some(dir) = &args.gfa {
+        crate::gfa::write_locus_gfa(record, repeat, &args.fasta, dir);
+    }
+    if let Some(dir) = &args.plot {
+        crate::plot::write_locus_svg(record, repeat, dir);
+    }
+    if let Some(dir) = &args.dotplot {
+        crate::dotplot::write_locus_dotplots(record, repeat, dir);
+    }
+    let format = |record: &VCFRecord| match args.format {
+        OutputFormat::Vcf => record.to_string(),
+        OutputFormat::Json => record.to_json(),
+    };
+    // with --split-nested, loci with two adjacent repeats are reported as two records
+    let split = match args.nested_motifs {
+        Some(max_length) if args.split_nested => {
+            record.split_nested(max_length, args.motif_mismatches)
+        }
+        _ => None,
+    };
+    // with --normalize, the records are left-aligned as the last step, as the other outputs use the locus coordinates
+    // with --no-sequence, only the lengths of the alleles are reported
+    let line = match split {
+        Some((mut first, mut second)) => {
+            if args.no_sequence {
+                first.set_symbolic_alleles();
+                second.set_symbolic_alleles();
+            }
+            if args.normalize {
+                first.normalize(&args.fasta);
+                second.normalize(&args.fasta);
+            }
+            format!("{}\n{}", format(&first), format(&second))
+        }
+        None => {
+            if args.no_sequence {
+                record.set_symbolic_alleles();
+            }
+            if args.normalize {
+                record.normalize(&args.fasta);
+            }
+            format(record)
+        }
+    };
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.save(repeat, &line);
+    }
+    line
+}
+
 fn get_targets(args: &Cli) -> RepeatIntervalIterator {
-    match (&args.region, &args.region_file, args.pathogenic) {
+    let targets = match (&args.region, &args.region_file, args.pathogenic) {
         // a region string
         (Some(region), None, false) => RepeatIntervalIterator::from_string(region, &args.fasta),
         // a region file
@@ -69,5 +335,7 @@ fn get_targets(args: &Cli) -> RepeatIntervalIterator {
             eprintln!("ERROR: Specify a region string (-r), a region_file (-R) or --pathogenic!\n");
             std::process::exit(1);
         }
-    }
+    };
+    // duplicated intervals are always removed, overlapping intervals only merged with --merge-intervals
+    targets.merge_intervals(args.merge_intervals)
 }
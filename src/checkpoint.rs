@@ -0,0 +1,192 @@
+use crate::repeats::RepeatInterval;
+use crate::Cli;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// A checkpoint file keeps the formatted output of every finished locus
+/// so that a rerun with the same checkpoint file can skip those loci
+/// Every line has the format chrom\tstart\tend\t<formatted record>
+/// A locus reported as multiple records, e.g. with --split-nested, has a line per record
+/// The first line is a header with the version and a hash of the parameters that affect the output
+pub struct Checkpoint {
+    file: Mutex<File>,
+    completed: HashMap<(String, u32, u32), String>,
+}
+
+impl Checkpoint {
+    pub fn open(path: &str, args: &Cli) -> Checkpoint {
+        let key = cache_key(args);
+        let completed = match std::fs::read_to_string(path) {
+            Ok(content) if content.is_empty() => HashMap::new(),
+            Ok(content) => {
+                let header = content.lines().next().unwrap_or_default();
+                if header != key {
+                    if !args.allow_stale_cache {
+                        error!(
+                            "Checkpoint {path} was created by another version or with other parameters ({header}), remove it or use --allow-stale-cache"
+                        );
+                        std::process::exit(1);
+                    }
+                    warn!("Reusing stale checkpoint {path} ({header})");
+                }
+                parse_checkpoint(&content)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            // any other error would otherwise overwrite the loci that were already genotyped
+            Err(err) => {
+                error!("Failed reading checkpoint {path}: {err}");
+                std::process::exit(1);
+            }
+        };
+        if !completed.is_empty() {
+            info!("Resuming from {path}: {} loci already genotyped", completed.len());
+        }
+        // the file is rewritten with only the valid entries,
+        // as an interrupted run may have left a truncated last line
+        // this is written to a temporary file that replaces the checkpoint, such that a crash keeps the old file
+        let tmp = format!("{path}.tmp");
+        let mut file = File::create(&tmp)
+            .unwrap_or_else(|err| panic!("Failed creating checkpoint file {tmp}: {err}"));
+        let mut content = format!("{key}\n");
+        for ((chrom, start, end), lines) in &completed {
+            for line in lines.lines() {
+                content.push_str(&format!("{chrom}\t{start}\t{end}\t{line}\n"));
+            }
+        }
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .unwrap_or_else(|err| panic!("Failed writing checkpoint file {tmp}: {err}"));
+        std::fs::rename(&tmp, path)
+            .unwrap_or_else(|err| panic!("Failed replacing checkpoint file {path}: {err}"));
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|err| panic!("Failed opening checkpoint file {path}: {err}"));
+        Checkpoint {
+            file: Mutex::new(file),
+            completed,
+        }
+    }
+
+    /// Get the formatted output of a locus genotyped in a previous run
+    pub fn get(&self, repeat: &RepeatInterval) -> Option<&String> {
+        self.completed
+            .get(&(repeat.chrom.clone(), repeat.start, repeat.end))
+    }
+
+    /// Store the formatted output of a locus, flushed immediately to survive a crash
+    pub fn save(&self, repeat: &RepeatInterval, lines: &str) {
+        let entry = lines
+            .lines()
+            .map(|line| format!("{}\t{}\t{}\t{line}\n", repeat.chrom, repeat.start, repeat.end))
+            .collect::<String>();
+        let mut file = self.file.lock().expect("Unable to lock checkpoint file");
+        file.write_all(entry.as_bytes())
+            .expect("Failed writing to checkpoint file");
+        file.flush().expect("Failed flushing checkpoint file");
+    }
+}
+
+// the version and the parameters that change the genotypes, hashed to detect stale checkpoints
+fn cache_key(args: &Cli) -> String {
+    let params = format!(
+        "{:?}",
+        // nested, as Debug is only implemented for tuples of up to 12 elements
+        (
+            (
+                &args.fasta,
+                &args.bam,
+                args.minlen,
+                args.minlen_units,
+                args.support,
+                args.keep_low_support,
+                args.no_coverage_calls,
+                args.flank_anchor,
+                args.padding,
+                args.read_filter(),
+            ),
+            (
+                args.somatic,
+                args.unphased,
+                args.snv_phasing,
+                args.em_iterations,
+                args.find_outliers,
+                args.cluster_options(),
+            ),
+            (&args.haploid, &args.karyotype, &args.ploidy_bed, &args.lowconf_regions),
+            (&args.second_fasta, &args.second_region_file, args.min_contraction),
+            (args.nested_motifs, args.split_nested, args.motif_mismatches, args.timeout),
+            (
+                args.emit_all_sites,
+                args.realign_qc,
+                args.read_qc,
+                args.purity,
+                args.normalize,
+                &args.format,
+                args.consensus_method(),
+                args.allele_ids || args.allele_registry.is_some(),
+            ),
+        )
+    );
+    format!(
+        "#STRdust v{}\tparams={:016x}",
+        env!("CARGO_PKG_VERSION"),
+        crate::utils::fnv1a(params.as_bytes())
+    )
+}
+
+fn parse_checkpoint(content: &str) -> HashMap<(String, u32, u32), String> {
+    let mut completed = HashMap::new();
+    let mut lines = content.split('\n').collect::<Vec<&str>>();
+    // the last element is either empty or a line that was not completely written
+    lines.pop();
+    for line in lines {
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields = line.splitn(4, '\t').collect::<Vec<&str>>();
+        if fields.len() < 4 {
+            continue;
+        }
+        if let (Ok(start), Ok(end)) = (fields[1].parse::<u32>(), fields[2].parse::<u32>()) {
+            completed
+                .entry((fields[0].to_string(), start, end))
+                .and_modify(|lines: &mut String| {
+                    lines.push('\n');
+                    lines.push_str(fields[3]);
+                })
+                .or_insert_with(|| fields[3].to_string());
+        }
+    }
+    completed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_checkpoint_truncated() {
+        let content = "#STRdust v0.8.0\tparams=0\nchr7\t100\t200\tfirst record\nchr7\t300\t400\tsecond record\nchr7\t500\t6";
+        let completed = parse_checkpoint(content);
+        assert_eq!(completed.len(), 2);
+        assert_eq!(
+            completed.get(&("chr7".to_string(), 300, 400)),
+            Some(&"second record".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_checkpoint_multiple_records() {
+        // a locus split in two records is kept as a single entry with both lines
+        let content = "#STRdust v0.8.0\tparams=0\nchr7\t100\t200\tfirst part\nchr7\t100\t200\tsecond part\n";
+        let completed = parse_checkpoint(content);
+        assert_eq!(
+            completed.get(&("chr7".to_string(), 100, 200)),
+            Some(&"first part\nsecond part".to_string())
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Compare the repeat lengths in STRdust VCFs with orthogonal sizing, such as capillary
+/// electrophoresis, RP-PCR or Southern blot results
+/// The sizing csv has the columns locus,sample,min,max and optionally motif_length,
+/// with the locus as chrom:start-end and the size range in repeat units (or bases without motif_length)
+/// Every sizing row is compared with the closest called allele of the sample, written as tsv to stdout
+#[derive(clap::Args, Debug)]
+pub struct ConcordanceArgs {
+    /// csv file with the sizing results
+    sizing: String,
+
+    /// vcf files produced by STRdust, can be single or multi-sample
+    #[clap(value_parser, required = true)]
+    vcfs: Vec<String>,
+
+    /// maximal difference in repeat units outside of the sizing range to be concordant
+    #[clap(long, value_parser, default_value_t = 0.0)]
+    tolerance: f32,
+}
+
+// a size range of a single allele of a sample
+struct Sizing {
+    chrom: String,
+    start: u32,
+    end: u32,
+    sample: String,
+    min: f32,
+    max: f32,
+    motif_length: usize,
+}
+
+// a locus called by STRdust, with the full length of the alleles in bases per sample
+struct Call {
+    start: u32,
+    end: u32,
+    lengths: HashMap<String, Vec<usize>>,
+}
+
+pub fn run(args: ConcordanceArgs) {
+    let sizings = crate::utils::reader(&args.sizing)
+        .lines()
+        .map(|line| line.expect("Failed reading sizing csv"))
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("locus"))
+        .map(|line| parse_sizing_line(&line))
+        .collect::<Vec<Sizing>>();
+    let mut calls: HashMap<String, Vec<Call>> = HashMap::new();
+    for vcf in &args.vcfs {
+        parse_calls(vcf, &mut calls);
+    }
+    println!("#locus\tsample\tmin\tmax\tcalled\tconcordant");
+    let (mut compared, mut concordant) = (0, 0);
+    for sizing in &sizings {
+        let locus = format!("{}:{}-{}", sizing.chrom, sizing.start, sizing.end);
+        let lengths = called_lengths(sizing, &calls);
+        let called = match lengths.and_then(|lengths| closest_allele(sizing, lengths)) {
+            Some(called) => called,
+            None => {
+                warn!("No call for {} in {locus}", sizing.sample);
+                println!(
+                    "{locus}\t{}\t{}\t{}\t.\t.",
+                    sizing.sample, sizing.min, sizing.max
+                );
+                continue;
+            }
+        };
+        compared += 1;
+        let is_concordant = within_range(sizing, called, args.tolerance);
+        if is_concordant {
+            concordant += 1;
+        }
+        println!(
+            "{locus}\t{}\t{}\t{}\t{called:.1}\t{is_concordant}",
+            sizing.sample, sizing.min, sizing.max
+        );
+    }
+    eprintln!("Sizings\t{}", sizings.len());
+    eprintln!("Compared\t{compared}");
+    eprintln!(
+        "Concordant\t{concordant}\t{:.4}",
+        concordant as f64 / compared.max(1) as f64
+    );
+}
+
+// the lengths of the sample in the first overlapping call that has the sample,
+// as every single-sample vcf has its own call of the locus
+fn called_lengths<'a>(
+    sizing: &Sizing,
+    calls: &'a HashMap<String, Vec<Call>>,
+) -> Option<&'a Vec<usize>> {
+    calls.get(&sizing.chrom)?.iter().find_map(|call| {
+        if call.start <= sizing.end && call.end >= sizing.start {
+            call.lengths.get(&sizing.sample).filter(|lengths| !lengths.is_empty())
+        } else {
+            None
+        }
+    })
+}
+
+// the called allele closest to the sizing range, in repeat units
+fn closest_allele(sizing: &Sizing, lengths: &[usize]) -> Option<f32> {
+    let distance = |size: f32| {
+        if size < sizing.min {
+            sizing.min - size
+        } else {
+            (size - sizing.max).max(0.0)
+        }
+    };
+    lengths
+        .iter()
+        .map(|length| *length as f32 / sizing.motif_length as f32)
+        .min_by(|a, b| distance(*a).partial_cmp(&distance(*b)).unwrap())
+}
+
+fn within_range(sizing: &Sizing, size: f32, tolerance: f32) -> bool {
+    size >= sizing.min - tolerance && size <= sizing.max + tolerance
+}
+
+fn parse_sizing_line(line: &str) -> Sizing {
+    let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+    if fields.len() < 4 {
+        panic!("Expected at least four columns in sizing line {line}");
+    }
+    let (chrom, interval) = fields[0]
+        .rsplit_once(':')
+        .unwrap_or_else(|| panic!("Invalid locus {} in sizing line {line}", fields[0]));
+    let (start, end) = interval
+        .split_once('-')
+        .unwrap_or_else(|| panic!("Invalid locus {} in sizing line {line}", fields[0]));
+    let position = |value: &str| -> u32 {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid locus {} in sizing line {line}", fields[0]))
+    };
+    let number = |value: &str| -> f32 {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid size {value} in sizing line {line}"))
+    };
+    let motif_length = fields
+        .get(4)
+        .filter(|f| !f.is_empty())
+        .map_or(1, |f| number(f) as usize);
+    if motif_length == 0 {
+        panic!("Invalid motif length {} in sizing line {line}", fields[4]);
+    }
+    Sizing {
+        chrom: chrom.to_string(),
+        start: position(start),
+        end: position(end),
+        sample: fields[1].to_string(),
+        min: number(fields[2]),
+        max: number(fields[3]),
+        motif_length,
+    }
+}
+
+fn parse_calls(vcf: &str, calls: &mut HashMap<String, Vec<Call>>) {
+    let mut samples = vec![];
+    for line in crate::utils::reader(vcf).lines() {
+        let line = line.unwrap_or_else(|err| panic!("Failed reading {vcf}: {err}"));
+        if line.starts_with("##") {
+            continue;
+        } else if line.starts_with('#') {
+            samples = line.split('\t').skip(9).map(|s| s.to_string()).collect();
+        } else if let Some((chrom, call)) = parse_call_line(&line, &samples) {
+            calls.entry(chrom).or_default().push(call);
+        }
+    }
+    debug!("Parsed calls for {} samples from {vcf}", samples.len());
+}
+
+// the full repeat lengths are taken from the FRB format field of every sample
+fn parse_call_line(line: &str, samples: &[String]) -> Option<(String, Call)> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 10 {
+        return None;
+    }
+    let end = fields[7]
+        .split(';')
+        .find_map(|info| info.strip_prefix("END="))?
+        .parse()
+        .ok()?;
+    let index = fields[8].split(':').position(|key| key == "FRB")?;
+    let lengths = samples
+        .iter()
+        .zip(fields.iter().skip(9))
+        .filter_map(|(sample, column)| {
+            let frb = column.split(':').nth(index)?;
+            let lengths = frb
+                .split(',')
+                .filter_map(|l| l.parse::<usize>().ok())
+                .collect::<Vec<usize>>();
+            Some((sample.clone(), lengths))
+        })
+        .collect();
+    Some((
+        fields[0].to_string(),
+        Call {
+            start: fields[1].parse().ok()?,
+            end,
+            lengths,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sizing_line() {
+        let sizing = parse_sizing_line("chr4:3074876-3074933, patient1, 40, 42, 3");
+        assert_eq!(sizing.chrom, "chr4");
+        assert_eq!(sizing.start, 3074876);
+        assert_eq!(sizing.sample, "patient1");
+        assert_eq!((sizing.min, sizing.max, sizing.motif_length), (40.0, 42.0, 3));
+        assert_eq!(parse_sizing_line("chr4:1-10,patient1,100,200").motif_length, 1);
+    }
+
+    #[test]
+    fn test_closest_allele() {
+        let sizing = parse_sizing_line("chr4:3074876-3074933,patient1,40,42,3");
+        // a normal allele of 20 units and an expansion of 43 units
+        let called = closest_allele(&sizing, &[60, 129]).expect("No closest allele");
+        assert_eq!(called, 43.0);
+        assert!(!within_range(&sizing, called, 0.0));
+        assert!(within_range(&sizing, called, 1.0));
+        assert_eq!(closest_allele(&sizing, &[]), None);
+    }
+
+    #[test]
+    fn test_parse_call_line() {
+        let samples = vec!["sample1".to_string(), "sample2".to_string()];
+        let line = "chr4\t3074876\t.\tCAGCAG\tCAGCAGCAG\t.\t.\tEND=3074933\tGT:RB:FRB\t1|0:3,0:9,6\t.:.:.";
+        let (chrom, call) = parse_call_line(line, &samples).expect("Failed parsing call");
+        assert_eq!(chrom, "chr4");
+        assert_eq!(call.end, 3074933);
+        assert_eq!(call.lengths["sample1"], vec![9, 6]);
+        assert!(call.lengths["sample2"].is_empty());
+    }
+
+    #[test]
+    fn test_called_lengths() {
+        // two single-sample vcfs with a call of the same locus
+        let line = |sample: &str, frb: &str| {
+            let line = format!(
+                "chr4\t3074876\t.\tCAG\tCAGCAG\t.\t.\tEND=3074933\tGT:FRB\t1|0:{frb}"
+            );
+            parse_call_line(&line, &[sample.to_string()]).expect("Failed parsing call")
+        };
+        let calls = HashMap::from([(
+            "chr4".to_string(),
+            vec![line("patient1", "6,3").1, line("patient2", "9,3").1],
+        )]);
+        let sizing = parse_sizing_line("chr4:3074876-3074933,patient2,3,3,3");
+        assert_eq!(called_lengths(&sizing, &calls), Some(&vec![9, 3]));
+        let sizing = parse_sizing_line("chr4:3074876-3074933,patient3,3,3,3");
+        assert_eq!(called_lengths(&sizing, &calls), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_motif_length() {
+        parse_sizing_line("chr4:1-10,patient1,100,200,0");
+    }
+}
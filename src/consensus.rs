@@ -1,8 +1,15 @@
+use crate::locus_log::{log_locus, Stage};
+use crate::ConsensusMethod;
+use std::collections::HashMap;
 use std::fmt;
 use bio::alignment::{pairwise::Scoring, poa::Aligner};
-use log::debug;
+use log::Level;
 use rand::seq::SliceRandom;
 
+// reads with a length further than this many MADs from the median length are trimmed
+const MAD_TRIM_FACTOR: f32 = 3.0;
+// but only if the deviation is also larger than this number of bases
+const MIN_TRIM_DEVIATION: f32 = 5.0;
 
 #[derive(Clone)]
 pub struct Consensus {
@@ -10,6 +17,8 @@ pub struct Consensus {
     pub support: usize,
     pub std_dev: usize,
     pub score: i32,
+    pub length_ci: Option<(usize, usize)>, // 5th and 95th percentile of the supporting read lengths
+    pub trimmed: usize, // number of reads trimmed as outlier by the median absolute deviation
 }
 
 impl Default for Consensus {
@@ -19,6 +28,8 @@ impl Default for Consensus {
             support: 0,
             std_dev: 0,
             score: -1,
+            length_ci: None,
+            trimmed: 0,
         }
     }
 }
@@ -40,6 +51,7 @@ pub fn consensus(
     seqs: &[String],
     support: usize,
     repeat: &crate::repeats::RepeatInterval,
+    method: ConsensusMethod,
 ) -> Consensus {
     if seqs.is_empty() {
         return Consensus {
@@ -47,14 +59,24 @@ pub fn consensus(
             support: 0,
             std_dev: 0,
             score: -1,
+            length_ci: None,
+            trimmed: 0,
         };
     }
     let num_reads_ = seqs.len();
     let (seqs, std_dev) = remove_outliers(seqs, repeat);
+    // a single chimeric read can still shift the allele length at low coverage
+    let (seqs, trimmed) = trim_mad_outliers(seqs);
     let num_reads = seqs.len();
-    debug!(
-        "{repeat}: Kept {}/{} reads after dropping outliers",
-        num_reads, num_reads_
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Genotyping,
+        format_args!(
+            "Kept {}/{} reads after dropping outliers, of which {trimmed} by MAD trimming",
+            num_reads,
+            num_reads_
+        ),
     );
     if num_reads < support {
         Consensus {
@@ -62,57 +84,29 @@ pub fn consensus(
             support: num_reads,
             std_dev,
             score: -1,
+            length_ci: None,
+            trimmed,
         }
     } else {
-        // if there are more than 20 reads, downsample to 20 before taking the consensus
-        // for performance and memory reasons
-        let seqs = if num_reads > 20 {
-            debug!("{repeat}: Too many reads, downsampling to 20");
-            seqs.choose_multiple(&mut rand::thread_rng(), 20)
-                .cloned()
-                .collect::<Vec<&String>>()
-        } else {
-            seqs
+        // the confidence interval is taken from all reads that are kept, before downsampling
+        let length_ci = Some(length_interval(&seqs));
+        let (seq, score) = match method {
+            ConsensusMethod::Poa | ConsensusMethod::SpoaLocal => {
+                poa(seqs, method == ConsensusMethod::SpoaLocal, repeat)
+            }
+            // no alignment is done, so there is no alignment score
+            ConsensusMethod::Majority => (majority(&seqs), 0),
+            ConsensusMethod::LongestRead => (longest_read(&seqs), 0),
+            ConsensusMethod::MedianLength => (median_length(&seqs), 0),
         };
-        let mut seqs_bytes = vec![];
-        // code below is for rust-bio, but going back to rust-spoa now to avoid errors
-        // hopefully able to revert back to rust-bio as soon as those errors are patched
-        for seq in seqs.iter() {
-            seqs_bytes.push(seq.to_string().bytes().collect::<Vec<u8>>());
-        }
-        
-        // let consensus_max_length = seqs.iter().map(|x| x.len()).max().unwrap_or(0);
-        // for seq in seqs.iter() {
-        //     seqs_bytes.push(format!("{seq}\0").bytes().collect::<Vec<u8>>());
-        // }
-        // let consensus = poa_consensus(
-        //     &seqs_bytes,
-        //     consensus_max_length,
-        //     1,   // 0 = local, 1 = global, 2 = gapped
-        //     3,   // match_score,
-        //     -4,  // mismatch_score,
-        //     -12, // gap_open,
-        //     -6,  // gap_extend,
-        // );    
-
-        // code below is again for rust-bio poa
-        // I empirically determined the following parameters to be suitable,
-        // but further testing on other repeats would be good
-        // mainly have to make sure the consensus does not get longer than the individual insertions
-        let scoring = Scoring::new(-12, -6, |a: u8, b: u8| if a == b { 3 } else { -4 });
-        let mut aligner = Aligner::new(scoring, &seqs_bytes[0]);
-        for seq in seqs_bytes.iter().skip(1) {
-            aligner.global(seq).add_to_graph();
-        }
-
-        let consensus = aligner.consensus();
-        let score = aligner.global(&consensus).alignment().score;
 
         Consensus {
-            seq: Some(std::str::from_utf8(&consensus).unwrap().to_string()),
+            seq: Some(seq),
             support: num_reads,
             std_dev,
             score,
+            length_ci,
+            trimmed,
         }
         // Consensus {
         //     seq: Some(std::str::from_utf8(&consensus).unwrap().to_string()),
@@ -123,6 +117,139 @@ pub fn consensus(
     }
 }
 
+// partial order alignment of the reads, with a global or local alignment of every read to the graph
+fn poa(
+    seqs: Vec<&String>,
+    local: bool,
+    repeat: &crate::repeats::RepeatInterval,
+) -> (String, i32) {
+    // if there are more than 20 reads, downsample to 20 before taking the consensus
+    // for performance and memory reasons
+    let seqs = if seqs.len() > 20 {
+        log_locus(Level::Debug, repeat, Stage::Genotyping, "Too many reads, downsampling to 20");
+        seqs.choose_multiple(&mut rand::thread_rng(), 20)
+            .cloned()
+            .collect::<Vec<&String>>()
+    } else {
+        seqs
+    };
+    let mut seqs_bytes = vec![];
+    // code below is for rust-bio, but going back to rust-spoa now to avoid errors
+    // hopefully able to revert back to rust-bio as soon as those errors are patched
+    for seq in seqs.iter() {
+        seqs_bytes.push(seq.to_string().bytes().collect::<Vec<u8>>());
+    }
+    
+    // let consensus_max_length = seqs.iter().map(|x| x.len()).max().unwrap_or(0);
+    // for seq in seqs.iter() {
+    //     seqs_bytes.push(format!("{seq}\0").bytes().collect::<Vec<u8>>());
+    // }
+    // let consensus = poa_consensus(
+    //     &seqs_bytes,
+    //     consensus_max_length,
+    //     1,   // 0 = local, 1 = global, 2 = gapped
+    //     3,   // match_score,
+    //     -4,  // mismatch_score,
+    //     -12, // gap_open,
+    //     -6,  // gap_extend,
+    // );    
+
+    // code below is again for rust-bio poa
+    // I empirically determined the following parameters to be suitable,
+    // but further testing on other repeats would be good
+    // mainly have to make sure the consensus does not get longer than the individual insertions
+    let scoring = Scoring::new(-12, -6, |a: u8, b: u8| if a == b { 3 } else { -4 });
+    let mut aligner = Aligner::new(scoring, &seqs_bytes[0]);
+    for seq in seqs_bytes.iter().skip(1) {
+        // with --timeout, the locus is abandoned and the consensus of the reads so far is not used
+        if crate::genotype::deadline_passed() {
+            break;
+        }
+        if local {
+            aligner.local(seq).add_to_graph();
+        } else {
+            aligner.global(seq).add_to_graph();
+        }
+    }
+
+    let consensus = aligner.consensus();
+    let score = if local {
+        aligner.local(&consensus).alignment().score
+    } else {
+        aligner.global(&consensus).alignment().score
+    };
+    (std::str::from_utf8(&consensus).unwrap().to_string(), score)
+}
+
+// the most frequent sequence, or the read with the median length if no sequence is seen twice
+fn majority(seqs: &[&String]) -> String {
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for seq in seqs {
+        *counts.entry(seq).or_insert(0) += 1;
+    }
+    // ties are broken by the sequence itself to keep the result deterministic
+    let (seq, count) = counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+        .expect("No sequences to take the majority of");
+    if count > 1 {
+        seq.to_string()
+    } else {
+        median_length(seqs)
+    }
+}
+
+// the read with the median length, the lower median for an even number of reads
+fn median_length(seqs: &[&String]) -> String {
+    let mut sorted = seqs.to_vec();
+    sorted.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    sorted[(sorted.len() - 1) / 2].to_string()
+}
+
+fn longest_read(seqs: &[&String]) -> String {
+    seqs.iter()
+        .max_by(|a, b| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+        .expect("No sequences to take the longest of")
+        .to_string()
+}
+
+// the 5th and 95th percentile of the sequence lengths, using the nearest rank
+fn length_interval(seqs: &[&String]) -> (usize, usize) {
+    let mut lengths = seqs.iter().map(|x| x.len()).collect::<Vec<usize>>();
+    lengths.sort_unstable();
+    let rank = |quantile: f32| ((lengths.len() - 1) as f32 * quantile).round() as usize;
+    (lengths[rank(0.05)], lengths[rank(0.95)])
+}
+
+// remove reads with a length beyond MAD_TRIM_FACTOR median absolute deviations from the median
+// returns the kept reads and the number of trimmed reads
+fn trim_mad_outliers(seqs: Vec<&String>) -> (Vec<&String>, usize) {
+    if seqs.len() < 3 {
+        return (seqs, 0);
+    }
+    let median = |values: &mut Vec<f32>| {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mid = values.len() / 2;
+        if values.len() % 2 == 0 {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        }
+    };
+    let mut lengths = seqs.iter().map(|x| x.len() as f32).collect::<Vec<f32>>();
+    let center = median(&mut lengths);
+    let mut deviations = lengths.iter().map(|l| (l - center).abs()).collect::<Vec<f32>>();
+    let mad = median(&mut deviations);
+    let max_deviation = (MAD_TRIM_FACTOR * mad).max(MIN_TRIM_DEVIATION);
+    let num_reads = seqs.len();
+    let kept = seqs
+        .into_iter()
+        .filter(|seq| (seq.len() as f32 - center).abs() <= max_deviation)
+        .collect::<Vec<&String>>();
+    let trimmed = num_reads - kept.len();
+    (kept, trimmed)
+}
+
 fn remove_outliers<'a>(
     seqs: &'a [String],
     repeat: &crate::repeats::RepeatInterval,
@@ -130,7 +257,7 @@ fn remove_outliers<'a>(
     // remove sequences that are shorter or longer than two standard deviations from the mean
     // except if the stdev is small
     let lengths = seqs.iter().map(|x| x.len()).collect::<Vec<usize>>();
-    debug!("{repeat}: lengths: {:?}", lengths);
+    log_locus(Level::Debug, repeat, Stage::Genotyping, format_args!("lengths: {:?}", lengths));
 
     let mean = lengths.iter().sum::<usize>() / lengths.len();
     let variance = lengths
@@ -142,15 +269,30 @@ fn remove_outliers<'a>(
     // so this keeps the std_dev smaller than it really is, but not by a lot
     // the places where this matter are probably negligible
     let std_dev = (variance as f64).sqrt() as usize;
-    debug!("mean: {}, std_dev: {}", mean, std_dev);
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Genotyping,
+        format_args!("mean: {}, std_dev: {}", mean, std_dev),
+    );
     if std_dev < 5 {
-        debug!("std_dev < 5, not removing any outliers");
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            "std_dev < 5, not removing any outliers",
+        );
         (seqs.iter().collect::<Vec<&String>>(), std_dev)
     } else {
         // avoid underflowing usize
         let min_val = mean.saturating_sub(2 * std_dev);
         let max_val = mean + 2 * std_dev;
-        debug!("Removing outliers outside of [{},{}]", min_val, max_val);
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            format_args!("Removing outliers outside of [{},{}]", min_val, max_val),
+        );
         let filtered_seqs = seqs
             .iter()
             .zip(lengths.iter())
@@ -197,7 +339,11 @@ mod tests {
                 chrom: "chr1".to_string(),
                 start: 1,
                 end: 100,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
+            ConsensusMethod::Poa,
         );
         println!("Consensus: {}", cons.seq.unwrap());
         println!("Num reads: {}", cons.support);
@@ -374,4 +520,36 @@ mod tests {
         println!("Consensus score: {}", score);
 }
 
+    #[test]
+    fn test_majority_and_longest_read() {
+        let seqs = ["CAGCAGCAG", "CAGCAGCAG", "CAGCAACAG", "CAGCAGCAGCAG"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        let seqs = seqs.iter().collect::<Vec<&String>>();
+        assert_eq!(majority(&seqs), "CAGCAGCAG");
+        assert_eq!(longest_read(&seqs), "CAGCAGCAGCAG");
+        // without a sequence seen twice the read with the median length is taken
+        assert_eq!(majority(&seqs[1..]), "CAGCAGCAG");
+        assert_eq!(median_length(&seqs[2..]), "CAGCAACAG");
+    }
+
+    #[test]
+    fn test_trim_mad_outliers() {
+        // a single chimeric read among reads of similar length
+        let seqs = ["CAG".repeat(10), "CAG".repeat(10), "CAG".repeat(11), "CAG".repeat(30)];
+        let (kept, trimmed) = trim_mad_outliers(seqs.iter().collect());
+        assert_eq!(trimmed, 1);
+        assert_eq!(kept.len(), 3);
+        // small differences in length are not trimmed
+        let seqs = ["CAG".repeat(10), "CAG".repeat(10), "CAG".repeat(11)];
+        assert_eq!(trim_mad_outliers(seqs.iter().collect()).1, 0);
+    }
+
+    #[test]
+    fn test_length_interval() {
+        let seqs = (1..=20).map(|i| "A".repeat(i)).collect::<Vec<String>>();
+        let seqs = seqs.iter().collect::<Vec<&String>>();
+        assert_eq!(length_interval(&seqs), (2, 19));
+    }
 }
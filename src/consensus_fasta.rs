@@ -0,0 +1,85 @@
+use crate::vcf::VCFRecord;
+use log::error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+/// A fasta file with the consensus sequence of every allele
+/// The header is >sample_chrom:start-end_hapN, with N the allele in the order of the genotype
+pub struct ConsensusFasta {
+    path: String,
+    sample: String,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl ConsensusFasta {
+    pub fn create(path: &str, sample: &str) -> ConsensusFasta {
+        let file = File::create(path)
+            .unwrap_or_else(|err| panic!("Failed creating consensus fasta {path}: {err}"));
+        ConsensusFasta {
+            path: path.to_string(),
+            sample: sample.to_string(),
+            writer: Mutex::new(BufWriter::new(file)),
+        }
+    }
+
+    /// Write the consensus of every allele of a record, alleles without a consensus are skipped
+    pub fn add(&self, record: &VCFRecord) {
+        let mut writer = self.writer.lock().expect("Unable to lock consensus fasta");
+        for (hap, seq) in consensus_sequences(record) {
+            writeln!(
+                writer,
+                ">{}_{}:{}-{}_hap{hap}\n{seq}",
+                self.sample, record.chrom, record.start, record.end
+            )
+            .unwrap_or_else(|err| panic!("Failed writing consensus fasta {}: {err}", self.path));
+        }
+    }
+}
+
+impl Drop for ConsensusFasta {
+    fn drop(&mut self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            if let Err(err) = writer.flush() {
+                error!("Failed writing consensus fasta {}: {err}", self.path);
+            }
+        }
+    }
+}
+
+/// The haplotype number and consensus of every allele, only the first for haploid loci
+pub fn consensus_sequences(record: &VCFRecord) -> Vec<(usize, &str)> {
+    let alleles = if record.haploid {
+        vec![record.consensus.0.as_str()]
+    } else {
+        [&record.consensus.0, &record.consensus.1]
+            .into_iter()
+            .chain(record.extra_alleles.iter().map(|(_, allele)| &allele.seq))
+            .map(|seq| seq.as_str())
+            .collect()
+    };
+    alleles
+        .into_iter()
+        .enumerate()
+        .filter(|(_, seq)| *seq != "." && !seq.is_empty())
+        .map(|(index, seq)| (index + 1, seq))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus_fasta() {
+        let path = crate::test_utils::temp_path("consensus.fa");
+        let path = path.as_str();
+        let repeat = crate::repeats::RepeatInterval::new("chr7", 100, 106);
+        let mut record = crate::test_utils::cag_record(&repeat, "0");
+        record.consensus = ("CAGCAGCAG".to_string(), ".".to_string());
+        ConsensusFasta::create(path, "HG002").add(&record);
+        let content = std::fs::read_to_string(path).expect("Failed reading consensus fasta");
+        assert_eq!(content, ">HG002_chr7:100-106_hap1\nCAGCAGCAG\n");
+        std::fs::remove_file(path).expect("Failed removing consensus fasta");
+    }
+}
@@ -0,0 +1,149 @@
+use crate::repeats::RepeatIntervalIterator;
+use crate::{genotype, parse_bam, Cli};
+use clap::Parser;
+use log::{error, info};
+use std::path::Path;
+
+// the demo data is a slice of the test data of this repository, downloaded on demand:
+// the reads overlapping the demo loci, and chr7 with only the sequence around them, the rest masked by N
+const DEMO_URL: &str = "https://github.com/wdecoster/STRdust/raw/main/test_data/demo";
+const DEMO_FILES: [&str; 5] = [
+    "chr7-demo.fa.gz",
+    "chr7-demo.fa.gz.fai",
+    "chr7-demo.fa.gz.gzi",
+    "demo.bam",
+    "demo.bam.bai",
+];
+// the loci covered by the reads in the demo bam file, with the expected length of the alleles
+// relative to the reference, the median net insertion of the reads of either haplotype:
+// a heterozygous expansion, a homozygous expansion and a homozygous contraction
+const DEMO_LOCI: [(&str, (i32, i32)); 3] = [
+    ("chr7:154654404-154654432", (46, 52)),
+    ("chr7:154666207-154666235", (8, 8)),
+    ("chr7:154669539-154669567", (-4, -4)),
+];
+// the consensus of an allele can differ by a few bases from the median of its reads
+const LENGTH_TOLERANCE: i32 = 5;
+
+/// Genotype a small demo dataset, to verify that the installation works end to end
+#[derive(clap::Args, Debug)]
+pub struct DemoArgs {
+    /// Directory to download the demo data to, files that are already present are reused
+    #[clap(long, value_parser, default_value = "strdust-demo")]
+    dir: String,
+}
+
+pub fn run(args: DemoArgs) {
+    std::fs::create_dir_all(&args.dir)
+        .unwrap_or_else(|err| panic!("Failed creating directory {}: {err}", args.dir));
+    for file in DEMO_FILES {
+        download(&args.dir, file);
+    }
+    let fasta = format!("{}/chr7-demo.fa.gz", args.dir);
+    let bam = format!("{}/demo.bam", args.dir);
+    let mut reader = parse_bam::create_bam_reader(&bam, &fasta);
+    let mut failed = 0;
+    for (locus, lengths) in DEMO_LOCI {
+        // the demo runs with the default parameters
+        let cli = Cli::parse_from(["STRdust", &fasta, &bam, "--region", locus]);
+        for repeat in RepeatIntervalIterator::from_string(locus, &fasta) {
+            let inputs = genotype::RunInputs::default();
+            match genotype::genotype_repeat_singlethreaded(&repeat, &cli, &mut reader, &inputs) {
+                Ok(record) if has_lengths(&record, lengths) => println!("{record}"),
+                Ok(record) => {
+                    error!("Demo locus {repeat} was not genotyped as expected:\n{record}");
+                    failed += 1;
+                }
+                Err(err) => {
+                    error!("Failed genotyping demo locus {repeat}: {err}");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        error!("Demo failed for {failed} of {} loci", DEMO_LOCI.len());
+        std::process::exit(1);
+    }
+    println!("STRdust demo finished successfully: the installation works");
+}
+
+// download a file of the demo data, unless it was downloaded before
+fn download(dir: &str, file: &str) {
+    let path = Path::new(dir).join(file);
+    if path.is_file() {
+        info!("Using {} from a previous download", path.display());
+        return;
+    }
+    info!("Downloading {file}");
+    let url = format!("{DEMO_URL}/{file}");
+    let content = reqwest::blocking::get(&url)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+        .unwrap_or_else(|err| panic!("Failed downloading {url}: {err}"));
+    // write to a temporary file first, such that an interrupted download is not reused
+    let partial = Path::new(dir).join(format!("{file}.part"));
+    std::fs::write(&partial, &content)
+        .unwrap_or_else(|err| panic!("Failed writing {}: {err}", partial.display()));
+    std::fs::rename(&partial, &path)
+        .unwrap_or_else(|err| panic!("Failed writing {}: {err}", path.display()));
+}
+
+// the demo loci are expected to be genotyped for both haplotypes, with the shortest allele first
+fn has_lengths(record: &crate::vcf::VCFRecord, expected: (i32, i32)) -> bool {
+    let mut lengths = match (record.length.0.parse::<i32>(), record.length.1.parse::<i32>()) {
+        (Ok(length1), Ok(length2)) => [length1, length2],
+        _ => return false,
+    };
+    lengths.sort_unstable();
+    (lengths[0] - expected.0).abs() <= LENGTH_TOLERANCE
+        && (lengths[1] - expected.1).abs() <= LENGTH_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the demo data is available locally as test data
+    #[test]
+    fn test_demo_loci_are_called() {
+        // the slice gives the same genotypes as the full test data
+        for (fasta, bam) in [
+            ("test_data/demo/chr7-demo.fa.gz", "test_data/demo/demo.bam"),
+            ("test_data/chr7.fa.gz", "test_data/small-test-phased.bam"),
+        ] {
+            let mut reader = parse_bam::create_bam_reader(bam, fasta);
+            for (locus, lengths) in DEMO_LOCI {
+                let cli = Cli::parse_from(["STRdust", fasta, bam, "--region", locus]);
+                let repeat = RepeatIntervalIterator::from_string(locus, fasta)
+                    .next()
+                    .expect("Failed parsing demo locus");
+                let inputs = genotype::RunInputs::default();
+                let record =
+                    genotype::genotype_repeat_singlethreaded(&repeat, &cli, &mut reader, &inputs)
+                        .expect("Unable to genotype repeat");
+                assert!(has_lengths(&record, lengths), "{locus}: {record}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_has_lengths() {
+        let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+        let mut record = crate::vcf::VCFRecord::missing_genotype(&repeat, "CAG", "0".to_string());
+        assert!(!has_lengths(&record, (46, 52)));
+        record.length = ("50".to_string(), "44".to_string());
+        assert!(has_lengths(&record, (46, 52)));
+        record.length.0 = "60".to_string();
+        assert!(!has_lengths(&record, (46, 52)));
+    }
+
+    // this test is ignored as it requires downloading the demo data
+    #[test]
+    #[ignore]
+    fn test_demo() {
+        run(DemoArgs {
+            dir: String::from("strdust-demo"),
+        });
+    }
+}
@@ -0,0 +1,198 @@
+use crate::parse_bam;
+use log::{debug, info};
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Cigar;
+use rust_htslib::bam::Read;
+use std::collections::{HashMap, HashSet};
+
+/// Scan the genome or the regions in a bed file for large insertions with repetitive content,
+/// clustered by position into candidate expansion loci, without a catalog of repeats
+/// The candidates are written as bed to stdout, with the motif, number of reads and median insertion length
+#[derive(clap::Args, Debug)]
+pub struct DiscoverArgs {
+    /// reference genome
+    fasta: String,
+
+    /// bam file to scan
+    bam: String,
+
+    /// Bed file with region(s) to scan, the whole genome if not provided
+    #[clap(short = 'R', long, value_parser)]
+    region_file: Option<String>,
+
+    /// minimal length of an insertion to consider
+    #[clap(long, value_parser, default_value_t = 100)]
+    min_insertion: u32,
+
+    /// maximal length of the motif of a repetitive insertion
+    #[clap(long, value_parser, default_value_t = 6)]
+    max_motif: usize,
+
+    /// maximal distance between insertions in the same candidate locus
+    #[clap(long, value_parser, default_value_t = 100)]
+    cluster_distance: i64,
+
+    /// minimal number of reads with a repetitive insertion to report a candidate locus
+    #[clap(long, value_parser, default_value_t = 3)]
+    min_reads: usize,
+}
+
+/// A repetitive insertion in a read, with the reference position at which it is inserted
+#[derive(Debug, Clone)]
+struct Insertion {
+    pos: i64,
+    length: u32,
+    motif: String,
+    read: String,
+}
+
+pub fn run(args: DiscoverArgs) {
+    let regions = match &args.region_file {
+        Some(bed) => crate::instability::bed_regions(bed),
+        None => crate::instability::genome_regions(&args.fasta),
+    };
+    let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
+    let mut candidates = 0;
+    for (chrom, start, end) in regions {
+        let tid = match bam.header().tid(chrom.as_bytes()) {
+            Some(tid) => tid,
+            None => {
+                debug!("Skipping {chrom}: not in the bam header");
+                continue;
+            }
+        };
+        let insertions = repetitive_insertions(&mut bam, tid, start, end, &args);
+        debug!("{chrom}:{start}-{end}: {} repetitive insertions", insertions.len());
+        for cluster in cluster_insertions(insertions, args.cluster_distance) {
+            let reads = cluster.iter().map(|i| &i.read).collect::<HashSet<_>>().len();
+            if reads < args.min_reads {
+                continue;
+            }
+            let cluster_start = cluster.iter().map(|i| i.pos).min().unwrap_or_default();
+            let cluster_end = cluster.iter().map(|i| i.pos).max().unwrap_or_default() + 1;
+            let mut lengths = cluster.iter().map(|i| i.length).collect::<Vec<u32>>();
+            lengths.sort_unstable();
+            println!(
+                "{chrom}\t{cluster_start}\t{cluster_end}\t{}\t{reads}\t{}",
+                most_frequent_motif(&cluster),
+                lengths[lengths.len() / 2]
+            );
+            candidates += 1;
+        }
+    }
+    info!("Reported {candidates} candidate loci");
+}
+
+// the insertions of at least min_insertion bases with a repeated motif in the reads of a region
+fn repetitive_insertions(
+    bam: &mut bam::IndexedReader,
+    tid: u32,
+    start: u32,
+    end: u32,
+    args: &DiscoverArgs,
+) -> Vec<Insertion> {
+    bam.fetch((tid, start, end)).unwrap_or_else(|err| {
+        panic!("Failure to extract reads from bam for {tid}:{start}-{end}:\n{err}")
+    });
+    let mut insertions = vec![];
+    for r in bam.rc_records() {
+        let r = r.unwrap_or_else(|err| panic!("Error reading BAM file:\n{err}"));
+        if r.mapq() == 0 || r.is_secondary() || r.is_supplementary() {
+            continue;
+        }
+        let seq = r.seq().as_bytes();
+        let read = String::from_utf8_lossy(r.qname()).to_string();
+        let mut ref_pos = r.reference_start();
+        let mut query_pos = 0;
+        for op in r.cigar().iter() {
+            match op {
+                Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                    ref_pos += *len as i64;
+                    query_pos += *len as usize;
+                }
+                Cigar::Del(len) | Cigar::RefSkip(len) => ref_pos += *len as i64,
+                Cigar::SoftClip(len) => query_pos += *len as usize,
+                Cigar::Ins(len) => {
+                    // insertions of reads overlapping the region boundaries are only reported once
+                    let in_region = ref_pos >= start as i64 && ref_pos < end as i64;
+                    if *len >= args.min_insertion && in_region {
+                        let inserted = &seq[query_pos..query_pos + *len as usize];
+                        if let Some(motif) = crate::motif::detect_motif(
+                            &String::from_utf8_lossy(inserted),
+                            args.max_motif,
+                        ) {
+                            insertions.push(Insertion {
+                                pos: ref_pos,
+                                length: *len,
+                                motif,
+                                read: read.clone(),
+                            });
+                        }
+                    }
+                    query_pos += *len as usize;
+                }
+                _ => (),
+            }
+        }
+    }
+    insertions
+}
+
+// group the insertions such that consecutive insertions are at most max_distance apart
+fn cluster_insertions(mut insertions: Vec<Insertion>, max_distance: i64) -> Vec<Vec<Insertion>> {
+    insertions.sort_by_key(|i| i.pos);
+    let mut clusters: Vec<Vec<Insertion>> = vec![];
+    for insertion in insertions {
+        match clusters.last_mut() {
+            Some(cluster)
+                if insertion.pos - cluster.last().expect("Empty cluster").pos <= max_distance =>
+            {
+                cluster.push(insertion)
+            }
+            _ => clusters.push(vec![insertion]),
+        }
+    }
+    clusters
+}
+
+fn most_frequent_motif(cluster: &[Insertion]) -> String {
+    let mut counts = HashMap::new();
+    for insertion in cluster {
+        *counts.entry(insertion.motif.as_str()).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(a, count_a), (b, count_b)| count_a.cmp(count_b).then(b.cmp(a)))
+        .map(|(motif, _)| motif.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insertion(pos: i64, motif: &str, read: &str) -> Insertion {
+        Insertion {
+            pos,
+            length: 150,
+            motif: motif.to_string(),
+            read: read.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_cluster_insertions() {
+        let insertions = vec![
+            insertion(1050, "AGC", "read2"),
+            insertion(1000, "AGC", "read1"),
+            insertion(5000, "AAAAG", "read3"),
+            insertion(1120, "AGC", "read3"),
+        ];
+        let clusters = cluster_insertions(insertions, 100);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].len(), 3);
+        assert_eq!(most_frequent_motif(&clusters[0]), "AGC");
+        assert_eq!(clusters[1][0].pos, 5000);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use std::collections::HashMap;
+use std::path::Path;
+
+// positions match if the words of this length starting there are identical
+const WORD_SIZE: usize = 6;
+// the maximal number of cells per side, longer alleles are binned
+const MAX_CELLS: usize = 600;
+const PLOT_SIZE: f32 = 600.0;
+const MARGIN: usize = 20;
+
+/// Write a self-similarity dotplot of the consensus of every allele as SVG
+/// to <dir>/<chrom>_<start>_<end>_hap<N>.svg, revealing motif switches and internal duplications
+/// A dot at (x, y) means the sequence at position x is repeated at position y
+pub fn write_locus_dotplots(record: &VCFRecord, repeat: &RepeatInterval, dir: &str) {
+    for (hap, seq) in crate::consensus_fasta::consensus_sequences(record) {
+        let svg = dotplot_svg(repeat, hap, seq);
+        let name = format!("{}_{}_{}_hap{hap}.svg", repeat.chrom, repeat.start, repeat.end);
+        let path = Path::new(dir).join(name);
+        std::fs::write(&path, svg)
+            .unwrap_or_else(|err| panic!("Failed writing dotplot to {}: {err}", path.display()));
+    }
+}
+
+// the cells of the self-similarity matrix with a matching word, with the number of bases per cell
+// every word is mapped to the distinct cells it occurs in, which bounds the work for long pure repeats
+fn dot_matrix(seq: &str) -> (Vec<Vec<bool>>, usize) {
+    let seq = seq.to_uppercase();
+    let bases = seq.as_bytes();
+    let bin_size = bases.len().div_ceil(MAX_CELLS).max(1);
+    let cells = bases.len().div_ceil(bin_size);
+    let mut matrix = vec![vec![false; cells]; cells];
+    let mut words: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (pos, word) in bases.windows(WORD_SIZE).enumerate() {
+        let bins = words.entry(word).or_default();
+        if bins.last() != Some(&(pos / bin_size)) {
+            bins.push(pos / bin_size);
+        }
+    }
+    for bins in words.values() {
+        for x in bins {
+            for y in bins {
+                matrix[*y][*x] = true;
+            }
+        }
+    }
+    (matrix, bin_size)
+}
+
+fn dotplot_svg(repeat: &RepeatInterval, hap: usize, seq: &str) -> String {
+    let (matrix, bin_size) = dot_matrix(seq);
+    let cell = PLOT_SIZE / matrix.len().max(1) as f32;
+    let mut body = String::new();
+    for (y, row) in matrix.iter().enumerate() {
+        // consecutive dots in a row are a single rectangle
+        let mut x = 0;
+        while x < row.len() {
+            if !row[x] {
+                x += 1;
+                continue;
+            }
+            let end = x + row[x..].iter().take_while(|dot| **dot).count();
+            body.push_str(&format!(
+                "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{cell:.2}\"/>\n",
+                MARGIN as f32 + x as f32 * cell,
+                2.0 * MARGIN as f32 + y as f32 * cell,
+                (end - x) as f32 * cell
+            ));
+            x = end;
+        }
+    }
+    let size = PLOT_SIZE as usize + 2 * MARGIN;
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{}\">\n<title>{repeat} haplotype {hap}</title>\n<text x=\"{MARGIN}\" y=\"{}\" font-size=\"12\" font-family=\"sans-serif\">{repeat} haplotype {hap} ({} bp, {bin_size} bp per dot)</text>\n{body}</svg>\n",
+        size + MARGIN,
+        MARGIN + 12,
+        seq.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_matrix() {
+        // the CAG repeat matches itself every three bases, the unique sequence only on the diagonal
+        let seq = format!("{}ATCGGATTCA", "CAG".repeat(10));
+        let (matrix, bin_size) = dot_matrix(&seq);
+        assert_eq!((matrix.len(), bin_size), (40, 1));
+        assert!(matrix[0][3] && matrix[3][0] && !matrix[0][1]);
+        assert!(matrix[32][32] && !matrix[32][2]);
+        // long sequences are binned
+        let (matrix, bin_size) = dot_matrix(&"CAG".repeat(1000));
+        assert_eq!((matrix.len(), bin_size), (600, 5));
+    }
+}
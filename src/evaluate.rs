@@ -0,0 +1,262 @@
+use crate::utils::IntervalIndex;
+use human_sort::compare as human_compare;
+use log::debug;
+use std::io::BufRead;
+
+// thresholds in bases of the concordance summary table, next to --tolerance
+const THRESHOLDS: [i64; 4] = [0, 1, 5, 10];
+
+/// Compare the repeat lengths in a STRdust VCF with a truth VCF, such as the GIAB TR benchmark
+/// Per locus results are written as tsv to stdout, the summary table to stderr
+#[derive(clap::Args, Debug)]
+pub struct EvaluateArgs {
+    /// vcf file produced by STRdust
+    vcf: String,
+
+    /// truth vcf file, repeat lengths are taken as the length difference of ALT and REF
+    truth: String,
+
+    /// maximal difference in bases for a locus to be concordant
+    #[clap(long, value_parser, default_value_t = 0)]
+    tolerance: i64,
+
+    /// bases around the repeat interval in which truth variants are matched to the locus
+    #[clap(long, value_parser, default_value_t = 10)]
+    window: u32,
+}
+
+// a variant from the truth vcf, with the length difference of every allele
+struct TruthVariant {
+    pos: u32,
+    lengths: (i64, i64),
+}
+
+// a locus genotyped by STRdust, with the repeat length relative to the reference per allele
+struct CalledLocus {
+    chrom: String,
+    start: u32,
+    end: u32,
+    lengths: Option<(i64, i64)>,
+}
+
+pub fn run(args: EvaluateArgs) {
+    let truth = parse_truth(&args.truth);
+    println!("#chrom\tstart\tend\tcalled\ttruth\tdifference\tconcordant");
+    let mut loci = 0;
+    let mut called = 0;
+    let mut total_difference = 0;
+    let mut concordant = vec![0; THRESHOLDS.len() + 1];
+    for locus in parse_calls(&args.vcf) {
+        loci += 1;
+        let truth_lengths = truth_lengths(&truth, &locus, args.window);
+        let called_lengths = match locus.lengths {
+            Some(lengths) => lengths,
+            None => {
+                println!(
+                    "{}\t{}\t{}\t.\t{},{}\t.\t.",
+                    locus.chrom, locus.start, locus.end, truth_lengths.0, truth_lengths.1
+                );
+                continue;
+            }
+        };
+        called += 1;
+        let difference = length_difference(called_lengths, truth_lengths);
+        total_difference += difference;
+        for (index, threshold) in THRESHOLDS.iter().chain([args.tolerance].iter()).enumerate() {
+            if difference <= *threshold {
+                concordant[index] += 1;
+            }
+        }
+        println!(
+            "{}\t{}\t{}\t{},{}\t{},{}\t{difference}\t{}",
+            locus.chrom,
+            locus.start,
+            locus.end,
+            called_lengths.0,
+            called_lengths.1,
+            truth_lengths.0,
+            truth_lengths.1,
+            difference <= args.tolerance
+        );
+    }
+    eprintln!("Loci\t{loci}");
+    eprintln!("Genotyped\t{called}");
+    if called > 0 {
+        eprintln!("Mean difference (bp)\t{:.2}", total_difference as f64 / called as f64);
+    }
+    for (index, threshold) in THRESHOLDS.iter().chain([args.tolerance].iter()).enumerate() {
+        eprintln!(
+            "Concordant within {threshold}bp\t{}\t{:.4}",
+            concordant[index],
+            concordant[index] as f64 / called.max(1) as f64
+        );
+    }
+}
+
+// the largest difference in bases between the called and truth alleles
+// the alleles are compared sorted by length, as the phasing of both vcfs may not agree
+fn length_difference(called: (i64, i64), truth: (i64, i64)) -> i64 {
+    let called = (called.0.min(called.1), called.0.max(called.1));
+    let truth = (truth.0.min(truth.1), truth.0.max(truth.1));
+    (called.0 - truth.0).abs().max((called.1 - truth.1).abs())
+}
+
+// the length of both truth haplotypes in a locus, as the sum of all truth variants in the locus
+// a locus without truth variants is assumed to be identical to the reference
+fn truth_lengths(
+    truth: &IntervalIndex<(i64, i64)>,
+    locus: &CalledLocus,
+    window: u32,
+) -> (i64, i64) {
+    let start = locus.start.saturating_sub(window) as u64;
+    let end = (locus.end + window) as u64 + 1;
+    truth
+        .overlapping(&locus.chrom, start, end)
+        .fold((0, 0), |lengths, (_, _, variant)| {
+            (lengths.0 + variant.0, lengths.1 + variant.1)
+        })
+}
+
+// the truth variants per chromosome, as an interval of a base at the position of the variant
+fn parse_truth(truth: &str) -> IntervalIndex<(i64, i64)> {
+    let variants = IntervalIndex::new(
+        crate::utils::reader(truth)
+            .lines()
+            .map(|line| line.expect("Failed reading truth vcf"))
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| parse_truth_line(&line))
+            .map(|(chrom, variant)| {
+                let pos = variant.pos as u64;
+                (chrom, pos, pos + 1, variant.lengths)
+            }),
+    );
+    debug!("Parsed {} truth variants", variants.len());
+    variants
+}
+
+// the length difference of the alleles in the genotype, a missing allele counts as reference
+fn parse_truth_line(line: &str) -> Option<(String, TruthVariant)> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 10 {
+        return None;
+    }
+    let ref_len = fields[3].len() as i64;
+    let alts = fields[4].split(',').collect::<Vec<&str>>();
+    let genotype = fields[9].split(':').next()?;
+    let allele_length = |allele: &str| match allele.parse::<usize>() {
+        Ok(index) if index > 0 && index <= alts.len() && !alts[index - 1].starts_with('<') => {
+            alts[index - 1].len() as i64 - ref_len
+        }
+        _ => 0,
+    };
+    let alleles = genotype.split(['|', '/']).collect::<Vec<&str>>();
+    let lengths = match alleles.as_slice() {
+        [allele] => (allele_length(*allele), allele_length(*allele)),
+        [allele1, allele2, ..] => (allele_length(*allele1), allele_length(*allele2)),
+        _ => return None,
+    };
+    Some((
+        fields[0].to_string(),
+        TruthVariant {
+            pos: fields[1].parse().ok()?,
+            lengths,
+        },
+    ))
+}
+
+fn parse_calls(vcf: &str) -> Vec<CalledLocus> {
+    let mut loci = crate::utils::reader(vcf)
+        .lines()
+        .map(|line| line.expect("Failed reading vcf"))
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| parse_call_line(&line))
+        .collect::<Vec<CalledLocus>>();
+    loci.sort_by(|a, b| human_compare(&a.chrom, &b.chrom).then(a.start.cmp(&b.start)));
+    loci
+}
+
+// the repeat lengths are taken from the RB format field
+fn parse_call_line(line: &str) -> Option<CalledLocus> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 10 {
+        return None;
+    }
+    let end = fields[7]
+        .split(';')
+        .find_map(|info| info.strip_prefix("END="))?
+        .parse()
+        .ok()?;
+    let rb = fields[8]
+        .split(':')
+        .position(|key| key == "RB")
+        .and_then(|index| fields[9].split(':').nth(index));
+    let lengths = rb.and_then(|rb| {
+        let lengths = rb
+            .split(',')
+            .map(|l| l.parse::<i64>().ok())
+            .collect::<Option<Vec<i64>>>()?;
+        match lengths.as_slice() {
+            // haploid loci have a single allele
+            [length] => Some((*length, *length)),
+            [length1, length2] => Some((*length1, *length2)),
+            _ => None,
+        }
+    });
+    Some(CalledLocus {
+        chrom: fields[0].to_string(),
+        start: fields[1].parse().ok()?,
+        end,
+        lengths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_call_line() {
+        let line = "chr7\t154654404\t.\tCAGCAG\tCAGCAGCAG\t.\t.\tEND=154654432;STDEV=0,0\tGT:RB:FRB:SUP:SC:CI\t1|0:3,0:9,6:10,12:30,18:3,3,0,0";
+        let locus = parse_call_line(line).expect("Failed parsing call");
+        assert_eq!(locus.end, 154654432);
+        assert_eq!(locus.lengths, Some((3, 0)));
+        let missing = "chr7\t154654404\t.\tCAGCAG\t.\t.\t.\tEND=154654432;STDEV=.,.\tGT:RB:FRB:SUP:SC:CI\t.|.:.,.:.,.:0,.:.,.:.,.,.,.";
+        assert_eq!(parse_call_line(missing).expect("Failed parsing").lengths, None);
+    }
+
+    #[test]
+    fn test_parse_truth_line() {
+        let line = "chr7\t154654410\t.\tA\tACAG,ACAGCAG\t.\tPASS\t.\tGT\t1|2";
+        let (chrom, variant) = parse_truth_line(line).expect("Failed parsing truth");
+        assert_eq!(chrom, "chr7");
+        assert_eq!(variant.lengths, (3, 6));
+        let line = "chr7\t154654410\t.\tACAG\tA\t.\tPASS\t.\tGT\t0/1";
+        assert_eq!(parse_truth_line(line).expect("Failed").1.lengths, (0, -3));
+    }
+
+    #[test]
+    fn test_truth_lengths() {
+        let truth = IntervalIndex::new([
+            ("chr7".to_string(), 95, 96, (3, 0)),
+            ("chr7".to_string(), 150, 151, (3, 3)),
+            ("chr7".to_string(), 300, 301, (9, 9)),
+            ("chr8".to_string(), 120, 121, (6, 6)),
+        ]);
+        let locus = CalledLocus {
+            chrom: "chr7".to_string(),
+            start: 100,
+            end: 140,
+            lengths: None,
+        };
+        // the variants within the window around the locus are summed
+        assert_eq!(truth_lengths(&truth, &locus, 10), (6, 3));
+        assert_eq!(truth_lengths(&truth, &locus, 0), (0, 0));
+    }
+
+    #[test]
+    fn test_length_difference() {
+        // the phase of the alleles does not matter
+        assert_eq!(length_difference((3, 0), (0, 3)), 0);
+        assert_eq!(length_difference((3, 10), (0, 12)), 3);
+    }
+}
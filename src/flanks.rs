@@ -0,0 +1,238 @@
+use crate::consensus::Consensus;
+use crate::phase_insertions::ClusterOptions;
+use crate::repeats::RepeatInterval;
+use crate::ConsensusMethod;
+use bio::alphabets::dna::revcomp;
+use log::{debug, info};
+use rust_htslib::bam::Read;
+use std::collections::HashMap;
+
+/// Genotype a locus given as the sequences of its left and right flank rather than coordinates,
+/// e.g. a locus absent from the reference, using the sequence between the flanks in the reads
+/// The alleles are written as tsv to stdout, with the length, support and consensus sequence
+#[derive(clap::Args, Debug)]
+pub struct FlanksArgs {
+    /// bam file to genotype
+    bam: String,
+
+    /// sequence of the left flank of the locus
+    left: String,
+
+    /// sequence of the right flank of the locus
+    right: String,
+
+    /// reference genome, only required for a cram file
+    #[clap(long, value_parser)]
+    fasta: Option<String>,
+
+    /// Only scan the reads in this region (chrom:start-end) of an indexed bam file, all reads if not provided
+    #[clap(long, value_parser)]
+    region: Option<String>,
+
+    /// name of the locus in the output
+    #[clap(long, value_parser, default_value = "locus")]
+    name: String,
+
+    /// maximal edit distance between a flank and the read
+    #[clap(long, value_parser, default_value_t = 2)]
+    max_edits: usize,
+
+    /// maximal length of the sequence between the flanks
+    #[clap(long, value_parser, default_value_t = 20000)]
+    max_length: usize,
+
+    /// minimal number of supporting reads per allele
+    #[clap(short, long, value_parser, default_value_t = 3)]
+    support: usize,
+
+    /// Reads are not phased
+    #[clap(long, value_parser, default_value_t = false)]
+    unphased: bool,
+
+    /// Algorithm to build the consensus of the sequences per allele
+    #[clap(long, value_enum, default_value_t = ConsensusMethod::Poa)]
+    consensus: ConsensusMethod,
+}
+
+pub fn run(args: FlanksArgs) {
+    let left = args.left.to_ascii_uppercase().into_bytes();
+    let right = args.right.to_ascii_uppercase().into_bytes();
+    let fasta = args.fasta.clone().unwrap_or_default();
+    let seqs = match &args.region {
+        Some(region) => {
+            let mut bam = crate::parse_bam::create_bam_reader(&args.bam, &fasta);
+            bam.fetch(region.as_str()).unwrap_or_else(|err| {
+                panic!("Failure to extract reads from bam for {region}:\n{err}")
+            });
+            sequences_between_flanks(&mut bam, &left, &right, &args)
+        }
+        None => {
+            let mut bam = crate::parse_bam::create_sequential_reader(&args.bam, &fasta);
+            sequences_between_flanks(&mut bam, &left, &right, &args)
+        }
+    };
+    info!(
+        "{}: {} reads with both flanks",
+        args.name,
+        seqs.values().map(|seqs| seqs.len()).sum::<usize>()
+    );
+    println!("#name\tallele\tlength\tsupport\tstd_dev\tsequence");
+    for (allele, consensus) in genotype(seqs, &args).iter().enumerate() {
+        let (length, seq) = match &consensus.seq {
+            Some(seq) => (seq.len().to_string(), seq.as_str()),
+            None => (".".to_string(), "."),
+        };
+        println!(
+            "{}\t{}\t{length}\t{}\t{}\t{seq}",
+            args.name,
+            allele + 1,
+            consensus.support,
+            consensus.std_dev
+        );
+    }
+}
+
+// the sequence between the flanks of every read with both flanks, per phase
+fn sequences_between_flanks(
+    bam: &mut impl Read,
+    left: &[u8],
+    right: &[u8],
+    args: &FlanksArgs,
+) -> HashMap<u8, Vec<String>> {
+    let mut seqs: HashMap<u8, Vec<String>> = HashMap::new();
+    for r in bam.records() {
+        let r = r.unwrap_or_else(|err| panic!("Error reading BAM file {}:\n{err}", args.bam));
+        // supplementary alignments can be hard clipped, the primary alignment has the full read
+        if r.is_secondary() || r.is_supplementary() {
+            continue;
+        }
+        if let Some(between) =
+            between_flanks(&r.seq().as_bytes(), left, right, args.max_edits, args.max_length)
+        {
+            let phase = if args.unphased {
+                0
+            } else {
+                crate::parse_bam::get_phase(&r)
+            };
+            seqs.entry(phase)
+                .or_default()
+                .push(String::from_utf8_lossy(&between).to_string());
+        }
+    }
+    seqs
+}
+
+// the consensus of the phased reads per haplotype, or of the clusters of unphased reads
+// a homozygous locus of unphased reads has a single allele
+fn genotype(mut seqs: HashMap<u8, Vec<String>>, args: &FlanksArgs) -> Vec<Consensus> {
+    let repeat = RepeatInterval::new(&args.name, 0, 0);
+    let consensus =
+        |seqs: &[String]| crate::consensus::consensus(seqs, args.support, &repeat, args.consensus);
+    let hap1 = seqs.remove(&1).unwrap_or_default();
+    let hap2 = seqs.remove(&2).unwrap_or_default();
+    if !hap1.is_empty() || !hap2.is_empty() {
+        return vec![consensus(&hap1), consensus(&hap2)];
+    }
+    let unphased = seqs.remove(&0).unwrap_or_default();
+    if unphased.len() < args.support.max(2) {
+        return vec![consensus(&unphased)];
+    }
+    let split =
+        crate::phase_insertions::split(&unphased, &repeat, false, &ClusterOptions::default());
+    match split.hap2 {
+        Some(hap2) => vec![consensus(&split.hap1), consensus(&hap2)],
+        None => vec![consensus(&split.hap1)],
+    }
+}
+
+/// The sequence between the left and right flank in a read, or in its reverse complement
+/// The flanks are matched with at most max_edits edits, and at most max_length bases apart
+fn between_flanks(
+    read: &[u8],
+    left: &[u8],
+    right: &[u8],
+    max_edits: usize,
+    max_length: usize,
+) -> Option<Vec<u8>> {
+    let between = |seq: &[u8]| {
+        let (_, left_end) = find_flank(seq, left, max_edits)?;
+        let (right_start, _) = find_flank(&seq[left_end..], right, max_edits)?;
+        if right_start > max_length {
+            debug!("Skipping read with flanks {right_start} bases apart");
+            return None;
+        }
+        Some(seq[left_end..left_end + right_start].to_vec())
+    };
+    between(read).or_else(|| between(&revcomp(read)))
+}
+
+/// The start and end of the best match of a flank in a sequence, with at most max_edits edits
+/// Ties are broken by the earliest end
+fn find_flank(seq: &[u8], flank: &[u8], max_edits: usize) -> Option<(usize, usize)> {
+    if flank.is_empty() || seq.len() + max_edits < flank.len() {
+        return None;
+    }
+    // with at most max_edits edits, one of max_edits + 1 pieces of the flank is found exactly
+    let piece = flank.len() / (max_edits + 1);
+    if piece > 0
+        && !(0..=max_edits).any(|i| {
+            let piece = &flank[i * piece..(i + 1) * piece];
+            seq.windows(piece.len()).any(|window| window == piece)
+        })
+    {
+        return None;
+    }
+    // semi-global alignment of the flank to the sequence, with the start of the match per cell
+    let mut previous = (0..=flank.len()).map(|i| (i, 0)).collect::<Vec<(usize, usize)>>();
+    let mut current = previous.clone();
+    let mut best: Option<(usize, usize, usize)> = None;
+    for (j, base) in seq.iter().enumerate() {
+        current[0] = (0, j + 1);
+        for i in 1..=flank.len() {
+            let diagonal = (
+                previous[i - 1].0 + usize::from(flank[i - 1] != *base),
+                previous[i - 1].1,
+            );
+            let insertion = (previous[i].0 + 1, previous[i].1);
+            let deletion = (current[i - 1].0 + 1, current[i - 1].1);
+            current[i] = diagonal.min(insertion).min(deletion);
+        }
+        let (edits, start) = current[flank.len()];
+        if edits <= max_edits && best.map_or(true, |(best_edits, _, _)| edits < best_edits) {
+            best = Some((edits, start, j + 1));
+            if edits == 0 {
+                break;
+            }
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    best.map(|(_, start, end)| (start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_between_flanks() {
+        let left = b"ACGTTGCAAGGT";
+        let right = b"TTGACCATGGCA";
+        let read = [&b"GGGG"[..], left, b"CAGCAGCAGCAG", right, b"AAAA"].concat();
+        assert_eq!(find_flank(&read, left, 0), Some((4, 16)));
+        assert_eq!(
+            between_flanks(&read, left, right, 0, 100),
+            Some(b"CAGCAGCAGCAG".to_vec())
+        );
+        // a read from the reverse strand
+        assert_eq!(
+            between_flanks(&revcomp(&read), left, right, 0, 100),
+            Some(b"CAGCAGCAGCAG".to_vec())
+        );
+        // a mismatch in the left flank and a deletion in the right flank
+        let read =
+            [&b"GGGG"[..], b"ACGTTCCAAGGT", b"CAGCAG", b"TTGACATGGCA", b"AAAA"].concat();
+        assert_eq!(between_flanks(&read, left, right, 0, 100), None);
+        assert_eq!(between_flanks(&read, left, right, 1, 100), Some(b"CAGCAG".to_vec()));
+        assert_eq!(between_flanks(&read, left, right, 1, 5), None);
+    }
+}
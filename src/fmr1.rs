@@ -0,0 +1,235 @@
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Aux;
+use serde_json::{json, Value};
+
+// the CGG repeat in the 5'UTR of FMR1 on GRCh38
+const FMR1_LOCUS: (&str, u32, u32) = ("X", 147912050, 147912110);
+// the lowest number of repeat units of the intermediate, premutation and full mutation categories
+const INTERMEDIATE: usize = 45;
+const PREMUTATION: usize = 55;
+const FULL_MUTATION: usize = 201;
+// a 5mC probability in the ML tag above this threshold is counted as methylated
+const METHYLATED: f32 = 0.5;
+
+/// The FMR1 specific annotation of a genotyped repeat, per allele
+/// copies: number of CGG repeat units, including the AGG interruptions
+/// interruptions: number of AGG interruptions within the repeat
+/// category: normal, intermediate, premutation or full mutation
+/// mosaic: 1 if the lengths of the supporting reads span multiple categories
+/// methylation: fraction of methylated CpGs in the repeat, only for phased or haploid reads
+#[derive(Clone)]
+pub struct Fmr1Annotation {
+    pub copies: (String, String),
+    pub interruptions: (String, String),
+    pub category: (String, String),
+    pub mosaic: (String, String),
+    pub methylation: (String, String),
+}
+
+pub fn is_fmr1(repeat: &RepeatInterval) -> bool {
+    let (chrom, start, end) = FMR1_LOCUS;
+    repeat.chrom.trim_start_matches("chr") == chrom && repeat.start < end && repeat.end > start
+}
+
+impl Fmr1Annotation {
+    pub fn new(record: &VCFRecord, methylation: (Option<f32>, Option<f32>)) -> Fmr1Annotation {
+        let copies = |full_length: &str| full_length.parse::<usize>().ok().map(|l| (l + 1) / 3);
+        let copies1 = copies(&record.full_length.0);
+        let copies2 = copies(&record.full_length.1);
+        let mosaic = |ci: &str| {
+            parse_ci(ci).map_or(".".to_string(), |(lower, upper)| {
+                let ref_len = record.ref_seq.len() as i32;
+                let category_of = |relative: i32| category((ref_len + relative).max(0) as usize / 3);
+                if category_of(lower) == category_of(upper) {
+                    "0".to_string()
+                } else {
+                    "1".to_string()
+                }
+            })
+        };
+        let or_missing = |value: Option<String>| value.unwrap_or_else(|| ".".to_string());
+        let fraction = |value: Option<f32>| or_missing(value.map(|f| format!("{f:.2}")));
+        Fmr1Annotation {
+            copies: (
+                or_missing(copies1.map(|c| c.to_string())),
+                or_missing(copies2.map(|c| c.to_string())),
+            ),
+            interruptions: (
+                interruptions(&record.consensus.0),
+                interruptions(&record.consensus.1),
+            ),
+            category: (
+                or_missing(copies1.map(|c| category(c).to_string())),
+                or_missing(copies2.map(|c| category(c).to_string())),
+            ),
+            mosaic: (mosaic(&record.ci.0), mosaic(&record.ci.1)),
+            methylation: (fraction(methylation.0), fraction(methylation.1)),
+        }
+    }
+
+    pub fn to_json(&self, haploid: bool) -> Value {
+        let values = |values: &(String, String)| {
+            let first = crate::vcf::missing_to_null(&values.0);
+            if haploid {
+                json!([first])
+            } else {
+                json!([first, crate::vcf::missing_to_null(&values.1)])
+            }
+        };
+        json!({
+            "copies": values(&self.copies),
+            "interruptions": values(&self.interruptions),
+            "category": values(&self.category),
+            "mosaic": values(&self.mosaic),
+            "methylation": values(&self.methylation),
+        })
+    }
+}
+
+fn category(copies: usize) -> &'static str {
+    if copies >= FULL_MUTATION {
+        "full_mutation"
+    } else if copies >= PREMUTATION {
+        "premutation"
+    } else if copies >= INTERMEDIATE {
+        "intermediate"
+    } else {
+        "normal"
+    }
+}
+
+// the number of AGG interruptions followed by a CGG unit
+fn interruptions(seq: &str) -> String {
+    if seq == "." {
+        ".".to_string()
+    } else {
+        seq.matches("AGGCGG").count().to_string()
+    }
+}
+
+// parse a 5th to 95th percentile range formatted as lower,upper
+fn parse_ci(ci: &str) -> Option<(i32, i32)> {
+    let (lower, upper) = ci.split_once(',')?;
+    Some((lower.parse().ok()?, upper.parse().ok()?))
+}
+
+/// The fraction of methylated CpGs in the repeat of a read, based on the 5mC calls in the MM and ML tags
+/// Returns None if the read has no 5mC calls in the repeat
+pub fn read_methylation(record: &bam::Record, repeat: &RepeatInterval) -> Option<f32> {
+    let mm = match record.aux(b"MM").or_else(|_| record.aux(b"Mm")) {
+        Ok(Aux::String(mm)) => mm.to_string(),
+        _ => return None,
+    };
+    let ml = match record.aux(b"ML").or_else(|_| record.aux(b"Ml")) {
+        Ok(Aux::ArrayU8(ml)) => ml.iter().collect::<Vec<u8>>(),
+        _ => return None,
+    };
+    // the query positions aligned to the start and end of the repeat
+    let pairs = record
+        .aligned_pairs()
+        .filter(|[_, rpos]| *rpos >= repeat.start as i64 && *rpos < repeat.end as i64)
+        .map(|[qpos, _]| qpos as usize)
+        .collect::<Vec<usize>>();
+    let (qstart, qend) = (*pairs.first()?, *pairs.last()?);
+    let mut seq = record.seq().as_bytes();
+    let reverse = record.is_reverse();
+    // the positions in the MM tag are relative to the sequence in the orientation of sequencing
+    if reverse {
+        seq = bio::alphabets::dna::revcomp(&seq);
+    }
+    let probabilities = methylation_calls(&mm, &ml, &seq)
+        .into_iter()
+        .map(|(pos, prob)| if reverse { (seq.len() - 1 - pos, prob) } else { (pos, prob) })
+        .filter(|(pos, _)| *pos >= qstart && *pos <= qend)
+        .map(|(_, prob)| prob)
+        .collect::<Vec<f32>>();
+    if probabilities.is_empty() {
+        None
+    } else {
+        let methylated = probabilities.iter().filter(|p| **p > METHYLATED).count();
+        Some(methylated as f32 / probabilities.len() as f32)
+    }
+}
+
+// the position in seq and probability of every 5mC call in the MM and ML tags
+fn methylation_calls(mm: &str, ml: &[u8], seq: &[u8]) -> Vec<(usize, f32)> {
+    let mut calls = vec![];
+    let mut ml_index = 0;
+    for entry in mm.split(';').filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(',');
+        let header = fields.next().unwrap_or_default();
+        let base = header.as_bytes().first().copied().unwrap_or(b'N');
+        let codes = header
+            .get(2..)
+            .unwrap_or_default()
+            .trim_end_matches(['?', '.'])
+            .to_string();
+        let code_index = codes.find('m');
+        let strand_is_forward = header.as_bytes().get(1) == Some(&b'+');
+        let positions = seq
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| base == b'N' || b.to_ascii_uppercase() == base)
+            .map(|(pos, _)| pos)
+            .collect::<Vec<usize>>();
+        let mut index = 0;
+        for skip in fields.filter_map(|f| f.parse::<usize>().ok()) {
+            index += skip;
+            // only 5mC calls on the forward strand of the read are used
+            if strand_is_forward && base == b'C' {
+                if let (Some(code_index), Some(pos)) = (code_index, positions.get(index)) {
+                    if let Some(prob) = ml.get(ml_index + code_index) {
+                        calls.push((*pos, *prob as f32 / 255.0));
+                    }
+                }
+            }
+            ml_index += codes.len().max(1);
+            index += 1;
+        }
+    }
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_fmr1() {
+        assert!(is_fmr1(&RepeatInterval::new("chrX", 147912050, 147912110)));
+        assert!(!is_fmr1(&RepeatInterval::new("chr7", 147912050, 147912110)));
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(category(30), "normal");
+        assert_eq!(category(50), "intermediate");
+        assert_eq!(category(100), "premutation");
+        assert_eq!(category(300), "full_mutation");
+    }
+
+    #[test]
+    fn test_interruptions() {
+        let seq = "CGG".repeat(10) + "AGG" + &"CGG".repeat(9) + "AGG" + &"CGG".repeat(9);
+        assert_eq!(interruptions(&seq), "2");
+        assert_eq!(interruptions("."), ".");
+    }
+
+    #[test]
+    fn test_parse_ci() {
+        assert_eq!(parse_ci("3,10"), Some((3, 10)));
+        assert_eq!(parse_ci("-6,-3"), Some((-6, -3)));
+        assert_eq!(parse_ci("-3,3"), Some((-3, 3)));
+        assert_eq!(parse_ci("."), None);
+    }
+
+    #[test]
+    fn test_methylation_calls() {
+        // the first and third C have a 5mC call
+        let calls = methylation_calls("C+m?,0,1;", &[255, 0], b"ACGTCGCG");
+        assert_eq!(calls, vec![(1, 1.0), (6, 0.0)]);
+    }
+}
@@ -1,17 +1,113 @@
-use crate::{parse_bam, Cli};
-use log::debug;
+use crate::locus_log::{log_locus, Stage};
+use crate::multiref::{SecondCall, SecondReference};
+use crate::repeats::PloidyRegions;
+use crate::stats::STATS;
+use crate::{parse_bam, Cli, Karyotype};
+use log::Level;
 use minimap2::*;
 use regex::Regex;
 use rust_htslib::bam;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+// the fraction of reads with a low mapping quality above which the flanks are considered poorly mappable
+const MAX_LOW_MAPQ_FRACTION: f32 = 0.5;
+
+// the size of the flanking sequence on either side of the repeat searched for heterozygous SNVs
+const SNV_PHASING_FLANK: i64 = 10000;
+
+// the maximal motif length detected in the reference of a locus without a motif in the bed file,
+// for --purity and the instability index with --somatic
+const MAX_DETECTED_MOTIF: usize = 6;
+
+// the regular expression to split the cs tag, compiled once
+static CS_REGEX: OnceLock<Regex> = OnceLock::new();
+
+pub fn cs_regex() -> &'static Regex {
+    CS_REGEX.get_or_init(|| Regex::new(r"(:\d+)|(\*\w+)|(\+\w+)|(-\w+)").unwrap())
+}
+
+thread_local! {
+    // when running multithreaded, every thread keeps its own indexedreader
+    // rather than creating a new one for every repeat, with the bam and fasta it was opened for
+    static BAM_READER: RefCell<Option<((String, String), bam::IndexedReader)>> =
+        RefCell::new(None);
+    // with --timeout, the time after which the locus genotyped by this thread is abandoned
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+    // whether a step of the locus genotyped by this thread stopped early because of the timeout
+    static ABANDONED: Cell<bool> = Cell::new(false);
+}
+
+/// Whether the timeout of the locus genotyped by this thread has passed
+/// The slow steps check this to stop early, such that a pathological locus does not block the run
+/// a step that stops early makes the locus a TIMEOUT no-call, as its result is incomplete
+pub fn deadline_passed() -> bool {
+    let passed =
+        DEADLINE.with(|deadline| deadline.get().is_some_and(|deadline| Instant::now() > deadline));
+    if passed {
+        ABANDONED.with(|abandoned| abandoned.set(true));
+    }
+    passed
+}
+
+fn start_deadline(args: &Cli) {
+    let deadline = args
+        .timeout
+        .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+    DEADLINE.with(|d| d.set(deadline));
+    ABANDONED.with(|abandoned| abandoned.set(false));
+}
+
+/// Inputs of a run that are loaded once and shared by all threads
+#[derive(Default)]
+pub struct RunInputs {
+    // with --second-fasta, the loci paired to the catalog in the second reference
+    pub second_reference: Option<SecondReference>,
+    // with --ploidy-bed, regions with an expected number of alleles other than two
+    pub ploidy: Option<PloidyRegions>,
+    // whether the genotyped loci are counted in the statistics of the run, for --stats
+    pub record_stats: bool,
+}
+
+impl RunInputs {
+    /// Load the inputs of --second-fasta, --second-region-file and --ploidy-bed
+    pub fn from_args(args: &Cli) -> RunInputs {
+        // with --second-fasta, every locus is also genotyped against the paired locus in the second reference
+        let second_reference =
+            match (&args.second_fasta, &args.second_region_file, &args.region_file) {
+                (Some(fasta), Some(bed), Some(region_file)) => {
+                    Some(SecondReference::from_beds(region_file, &args.fasta, bed, fasta))
+                }
+                _ => None,
+            };
+        // with --ploidy-bed, loci in the regions are genotyped with their expected number of alleles
+        RunInputs {
+            second_reference,
+            ploidy: args.ploidy_bed.as_ref().map(|bed| PloidyRegions::from_bed(bed)),
+            record_stats: false,
+        }
+    }
+}
 
-// when running multithreaded, the indexedreader has to be created every time again
-// this is probably expensive
 pub fn genotype_repeat_multithreaded(
     repeat: &crate::repeats::RepeatInterval,
     args: &Cli,
+    inputs: &RunInputs,
 ) -> Result<crate::vcf::VCFRecord, String> {
-    let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-    genotype_repeat(repeat, args, &mut bam)
+    BAM_READER.with(|reader| {
+        let mut reader = reader.borrow_mut();
+        let opened = reader
+            .as_ref()
+            .is_some_and(|((bam, fasta), _)| *bam == args.bam && *fasta == args.fasta);
+        if !opened {
+            let bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
+            *reader = Some(((args.bam.clone(), args.fasta.clone()), bam));
+        }
+        let (_, bam) = reader.as_mut().expect("Bam reader was just opened");
+        genotype_repeat(repeat, args, bam, inputs)
+    })
 }
 
 // when running singlethreaded, the indexedreader is created once and simply passed on
@@ -19,25 +115,179 @@ pub fn genotype_repeat_singlethreaded(
     repeat: &crate::repeats::RepeatInterval,
     args: &Cli,
     bam: &mut bam::IndexedReader,
+    inputs: &RunInputs,
 ) -> Result<crate::vcf::VCFRecord, String> {
-    genotype_repeat(repeat, args, bam)
+    genotype_repeat(repeat, args, bam, inputs)
 }
 
 /// This function genotypes a particular repeat defined by chrom, start and end in the specified bam file
 /// All indel cigar operations longer than minlen are considered
 /// The bam file is expected to be phased using the HP tag, unless --unphased is specified
-/// haploid (sex) chromosomes should be listed under --haploid
+/// haploid (sex) chromosomes should be listed under --haploid, or follow from --karyotype
 fn genotype_repeat(
     repeat: &crate::repeats::RepeatInterval,
     args: &Cli,
     bam: &mut bam::IndexedReader,
+    inputs: &RunInputs,
+) -> Result<crate::vcf::VCFRecord, String> {
+    let unphased = reads_are_unphased(repeat, args, inputs);
+    // the timeout includes collecting the reads
+    start_deadline(args);
+    let start = Instant::now();
+    // with --padding, the reads are collected for the padded interval
+    let reads = crate::parse_bam::get_overlapping_reads(
+        bam,
+        &repeat.padded(args.padding),
+        unphased,
+        args.snv_phasing,
+        &args.read_filter(),
+    );
+    let reading_time = start.elapsed();
+    let start = Instant::now();
+    let record = genotype_repeat_from_reads(repeat, args, reads, inputs);
+    if inputs.record_stats {
+        STATS.add_reading_time(reading_time);
+        STATS.add_genotyping_time(start.elapsed());
+    }
+    record
+}
+
+// alignments can be extracted in an unphased manner, if the chromosome is haploid or the --unphased is set
+// this means that --haploid overrides the phases which could be present in the bam file
+// the reads of loci with a ploidy above two are clustered, so are also extracted unphased
+pub fn reads_are_unphased(
+    repeat: &crate::repeats::RepeatInterval,
+    args: &Cli,
+    inputs: &RunInputs,
+) -> bool {
+    ploidy(repeat, args, inputs) != 2 || args.unphased
+}
+
+// the expected number of alleles of a repeat, from --ploidy-bed or otherwise 1 for haploid loci and 2 for others
+fn ploidy(repeat: &crate::repeats::RepeatInterval, args: &Cli, inputs: &RunInputs) -> usize {
+    match inputs.ploidy.as_ref().and_then(|regions| regions.ploidy(repeat)) {
+        Some(ploidy) => ploidy,
+        None if is_haploid(repeat, args) => 1,
+        None => 2,
+    }
+}
+
+// a repeat is haploid if the chromosome is listed under --haploid,
+// or if it is on chrX or chrY outside of the pseudoautosomal regions with --karyotype XY
+fn is_haploid(repeat: &crate::repeats::RepeatInterval, args: &Cli) -> bool {
+    let listed = args
+        .haploid
+        .as_ref()
+        .is_some_and(|haploid| haploid.split(',').any(|chrom| chrom == repeat.chrom));
+    listed || (args.karyotype == Some(Karyotype::Xy) && is_hemizygous(repeat))
+}
+
+// the pseudoautosomal regions of GRCh38, which are diploid in XY samples
+const PSEUDOAUTOSOMAL_REGIONS: [(&str, u32, u32); 4] = [
+    ("X", 10001, 2781479),
+    ("X", 155701383, 156030895),
+    ("Y", 10001, 2781479),
+    ("Y", 56887903, 57217415),
+];
+
+fn is_hemizygous(repeat: &crate::repeats::RepeatInterval) -> bool {
+    let chrom = repeat.chrom.trim_start_matches("chr");
+    (chrom == "X" || chrom == "Y")
+        && !PSEUDOAUTOSOMAL_REGIONS
+            .iter()
+            .any(|(par, start, end)| chrom == *par && repeat.start < *end && repeat.end > *start)
+}
+
+// genotype a repeat from reads that were already collected from the bam file,
+// either by fetching the region from an indexed bam or by scanning an unindexed bam
+// with --timeout, a locus of which a step was stopped at the timeout gets the TIMEOUT filter without alleles
+// a locus that was genotyped completely is kept, even if it finished after the timeout
+pub fn genotype_repeat_from_reads(
+    repeat: &crate::repeats::RepeatInterval,
+    args: &Cli,
+    reads: Option<parse_bam::Reads>,
+    inputs: &RunInputs,
+) -> Result<crate::vcf::VCFRecord, String> {
+    // the deadline was already set when the reads were collected from an indexed bam
+    if DEADLINE.with(|d| d.get()).is_none() {
+        start_deadline(args);
+    }
+    let record = genotype_reads(repeat, args, reads, inputs);
+    let timed_out = ABANDONED.with(|abandoned| abandoned.replace(false));
+    DEADLINE.with(|d| d.set(None));
+    if timed_out {
+        log_locus(
+            Level::Warn,
+            repeat,
+            Stage::Genotyping,
+            format_args!("stopped after the timeout of {}s", args.timeout.unwrap_or_default()),
+        );
+        let ref_seq = repeat
+            .reference_repeat_sequence(&args.fasta)
+            .unwrap_or_else(|| "N".to_string());
+        let mut record =
+            crate::vcf::VCFRecord::missing_genotype(repeat, &ref_seq, ".".to_string());
+        record.filters = vec!["TIMEOUT".to_string()];
+        return Ok(record);
+    }
+    record
+}
+
+fn genotype_reads(
+    repeat: &crate::repeats::RepeatInterval,
+    args: &Cli,
+    mut reads: Option<parse_bam::Reads>,
+    inputs: &RunInputs,
+) -> Result<crate::vcf::VCFRecord, String> {
+    // the ploidy is determined in the coordinates of the primary reference
+    let ploidy = ploidy(repeat, args, inputs);
+    if let Some(reads) = reads.as_mut() {
+        if inputs.record_stats {
+            STATS.add_depth(reads.depth);
+        }
+        // with --snv-phasing, reads without haplotype tags are first phased using heterozygous SNVs in the flanks
+        // this falls back to clustering of the insertions if no informative SNVs are found
+        // loci with tagged reads keep their haplotype tags, as the SNV haplotypes are numbered independently
+        if args.snv_phasing && ploidy == 2 && reads.snv_phasable() {
+            if let Some((hap1, hap2)) =
+                crate::snv_phasing::phase_reads(&reads.records, repeat, SNV_PHASING_FLANK)
+            {
+                reads.assign_phases(hap1, hap2);
+            }
+        }
+    }
+    // with --second-fasta, the same reads are also genotyped against the paired locus in the second reference
+    let second = inputs.second_reference.as_ref();
+    let second_call = match second.and_then(|s| s.paired_locus(repeat).map(|locus| (s, locus))) {
+        Some((second, locus)) => {
+            let record =
+                genotype_against_reference(locus, args, &second.fasta, reads.clone(), ploidy)?;
+            Some((record, locus))
+        }
+        None => None,
+    };
+    let mut record = genotype_against_reference(repeat, args, &args.fasta, reads, ploidy)?;
+    if let Some((second_record, locus)) = second_call {
+        record.second_reference = Some(SecondCall::new(&record, &second_record, locus));
+    }
+    Ok(record)
+}
+
+// genotype a repeat against the reference in fasta, the reads are already phased
+fn genotype_against_reference(
+    repeat: &crate::repeats::RepeatInterval,
+    args: &Cli,
+    fasta: &String,
+    reads: Option<parse_bam::Reads>,
+    ploidy: usize,
 ) -> Result<crate::vcf::VCFRecord, String> {
     let flanking = 5000;
     let mut flags = vec![];
-    let repeat_ref_seq = match repeat.reference_repeat_sequence(&args.fasta) {
+    let repeat_ref_seq = match repeat.reference_repeat_sequence(fasta) {
         Some(seq) => seq,
         // Return a missing genotype if the repeat is not found in the fasta file
         None => {
+            log_locus(Level::Warn, repeat, Stage::Reading, "repeat not found in the fasta file");
             return Ok(crate::vcf::VCFRecord::missing_genotype(
                 repeat,
                 "N",
@@ -46,7 +296,7 @@ fn genotype_repeat(
         }
     };
 
-    let repeat_compressed_reference = repeat.make_repeat_compressed_sequence(&args.fasta, flanking);
+    let repeat_compressed_reference = repeat.make_repeat_compressed_sequence(fasta, flanking);
     if args.debug {
         // write the repeat compressed reference to a file
         use std::fs;
@@ -55,16 +305,16 @@ fn genotype_repeat(
         fs::write("repeat_compressed.fa", header + &fas).expect("Unable to write repeat compressed reference to file");
     }
 
-    // alignments can be extracted in an unphased manner, if the chromosome is --haploid or the --unphased is set
-    // this means that --haploid overrides the phases which could be present in the bam file
-    let unphased = (args.haploid.is_some()
-        && args.haploid.as_ref().unwrap().contains(&repeat.chrom))
-        || args.unphased;
-
-    let reads = match crate::parse_bam::get_overlapping_reads(bam, repeat, unphased) {
+    let reads = match reads {
         Some(seqs) => seqs,
         None => {
             // Return a missing genotype if no (phased) reads overlap the repeat
+            log_locus(Level::Debug, repeat, Stage::Reading, "no reads");
+            if args.no_coverage_calls {
+                let mut record = crate::vcf::VCFRecord::no_coverage(repeat, &repeat_ref_seq, 0);
+                record.haploid = ploidy == 1;
+                return Ok(record);
+            }
             return Ok(crate::vcf::VCFRecord::missing_genotype(
                 repeat,
                 &repeat_ref_seq,
@@ -73,6 +323,52 @@ fn genotype_repeat(
         }
     };
 
+    // with --emit-all-sites the depth and number of spanning reads are reported for every locus
+    let coverage = if args.emit_all_sites {
+        Some((reads.depth, reads.spanning))
+    } else {
+        None
+    };
+    // the spanning reads used per read group, sorted by read group
+    let read_groups = if args.read_group_filter().is_active() {
+        let mut read_groups = reads
+            .read_groups
+            .iter()
+            .map(|(rg, count)| (rg.clone(), *count))
+            .collect::<Vec<(String, usize)>>();
+        read_groups.sort();
+        Some(read_groups)
+    } else {
+        None
+    };
+    // the spanning reads excluded by --min-read-identity and --min-read-length
+    let excluded = if args.read_filter().excludes_reads() {
+        Some(reads.excluded)
+    } else {
+        None
+    };
+
+    // reads with a low mapping quality suggest that the flanks of the repeat are poorly mappable
+    let mut filters = vec![];
+    if reads.low_mapq as f32 > MAX_LOW_MAPQ_FRACTION * reads.depth as f32 {
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Reading,
+            format_args!("{} of {} reads with a low mapping quality", reads.low_mapq, reads.depth),
+        );
+        filters.push("LOWCONF_FLANK".to_string());
+    }
+    if args.no_coverage_calls && reads.spanning == 0 {
+        log_locus(Level::Debug, repeat, Stage::Reading, "no spanning reads");
+        let mut record = crate::vcf::VCFRecord::no_coverage(repeat, &repeat_ref_seq, reads.depth);
+        record.read_groups = read_groups;
+        record.excluded = excluded;
+        record.filters.extend(filters);
+        record.haploid = ploidy == 1;
+        return Ok(record);
+    }
+
     // Create an index for minimap2 alignment to the artificial reference
     let aligner = minimap2::Aligner::builder()
         .map_ont()
@@ -82,8 +378,12 @@ fn genotype_repeat(
 
     // Set up vectors to collect the results
     let mut consenses: Vec<crate::consensus::Consensus> = vec![];
-    // only used with --somatic: collecting all individual insertions
-    let mut all_insertions = if args.somatic { Some(vec![]) } else { None };
+    // only used with --somatic or --plot: collecting all individual insertions
+    let mut all_insertions = if args.somatic || args.plot.is_some() {
+        Some(vec![])
+    } else {
+        None
+    };
     // only used with --find_outliers: collecting all outlier insertions that could not be phased
     let mut outliers = if args.find_outliers {
         Some(vec![])
@@ -91,82 +391,227 @@ fn genotype_repeat(
         None
     };
 
+    let haploid = ploidy == 1;
+    // the alleles beyond the first two, only for a ploidy above two
+    let mut extra_consenses = vec![];
+    // the phase and the indices of the reads supporting the first two alleles, for --read-qc
+    let mut allele_reads: Vec<(u8, Vec<usize>)> = vec![];
+    // the repeat length in every read, for the genotype likelihoods
+    let mut read_lengths: Vec<usize> = vec![];
+    // contracted alleles are called from the remaining sequence with --min-contraction, not by lowering minlen
+    let minlen = locus_minlen(repeat, args.minlen, args.minlen_units);
+    // with --flank-anchor, the read sequence between the anchors is used rather than the insertions
+    let anchor = args
+        .flank_anchor
+        .map(|size| (size, repeat_compressed_reference.as_slice()));
+
     // The rest of the function has three mutually exclusive options from here.
     // Either the reads are from a haploid chromosome, unphased or phased by a tool like WhatsHap/hiphase/...
     // A chromosome being haploid overrides the other options, including if the alignments were phased by a tool
 
-    if args.haploid.is_some()
-        && args
-            .haploid
-            .as_ref()
-            .unwrap()
-            .split(',')
-            .collect::<Vec<&str>>()
-            .contains(&repeat.chrom.as_str())
-    {
+    // reads are clustered if --unphased is set, or if none of the reads has a haplotype tag
+    // unless the reads were phased using SNVs, and always for a ploidy above two
+    let clustered = ploidy > 2 || (args.unphased && !reads.snv_phased) || reads.is_untagged();
+    if haploid {
         // if the chromosome is haploid, all reads are put in phase 0
         let seq = reads.seqs.get(&0).unwrap();
-        debug!("{repeat}: Haploid: Aligning {} reads", seq.len());
-        let insertions = find_insertions(seq, &aligner, args.minlen, flanking, repeat);
-        debug!(
-            "{repeat}: Haploid: Creating consensus from {} insertions",
-            insertions.len(),
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            format_args!("Haploid: Aligning {} reads", seq.len()),
+        );
+        let (insertion_reads, insertions): (Vec<usize>, Vec<String>) =
+            find_insertions(seq, &aligner, minlen, flanking, args.padding, repeat, anchor)
+                .into_iter()
+                .unzip();
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            format_args!("Haploid: Creating consensus from {} insertions", insertions.len()),
         );
         if insertions.len() < args.support {
             // Return a missing genotype if not enough insertions are found
-            return Ok(crate::vcf::VCFRecord::missing_genotype(
+            let mut record = crate::vcf::VCFRecord::missing_genotype(
                 repeat,
                 &repeat_ref_seq,
                 insertions.len().to_string(),
-            ));
+            );
+            record.coverage = coverage;
+            record.read_groups = read_groups.clone();
+            record.excluded = excluded;
+            record.filters = filters;
+            record.haploid = true;
+            if args.keep_low_support {
+                record.flag_low_support();
+            }
+            return Ok(record);
         }
-        // there is only one haplotype, haploid, so this gets duplicated for reporting in the VCF module
-        // Ideally vcf.rs would explicitly handle haploid chromosomes
-        let consensus = crate::consensus::consensus(&insertions, args.support, repeat);
+        read_lengths.extend(insertions.iter().map(|insertion| insertion.len()));
+        // there is only one haplotype, haploid, so this gets duplicated
+        // and only the first allele is reported in the VCF module
+        let consensus = crate::consensus::consensus(
+            &insertions,
+            args.support,
+            repeat,
+            args.consensus_method(),
+        );
         consenses.push(consensus.clone());
         consenses.push(consensus);
+        allele_reads = vec![(0, insertion_reads.clone()), (0, insertion_reads)];
         if let Some(ref mut all_ins) = all_insertions {
             // store all inserted sequences for identifying somatic variation
             all_ins.push(insertions.join(":"));
         }
-    } else if args.unphased {
+    } else if ploidy > 2 {
+        // e.g. amplified regions in tumors, the insertions are clustered in up to ploidy alleles
+        let seq = reads.seqs.get(&0).unwrap();
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            format_args!("Ploidy {ploidy}: Aligning {} reads", seq.len()),
+        );
+        let (insertion_reads, insertions): (Vec<usize>, Vec<String>) =
+            find_insertions(seq, &aligner, minlen, flanking, args.padding, repeat, anchor)
+                .into_iter()
+                .unzip();
+        if insertions.len() < args.support {
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Genotyping,
+                format_args!("not enough insertions found: {}", insertions.len()),
+            );
+            let mut record = crate::vcf::VCFRecord::missing_genotype(
+                repeat,
+                &repeat_ref_seq,
+                insertions.len().to_string(),
+            );
+            record.coverage = coverage;
+            record.read_groups = read_groups.clone();
+            record.excluded = excluded;
+            record.filters = filters;
+            if args.keep_low_support {
+                record.flag_low_support();
+            }
+            return Ok(record);
+        }
+        let clusters = crate::phase_insertions::split_clusters(&insertions, ploidy, repeat);
+        for cluster in &clusters {
+            consenses.push(crate::consensus::consensus(
+                cluster,
+                args.support,
+                repeat,
+                args.consensus_method(),
+            ));
+        }
+        // a single cluster is homozygous, and gets duplicated like for unphased reads
+        if consenses.len() == 1 {
+            consenses.push(consenses[0].clone());
+        }
+        for cluster in clusters.iter().cycle().take(2) {
+            allele_reads.push((0, reads_of(cluster, &insertions, &insertion_reads)));
+        }
+        // the first two alleles go in the record as usual, the others are added later
+        extra_consenses = consenses.split_off(2);
+        if let Some(ref mut all_ins) = all_insertions {
+            all_ins.extend(clusters.iter().map(|cluster| cluster.join(":")));
+        }
+    } else if clustered {
+        if !args.unphased {
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Phasing,
+                "No reads with a haplotype tag, clustering insertions instead",
+            );
+        }
         // get the sequences
         let seq = reads.seqs.get(&0).unwrap();
-        debug!("{repeat}: Unphased: Aligning {} reads", seq.len());
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Genotyping,
+            format_args!("Unphased: Aligning {} reads", seq.len()),
+        );
         // align the reads to the new repeat-compressed reference
-        let insertions = find_insertions(seq, &aligner, args.minlen, flanking, repeat);
+        let (insertion_reads, insertions): (Vec<usize>, Vec<String>) =
+            find_insertions(seq, &aligner, minlen, flanking, args.padding, repeat, anchor)
+                .into_iter()
+                .unzip();
         if insertions.len() < args.support {
             // Return a missing genotype if not enough insertions are found
             // this is too lenient - the support parameter is meant to be per haplotype
-            debug!("{repeat}: Not enough insertions found: {}", insertions.len());
-            return Ok(crate::vcf::VCFRecord::missing_genotype(
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Genotyping,
+                format_args!("not enough insertions found: {}", insertions.len()),
+            );
+            let mut record = crate::vcf::VCFRecord::missing_genotype(
                 repeat,
                 &repeat_ref_seq,
                 insertions.len().to_string(),
-            ));
+            );
+            record.coverage = coverage;
+            record.read_groups = read_groups.clone();
+            record.excluded = excluded;
+            record.filters = filters;
+            if args.keep_low_support {
+                record.flag_low_support();
+            }
+            return Ok(record);
         }
-        debug!("{repeat}: Phasing {} insertions", insertions.len(),);
-        let phased = crate::phase_insertions::split(&insertions, repeat, args.find_outliers);
+        read_lengths.extend(insertions.iter().map(|insertion| insertion.len()));
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Phasing,
+            format_args!("Phasing {} insertions", insertions.len()),
+        );
+        let phased = crate::phase_insertions::split(
+            &insertions,
+            repeat,
+            args.find_outliers,
+            &args.cluster_options(),
+        );
         match phased.hap2 {
             Some(phase2) => {
-                consenses.push(crate::consensus::consensus(
-                    &phased.hap1,
+                // the clustering is refined by reassigning insertions to the closest consensus
+                let refined = crate::phase_insertions::refine(
+                    phased.hap1,
+                    phase2,
                     args.support,
                     repeat,
-                ));
-                consenses.push(crate::consensus::consensus(&phase2, args.support, repeat));
+                    args.em_iterations,
+                    args.consensus_method(),
+                );
+                consenses.push(refined.consensus1);
+                consenses.push(refined.consensus2);
+                for hap in [&refined.hap1, &refined.hap2] {
+                    allele_reads.push((0, reads_of(hap, &insertions, &insertion_reads)));
+                }
                 // store all inserted sequences for identifying somatic variation
                 if let Some(ref mut all_ins) = all_insertions {
-                    all_ins.extend([phased.hap1.join(":"), phase2.join(":")]);
+                    all_ins.extend([refined.hap1.join(":"), refined.hap2.join(":")]);
                 }
             }
             None => {
                 // there was only one haplotype, homozygous, so this gets duplicated for reporting
                 // not sure if cloning is the best approach here, but this is only the case for unphased data
                 // and therefore is typically for small datasets obtained through capture methods
-                let consensus = crate::consensus::consensus(&phased.hap1, args.support, repeat);
+                let consensus = crate::consensus::consensus(
+                    &phased.hap1,
+                    args.support,
+                    repeat,
+                    args.consensus_method(),
+                );
                 consenses.push(consensus.clone());
                 consenses.push(consensus);
+                let hap_reads = reads_of(&phased.hap1, &insertions, &insertion_reads);
+                allele_reads = vec![(0, hap_reads.clone()), (0, hap_reads)];
                 // store all inserted sequences for identifying somatic variation
                 if let Some(ref mut all_ins) = all_insertions {
                     all_ins.push(phased.hap1.join(":"));
@@ -189,27 +634,50 @@ fn genotype_repeat(
         for phase in [1, 2] {
             // get the sequences of this phase
             let seq = reads.seqs.get(&phase).unwrap();
-            debug!("{repeat}: Phase {}: Aligning {} reads", phase, seq.len());
-            let insertions = find_insertions(seq, &aligner, args.minlen, flanking, repeat);
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Genotyping,
+                format_args!("Phase {}: Aligning {} reads", phase, seq.len()),
+            );
+            let (insertion_reads, insertions): (Vec<usize>, Vec<String>) =
+                find_insertions(seq, &aligner, minlen, flanking, args.padding, repeat, anchor)
+                    .into_iter()
+                    .unzip();
 
-            debug!(
-                "{repeat}: Phase {}: Creating consensus from {} insertions",
-                phase,
-                insertions.len(),
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Genotyping,
+                format_args!(
+                    "Phase {}: Creating consensus from {} insertions",
+                    phase,
+                    insertions.len()
+                ),
             );
             consenses.push(crate::consensus::consensus(
                 &insertions,
                 args.support,
                 repeat,
+                args.consensus_method(),
             ));
 
+            allele_reads.push((phase, insertion_reads));
+            read_lengths.extend(insertions.iter().map(|insertion| insertion.len()));
             if let Some(ref mut all_ins) = all_insertions {
                 // store all inserted sequences for identifying somatic variation
                 all_ins.push(insertions.join(":"));
             }
         }
     }
-    Ok(crate::vcf::VCFRecord::new(
+    // the insertions are only reported in the VCF with --somatic
+    let plot_insertions = if args.plot.is_some() {
+        all_insertions.clone()
+    } else {
+        None
+    };
+    let all_insertions = if args.somatic { all_insertions } else { None };
+    let mut record = crate::vcf::VCFRecord::new(
         consenses,
         repeat_ref_seq,
         all_insertions,
@@ -217,26 +685,152 @@ fn genotype_repeat(
         repeat,
         reads.ps,
         flags,
-    ))
+    );
+    record.coverage = coverage;
+    record.clipping = Some((reads.anchored, reads.clipped));
+    record.read_groups = read_groups;
+    record.excluded = excluded;
+    record.haploid = haploid;
+    record.filters = filters;
+    record.plot_insertions = plot_insertions;
+    if let Some(min_contraction) = args.min_contraction {
+        record.call_contractions(min_contraction);
+    }
+    if !extra_consenses.is_empty() {
+        record.add_alleles(extra_consenses, repeat, args.min_contraction);
+    } else if ploidy <= 2 {
+        record.set_likelihoods(&read_lengths);
+    }
+    if args.keep_low_support {
+        record.flag_low_support();
+    }
+    if let Some(structure) = &repeat.structure {
+        record.set_motif_counts(structure, args.motif_mismatches);
+    }
+    if let Some(max_length) = args.nested_motifs {
+        record.set_motifs(max_length, args.motif_mismatches);
+    }
+    if args.purity || args.somatic {
+        // the motif from the bed file, or of the reference sequence of the locus
+        let motif = repeat
+            .motif
+            .clone()
+            .or_else(|| crate::motif::detect_motif(&record.ref_seq, MAX_DETECTED_MOTIF));
+        if args.purity {
+            record.set_purity(motif.as_deref());
+        }
+        if args.somatic {
+            record.set_instability_index(motif.as_deref(), args.motif_mismatches);
+        }
+    }
+    if args.read_qc && allele_reads.len() == 2 {
+        // the strand and base quality of the reads supporting every allele
+        let qc = |(phase, indices): &(u8, Vec<usize>)| {
+            indices
+                .iter()
+                .map(|index| reads.qc[phase][*index])
+                .collect::<Vec<crate::read_qc::ReadQc>>()
+        };
+        let annotation =
+            crate::read_qc::ReadQcAnnotation::new(&qc(&allele_reads[0]), &qc(&allele_reads[1]));
+        if annotation.strand_bias {
+            record.filters.push("STRAND_BIAS".to_string());
+        }
+        record.read_qc = Some(annotation);
+    }
+    if crate::fmr1::is_fmr1(repeat) {
+        // the methylation of an allele is only known for phased reads, or a haploid locus
+        let mean = |phase: u8| {
+            let fractions = reads.methylation[&phase].iter().flatten().collect::<Vec<&f32>>();
+            if fractions.is_empty() {
+                None
+            } else {
+                Some(fractions.iter().copied().sum::<f32>() / fractions.len() as f32)
+            }
+        };
+        let methylation = if haploid {
+            (mean(0), None)
+        } else if clustered {
+            (None, None)
+        } else {
+            (mean(1), mean(2))
+        };
+        record.fmr1 = Some(crate::fmr1::Fmr1Annotation::new(&record, methylation));
+    }
+    if args.realign_qc {
+        // the reads are realigned to a personalized reference with the called alleles
+        // phased reads only to the allele of their haplotype
+        let (left, right) =
+            repeat.flanking_sequences(fasta, crate::realign::REALIGN_FLANKING);
+        let read_sets: Vec<&[Vec<u8>]> = if haploid || clustered {
+            vec![reads.seqs[&0].as_slice()]
+        } else {
+            vec![reads.seqs[&1].as_slice(), reads.seqs[&2].as_slice()]
+        };
+        let [residual1, residual2] = crate::realign::residual_errors(
+            &read_sets,
+            [&record.consensus.0, &record.consensus.1],
+            &left,
+            &right,
+        );
+        record.residual_error = Some((residual1, residual2));
+    }
+    Ok(record)
+}
+
+// the minimal insertion length in bases, from the motif length with --minlen-units if the motif is known
+fn locus_minlen(
+    repeat: &crate::repeats::RepeatInterval,
+    minlen: usize,
+    minlen_units: Option<usize>,
+) -> usize {
+    match (minlen_units, &repeat.motif) {
+        (Some(units), Some(motif)) => units * motif.len(),
+        _ => minlen,
+    }
+}
+
+// the reads of the insertions of an allele, by looking up the insertions among the insertions of all reads
+fn reads_of(allele: &[String], insertions: &[String], insertion_reads: &[usize]) -> Vec<usize> {
+    let mut reads: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (insertion, read) in insertions.iter().zip(insertion_reads) {
+        reads.entry(insertion.as_str()).or_default().push(*read);
+    }
+    allele
+        .iter()
+        .filter_map(|insertion| reads.get_mut(insertion.as_str()).and_then(|reads| reads.pop()))
+        .collect()
 }
 
 // may adapt the function below to allow for multiple alignment methods later
+// returns the index of the read with every insertion
 fn find_insertions(
     seq: &Vec<Vec<u8>>,
     aligner: &Aligner,
     minlen: usize,
     flanking: u32,
+    padding: u32,
     repeat: &crate::repeats::RepeatInterval,
-) -> Vec<String> {
+    anchor: Option<(u32, &[u8])>, // the anchor size and the repeat-compressed reference
+) -> Vec<(usize, String)> {
     let mut insertions = vec![];
 
     // align the reads to the new repeat-compressed reference
-    for s in seq {
+    for (index, s) in seq.iter().enumerate() {
+        if deadline_passed() {
+            break;
+        }
         let mapping = aligner.map(s.as_slice(), true, false, None, None).unwrap_or_else(|err| panic!("Unable to align read with seq {s:?} to repeat-compressed reference for {repeat}\n{err}", s=s.to_ascii_uppercase()));
         for read in mapping {
-            if let Some(s) = parse_cs(read, minlen, flanking, repeat) {
+            let sequence = match anchor {
+                Some((size, reference)) => {
+                    parse_cs_anchored(read, reference, size, minlen, flanking, repeat)
+                }
+                None => parse_cs(read, minlen, flanking, padding, repeat),
+            };
+            if let Some(s) = sequence {
                 // slice out inserted sequences from the CS tag
-                insertions.push(s.to_uppercase())
+                insertions.push((index, s.to_uppercase()))
             }
         }
     }
@@ -247,6 +841,7 @@ fn parse_cs(
     read: Mapping,
     minlen: usize,
     flanking: u32,
+    padding: u32,
     repeat: &crate::repeats::RepeatInterval,
 ) -> Option<String> {
     // parses the CS tag of a <read> and returns the inserted sequence if it is longer than <minlen>
@@ -258,15 +853,18 @@ fn parse_cs(
 
     // split the cs tag using the regular expression
     // e.g. ':32*nt*na:10-gga:5+aaa:10' into (':32', '*nt', '*na', ':10', '-gga', ':5', '+aaa', ':10')
-    let re = Regex::new(r"(:\d+)|(\*\w+)|(\+\w+)|(-\w+)").unwrap();
+    let re = cs_regex();
 
     let mut insertions = Vec::new();
-    debug!(
-        "{repeat}: Parsing CS tag for read at {ref_pos}:{cs}",
-        ref_pos = ref_pos,
-        cs = cs
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Genotyping,
+        format_args!("Parsing CS tag for read at {ref_pos}:{cs}", ref_pos = ref_pos, cs = cs),
     );
-    let interval_around_junction = flanking as i32 - 15..=flanking as i32 + 15;
+    // with --padding, insertions placed further from the junction are also considered
+    let interval_around_junction =
+        flanking as i32 - 15 - padding as i32..=flanking as i32 + 15 + padding as i32;
 
     for cap in re.captures_iter(&cs) {
         let op = &cap[0].chars().next().unwrap();
@@ -298,10 +896,15 @@ fn parse_cs(
                 if cap[0][1..].len() > minlen && interval_around_junction.contains(&ref_pos) {
                     insertions.push(cap[0][1..].to_string());
                 } else if cap[0][1..].len() > minlen {
-                    debug!(
-                        "{repeat}: Insertion at {} is too far from the junction to be considered: {}",
-                        ref_pos,
-                        cap[0][1..].to_string(),
+                    log_locus(
+                        Level::Debug,
+                        repeat,
+                        Stage::Genotyping,
+                        format_args!(
+                            "Insertion at {} is too far from the junction to be considered: {}",
+                            ref_pos,
+                            cap[0][1..].to_string()
+                        ),
                     );
                 }
             }
@@ -318,10 +921,116 @@ fn parse_cs(
     }
 }
 
+// returns the read sequence between the anchors of <anchor> bases on either side of the junction,
+// without the anchors, if it is longer than <minlen>
+// this is robust to insertions that the aligner placed a few bases away from the junction
+fn parse_cs_anchored(
+    read: Mapping,
+    reference: &[u8],
+    anchor: u32,
+    minlen: usize,
+    flanking: u32,
+    repeat: &crate::repeats::RepeatInterval,
+) -> Option<String> {
+    let window_start = flanking.saturating_sub(anchor) as i32;
+    let window_end = (flanking + anchor) as i32;
+    // the read has to span both anchors
+    if read.target_start > window_start || read.target_end < window_end {
+        log_locus(Level::Debug, repeat, Stage::Genotyping, "Read does not span the flank anchors");
+        return None;
+    }
+    let alignment = read.alignment.expect("Unable to access alignment");
+    let cs = alignment.cs.expect("Unable to get the cs field");
+    let segment = anchored_segment(&cs, read.target_start, reference, window_start, window_end);
+    let anchors = (window_end - window_start) as usize;
+    if segment.len() > anchors + minlen {
+        let left = (flanking as i32 - window_start) as usize;
+        Some(segment[left..segment.len() - (anchors - left)].to_string())
+    } else {
+        None
+    }
+}
+
+// the read sequence aligned to the reference between window_start and window_end, reconstructed from the cs tag
+fn anchored_segment(
+    cs: &str,
+    target_start: i32,
+    reference: &[u8],
+    window_start: i32,
+    window_end: i32,
+) -> String {
+    let mut ref_pos = target_start;
+    let mut segment = String::new();
+    let in_window = |pos: i32| pos >= window_start && pos < window_end;
+    for cap in cs_regex().captures_iter(cs) {
+        let op = &cap[0][..1];
+        let seq = &cap[0][1..];
+        match op {
+            ":" => {
+                let len = seq
+                    .parse::<i32>()
+                    .expect("Unable to parse length from CS':' operation");
+                for pos in ref_pos..ref_pos + len {
+                    if in_window(pos) {
+                        segment.push(reference[pos as usize] as char);
+                    }
+                }
+                ref_pos += len;
+            }
+            "*" => {
+                // *na with n the reference and a the read nucleotide
+                if in_window(ref_pos) {
+                    segment.push_str(&seq[1..]);
+                }
+                ref_pos += 1;
+            }
+            "-" => ref_pos += seq.len() as i32,
+            "+" => {
+                // the insertion is between ref_pos - 1 and ref_pos, both have to be in the window
+                if ref_pos > window_start && ref_pos < window_end {
+                    segment.push_str(seq);
+                }
+            }
+            _ => panic!("Unexpected operation in cs tag: {op}"),
+        }
+    }
+    segment.to_uppercase()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_hemizygous() {
+        use crate::repeats::RepeatInterval;
+        // FMR1 is outside of the PARs
+        assert!(is_hemizygous(&RepeatInterval::new("chrX", 147912050, 147912110)));
+        assert!(!is_hemizygous(&RepeatInterval::new("chrX", 100000, 100100)));
+        assert!(!is_hemizygous(&RepeatInterval::new("chr7", 154654404, 154654432)));
+    }
+
+    #[test]
+    fn test_anchored_segment() {
+        // the junction of the repeat-compressed reference is between the C and G bases
+        let reference = b"AAAACCCCGGGGTTTT";
+        // the insertion is placed two bases before the junction
+        let segment = anchored_segment(":6+cagcag:10", 0, reference, 4, 12);
+        assert_eq!(segment, "CCCAGCAGCCGGGG");
+        // a deletion in the anchors shortens the segment
+        let segment = anchored_segment(":5-c:10", 0, reference, 4, 12);
+        assert_eq!(segment, "CCCGGGG");
+    }
+
+    #[test]
+    fn test_locus_minlen() {
+        let mut repeat = crate::repeats::RepeatInterval::new("chr4", 3074876, 3074933);
+        assert_eq!(locus_minlen(&repeat, 5, Some(1)), 5);
+        repeat.motif = Some("CAG".to_string());
+        assert_eq!(locus_minlen(&repeat, 5, Some(1)), 3);
+        assert_eq!(locus_minlen(&repeat, 5, None), 5);
+    }
+
     #[test]
     fn test_parse_cs() {
         let bam = String::from("test_data/small-test-phased.bam");
@@ -330,6 +1039,9 @@ mod tests {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
         let flanking = 2000;
         let minlen = 5;
@@ -337,7 +1049,14 @@ mod tests {
         let unphased = false;
         let repeat_compressed_reference = repeat.make_repeat_compressed_sequence(&fasta, flanking);
         let mut bam = parse_bam::create_bam_reader(&bam, &fasta);
-        let binding = crate::parse_bam::get_overlapping_reads(&mut bam, &repeat, unphased).unwrap();
+        let binding = crate::parse_bam::get_overlapping_reads(
+            &mut bam,
+            &repeat,
+            unphased,
+            false,
+            &crate::parse_bam::ReadFilter::default(),
+        )
+        .unwrap();
         let read = binding
             .seqs
             .get(&1)
@@ -359,6 +1078,7 @@ mod tests {
                 .clone(),
             minlen,
             flanking,
+            0,
             &repeat,
         );
     }
@@ -369,25 +1089,18 @@ mod tests {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
-        let args = Cli {
-            bam: String::from("test_data/small-test-phased.bam"),
-            fasta: String::from("test_data/chr7.fa.gz"),
-            region: None,
-            region_file: None,
-            pathogenic: false,
-            minlen: 5,
-            support: 1,
-            somatic: false,
-            unphased: false,
-            find_outliers: false,
-            threads: 1,
-            sample: None,
-            haploid: None,
-            debug: false,
-        };
+        let args = Cli::from_args(
+            "test_data/chr7.fa.gz",
+            "test_data/small-test-phased.bam",
+            &["--support", "1"],
+        )
+        .expect("Invalid options");
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        let genotype = genotype_repeat(&repeat, &args, &mut bam);
+        let genotype = genotype_repeat(&repeat, &args, &mut bam, &RunInputs::default());
         println!("{}", genotype.expect("Unable to genotype repeat"));
     }
 
@@ -397,110 +1110,82 @@ mod tests {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
-        let args = Cli {
-            bam: String::from("test_data/small-test-phased.bam"),
-            fasta: String::from("test_data/chr7.fa.gz"),
-            region: None,
-            region_file: None,
-            pathogenic: false,
-            minlen: 5,
-            support: 1,
-            somatic: false,
-            unphased: true,
-            find_outliers: false,
-            threads: 1,
-            sample: None,
-            haploid: Some(String::from("chr7")),
-            debug: false,
-        };
+        let args = Cli::from_args(
+            "test_data/chr7.fa.gz",
+            "test_data/small-test-phased.bam",
+            &["--support", "1", "--unphased", "--haploid", "chr7"],
+        )
+        .expect("Invalid options");
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        let genotype = genotype_repeat(&repeat, &args, &mut bam);
+        let genotype = genotype_repeat(&repeat, &args, &mut bam, &RunInputs::default());
         println!("{}", genotype.expect("Unable to genotype repeat"));
     }
 
     #[test]
     fn test_genotype_repeat_unphased() {
-        let args = Cli {
-            bam: String::from("test_data/small-test-phased.bam"),
-            fasta: String::from("test_data/chr7.fa.gz"),
-            region: None,
-            region_file: None,
-            pathogenic: false,
-            minlen: 5,
-            support: 1,
-            somatic: false,
-            unphased: true,
-            find_outliers: false,
-            threads: 1,
-            sample: None,
-            haploid: None,
-            debug: false,
-        };
+        let args = Cli::from_args(
+            "test_data/chr7.fa.gz",
+            "test_data/small-test-phased.bam",
+            &["--support", "1", "--unphased"],
+        )
+        .expect("Invalid options");
         let repeat = crate::repeats::RepeatInterval {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        let genotype = genotype_repeat(&repeat, &args, &mut bam);
+        let genotype = genotype_repeat(&repeat, &args, &mut bam, &RunInputs::default());
         println!("{}", genotype.expect("Unable to genotype repeat"));
     }
 
     #[test]
     fn test_genotype_repeat_somatic() {
-        let args = Cli {
-            bam: String::from("test_data/small-test-phased.bam"),
-            fasta: String::from("test_data/chr7.fa.gz"),
-            region: None,
-            region_file: None,
-            pathogenic: false,
-            minlen: 5,
-            support: 1,
-            somatic: true,
-            unphased: false,
-            find_outliers: false,
-            threads: 1,
-            sample: None,
-            haploid: None,
-            debug: false,
-        };
+        let args = Cli::from_args(
+            "test_data/chr7.fa.gz",
+            "test_data/small-test-phased.bam",
+            &["--support", "1", "--somatic"],
+        )
+        .expect("Invalid options");
         let repeat = crate::repeats::RepeatInterval {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        let genotype = genotype_repeat(&repeat, &args, &mut bam);
+        let genotype = genotype_repeat(&repeat, &args, &mut bam, &RunInputs::default());
         println!("{}", genotype.expect("Unable to genotype repeat"));
     }
 
     #[test]
     fn test_genotype_repeat_url() {
-        let args = Cli {
-            bam: String::from("https://ftp.1000genomes.ebi.ac.uk/vol1/ftp/data_collections/1KG_ONT_VIENNA/hg38/HG00096.hg38.cram"),
-            fasta: String::from("test_data/chr7.fa.gz"),
-            region: None,
-            region_file: None,
-            pathogenic: false,
-            minlen: 5,
-            support: 1,
-            somatic: true,
-            unphased: false,
-            find_outliers: false,
-            threads: 1,
-            sample: None,
-            haploid: None,
-            debug: false,
-        };
+        let args = Cli::from_args(
+            "test_data/chr7.fa.gz",
+            "https://ftp.1000genomes.ebi.ac.uk/vol1/ftp/data_collections/1KG_ONT_VIENNA/hg38/HG00096.hg38.cram",
+            &["--support", "1", "--somatic"],
+        )
+        .expect("Invalid options");
 
         let repeat = crate::repeats::RepeatInterval {
             chrom: String::from("chr7"),
             start: 154654404,
             end: 154654432,
+            motif: None,
+            annotation: None,
+            structure: None,
         };
         let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
-        let genotype = genotype_repeat(&repeat, &args, &mut bam);
+        let genotype = genotype_repeat(&repeat, &args, &mut bam, &RunInputs::default());
         println!("{}", genotype.expect("Unable to genotype repeat"));
     }
 }
@@ -0,0 +1,102 @@
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use std::path::Path;
+
+// the length of reference flanking sequence shared by all alleles in the graph
+const GFA_FLANKING: u32 = 100;
+
+/// Write the observed alleles of a locus as a GFA graph to <dir>/<chrom>_<start>_<end>.gfa
+/// The alleles are divergent paths between the shared reference flanks
+pub fn write_locus_gfa(record: &VCFRecord, repeat: &RepeatInterval, fasta: &str, dir: &str) {
+    let (left, right) = repeat.flanking_sequences(fasta, GFA_FLANKING);
+    let mut haplotypes = vec![(record.allele.0.as_str(), record.consensus.0.as_str())];
+    if !record.haploid {
+        haplotypes.push((record.allele.1.as_str(), record.consensus.1.as_str()));
+    }
+    let gfa = locus_gfa(repeat, &left, &record.ref_seq, &right, &haplotypes);
+    let name = format!("{}_{}_{}.gfa", repeat.chrom, repeat.start, repeat.end);
+    let path = Path::new(dir).join(name);
+    std::fs::write(&path, gfa)
+        .unwrap_or_else(|err| panic!("Failed writing GFA to {}: {err}", path.display()));
+}
+
+// build the graph with a segment for the left flank, the reference repeat,
+// every distinct alternative allele and the right flank
+// haplotypes are (genotype, consensus sequence) pairs, missing haplotypes are not added as a path
+fn locus_gfa(
+    repeat: &RepeatInterval,
+    left: &str,
+    ref_seq: &str,
+    right: &str,
+    haplotypes: &[(&str, &str)],
+) -> String {
+    let mut segments = vec![left.to_string(), ref_seq.to_string()];
+    let mut paths = vec![("ref".to_string(), 2)];
+    for (index, (genotype, seq)) in haplotypes.iter().enumerate() {
+        let segment = match *genotype {
+            "." => continue,
+            "0" => 2,
+            _ => match segments.iter().skip(2).position(|s| s == seq) {
+                Some(existing) => existing + 3,
+                None => {
+                    segments.push(seq.to_string());
+                    segments.len()
+                }
+            },
+        };
+        paths.push((format!("hap{}", index + 1), segment));
+    }
+    segments.push(right.to_string());
+    let right_id = segments.len();
+
+    let mut gfa = String::from("H\tVN:Z:1.0\n");
+    for (id, seq) in segments.iter().enumerate() {
+        gfa.push_str(&format!("S\t{}\t{seq}\n", id + 1));
+    }
+    // every repeat segment, reference or alternative, connects both flanks
+    for id in 2..right_id {
+        gfa.push_str(&format!("L\t1\t+\t{id}\t+\t0M\n"));
+        gfa.push_str(&format!("L\t{id}\t+\t{right_id}\t+\t0M\n"));
+    }
+    for (name, segment) in paths {
+        gfa.push_str(&format!("P\t{repeat}#{name}\t1+,{segment}+,{right_id}+\t*\n"));
+    }
+    gfa
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locus_gfa() {
+        let repeat = RepeatInterval::new("chr7", 100, 106);
+        // a heterozygous expansion, with the other allele matching the reference
+        let gfa = locus_gfa(
+            &repeat,
+            "ACGT",
+            "CAGCAG",
+            "TTGA",
+            &[("1", "CAGCAGCAG"), ("0", "CAGCAG")],
+        );
+        let lines = gfa.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.iter().filter(|l| l.starts_with('S')).count(), 4);
+        assert_eq!(lines.iter().filter(|l| l.starts_with('L')).count(), 4);
+        assert!(lines.contains(&"P\tchr7:100-106#hap1\t1+,3+,4+\t*"));
+        assert!(lines.contains(&"P\tchr7:100-106#hap2\t1+,2+,4+\t*"));
+    }
+
+    #[test]
+    fn test_locus_gfa_shared_allele() {
+        let repeat = RepeatInterval::new("chr7", 100, 106);
+        let gfa = locus_gfa(
+            &repeat,
+            "ACGT",
+            "CAGCAG",
+            "TTGA",
+            &[("1", "CAGCAGCAG"), ("1", "CAGCAGCAG")],
+        );
+        assert_eq!(gfa.lines().filter(|l| l.starts_with('S')).count(), 4);
+        assert!(gfa.contains("P\tchr7:100-106#hap2\t1+,3+,4+\t*"));
+    }
+}
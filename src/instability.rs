@@ -0,0 +1,275 @@
+use crate::parse_bam;
+use log::{debug, info};
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use rust_htslib::bam::record::Cigar;
+use rust_htslib::bam::Read;
+use std::collections::HashMap;
+
+// the expansion and contraction index of an allele are only computed with at least this number of reads
+const MIN_INDEX_READS: usize = 3;
+// the fraction of the reads of a window with a length to consider it the second germline allele
+const MIN_SECOND_MODE_FRACTION: f64 = 0.2;
+
+/// Scan the genome or the regions in a bed file in windows for somatic repeat instability,
+/// as the variance of the net indel length of the reads spanning the window around the nearest germline allele,
+/// such that the length difference between heterozygous germline alleles does not count as instability
+/// The result is written as bedGraph to stdout
+#[derive(clap::Args, Debug)]
+pub struct InstabilityArgs {
+    /// reference genome
+    fasta: String,
+
+    /// bam file to scan
+    bam: String,
+
+    /// Bed file with region(s) to scan, the whole genome if not provided
+    #[clap(short = 'R', long, value_parser)]
+    region_file: Option<String>,
+
+    /// size of the windows in bases
+    #[clap(long, value_parser, default_value_t = 1000)]
+    window: u32,
+
+    /// distance between the start of consecutive windows, the window size if not provided
+    #[clap(long, value_parser)]
+    step: Option<u32>,
+
+    /// minimal number of reads spanning a window to report it
+    #[clap(long, value_parser, default_value_t = 5)]
+    min_reads: usize,
+
+    /// minimal length of insertion/deletion operations to count
+    #[clap(short, long, value_parser, default_value_t = 5)]
+    minlen: u32,
+}
+
+pub fn run(args: InstabilityArgs) {
+    let regions = match &args.region_file {
+        Some(bed) => bed_regions(bed),
+        None => genome_regions(&args.fasta),
+    };
+    let step = args.step.unwrap_or(args.window).max(1);
+    let mut bam = parse_bam::create_bam_reader(&args.bam, &args.fasta);
+    println!("track type=bedGraph name=\"STRdust instability\" description=\"Variance of the net indel length of spanning reads around the nearest germline allele\"");
+    let mut windows = 0;
+    for (chrom, start, end) in regions {
+        let tid = match bam.header().tid(chrom.as_bytes()) {
+            Some(tid) => tid,
+            None => {
+                debug!("Skipping {chrom}: not in the bam header");
+                continue;
+            }
+        };
+        let mut window_start = start;
+        while window_start < end {
+            let window_end = (window_start + args.window).min(end);
+            let lengths = window_lengths(&mut bam, tid, window_start, window_end, args.minlen);
+            if lengths.len() >= args.min_reads {
+                println!(
+                    "{chrom}\t{window_start}\t{window_end}\t{:.3}",
+                    variance_around_modes(&lengths, args.minlen.into())
+                );
+                windows += 1;
+            }
+            window_start += step;
+        }
+    }
+    info!("Reported {windows} windows");
+}
+
+// the net indel length of every read spanning the window
+fn window_lengths(
+    bam: &mut bam::IndexedReader,
+    tid: u32,
+    start: u32,
+    end: u32,
+    minlen: u32,
+) -> Vec<i64> {
+    bam.fetch((tid, start, end)).unwrap_or_else(|err| {
+        panic!("Failure to extract reads from bam for {tid}:{start}-{end}:\n{err}")
+    });
+    let mut lengths = vec![];
+    for r in bam.rc_records() {
+        let r = r.unwrap_or_else(|err| panic!("Error reading BAM file:\n{err}"));
+        if r.mapq() == 0
+            || r.is_secondary()
+            || r.is_supplementary()
+            || r.reference_start() > start.into()
+            || r.reference_end() < end.into()
+        {
+            continue;
+        }
+        let cigar = r.cigar();
+        lengths.push(net_indel_length(
+            &cigar,
+            r.reference_start(),
+            start.into(),
+            end.into(),
+            minlen,
+        ));
+    }
+    lengths
+}
+
+// the inserted minus the deleted bases between start and end, for operations of at least minlen
+fn net_indel_length(cigar: &[Cigar], mut ref_pos: i64, start: i64, end: i64, minlen: u32) -> i64 {
+    let mut length = 0;
+    for op in cigar {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::RefSkip(len) => {
+                ref_pos += *len as i64
+            }
+            Cigar::Ins(len) => {
+                if *len >= minlen && ref_pos >= start && ref_pos <= end {
+                    length += *len as i64;
+                }
+            }
+            Cigar::Del(len) => {
+                if *len >= minlen {
+                    let overlap = (ref_pos + *len as i64).min(end) - ref_pos.max(start);
+                    length -= overlap.max(0);
+                }
+                ref_pos += *len as i64;
+            }
+            _ => (),
+        }
+    }
+    length
+}
+
+// the mean squared difference of the lengths to the nearest germline allele, of which the first is the modal length
+// and the second the most frequent length more than separation from it, if that has enough reads
+fn variance_around_modes(lengths: &[i64], separation: i64) -> f64 {
+    let mut frequencies: HashMap<i64, usize> = HashMap::new();
+    for length in lengths {
+        *frequencies.entry(*length).or_default() += 1;
+    }
+    let first = match most_frequent(frequencies.iter()) {
+        Some((first, _)) => first,
+        None => return 0.0,
+    };
+    let mut modes = vec![first];
+    if let Some((second, count)) = most_frequent(
+        frequencies
+            .iter()
+            .filter(|(length, _)| (**length - first).abs() > separation),
+    ) {
+        if count as f64 >= MIN_SECOND_MODE_FRACTION * lengths.len() as f64 {
+            modes.push(second);
+        }
+    }
+    lengths
+        .iter()
+        .map(|l| {
+            modes
+                .iter()
+                .map(|mode| ((l - mode) as f64).powi(2))
+                .fold(f64::INFINITY, f64::min)
+        })
+        .sum::<f64>()
+        / lengths.len() as f64
+}
+
+// the most frequent length and its count, with ties broken by the shortest length to keep the output deterministic
+fn most_frequent<'a>(
+    frequencies: impl Iterator<Item = (&'a i64, &'a usize)>,
+) -> Option<(i64, usize)> {
+    frequencies
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(length, count)| (*length, *count))
+}
+
+/// The expansion and contraction index of the repeat counts of the reads of an allele,
+/// as the mean number of repeat units that the reads are longer or shorter than the modal count
+/// None for fewer than MIN_INDEX_READS reads
+pub fn expansion_contraction_index(counts: &[usize]) -> Option<(f32, f32)> {
+    if counts.len() < MIN_INDEX_READS {
+        return None;
+    }
+    let mut frequencies: HashMap<usize, usize> = HashMap::new();
+    for count in counts {
+        *frequencies.entry(*count).or_default() += 1;
+    }
+    // ties are broken by the shortest count, to keep the output deterministic
+    let modal = frequencies
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(count, _)| *count)?;
+    let expansion = counts.iter().map(|count| count.saturating_sub(modal)).sum::<usize>();
+    let contraction = counts.iter().map(|count| modal.saturating_sub(*count)).sum::<usize>();
+    Some((
+        expansion as f32 / counts.len() as f32,
+        contraction as f32 / counts.len() as f32,
+    ))
+}
+
+pub fn bed_regions(bed: &str) -> Vec<(String, u32, u32)> {
+    let mut reader = bio::io::bed::Reader::from_file(bed).expect("Problem reading bed file!");
+    reader
+        .records()
+        .map(|record| {
+            let rec = record.expect("Error reading bed record.");
+            (rec.chrom().to_string(), rec.start() as u32, rec.end() as u32)
+        })
+        .collect()
+}
+
+// every chromosome in the fasta index
+pub fn genome_regions(fasta: &str) -> Vec<(String, u32, u32)> {
+    crate::repeats::fasta_contigs(fasta)
+        .into_iter()
+        .map(|(name, length)| (name, 0, length as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_net_indel_length() {
+        let cigar = [
+            Cigar::Match(100),
+            Cigar::Ins(30),
+            Cigar::Match(50),
+            Cigar::Del(20),
+            Cigar::Match(10),
+            Cigar::Ins(2),
+            Cigar::Match(100),
+        ];
+        // the insertion and deletion are within the window, the small insertion is ignored
+        assert_eq!(net_indel_length(&cigar, 0, 50, 300, 5), 30 - 20);
+        // only the part of the deletion overlapping the window is counted
+        assert_eq!(net_indel_length(&cigar, 0, 160, 300, 5), -10);
+        assert_eq!(net_indel_length(&cigar, 0, 250, 300, 5), 0);
+    }
+
+    #[test]
+    fn test_variance_around_modes() {
+        assert_eq!(variance_around_modes(&[0, 0, 0], 5), 0.0);
+        assert_eq!(variance_around_modes(&[], 5), 0.0);
+        // the modal length is 0, and 2 is not a second allele as it is within the separation
+        assert_eq!(variance_around_modes(&[0, 0, 2, -2], 5), 2.0);
+        // heterozygous germline alleles of 0 and 30 are not instability
+        assert_eq!(variance_around_modes(&[0, 0, 0, 30, 30, 30], 5), 0.0);
+        assert_eq!(variance_around_modes(&[0, 0, 2, 30, 30, 28], 5), 4.0 / 3.0);
+        // a single read of another length is not a germline allele
+        assert_eq!(variance_around_modes(&[0, 0, 0, 0, 0, 0, 30], 5), 900.0 / 7.0);
+    }
+
+    #[test]
+    fn test_expansion_contraction_index() {
+        // modal count of 100, two reads expanded by 10 and 20 units and one contracted by 5
+        let counts = [100, 100, 100, 110, 120, 95];
+        assert_eq!(expansion_contraction_index(&counts), Some((5.0, 5.0 / 6.0)));
+        assert_eq!(expansion_contraction_index(&[100, 100, 100]), Some((0.0, 0.0)));
+        assert_eq!(expansion_contraction_index(&[100, 110]), None);
+    }
+
+    #[test]
+    fn test_genome_regions() {
+        let regions = genome_regions("test_data/chr7.fa.gz");
+        assert_eq!(regions[0].0, "chr7");
+    }
+}
@@ -0,0 +1,653 @@
+//! Genotyping of STRs from long reads
+//!
+//! The binary is a thin wrapper around this library, which can also be used to genotype repeats
+//! from another tool. [genotype_repeat] genotypes a single locus and returns a [LocusResult]
+//! without writing any output, the options are those of the call subcommand:
+//!
+//! ```no_run
+//! use strdust::repeats::RepeatIntervalIterator;
+//! use strdust::Cli;
+//!
+//! let options = Cli::from_args("ref.fa", "reads.bam", &["--unphased"]).expect("Invalid options");
+//! let inputs = strdust::genotype::RunInputs::from_args(&options);
+//! let mut reader = strdust::parse_bam::create_bam_reader("reads.bam", "ref.fa");
+//! let interval = RepeatIntervalIterator::from_string("chr4:3074876-3074939", "ref.fa")
+//!     .next()
+//!     .expect("Invalid locus");
+//! let result = strdust::genotype_repeat(&interval, &options, &mut reader, &inputs)
+//!     .expect("Genotyping failed");
+//! for allele in result.alleles {
+//!     println!("{:?} {:?}", allele.length, allele.support);
+//! }
+//! ```
+use clap::{CommandFactory, FromArgMatches, Parser};
+use rust_htslib::bam;
+use std::path::PathBuf;
+
+pub mod call;
+pub mod checkpoint;
+pub mod concordance;
+pub mod consensus;
+pub mod consensus_fasta;
+pub mod demo;
+pub mod discover;
+pub mod dotplot;
+pub mod evaluate;
+pub mod flanks;
+pub mod fmr1;
+pub mod genotype;
+pub mod gfa;
+pub mod instability;
+pub mod likelihood;
+pub mod locus_log;
+pub mod merge;
+pub mod motif;
+pub mod multiref;
+pub mod parse_bam;
+pub mod phase_insertions;
+pub mod plot;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod read_qc;
+pub mod realign;
+pub mod recalibrate;
+pub mod registry;
+pub mod repeats;
+pub mod serve;
+#[cfg(test)]
+mod simulate;
+pub mod snv_phasing;
+pub mod spill;
+pub mod stats;
+pub mod targets;
+#[cfg(test)]
+mod test_utils;
+pub mod utils;
+pub mod vcf;
+
+pub use crate::repeats::RepeatInterval;
+pub use crate::vcf::VCFRecord;
+
+// The arguments of the call subcommand end up in the Cli struct
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// reference genome
+    #[clap(validator=is_file)]
+    fasta: String,
+
+    /// bam file to call STRs in
+    #[clap(validator=is_file)]
+    bam: String,
+
+    /// region string to genotype expansion in
+    #[clap(short, long, value_parser)]
+    region: Option<String>,
+
+    /// Bed file with region(s) to genotype expansion(s) in
+    #[clap(short = 'R', long, value_parser, validator=is_file)]
+    region_file: Option<String>,
+
+    /// Second reference genome to also genotype the loci against, e.g. CHM13 next to GRCh38
+    #[clap(long, value_parser, validator=is_file, requires = "second_region_file")]
+    second_fasta: Option<String>,
+
+    /// Bed file with the loci of --region-file in the second reference, paired line by line
+    #[clap(long, value_parser, validator=is_file, requires_all = &["second_fasta", "region_file"])]
+    second_region_file: Option<String>,
+
+    /// Genotype the pathogenic STRs from STRchive
+    #[clap(long, value_parser, default_value_t = false)]
+    pathogenic: bool,
+
+    /// Merge overlapping repeat intervals and intervals closer than this number of bases
+    #[clap(long, value_parser)]
+    merge_intervals: Option<u32>,
+
+    /// Do not match contig names that differ in a chr prefix between the bed, fasta and bam file
+    #[clap(long, value_parser, default_value_t = false)]
+    no_contig_reconciliation: bool,
+
+    /// Defaults of the sequencing platform for minlen, support, consensus, clustering and motif mismatches, the options given override the preset
+    #[clap(long, value_enum)]
+    preset: Option<Preset>,
+
+    /// minimal length of insertion/deletion operation
+    #[clap(short, long, value_parser, default_value_t = 5)]
+    minlen: usize,
+
+    /// minimal length of insertion/deletion operation in repeat units of the motif in the bed file, falls back to --minlen for loci without a motif
+    #[clap(long, value_parser)]
+    minlen_units: Option<usize>,
+
+    /// Report alleles at least this number of bases shorter than the reference as contraction
+    #[clap(long, value_parser)]
+    min_contraction: Option<usize>,
+
+    /// Use the read sequence between anchors of this size in the flanks instead of only the insertions at the repeat
+    #[clap(long, value_parser)]
+    flank_anchor: Option<u32>,
+
+    /// Also consider insertions up to this number of bases outside the repeat interval, the locus keeps its coordinates
+    #[clap(long, value_parser, default_value_t = 0)]
+    padding: u32,
+
+    /// Report the repeated motifs of at most this length per allele, e.g. a VNTR adjacent to the catalog repeat
+    #[clap(long, value_parser)]
+    nested_motifs: Option<usize>,
+
+    /// Split loci with two adjacent repeats with a different motif in the reference or an allele in a record per repeat
+    #[clap(long, value_parser, default_value_t = false, requires = "nested_motifs")]
+    split_nested: bool,
+
+    /// Left-align the REF and ALT alleles and trim them to a single anchor base, as with bcftools norm
+    #[clap(long, value_parser, default_value_t = false)]
+    normalize: bool,
+
+    /// Maximal number of mismatches in an interrupted copy of a motif, when counting motif copies and finding nested motifs
+    #[clap(long, value_parser, default_value_t = 1)]
+    motif_mismatches: usize,
+
+    /// minimal number of supporting reads per haplotype
+    #[clap(short, long, value_parser, default_value_t = 3)]
+    support: usize,
+
+    /// Flag the alleles with fewer supporting reads than --support in the LS FORMAT field, e.g. 0,1 for 1|.
+    #[clap(long, value_parser, default_value_t = false)]
+    keep_low_support: bool,
+
+    /// Report loci without spanning reads as a no-call ./. with the NOCOV filter and their depth
+    #[clap(long, value_parser, default_value_t = false)]
+    no_coverage_calls: bool,
+
+    /// Maximal time in seconds to genotype a single locus, slower loci get the TIMEOUT filter
+    #[clap(long, value_parser)]
+    timeout: Option<u64>,
+
+    /// Number of parallel threads to use
+    #[clap(short, long, value_parser, default_value_t = 1)]
+    threads: usize,
+
+    /// Memory in MB for the genotypes of a multithreaded run, above which they are sorted on disk
+    #[clap(long, value_parser)]
+    max_mem: Option<usize>,
+
+    /// With multiple threads, sort the loci by position and genotype chunks of this number of consecutive loci per thread
+    #[clap(long, value_parser)]
+    chunk_size: Option<usize>,
+
+    /// Sample name to use in VCF header, if not provided, the bam file name is used
+    #[clap(long, value_parser)]
+    sample: Option<String>,
+
+    /// comma-separated list of read groups (RG tag) to use, other reads are ignored
+    #[clap(long, value_parser)]
+    read_group: Option<String>,
+
+    /// Maximal number of spanning reads to use per read group, such that one run cannot dominate the consensus
+    #[clap(long, value_parser)]
+    max_reads_per_read_group: Option<usize>,
+
+    /// Minimal gap-compressed identity of the alignment of a spanning read, to exclude chimeric and low-identity reads
+    #[clap(long, value_parser)]
+    min_read_identity: Option<f32>,
+
+    /// Minimal length of a spanning read
+    #[clap(long, value_parser)]
+    min_read_length: Option<usize>,
+
+    /// Print information on somatic variability
+    #[clap(long, value_parser, default_value_t = false)]
+    somatic: bool,
+
+    /// Reads are not phased
+    #[clap(long, value_parser, default_value_t = false)]
+    unphased: bool,
+
+    /// Phase reads using heterozygous SNVs in the flanking sequence at loci without haplotype tags
+    #[clap(long, value_parser, default_value_t = false)]
+    snv_phasing: bool,
+
+    /// Maximal number of iterations to refine the clustering of unphased reads (0 to disable)
+    #[clap(long, value_parser, default_value_t = 5)]
+    em_iterations: usize,
+
+    /// Algorithm to cluster the insertions of unphased reads in two alleles
+    #[clap(long, value_enum, default_value_t = ClusterMethod::Hierarchical)]
+    cluster_method: ClusterMethod,
+
+    /// Distance between insertions to cluster unphased reads, edit distance of the sequences or difference in length
+    #[clap(long, value_enum, default_value_t = ClusterMetric::Edit)]
+    cluster_metric: ClusterMetric,
+
+    /// Distance below which the insertions of unphased reads are not split in two alleles
+    #[clap(long, value_parser, default_value_t = 5.0)]
+    split_threshold: f32,
+
+    /// Identify poorly supported outlier expansions (only with --unphased)
+    #[clap(long, value_parser, default_value_t = false)]
+    find_outliers: bool,
+
+    /// comma-separated list of haploid (sex) chromosomes
+    #[clap(long, value_parser)]
+    haploid: Option<String>,
+
+    /// Sex chromosome karyotype, with XY chrX and chrY outside of the PARs are haploid
+    #[clap(long, value_enum, ignore_case = true)]
+    karyotype: Option<Karyotype>,
+
+    /// Bed file with the expected ploidy of regions in the fourth column, e.g. 1 for haploid or higher for amplified regions
+    #[clap(long, value_parser, validator=is_file)]
+    ploidy_bed: Option<String>,
+
+    /// Bed file with low confidence regions, e.g. segmental duplications, overlapping repeats are filtered
+    #[clap(long, value_parser, validator=is_file)]
+    lowconf_regions: Option<String>,
+
+    /// Replace the sample name, read groups and file paths in the output by a hash, for sharing outside the institution
+    #[clap(long, value_parser, default_value_t = false)]
+    anonymize: bool,
+
+    /// Secret key of the hash with --anonymize, to get the same hash for a sample in every run, by default random
+    #[clap(long, value_parser, requires = "anonymize")]
+    anonymize_key: Option<String>,
+
+    /// Report depth and spanning reads for every locus, including homozygous reference sites
+    #[clap(long, value_parser, default_value_t = false)]
+    emit_all_sites: bool,
+
+    /// Output format, VCF or one JSON object per locus (NDJSON)
+    #[clap(long, value_enum, default_value_t = OutputFormat::Vcf)]
+    format: OutputFormat,
+
+    /// Realign the reads to the called alleles and report the residual error per allele
+    #[clap(long, value_parser, default_value_t = false)]
+    realign_qc: bool,
+
+    /// Report the strand and base quality of the supporting reads per allele, and filter strand biased calls
+    #[clap(long, value_parser, default_value_t = false)]
+    read_qc: bool,
+
+    /// Report the fraction of every allele in perfect copies of the motif and its edit distance to a perfect repeat
+    #[clap(long, value_parser, default_value_t = false)]
+    purity: bool,
+
+    /// Algorithm to build the consensus of the insertions per haplotype, majority and longest-read are faster
+    #[clap(long, value_enum, default_value_t = ConsensusMethod::Poa)]
+    consensus: ConsensusMethod,
+
+    /// Skip the consensus and report the length of every allele as a symbolic ALT allele, e.g. <STR42>
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = false,
+        conflicts_with_all = &["normalize", "allele_ids", "allele_registry", "consensus_fasta", "gfa"]
+    )]
+    no_sequence: bool,
+
+    /// Add a stable identifier of every ALT allele to the INFO field
+    #[clap(long, value_parser, default_value_t = false)]
+    allele_ids: bool,
+
+    /// Tsv file to keep every observed allele in with its identifier, implies --allele-ids
+    #[clap(long, value_parser)]
+    allele_registry: Option<String>,
+
+    /// Fasta file to write the consensus sequence of every allele to
+    #[clap(long, value_parser)]
+    consensus_fasta: Option<String>,
+
+    /// Directory to write a GFA graph of the observed alleles per locus to
+    #[clap(long, value_parser)]
+    gfa: Option<String>,
+
+    /// Directory to write a waterfall plot of the insertions per haplotype per locus to, as SVG
+    #[clap(long, value_parser)]
+    plot: Option<String>,
+
+    /// Directory to write a self-similarity dotplot of the consensus per allele per locus to, as SVG
+    #[clap(long, value_parser)]
+    dotplot: Option<String>,
+
+    /// File to keep finished loci in, a rerun with the same file skips those loci
+    /// as only the formatted records are kept, it can't be combined with the options that write other outputs per locus
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &["gfa", "plot", "dotplot", "consensus_fasta", "allele_registry"]
+    )]
+    checkpoint: Option<String>,
+
+    /// Reuse a checkpoint created by another version of STRdust or with other parameters
+    #[clap(long, value_parser, default_value_t = false)]
+    allow_stale_cache: bool,
+
+    /// Write statistics of the run as JSON to this file, or to stderr with -
+    #[clap(long, value_parser)]
+    stats: Option<String>,
+
+    /// Scan the bam file sequentially instead of using the index, done automatically for few regions
+    #[clap(long, value_parser, default_value_t = false)]
+    no_index_scan: bool,
+
+    /// Debug mode
+    #[clap(long, value_parser, default_value_t = false)]
+    debug: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Vcf,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq, Eq)]
+pub enum Karyotype {
+    Xx,
+    Xy,
+}
+
+/// poa: partial order alignment with global alignment of the reads
+/// spoa-local: partial order alignment with local alignment of the reads
+/// majority: the most frequent sequence, or the read with the median length
+/// longest-read: the longest read
+/// the median length read is only used with --no-sequence
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsensusMethod {
+    Poa,
+    SpoaLocal,
+    Majority,
+    LongestRead,
+    #[clap(skip)]
+    MedianLength,
+}
+
+/// The defaults per sequencing platform
+/// hifi: PacBio HiFi reads with few errors
+/// ont-r9 and ont-r10: Oxford Nanopore reads of the R9.4.1 and R10.4.1 pores, the former with more indel errors
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    Hifi,
+    OntR9,
+    OntR10,
+}
+
+/// hierarchical: Ward linkage of the distances between the insertions
+/// medoids: two medoids, starting from the medoid of all insertions and the insertion farthest from it
+/// density: insertions connected by distances up to the split threshold, dropping sparse insertions as noise
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterMethod {
+    Hierarchical,
+    Medoids,
+    Density,
+}
+
+/// edit: levenshtein distance between the insertion sequences
+/// length: absolute difference in length of the insertions
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterMetric {
+    Edit,
+    Length,
+}
+
+impl Cli {
+    /// The options of the call subcommand for programmatic use, e.g. `&["--unphased", "--support", "5"]`
+    pub fn from_args(fasta: &str, bam: &str, options: &[&str]) -> Result<Cli, clap::Error> {
+        let matches = Cli::command().try_get_matches_from(
+            ["STRdust", fasta, bam].into_iter().chain(options.iter().copied()),
+        )?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        cli.apply_preset(&matches);
+        Ok(cli)
+    }
+
+    /// Set the options of the preset, except for those given on the command line
+    pub fn apply_preset(&mut self, matches: &clap::ArgMatches) {
+        let preset = match self.preset {
+            Some(preset) => preset,
+            None => return,
+        };
+        // minlen, support, consensus, cluster metric, split threshold and motif mismatches
+        // HiFi reads have few indel errors, so shorter indels, smaller differences between the alleles
+        // and interruptions of the motif are real, while the indel errors of R9 reads need the opposite
+        // most HiFi insertions of an allele are identical, so the faster majority consensus suffices
+        let (minlen, support, consensus, metric, split_threshold, motif_mismatches) = match preset {
+            Preset::Hifi => (3, 2, ConsensusMethod::Majority, ClusterMetric::Edit, 3.0, 0),
+            Preset::OntR9 => (10, 4, ConsensusMethod::Poa, ClusterMetric::Length, 10.0, 2),
+            Preset::OntR10 => (5, 3, ConsensusMethod::Poa, ClusterMetric::Edit, 8.0, 1),
+        };
+        let unset = |id: &str| matches.value_source(id) != Some(clap::ValueSource::CommandLine);
+        if unset("minlen") {
+            self.minlen = minlen;
+        }
+        if unset("support") {
+            self.support = support;
+        }
+        if unset("consensus") {
+            self.consensus = consensus;
+        }
+        if unset("cluster_metric") {
+            self.cluster_metric = metric;
+        }
+        if unset("split_threshold") {
+            self.split_threshold = split_threshold;
+        }
+        if unset("motif_mismatches") {
+            self.motif_mismatches = motif_mismatches;
+        }
+    }
+
+    /// The consensus algorithm, --no-sequence overrides --consensus
+    pub fn consensus_method(&self) -> ConsensusMethod {
+        if self.no_sequence {
+            ConsensusMethod::MedianLength
+        } else {
+            self.consensus
+        }
+    }
+
+    pub fn cluster_options(&self) -> phase_insertions::ClusterOptions {
+        phase_insertions::ClusterOptions {
+            method: self.cluster_method,
+            metric: self.cluster_metric,
+            split_threshold: self.split_threshold,
+        }
+    }
+
+    pub fn read_group_filter(&self) -> parse_bam::ReadGroupFilter {
+        parse_bam::ReadGroupFilter {
+            keep: self
+                .read_group
+                .as_ref()
+                .map(|groups| groups.split(',').map(|rg| rg.to_string()).collect()),
+            max_reads: self.max_reads_per_read_group,
+        }
+    }
+
+    /// With --anonymize, the keyed hash that replaces the sample name and file paths
+    pub fn anonymizer(&self) -> Option<utils::Anonymizer> {
+        self.anonymize
+            .then(|| utils::Anonymizer::new(self.anonymize_key.as_deref()))
+    }
+
+    pub fn read_filter(&self) -> parse_bam::ReadFilter {
+        parse_bam::ReadFilter {
+            read_groups: self.read_group_filter(),
+            min_identity: self.min_read_identity,
+            min_length: self.min_read_length,
+            read_qc: self.read_qc,
+        }
+    }
+}
+
+fn is_file(pathname: &str) -> Result<(), String> {
+    let path = PathBuf::from(pathname);
+    if path.is_file() || utils::is_remote(pathname) {
+        Ok(())
+    } else {
+        Err(format!("Input file {} is invalid", path.display()))
+    }
+}
+
+/// The genotype of a single repeat locus, with the reads of the bam file overlapping the interval
+/// The result only holds the called alleles, formatting them as VCF or JSON is left to the caller
+/// The inputs are loaded once from the options with [genotype::RunInputs::from_args]
+/// A failure, e.g. a contig that is not in the bam file, is returned as an error rather than a panic
+pub fn genotype_repeat(
+    interval: &RepeatInterval,
+    options: &Cli,
+    reader: &mut bam::IndexedReader,
+    inputs: &genotype::RunInputs,
+) -> Result<LocusResult, String> {
+    crate::utils::catch_panic(
+        || genotype::genotype_repeat_singlethreaded(interval, options, reader, inputs),
+        &format!("Failed genotyping {interval}"),
+    )?
+    .map(|record| LocusResult::from_record(&record))
+}
+
+/// A called allele, with None for values that could not be determined
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlleleResult {
+    pub length: Option<i64>, // length relative to the reference
+    pub full_length: Option<usize>,
+    pub sequence: Option<String>,
+    pub support: Option<usize>,
+    pub std_dev: Option<f32>,
+    pub score: Option<i32>,
+}
+
+impl AlleleResult {
+    fn new(
+        length: &str,
+        full_length: &str,
+        seq: &str,
+        support: &str,
+        std_dev: &str,
+        score: &str,
+    ) -> AlleleResult {
+        AlleleResult {
+            length: length.parse().ok(),
+            full_length: full_length.parse().ok(),
+            sequence: if seq == "." { None } else { Some(seq.to_string()) },
+            support: support.parse().ok(),
+            std_dev: std_dev.parse().ok(),
+            score: score.parse().ok(),
+        }
+    }
+}
+
+/// The genotype of a locus, with one allele for haploid loci and two or more otherwise
+/// genotype: per allele 0 for the reference, the index of the ALT allele, or None if not called
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocusResult {
+    pub chrom: String,
+    pub start: u32,
+    pub end: u32,
+    pub ref_seq: String,
+    pub alt_seqs: Vec<String>,
+    pub genotype: Vec<Option<usize>>,
+    pub alleles: Vec<AlleleResult>,
+    pub phase_set: Option<u32>,
+    pub filters: Vec<String>,
+}
+
+impl LocusResult {
+    pub fn from_record(record: &VCFRecord) -> LocusResult {
+        // the values of the first or second allele of the record
+        let pick = |values: &(String, String), index: usize| -> String {
+            if index == 0 {
+                values.0.clone()
+            } else {
+                values.1.clone()
+            }
+        };
+        let reported = if record.haploid { 1 } else { 2 };
+        let mut genotype = vec![];
+        let mut alleles = vec![];
+        for index in 0..reported {
+            genotype.push(pick(&record.allele, index).parse().ok());
+            alleles.push(AlleleResult::new(
+                &pick(&record.length, index),
+                &pick(&record.full_length, index),
+                &pick(&record.consensus, index),
+                &pick(&record.support, index),
+                &pick(&record.std_dev, index),
+                &pick(&record.score, index),
+            ));
+        }
+        for (gt, allele) in &record.extra_alleles {
+            genotype.push(gt.parse().ok());
+            alleles.push(AlleleResult::new(
+                &allele.length,
+                &allele.full_length,
+                &allele.seq,
+                &allele.support,
+                &allele.std_dev,
+                &allele.score,
+            ));
+        }
+        LocusResult {
+            chrom: record.chrom.clone(),
+            start: record.start,
+            end: record.end,
+            ref_seq: record.ref_seq.clone(),
+            alt_seqs: match record.alt_seq.as_deref() {
+                Some(".") | None => vec![],
+                Some(alts) => alts.split(',').map(|alt| alt.to_string()).collect(),
+            },
+            genotype,
+            alleles,
+            phase_set: record.ps,
+            filters: record.filters.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[ctor::ctor]
+fn init() {
+    env_logger::init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locus_result() {
+        let repeat = RepeatInterval::new("chr7", 100, 106);
+        let mut record = crate::test_utils::cag_record(&repeat, "0");
+        record.alt_seq = Some("CAGCAGCAG".to_string());
+        record.allele = ("0".to_string(), "1".to_string());
+        record.length = ("0".to_string(), "3".to_string());
+        record.full_length = ("6".to_string(), "9".to_string());
+        record.consensus = ("CAGCAG".to_string(), "CAGCAGCAG".to_string());
+        let result = LocusResult::from_record(&record);
+        assert_eq!(result.genotype, vec![Some(0), Some(1)]);
+        assert_eq!(result.alt_seqs, vec!["CAGCAGCAG".to_string()]);
+        assert_eq!(result.alleles[1].length, Some(3));
+        assert_eq!(result.alleles[1].sequence.as_deref(), Some("CAGCAGCAG"));
+        record.haploid = true;
+        assert_eq!(LocusResult::from_record(&record).alleles.len(), 1);
+    }
+
+    #[test]
+    fn test_from_args() {
+        let fasta = "test_data/chr7.fa.gz";
+        let bam = "test_data/small-test-phased.bam";
+        let options = Cli::from_args(fasta, bam, &["--unphased"]).expect("Invalid options");
+        assert!(options.unphased);
+        assert!(Cli::from_args(fasta, bam, &["--support", "x"]).is_err());
+        // the options given override the preset
+        let options = Cli::from_args(fasta, bam, &["--preset", "ont-r9", "--support", "2"])
+            .expect("Invalid options");
+        assert_eq!((options.minlen, options.support), (10, 2));
+        assert_eq!(options.cluster_metric, ClusterMetric::Length);
+        let options = Cli::from_args(fasta, bam, &["--preset", "hifi"]).expect("Invalid options");
+        assert_eq!((options.minlen, options.support, options.motif_mismatches), (3, 2, 0));
+        assert_eq!(options.consensus, ConsensusMethod::Majority);
+        let options = Cli::from_args(fasta, bam, &["--preset", "hifi", "--consensus", "poa"])
+            .expect("Invalid options");
+        assert_eq!(options.consensus, ConsensusMethod::Poa);
+    }
+}
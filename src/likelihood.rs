@@ -0,0 +1,131 @@
+use std::f64::consts::PI;
+
+// the standard deviation of the repeat length in the reads of an allele, relative to its length
+const LENGTH_SD_FRACTION: f64 = 0.02;
+// the standard deviation is at least a base, for short alleles
+const MIN_LENGTH_SD: f64 = 1.0;
+// the probability that the length of a read is unrelated to its allele, e.g. for a mismapped read
+const ERROR_PROBABILITY: f64 = 0.01;
+// the probability of every length of a read that is unrelated to its allele
+const ERROR_DENSITY: f64 = 1e-4;
+// the genotype quality is capped, like by most variant callers
+const MAX_GQ: u32 = 99;
+
+/// The genotypes of a number of alleles in the order of the PL field,
+/// e.g. 0/0, 0/1, 1/1, 0/2, 1/2, 2/2 for a diploid locus with two ALT alleles
+pub fn genotypes(alleles: usize, ploidy: usize) -> Vec<Vec<usize>> {
+    if ploidy == 1 {
+        (0..alleles).map(|allele| vec![allele]).collect()
+    } else {
+        (0..alleles).flat_map(|k| (0..=k).map(move |j| vec![j, k])).collect()
+    }
+}
+
+/// The index of a haploid or diploid genotype in the order of the PL field
+pub fn genotype_index(genotype: &[usize]) -> usize {
+    match genotype {
+        [allele] => *allele,
+        [a, b] => {
+            let (j, k) = (*a.min(b), *a.max(b));
+            k * (k + 1) / 2 + j
+        }
+        _ => panic!("Genotype likelihoods are only computed for a ploidy of one or two"),
+    }
+}
+
+// the probability of the repeat length of a read given the length of its allele,
+// with a normally distributed length error that increases with the length of the allele
+fn read_probability(read: usize, allele: usize) -> f64 {
+    let sd = (allele as f64 * LENGTH_SD_FRACTION).max(MIN_LENGTH_SD);
+    let z = (read as f64 - allele as f64) / sd;
+    (1.0 - ERROR_PROBABILITY) * (-0.5 * z * z).exp() / (sd * (2.0 * PI).sqrt())
+        + ERROR_PROBABILITY * ERROR_DENSITY
+}
+
+/// The phred-scaled likelihood of every genotype given the repeat length of the reads, in the order of the PL field
+/// Every read is drawn from the alleles of a genotype with equal probability,
+/// and the likelihoods are relative to the most likely genotype
+pub fn phred_likelihoods(
+    read_lengths: &[usize],
+    allele_lengths: &[usize],
+    ploidy: usize,
+) -> Vec<u32> {
+    let log_likelihoods = genotypes(allele_lengths.len(), ploidy)
+        .iter()
+        .map(|genotype| {
+            read_lengths
+                .iter()
+                .map(|read| {
+                    let probability = genotype
+                        .iter()
+                        .map(|allele| read_probability(*read, allele_lengths[*allele]))
+                        .sum::<f64>()
+                        / genotype.len() as f64;
+                    probability.log10()
+                })
+                .sum::<f64>()
+        })
+        .collect::<Vec<f64>>();
+    let max = log_likelihoods.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    log_likelihoods
+        .iter()
+        .map(|likelihood| (-10.0 * (likelihood - max)).round() as u32)
+        .collect()
+}
+
+/// The phred-scaled probability that the called genotype is wrong, from the phred-scaled likelihoods and a flat prior
+pub fn genotype_quality(pl: &[u32], called: usize) -> u32 {
+    genotype_quality_with_prior(pl, called, &vec![1.0; pl.len()])
+}
+
+/// The genotype quality with a prior probability of every genotype, in the order of the PL field,
+/// e.g. from the genotypes of the whole run with STRdust recalibrate
+pub fn genotype_quality_with_prior(pl: &[u32], called: usize, prior: &[f64]) -> u32 {
+    let probabilities = pl
+        .iter()
+        .zip(prior)
+        .map(|(pl, prior)| 10f64.powf(-(*pl as f64) / 10.0) * prior)
+        .collect::<Vec<f64>>();
+    let total = probabilities.iter().sum::<f64>();
+    let error = probabilities
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != called)
+        .map(|(_, probability)| probability)
+        .sum::<f64>()
+        / total;
+    if error <= 0.0 {
+        return MAX_GQ;
+    }
+    ((-10.0 * error.log10()).round() as u32).min(MAX_GQ)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genotype_likelihoods() {
+        assert_eq!(
+            genotypes(3, 2),
+            vec![vec![0, 0], vec![0, 1], vec![1, 1], vec![0, 2], vec![1, 2], vec![2, 2]]
+        );
+        assert_eq!(genotype_index(&[2, 1]), 4);
+        assert_eq!(genotype_index(&[1]), 1);
+        // a heterozygous locus with ten reads of both alleles
+        let reads = [vec![30; 10], vec![60; 10]].concat();
+        let pl = phred_likelihoods(&reads, &[30, 60], 2);
+        assert_eq!(pl[1], 0);
+        assert!(pl[0] > 100 && pl[2] > 100);
+        assert_eq!(genotype_quality(&pl, 1), 99);
+        assert_eq!(genotype_quality(&pl, 0), 0);
+        // a single read hardly distinguishes homozygous from heterozygous
+        let pl = phred_likelihoods(&[30], &[30, 60], 2);
+        assert_eq!(pl[0], 0);
+        assert!(genotype_quality(&pl, 0) < 10);
+        // a prior against heterozygous genotypes lowers the quality of a heterozygous call
+        let pl = [30, 0, 40];
+        assert_eq!(genotype_quality_with_prior(&pl, 1, &[1.0, 1.0, 1.0]), 30);
+        assert!(genotype_quality_with_prior(&pl, 1, &[0.495, 0.01, 0.495]) < 20);
+    }
+}
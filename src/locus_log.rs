@@ -0,0 +1,62 @@
+use crate::repeats::RepeatInterval;
+use log::Level;
+use std::fmt;
+
+/// The target of the per-locus log records, e.g. to only show them with RUST_LOG=strdust::locus=debug
+pub const LOCUS_TARGET: &str = "strdust::locus";
+
+/// The stage of genotyping a locus that a per-locus log record is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Reading,
+    Phasing,
+    Genotyping,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stage = match self {
+            Stage::Reading => "reading",
+            Stage::Phasing => "phasing",
+            Stage::Genotyping => "genotyping",
+        };
+        write!(f, "{stage}")
+    }
+}
+
+/// Log a record about a locus with the locus, stage and reason as key=value fields,
+/// such that the records of a multithreaded run can be attributed to a locus and triaged afterwards
+/// The record is only formatted if the locus target is enabled for the level,
+/// such that a reason from format_args! costs nothing for the records that are filtered out
+pub fn log_locus(level: Level, repeat: &RepeatInterval, stage: Stage, reason: impl fmt::Display) {
+    if log::log_enabled!(target: LOCUS_TARGET, level) {
+        log::log!(
+            target: LOCUS_TARGET,
+            level,
+            "{}",
+            locus_record(repeat, stage, &reason.to_string())
+        );
+    }
+}
+
+// the reason is quoted, as it can contain spaces
+fn locus_record(repeat: &RepeatInterval, stage: Stage, reason: &str) -> String {
+    format!(
+        "locus={repeat} stage={stage} reason=\"{}\"",
+        reason.replace('"', "'").replace('\n', " ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locus_record() {
+        let repeat = RepeatInterval::new("chr4", 3074876, 3074933);
+        assert_eq!(
+            locus_record(&repeat, Stage::Reading, "no \"phased\" reads\nfound"),
+            "locus=chr4:3074876-3074933 stage=reading reason=\"no 'phased' reads found\""
+        );
+    }
+}
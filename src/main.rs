@@ -1,108 +1,133 @@
 #![allow(non_snake_case)]
 use clap::AppSettings::DeriveDisplayOrder;
-use clap::Parser;
-use log::{info, warn};
-use std::path::PathBuf;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use log::info;
 
-pub mod call;
-pub mod consensus;
-pub mod genotype;
-pub mod motif;
-pub mod parse_bam;
-pub mod phase_insertions;
-pub mod repeats;
-pub mod utils;
-pub mod vcf;
+use strdust::{
+    call, concordance, demo, discover, evaluate, flanks, instability, merge, recalibrate, serve,
+    targets, Cli,
+};
 
-// The arguments end up in the Cli struct
 #[derive(Parser, Debug)]
 #[structopt(global_settings=&[DeriveDisplayOrder])]
 #[clap(author, version, about="Tool to genotype STRs from long reads", long_about = None)]
-pub struct Cli {
-    /// reference genome
-    #[clap(validator=is_file)]
-    fasta: String,
-
-    /// bam file to call STRs in
-    #[clap(validator=is_file)]
-    bam: String,
-
-    /// region string to genotype expansion in
-    #[clap(short, long, value_parser)]
-    region: Option<String>,
-
-    /// Bed file with region(s) to genotype expansion(s) in
-    #[clap(short = 'R', long, value_parser, validator=is_file)]
-    region_file: Option<String>,
-
-    /// Genotype the pathogenic STRs from STRchive
-    #[clap(long, value_parser, default_value_t = false)]
-    pathogenic: bool,
-
-    /// minimal length of insertion/deletion operation
-    #[clap(short, long, value_parser, default_value_t = 5)]
-    minlen: usize,
-
-    /// minimal number of supporting reads per haplotype
-    #[clap(short, long, value_parser, default_value_t = 3)]
-    support: usize,
-
-    /// Number of parallel threads to use
-    #[clap(short, long, value_parser, default_value_t = 1)]
-    threads: usize,
-
-    /// Sample name to use in VCF header, if not provided, the bam file name is used
-    #[clap(long, value_parser)]
-    sample: Option<String>,
-
-    /// Print information on somatic variability
-    #[clap(long, value_parser, default_value_t = false)]
-    somatic: bool,
-
-    /// Reads are not phased
-    #[clap(long, value_parser, default_value_t = false)]
-    unphased: bool,
-
-    /// Identify poorly supported outlier expansions (only with --unphased)
-    #[clap(long, value_parser, default_value_t = false)]
-    find_outliers: bool,
-
-    /// comma-separated list of haploid (sex) chromosomes
-    #[clap(long, value_parser)]
-    haploid: Option<String>,
-
-    /// Debug mode
-    #[clap(long, value_parser, default_value_t = false)]
-    debug: bool,
+struct App {
+    #[clap(subcommand)]
+    command: Commands,
+
+    /// Log more information, -v for progress messages and -vv for debugging, overrides RUST_LOG
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u64,
+
+    /// Do not log anything and hide the progress bar, overrides RUST_LOG
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value_t = false,
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+
+    /// Log level (off, error, warn, info, debug or trace), overrides -v and RUST_LOG
+    #[clap(long, value_parser, global = true)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Write the log to this file rather than to stderr
+    #[clap(long, value_parser, global = true)]
+    log_file: Option<String>,
 }
 
-fn is_file(pathname: &str) -> Result<(), String> {
-    let path = PathBuf::from(pathname);
-    if path.is_file() || pathname.starts_with("http") {
-        Ok(())
-    } else {
-        Err(format!("Input file {} is invalid", path.display()))
-    }
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    /// Genotype STRs in a bam/cram file
+    Call(Cli),
+    /// Merge single-sample STRdust VCFs into a multi-sample VCF
+    Merge(merge::MergeArgs),
+    /// Scan for somatic repeat instability in windows, written as bedGraph
+    Instability(instability::InstabilityArgs),
+    /// Scan for large repetitive insertions as candidate expansion loci, without a catalog
+    Discover(discover::DiscoverArgs),
+    /// Genotype a locus given as the sequences of its flanks, e.g. a locus absent from the reference
+    Flanks(flanks::FlanksArgs),
+    /// Write the padded, merged and sorted target regions of a catalog for adaptive sampling, as bed
+    Targets(targets::TargetsArgs),
+    /// Genotype loci on demand over HTTP, keeping the bam and fasta readers open
+    Serve(serve::ServeArgs),
+    /// Compare the repeat lengths in a STRdust VCF with a truth set
+    Evaluate(evaluate::EvaluateArgs),
+    /// Compare the repeat lengths in STRdust VCFs with capillary or Southern blot sizing
+    Concordance(concordance::ConcordanceArgs),
+    /// Recalibrate the genotype qualities of a STRdust VCF with the genotypes of the whole run
+    Recalibrate(recalibrate::RecalibrateArgs),
+    /// Genotype a small demo dataset to verify the installation
+    Demo(demo::DemoArgs),
 }
 
 fn main() {
-    env_logger::init();
-    let args = Cli::parse();
-    if args.find_outliers && !args.unphased {
-        warn!("--find-outliers is only effective with --unphased");
+    let matches = App::command().get_matches();
+    let app = App::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    // RUST_LOG is still honored for the modules and the level without -v or -q
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = log_level(app.verbose, app.quiet, app.log_level) {
+        logger.filter_level(level);
+    }
+    if let Some(log_file) = &app.log_file {
+        let file = std::fs::File::create(log_file)
+            .unwrap_or_else(|err| panic!("Failed creating log file {log_file}: {err}"));
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
+    strdust::utils::set_ca_bundle();
+    match app.command {
+        Commands::Call(mut args) => {
+            if let Some(("call", call_matches)) = matches.subcommand() {
+                args.apply_preset(call_matches);
+            }
+            info!("Collected arguments");
+            call::genotype_repeats(args);
+        }
+        Commands::Merge(args) => merge::run(args),
+        Commands::Instability(args) => instability::run(args),
+        Commands::Discover(args) => discover::run(args),
+        Commands::Flanks(args) => flanks::run(args),
+        Commands::Targets(args) => targets::run(args),
+        Commands::Serve(args) => serve::run(args),
+        Commands::Evaluate(args) => evaluate::run(args),
+        Commands::Concordance(args) => concordance::run(args),
+        Commands::Recalibrate(args) => recalibrate::run(args),
+        Commands::Demo(args) => demo::run(args),
     }
-    info!("Collected arguments");
-    call::genotype_repeats(args);
 }
 
-#[cfg(test)]
-#[ctor::ctor]
-fn init() {
-    env_logger::init();
+// -q takes precedence over --log-level, which takes precedence over -v
+fn log_level(
+    verbose: u64,
+    quiet: bool,
+    level: Option<log::LevelFilter>,
+) -> Option<log::LevelFilter> {
+    match (verbose, quiet) {
+        (_, true) => Some(log::LevelFilter::Off),
+        _ if level.is_some() => level,
+        (0, false) => None,
+        (1, false) => Some(log::LevelFilter::Info),
+        (2, false) => Some(log::LevelFilter::Debug),
+        _ => Some(log::LevelFilter::Trace),
+    }
 }
 
 #[test]
 fn verify_app() {
-    use clap::CommandFactory;
-    Cli::command().debug_assert()
+    App::command().debug_assert()
+}
+
+#[test]
+fn test_log_level() {
+    assert_eq!(log_level(0, false, None), None);
+    assert_eq!(log_level(2, false, None), Some(log::LevelFilter::Debug));
+    assert_eq!(log_level(0, true, None), Some(log::LevelFilter::Off));
+    let warn = Some(log::LevelFilter::Warn);
+    assert_eq!(log_level(2, false, warn), warn);
+    assert_eq!(log_level(0, true, warn), Some(log::LevelFilter::Off));
 }
@@ -0,0 +1,575 @@
+use human_sort::compare as human_compare;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Merge single-sample STRdust VCFs into a multi-sample VCF
+/// Alleles of the same locus are harmonized across samples, such that the same sequence
+/// gets the same ALT allele, and the genotypes are updated accordingly
+/// The filters of every sample are kept in the FT FORMAT field
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    /// vcf files produced by STRdust, with one sample each
+    #[clap(value_parser, required = true)]
+    vcfs: Vec<String>,
+
+    /// Flag samples with a longest allele this many standard deviations longer than the rest of the cohort
+    #[clap(long, value_parser)]
+    outlier_z: Option<f32>,
+
+    /// reference genome, required to merge records of a locus with another POS or REF per sample, e.g. with --normalize
+    #[clap(long, value_parser)]
+    fasta: Option<String>,
+
+    /// Left-align the merged REF and ALT alleles and trim them to a single anchor base, requires --fasta
+    #[clap(long, value_parser, default_value_t = false, requires = "fasta")]
+    normalize: bool,
+}
+
+// the minimal number of other samples with a call to compute a z-score
+const MIN_COHORT_SIZE: usize = 3;
+
+// a locus genotyped in a single sample
+#[derive(Clone)]
+struct SampleRecord {
+    pos: u32,
+    end: u32,
+    normalized: bool, // the record has a LOCUS INFO field, as it was normalized by call --normalize
+    id: String,
+    annotation: Vec<String>, // the GENE and DISEASE INFO fields from the bed file
+    ref_seq: String,
+    alts: Vec<String>,
+    filters: Vec<String>,
+    format: Vec<String>,
+    values: Vec<String>,
+}
+
+// the header lines and records of a single sample vcf, by their catalog locus
+struct SampleVcf {
+    header: Vec<String>,
+    sample: String,
+    records: HashMap<(String, u32, u32), SampleRecord>,
+}
+
+pub fn run(args: MergeArgs) {
+    let vcfs = args.vcfs.iter().map(|vcf| parse_vcf(vcf)).collect::<Vec<_>>();
+    let reference = |vcf: &SampleVcf| {
+        vcf.header
+            .iter()
+            .find(|line| line.starts_with("##reference="))
+            .cloned()
+    };
+    for (vcf, path) in vcfs.iter().zip(args.vcfs.iter()).skip(1) {
+        if reference(vcf) != reference(&vcfs[0]) {
+            warn!("{path} was genotyped with another reference than {}", args.vcfs[0]);
+        }
+    }
+    // the header lines of all vcfs, as they can be created with other options
+    let headers = vcfs.iter().map(|vcf| vcf.header.as_slice()).collect::<Vec<&[String]>>();
+    for line in merge_headers(&headers) {
+        println!("{line}");
+    }
+    println!(
+        r#"##INFO=<ID=NS,Number=1,Type=Integer,Description="Number of samples with a genotype for the locus">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FT,Number=1,Type=String,Description="Filters of the sample, PASS if none">"#
+    );
+    if args.outlier_z.is_some() {
+        println!(
+            r#"##INFO=<ID=EXPANDED,Number=.,Type=String,Description="Samples with an expansion compared to the rest of the cohort">"#
+        );
+        println!(
+            r#"##FORMAT=<ID=ZS,Number=1,Type=Float,Description="Z-score of the longest allele compared to the other samples">"#
+        );
+    }
+    let samples = vcfs.iter().map(|vcf| vcf.sample.as_str()).collect::<Vec<&str>>();
+    println!(
+        "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}",
+        samples.join("\t")
+    );
+    let mut loci = vcfs
+        .iter()
+        .flat_map(|vcf| vcf.records.keys().cloned())
+        .collect::<Vec<(String, u32, u32)>>();
+    loci.sort_by(|a, b| human_compare(&a.0, &b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+    loci.dedup();
+    debug!("Merging {} loci of {} samples", loci.len(), samples.len());
+    for locus in loci {
+        let records = vcfs
+            .iter()
+            .map(|vcf| vcf.records.get(&locus))
+            .collect::<Vec<Option<&SampleRecord>>>();
+        let records = harmonize(&locus.0, &records, args.fasta.as_deref());
+        let records = records.iter().map(|record| record.as_ref()).collect::<Vec<_>>();
+        let normalize = args.fasta.as_deref().filter(|_| args.normalize);
+        println!(
+            "{}",
+            merge_locus(&locus, &records, &samples, args.outlier_z, normalize)
+        );
+    }
+}
+
+// the records of a locus extended with the reference to the span of all records, with a shared POS and REF
+// the normalized records of a locus have another POS and REF per sample, depending on their alleles
+fn harmonize(
+    chrom: &str,
+    records: &[Option<&SampleRecord>],
+    fasta: Option<&str>,
+) -> Vec<Option<SampleRecord>> {
+    let present = records.iter().flatten().copied().collect::<Vec<&SampleRecord>>();
+    let first = present.first().expect("No records to merge");
+    if present.iter().all(|record| record.pos == first.pos && record.ref_seq == first.ref_seq) {
+        return records.iter().map(|record| record.cloned()).collect();
+    }
+    let fasta = fasta.unwrap_or_else(|| {
+        panic!(
+            "The records of {chrom}:{} have another POS or REF per sample, e.g. with call --normalize, merge them with --fasta",
+            first.pos
+        )
+    });
+    let ref_end = |record: &SampleRecord| record.pos + record.ref_seq.len() as u32 - 1;
+    let start = present.iter().map(|record| record.pos).min().unwrap_or(first.pos);
+    let end = present.iter().map(|record| ref_end(*record)).max().unwrap_or(first.pos);
+    let reference = |from: u32, to: u32| {
+        if from > to {
+            return String::new();
+        }
+        crate::repeats::reference_sequence(fasta, chrom, from, to)
+            .unwrap_or_else(|| panic!("{chrom}:{from}-{to} is not in the fasta file {fasta}"))
+    };
+    records
+        .iter()
+        .map(|record| {
+            record.map(|record| {
+                let prefix = reference(start, record.pos - 1);
+                let suffix = reference(ref_end(record) + 1, end);
+                let extend = |seq: &String| format!("{prefix}{seq}{suffix}");
+                SampleRecord {
+                    pos: start,
+                    end,
+                    ref_seq: extend(&record.ref_seq),
+                    alts: record.alts.iter().map(extend).collect(),
+                    ..record.clone()
+                }
+            })
+        })
+        .collect()
+}
+
+// combine the records of all samples for a locus, a sample without the locus gets a missing genotype
+// with outlier_z, the samples with an expansion relative to the cohort are reported
+// with the fasta file to normalize, the merged alleles are left-aligned and trimmed
+fn merge_locus(
+    locus: &(String, u32, u32),
+    records: &[Option<&SampleRecord>],
+    samples: &[&str],
+    outlier_z: Option<f32>,
+    normalize: Option<&str>,
+) -> String {
+    let ref_seq = records
+        .iter()
+        .flatten()
+        .next()
+        .map(|record| record.ref_seq.clone())
+        .expect("No records to merge");
+    // the alleles are keyed by their uppercased sequence, the first observed sequence is reported
+    // at a fixed locus a rotation of the sequence is another allele
+    let ref_key = ref_seq.to_uppercase();
+    let mut alleles: Vec<(String, String)> = vec![];
+    for record in records.iter().flatten() {
+        for alt in &record.alts {
+            let key = alt.to_uppercase();
+            if key != ref_key && !alleles.iter().any(|(k, _)| *k == key) {
+                alleles.push((key, alt.clone()));
+            }
+        }
+    }
+    // sorted by length, such that the order of the ALT alleles doesn't depend on the order of the samples
+    alleles.sort_by(|a, b| a.1.len().cmp(&b.1.len()).then(a.0.cmp(&b.0)));
+    let mut format: Vec<&str> = vec![];
+    for record in records.iter().flatten() {
+        for key in &record.format {
+            if !format.contains(&key.as_str()) {
+                format.push(key);
+            }
+        }
+    }
+    format.push("FT");
+    // a filter of the site is shared by all samples with a record, the other filters are per sample
+    let first = records.iter().flatten().next().expect("No records to merge");
+    let filters = first
+        .filters
+        .iter()
+        .filter(|filter| records.iter().flatten().all(|record| record.filters.contains(*filter)))
+        .map(|filter| filter.as_str())
+        .collect::<Vec<&str>>();
+    let id = records
+        .iter()
+        .flatten()
+        .map(|record| record.id.as_str())
+        .find(|id| *id != ".")
+        .unwrap_or(".");
+    let annotation = records
+        .iter()
+        .flatten()
+        .map(|record| &record.annotation)
+        .find(|annotation| !annotation.is_empty())
+        .map_or(String::new(), |annotation| {
+            annotation.iter().map(|field| format!(";{field}")).collect()
+        });
+    let mut called = 0;
+    let mut columns = vec![];
+    for record in records {
+        let record = match record {
+            Some(record) => record,
+            None => {
+                columns.push(vec!["."; format.len()].join(":"));
+                continue;
+            }
+        };
+        // the index of the harmonized allele for every allele index in this sample
+        let index_map = std::iter::once(0)
+            .chain(record.alts.iter().map(|alt| {
+                let key = alt.to_uppercase();
+                alleles
+                    .iter()
+                    .position(|(k, _)| *k == key)
+                    .map_or(0, |index| index + 1)
+            }))
+            .collect::<Vec<usize>>();
+        // the genotype likelihoods are only valid if the alleles of the sample keep their index
+        let same_alleles = record.alts.len() == alleles.len()
+            && index_map.iter().enumerate().all(|(old, new)| old == *new);
+        let values = format
+            .iter()
+            .map(|key| {
+                let value = record
+                    .format
+                    .iter()
+                    .position(|k| k == key)
+                    .and_then(|index| record.values.get(index))
+                    .map_or(".", |value| value.as_str());
+                if *key == "GT" {
+                    remap_genotype(value, &index_map)
+                } else if *key == "FT" {
+                    if record.filters.is_empty() {
+                        "PASS".to_string()
+                    } else {
+                        record.filters.join(";")
+                    }
+                } else if *key == "PL" && !same_alleles {
+                    ".".to_string()
+                } else {
+                    value.to_string()
+                }
+            })
+            .collect::<Vec<String>>();
+        if values.first().map_or(false, |gt| gt.chars().any(|c| c.is_ascii_digit())) {
+            called += 1;
+        }
+        columns.push(values.join(":"));
+    }
+    // the POS of the records only differs from the catalog locus if they are normalized
+    let (mut pos, mut end, mut ref_seq) = (first.pos, first.end, ref_seq);
+    if let Some(fasta) = normalize {
+        let mut seqs = std::iter::once(ref_seq.clone())
+            .chain(alleles.iter().map(|(_, seq)| seq.clone()))
+            .collect::<Vec<String>>();
+        if seqs.len() > 1 {
+            let reference_base = |pos| crate::repeats::reference_base(fasta, &locus.0, pos);
+            let normalized = crate::vcf::normalize_alleles(pos, &mut seqs, reference_base);
+            if let Some(normalized) = normalized {
+                pos = normalized;
+                end = pos + seqs[0].len() as u32 - 1;
+                ref_seq = seqs.remove(0);
+                for ((_, seq), normalized) in alleles.iter_mut().zip(seqs) {
+                    *seq = normalized;
+                }
+            }
+        }
+    }
+    let alt = if alleles.is_empty() {
+        ".".to_string()
+    } else {
+        alleles
+            .into_iter()
+            .map(|(_, seq)| seq)
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    let filter = if filters.is_empty() {
+        ".".to_string()
+    } else {
+        filters.join(";")
+    };
+    let mut info = format!("END={end};NS={called}{annotation}");
+    if normalize.is_some() || records.iter().flatten().any(|record| record.normalized) {
+        info.push_str(&format!(";LOCUS={}:{}-{}", locus.0, locus.1, locus.2));
+    }
+    let mut format = format.join(":");
+    if let Some(min_z) = outlier_z {
+        let lengths = records
+            .iter()
+            .map(|record| record.and_then(longest_allele))
+            .collect::<Vec<Option<i64>>>();
+        let z_scores = cohort_z_scores(&lengths);
+        for (column, z) in columns.iter_mut().zip(z_scores.iter()) {
+            match z {
+                Some(z) => column.push_str(&format!(":{z:.2}")),
+                None => column.push_str(":."),
+            }
+        }
+        format.push_str(":ZS");
+        let expanded = samples
+            .iter()
+            .zip(z_scores.iter())
+            .filter(|(_, z)| z.map_or(false, |z| z >= min_z))
+            .map(|(sample, _)| *sample)
+            .collect::<Vec<&str>>();
+        if !expanded.is_empty() {
+            info.push_str(&format!(";EXPANDED={}", expanded.join(",")));
+        }
+    }
+    format!(
+        "{}\t{pos}\t{id}\t{ref_seq}\t{alt}\t.\t{filter}\t{info}\t{format}\t{}",
+        locus.0,
+        columns.join("\t")
+    )
+}
+
+// the length relative to the reference of the longest allele, from the RB field
+fn longest_allele(record: &SampleRecord) -> Option<i64> {
+    let index = record.format.iter().position(|key| key == "RB")?;
+    record
+        .values
+        .get(index)?
+        .split(',')
+        .filter_map(|length| length.parse::<i64>().ok())
+        .max()
+}
+
+// the z-score of every sample compared to the mean and standard deviation of the other samples
+// the standard deviation is at least one base, as the lengths in a cohort are often identical
+fn cohort_z_scores(lengths: &[Option<i64>]) -> Vec<Option<f32>> {
+    lengths
+        .iter()
+        .enumerate()
+        .map(|(index, length)| {
+            let length = (*length)? as f32;
+            let others = lengths
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .filter_map(|(_, l)| l.map(|l| l as f32))
+                .collect::<Vec<f32>>();
+            if others.len() < MIN_COHORT_SIZE {
+                return None;
+            }
+            let mean = others.iter().sum::<f32>() / others.len() as f32;
+            let variance =
+                others.iter().map(|l| (l - mean).powi(2)).sum::<f32>() / others.len() as f32;
+            Some((length - mean) / variance.sqrt().max(1.0))
+        })
+        .collect()
+}
+
+// the header lines of all vcfs in the order of the first vcf that has them
+// a structured line such as ##INFO=<ID=..> is kept once per ID, another line such as ##reference= once per key
+fn merge_headers(headers: &[&[String]]) -> Vec<String> {
+    let key = |line: &str| {
+        let (key, value) = line.split_once('=').unwrap_or((line, ""));
+        match value.strip_prefix("<ID=") {
+            Some(rest) => {
+                let id = rest.split(|c| c == ',' || c == '>').next().unwrap_or("");
+                format!("{key}={id}")
+            }
+            None => key.to_string(),
+        }
+    };
+    let mut keys = std::collections::HashSet::new();
+    let mut merged = vec![];
+    for line in headers.iter().flat_map(|header| header.iter()) {
+        if keys.insert(key(line)) {
+            merged.push(line.clone());
+        }
+    }
+    merged
+}
+
+// translate the allele indices of a genotype, keeping the separator and missing alleles
+fn remap_genotype(genotype: &str, index_map: &[usize]) -> String {
+    let mut remapped = String::new();
+    let mut index = String::new();
+    for c in genotype.chars().chain(std::iter::once('|')) {
+        if c.is_ascii_digit() {
+            index.push(c);
+            continue;
+        }
+        if !index.is_empty() {
+            let old = index.parse::<usize>().expect("Failed parsing genotype");
+            let new = index_map.get(old).copied().unwrap_or(old);
+            remapped.push_str(&new.to_string());
+            index.clear();
+        }
+        remapped.push(c);
+    }
+    // remove the separator that was added to process the last allele
+    remapped.pop();
+    remapped
+}
+
+fn parse_vcf(vcf: &str) -> SampleVcf {
+    let mut header = vec![];
+    let mut sample = None;
+    let mut records = HashMap::new();
+    for line in crate::utils::reader(vcf).lines() {
+        let line = line.unwrap_or_else(|err| panic!("Failed reading {vcf}: {err}"));
+        if line.starts_with("##") {
+            header.push(line);
+        } else if line.starts_with('#') {
+            let columns = line.split('\t').collect::<Vec<&str>>();
+            if columns.len() != 10 {
+                panic!("{vcf} does not contain a single sample");
+            }
+            sample = Some(columns[9].to_string());
+        } else if let Some((locus, record)) = parse_record(&line) {
+            records.insert(locus, record);
+        }
+    }
+    SampleVcf {
+        header,
+        sample: sample.unwrap_or_else(|| panic!("No #CHROM header line in {vcf}")),
+        records,
+    }
+}
+
+fn parse_record(line: &str) -> Option<((String, u32, u32), SampleRecord)> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 10 {
+        return None;
+    }
+    let end = fields[7]
+        .split(';')
+        .find_map(|info| info.strip_prefix("END="))?
+        .parse()
+        .ok()?;
+    let alts = match fields[4] {
+        "." => vec![],
+        alts => alts.split(',').map(|alt| alt.to_string()).collect(),
+    };
+    let filters = match fields[6] {
+        "." | "PASS" => vec![],
+        filters => filters.split(';').map(|f| f.to_string()).collect(),
+    };
+    let annotation = fields[7]
+        .split(';')
+        .filter(|info| info.starts_with("GENE=") || info.starts_with("DISEASE="))
+        .map(|info| info.to_string())
+        .collect();
+    let pos = fields[1].parse().ok()?;
+    // a normalized record is matched by its catalog locus, as its POS depends on the alleles
+    let catalog_locus = fields[7].split(';').find_map(|info| info.strip_prefix("LOCUS="));
+    let locus = match catalog_locus {
+        Some(locus) => {
+            let (chrom, interval) = locus.rsplit_once(':')?;
+            let (start, locus_end) = interval.split_once('-')?;
+            (chrom.to_string(), start.parse().ok()?, locus_end.parse().ok()?)
+        }
+        None => (fields[0].to_string(), pos, end),
+    };
+    Some((
+        locus,
+        SampleRecord {
+            pos,
+            end,
+            normalized: catalog_locus.is_some(),
+            id: fields[2].to_string(),
+            annotation,
+            ref_seq: fields[3].to_string(),
+            alts,
+            filters,
+            format: fields[8].split(':').map(|key| key.to_string()).collect(),
+            values: fields[9].split(':').map(|value| value.to_string()).collect(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap_genotype() {
+        assert_eq!(remap_genotype("1|2", &[0, 2, 1]), "2|1");
+        assert_eq!(remap_genotype("0|.", &[0, 1]), "0|.");
+        assert_eq!(remap_genotype("1", &[0, 3]), "3");
+    }
+
+    #[test]
+    fn test_merge_locus() {
+        let line1 = "chr7\t100\t.\tCAGCAG\tCAGCAGCAG\t.\t.\tEND=106;STDEV=0,0\tGT:RB\t1|0:3,0";
+        // a rotation of the expansion is another allele, and a longer allele
+        let line2 = "chr7\t100\tHD\tCAGCAG\tAGCAGCAGC,CAGCAGCAGCAG\t.\tLOWCONF_FLANK\tEND=106;STDEV=0,0;GENE=HTT\tGT:RB:DP:SPAN\t2|1:6,3:10:8";
+        let (locus, record1) = parse_record(line1).expect("Failed parsing record");
+        let (_, record2) = parse_record(line2).expect("Failed parsing record");
+        let merged = merge_locus(
+            &locus,
+            &[Some(&record1), Some(&record2), None],
+            &["sample1", "sample2", "sample3"],
+            None,
+            None,
+        );
+        assert_eq!(
+            merged,
+            "chr7\t100\tHD\tCAGCAG\tAGCAGCAGC,CAGCAGCAG,CAGCAGCAGCAG\t.\t.\tEND=106;NS=2;GENE=HTT\tGT:RB:DP:SPAN:FT\t2|0:3,0:.:.:PASS\t3|1:6,3:10:8:LOWCONF_FLANK\t.:.:.:.:."
+        );
+    }
+
+    #[test]
+    fn test_merge_normalized() {
+        // an insertion and a deletion of TTTC, normalized by call --normalize to another REF allele
+        let line1 = "chr7\t154654403\t.\tA\tATCTT\t.\t.\tEND=154654403;STDEV=0,0;LOCUS=chr7:154654404-154654432\tGT\t1|0";
+        let line2 = "chr7\t154654403\t.\tATCTT\tA\t.\t.\tEND=154654407;STDEV=0,0;LOCUS=chr7:154654404-154654432\tGT\t0|1";
+        let (locus, record1) = parse_record(line1).expect("Failed parsing record");
+        let (locus2, record2) = parse_record(line2).expect("Failed parsing record");
+        assert_eq!(locus, ("chr7".to_string(), 154654404, 154654432));
+        assert_eq!(locus, locus2);
+        let fasta = "test_data/chr7.fa.gz";
+        let records = harmonize(&locus.0, &[Some(&record1), Some(&record2)], Some(fasta));
+        let records = records.iter().map(|record| record.as_ref()).collect::<Vec<_>>();
+        let merged = merge_locus(&locus, &records, &["sample1", "sample2"], None, Some(fasta));
+        assert_eq!(
+            merged,
+            "chr7\t154654403\t.\tATCTT\tA,ATCTTTCTT\t.\t.\tEND=154654407;NS=2;LOCUS=chr7:154654404-154654432\tGT:FT\t2|0:PASS\t0|1:PASS"
+        );
+    }
+
+    #[test]
+    fn test_merge_headers() {
+        let header1 = vec![
+            "##reference=ref.fa".to_string(),
+            r#"##FILTER=<ID=LOWCONF_FLANK,Description="Low confidence">"#.to_string(),
+        ];
+        let header2 = vec![
+            "##reference=other.fa".to_string(),
+            r#"##FILTER=<ID=LOWCONF_FLANK,Description="Low confidence">"#.to_string(),
+            r#"##FILTER=<ID=NOCOV,Description="No reads">"#.to_string(),
+        ];
+        let merged = merge_headers(&[&header1, &header2]);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0], "##reference=ref.fa");
+        assert!(merged[2].contains("NOCOV"));
+    }
+
+    #[test]
+    fn test_cohort_z_scores() {
+        let lengths = [Some(0), Some(0), Some(3), Some(300), None];
+        let z_scores = cohort_z_scores(&lengths);
+        assert!(z_scores[3].expect("No z-score") > 10.0);
+        assert!(z_scores[0].expect("No z-score") < 1.0);
+        assert_eq!(z_scores[4], None);
+        // too few other samples to compute a z-score
+        assert_eq!(cohort_z_scores(&[Some(0), Some(300)]), vec![None, None]);
+    }
+}
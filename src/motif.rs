@@ -1,8 +1,313 @@
-// write a function that takes a long string like CAGCAGCAGCAGCGGCGGCGGCAGCAGCAG and converts it to a condensed representation like (CAG)4(CGG)3(CAG)3
-// the function should identify repeated parts and replace them with (repeat)number
-
+// takes a long string like CAGCAGCAGCAGCGGCGGCGGCAGCAGCAG and converts it to a condensed representation like (CAG)4(CGG)3(CAG)3
+// the repeated parts are replaced with (repeat)number, the sequence in between is kept as is
 fn create_motif(seq: &str) -> String {
-    unimplemented!()
+    let seq = seq.to_uppercase();
+    let mut condensed = String::new();
+    let mut pos = 0;
+    for run in motif_runs(&seq, 6, 1) {
+        condensed.push_str(&seq[pos..run.start]);
+        condensed.push_str(&format!("({}){}", run.motif, run.copies));
+        pos = run.end;
+    }
+    condensed.push_str(&seq[pos..]);
+    condensed
+}
+
+/// A run of consecutive copies of a motif in a sequence, from start to end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MotifRun {
+    pub motif: String,
+    pub copies: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The runs of at least MIN_COPIES copies of a motif of at most max_length bases, from left to right
+/// At every position the motif covering the longest sequence is taken, the shortest motif for a tie
+/// Copies with up to mismatches differing bases are counted within a run, as with --motif-mismatches
+pub fn motif_runs(seq: &str, max_length: usize, mismatches: usize) -> Vec<MotifRun> {
+    let seq = seq.to_uppercase();
+    let mut runs = vec![];
+    let mut pos = 0;
+    while pos < seq.len() {
+        let longest = (1..=max_length)
+            .filter(|k| pos + k <= seq.len() && seq.is_char_boundary(pos + k))
+            .map(|k| (k, count_copies(&seq, pos, &seq[pos..pos + k], None, mismatches)))
+            .filter(|(_, (copies, _))| *copies >= MIN_COPIES)
+            .max_by(|(k_a, (_, end_a)), (k_b, (_, end_b))| end_a.cmp(end_b).then(k_b.cmp(k_a)));
+        match longest {
+            Some((k, (copies, end))) => {
+                runs.push(MotifRun {
+                    motif: seq[pos..pos + k].to_string(),
+                    copies,
+                    start: pos,
+                    end,
+                });
+                pos = end;
+            }
+            None => pos += 1,
+        }
+    }
+    runs
+}
+
+/// The runs of at least MIN_NESTED_LENGTH bases, to find adjacent repeats with a different motif
+/// such as the catalog motif followed by a VNTR in the same allele
+pub fn nested_runs(seq: &str, max_length: usize, mismatches: usize) -> Vec<MotifRun> {
+    motif_runs(seq, max_length, mismatches)
+        .into_iter()
+        .filter(|run| run.end - run.start >= MIN_NESTED_LENGTH)
+        .collect()
+}
+
+/// The distinct motifs of the nested runs in a sequence, in order of occurrence
+/// rotations of the same motif (e.g. CAG and AGC) are considered the same motif
+pub fn nested_motifs(seq: &str, max_length: usize, mismatches: usize) -> Vec<String> {
+    let mut motifs: Vec<String> = vec![];
+    for run in nested_runs(seq, max_length, mismatches) {
+        if !motifs.iter().any(|motif| same_motif(motif, &run.motif)) {
+            motifs.push(run.motif);
+        }
+    }
+    motifs
+}
+
+pub fn same_motif(a: &str, b: &str) -> bool {
+    crate::utils::canonical_rotation(a) == crate::utils::canonical_rotation(b)
+}
+
+// the fraction of bases equal to the base one period further for a sequence to be considered repetitive
+const MIN_PERIODICITY: f32 = 0.8;
+// the minimal number of copies of a motif in a run
+const MIN_COPIES: usize = 3;
+// the minimal length of a run to be reported as a nested repeat
+const MIN_NESTED_LENGTH: usize = 12;
+
+/// A part of a locus structure, either a repeated motif or a fixed sequence in between
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Repeat(String),
+    Fixed(String),
+}
+
+/// The structure of a compound locus, such as (CAG)nCAACAG(CCG)n for HTT
+/// as specified by STRUC= in TRGT-style catalogs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocusStructure {
+    pub segments: Vec<Segment>,
+}
+
+impl LocusStructure {
+    /// Parse a structure like (CAG)nCAACAG(CCG)n, returns None if it can not be parsed
+    pub fn parse(struc: &str) -> Option<Self> {
+        let mut segments = vec![];
+        let mut rest = struc.trim();
+        while !rest.is_empty() {
+            if let Some(inner) = rest.strip_prefix('(') {
+                let (motif, after) = inner.split_once(")n")?;
+                if !is_nucleotide_sequence(motif) {
+                    return None;
+                }
+                segments.push(Segment::Repeat(motif.to_uppercase()));
+                rest = after;
+            } else {
+                let end = rest.find('(').unwrap_or(rest.len());
+                if !is_nucleotide_sequence(&rest[..end]) {
+                    return None;
+                }
+                segments.push(Segment::Fixed(rest[..end].to_uppercase()));
+                rest = &rest[end..];
+            }
+        }
+        if segments.iter().any(|s| matches!(s, Segment::Repeat(_))) {
+            Some(LocusStructure { segments })
+        } else {
+            None
+        }
+    }
+
+    /// The motif of the first repeated segment
+    pub fn first_motif(&self) -> Option<String> {
+        self.segments.iter().find_map(|s| match s {
+            Segment::Repeat(motif) => Some(motif.clone()),
+            Segment::Fixed(_) => None,
+        })
+    }
+
+    /// Count the copies of every repeated motif in an allele sequence, with up to mismatches bases per interrupted copy
+    /// formatted as the counts per repeat segment joined by '_', or '.' if the sequence does not fit the structure
+    pub fn motif_counts(&self, seq: &str, mismatches: usize) -> String {
+        if seq == "." {
+            return ".".to_string();
+        }
+        let seq = seq.to_uppercase();
+        let mut pos = 0;
+        let mut counts = vec![];
+        for (index, segment) in self.segments.iter().enumerate() {
+            // the start of the next segment, to avoid interrupted copies consuming the next segment
+            let next = self.segments.get(index + 1).map(|s| match s {
+                Segment::Repeat(motif) | Segment::Fixed(motif) => motif.as_str(),
+            });
+            match segment {
+                Segment::Repeat(motif) => {
+                    let (copies, new_pos) = count_copies(&seq, pos, motif, next, mismatches);
+                    counts.push(copies.to_string());
+                    pos = new_pos;
+                }
+                Segment::Fixed(fixed) => {
+                    // the fixed sequence is allowed a few mismatches
+                    if pos + fixed.len() > seq.len()
+                        || hamming(&seq[pos..pos + fixed.len()], fixed) > fixed.len() / 5
+                    {
+                        return ".".to_string();
+                    }
+                    pos += fixed.len();
+                }
+            }
+        }
+        counts.join("_")
+    }
+}
+
+// count consecutive copies of a motif from pos, tolerating interruptions with up to mismatches differing bases
+// if the sequence continues with the motif afterwards and the interruption is not the next segment
+// at most half of the bases of an interrupted copy can differ, such that short motifs do not match anything
+fn count_copies(
+    seq: &str,
+    mut pos: usize,
+    motif: &str,
+    next: Option<&str>,
+    mismatches: usize,
+) -> (usize, usize) {
+    let mut copies = 0;
+    let len = motif.len();
+    let max_mismatches = mismatches.min(len.div_ceil(2));
+    while pos + len <= seq.len() {
+        if seq[pos..].starts_with(motif) {
+            copies += 1;
+            pos += len;
+        } else if next.map_or(true, |next| !seq[pos..].starts_with(next))
+            && (1..=max_mismatches).contains(&hamming(&seq[pos..pos + len], motif))
+            && seq[pos + len..].starts_with(motif)
+        {
+            copies += 1;
+            pos += len;
+        } else {
+            break;
+        }
+    }
+    (copies, pos)
+}
+
+/// The repeated motif of a sequence with the shortest period of at most max_length
+/// such that at least MIN_PERIODICITY of the bases equal the base one period further
+/// The motif is the most frequent unit, as its lexicographically smallest rotation
+/// Returns None if the sequence is not repetitive
+pub fn detect_motif(seq: &str, max_length: usize) -> Option<String> {
+    let seq = seq.to_uppercase();
+    let bases = seq.as_bytes();
+    let period = (1..=max_length)
+        .filter(|k| bases.len() >= 2 * k)
+        .find(|k| {
+            let matches = (0..bases.len() - k).filter(|i| bases[*i] == bases[i + k]).count();
+            matches as f32 >= MIN_PERIODICITY * (bases.len() - k) as f32
+        })?;
+    let mut counts = std::collections::HashMap::new();
+    for unit in bases.chunks_exact(period) {
+        let unit = String::from_utf8_lossy(unit);
+        *counts.entry(crate::utils::canonical_rotation(&unit)).or_insert(0) += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(a, count_a), (b, count_b)| count_a.cmp(count_b).then(b.cmp(a)))
+        .map(|(motif, _)| motif)
+}
+
+// the minimal number of consecutive perfect copies of a motif to count towards the purity
+const MIN_PURE_COPIES: usize = 2;
+// the edit distance to a perfect repeat is not computed for longer sequences, as it is quadratic
+const MAX_EDIT_DISTANCE_LENGTH: usize = 5000;
+
+/// The fraction of a sequence in runs of perfect copies of a motif, in any rotation,
+/// and the edit distance to the closest perfect repeat of the motif with the same length
+/// A small fraction suggests that the sequence is not an expansion of the motif, e.g. an inserted mobile element
+pub fn purity(seq: &str, motif: &str) -> (f32, Option<usize>) {
+    let seq = seq.to_uppercase();
+    let motif = motif.to_uppercase();
+    if seq.is_empty() || motif.is_empty() {
+        return (0.0, None);
+    }
+    let rotations = crate::utils::rotations(&motif);
+    let mut pure = 0;
+    let mut pos = 0;
+    while pos < seq.len() {
+        // the rotation with the longest run of perfect copies from this position
+        let longest = rotations
+            .iter()
+            .map(|rotation| {
+                let mut copies = 0;
+                while pos + (copies + 1) * motif.len() <= seq.len()
+                    && seq[pos + copies * motif.len()..].starts_with(rotation.as_str())
+                {
+                    copies += 1;
+                }
+                copies
+            })
+            .max()
+            .unwrap_or_default();
+        if longest >= MIN_PURE_COPIES {
+            pure += longest * motif.len();
+            pos += longest * motif.len();
+        } else {
+            pos += 1;
+        }
+    }
+    let distance = (seq.len() <= MAX_EDIT_DISTANCE_LENGTH).then(|| {
+        rotations
+            .iter()
+            .map(|rotation| {
+                let perfect = rotation.repeat(seq.len() / motif.len() + 1);
+                crate::utils::edit_distance(&seq, &perfect[..seq.len()])
+            })
+            .min()
+            .unwrap_or_default()
+    });
+    (pure as f32 / seq.len() as f32, distance)
+}
+
+/// The number of copies of a motif in a sequence, in any rotation, in runs of at least MIN_PURE_COPIES copies
+/// Interrupted copies within a run are counted as a copy, while other sequence between the runs is not counted
+pub fn repeat_units(seq: &str, motif: &str, mismatches: usize) -> usize {
+    let seq = seq.to_uppercase();
+    let motif = motif.to_uppercase();
+    if motif.is_empty() {
+        return 0;
+    }
+    let rotations = crate::utils::rotations(&motif);
+    let mut units = 0;
+    let mut pos = 0;
+    while pos < seq.len() {
+        let (copies, end) = rotations
+            .iter()
+            .map(|rotation| count_copies(&seq, pos, rotation, None, mismatches))
+            .max_by_key(|(copies, _)| *copies)
+            .unwrap_or((0, pos));
+        if copies >= MIN_PURE_COPIES {
+            units += copies;
+            pos = end;
+        } else {
+            pos += 1;
+        }
+    }
+    units
+}
+
+fn hamming(a: &str, b: &str) -> usize {
+    a.bytes().zip(b.bytes()).filter(|(x, y)| x != y).count()
+}
+
+fn is_nucleotide_sequence(seq: &str) -> bool {
+    !seq.is_empty() && seq.chars().all(|c| "ACGTNacgtn".contains(c))
 }
 
 #[cfg(test)]
@@ -10,11 +315,90 @@ mod tests {
     use super::*;
 
     #[test]
-    #[ignore]
     fn test_create_motif() {
         assert_eq!(
             create_motif("CAGCAGCAGCAGCGGCGGCGGCAGCAGCAG"),
             "(CAG)4(CGG)3(CAG)3"
         );
     }
+
+    #[test]
+    fn test_nested_motifs() {
+        // the CAG repeat is followed by a VNTR with a 10 base motif
+        let seq = "CAG".repeat(15) + "TT" + &"GCCCCGCCTG".repeat(4);
+        assert_eq!(nested_motifs(&seq, 20, 1), vec!["CAG", "GCCCCGCCTG"]);
+        let runs = nested_runs(&seq, 20, 1);
+        assert_eq!(runs[1].start, 47);
+        assert_eq!(runs[1].copies, 4);
+        // a rotation of the motif is not a second motif
+        let seq = "CAG".repeat(10) + "TTTTT" + &"AGC".repeat(10);
+        assert_eq!(nested_motifs(&seq, 20, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_detect_motif() {
+        assert_eq!(detect_motif(&"CAG".repeat(30), 6), Some("AGC".to_string()));
+        assert_eq!(detect_motif(&"AAAAG".repeat(10), 6), Some("AAAAG".to_string()));
+        // an interruption is tolerated
+        let seq = "CGG".repeat(10) + "AGG" + &"CGG".repeat(10);
+        assert_eq!(detect_motif(&seq, 6), Some("CGG".to_string()));
+        assert_eq!(detect_motif("ACGTTGCATGCAGTCAGTACGATCGATGCTAGCTAGTCGAT", 6), None);
+    }
+
+    #[test]
+    fn test_parse_locus_structure() {
+        let structure = LocusStructure::parse("(CAG)nCAACAG(CCG)n").expect("Failed parsing");
+        assert_eq!(
+            structure.segments,
+            vec![
+                Segment::Repeat("CAG".to_string()),
+                Segment::Fixed("CAACAG".to_string()),
+                Segment::Repeat("CCG".to_string()),
+            ]
+        );
+        assert!(LocusStructure::parse("<ATXN8>").is_none());
+        assert!(LocusStructure::parse("(CAG)n(CCG").is_none());
+    }
+
+    #[test]
+    fn test_motif_counts() {
+        let structure = LocusStructure::parse("(CAG)nCAACAG(CCG)n").expect("Failed parsing");
+        let seq = "CAG".repeat(20) + "CAACAG" + &"CCG".repeat(7);
+        assert_eq!(structure.motif_counts(&seq, 1), "20_7");
+        // an interruption within the first repeat is counted as a copy
+        let seq = "CAG".repeat(10) + "CTG" + &"CAG".repeat(9) + "CAACAG" + &"CCG".repeat(7);
+        assert_eq!(structure.motif_counts(&seq, 1), "20_7");
+        // unless interruptions are not allowed
+        assert_eq!(structure.motif_counts(&seq, 0), ".");
+        assert_eq!(structure.motif_counts(".", 1), ".");
+
+        let structure = LocusStructure::parse("(AAGGG)n(AAAAG)n").expect("Failed parsing");
+        let seq = "AAGGG".repeat(12) + &"AAAAG".repeat(3);
+        assert_eq!(structure.motif_counts(&seq, 1), "12_3");
+    }
+
+    #[test]
+    fn test_repeat_units() {
+        assert_eq!(repeat_units("CAGCAGCAGCAG", "CAG", 1), 4);
+        // in another rotation, with an interrupted copy
+        assert_eq!(repeat_units("AGCAGCAACAGCAGC", "CAG", 1), 5);
+        // the sequence between the runs is not counted
+        assert_eq!(repeat_units("CAGCAGCAGTTTTTTTTCAGCAG", "CAG", 1), 5);
+        assert_eq!(repeat_units("ACGT", "CAG", 1), 0);
+    }
+
+    #[test]
+    fn test_purity() {
+        assert_eq!(purity(&"CAG".repeat(10), "CAG"), (1.0, Some(0)));
+        // a rotation of the motif is also a perfect repeat
+        assert_eq!(purity(&"AGC".repeat(10), "cag"), (1.0, Some(0)));
+        let seq = "CAG".repeat(10) + "ATTGCAATGCTTACGGATTACCTGAATCGT";
+        let (fraction, distance) = purity(&seq, "CAG");
+        assert_eq!(fraction, 0.5);
+        assert!(distance.is_some_and(|distance| distance > 10));
+        // a single interruption
+        let seq = "CAG".repeat(5) + "CAA" + &"CAG".repeat(4);
+        assert_eq!(purity(&seq, "CAG").1, Some(1));
+        assert_eq!(purity("", "CAG"), (0.0, None));
+    }
 }
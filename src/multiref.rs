@@ -0,0 +1,111 @@
+use crate::repeats::{RepeatInterval, RepeatIntervalIterator};
+use crate::vcf::VCFRecord;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// alleles with a full length differing by more than this number of bases between references are flagged
+const MAX_LENGTH_DIFFERENCE: i64 = 5;
+
+/// A second reference genome with a catalog of the same loci, in the same order as the primary catalog
+/// Every locus of the primary catalog is paired to the locus on the same line of the second catalog
+pub struct SecondReference {
+    pub fasta: String,
+    loci: HashMap<(String, u32, u32), RepeatInterval>,
+}
+
+impl SecondReference {
+    pub fn from_beds(
+        region_file: &String,
+        fasta: &str,
+        second_region_file: &String,
+        second_fasta: &str,
+    ) -> SecondReference {
+        let primary = RepeatIntervalIterator::from_bed(region_file, fasta);
+        let second = RepeatIntervalIterator::from_bed(second_region_file, second_fasta);
+        if primary.num_intervals != second.num_intervals {
+            panic!(
+                "The catalogs {region_file} and {second_region_file} have a different number of loci ({} and {})",
+                primary.num_intervals, second.num_intervals
+            );
+        }
+        let loci = primary
+            .zip(second)
+            .map(|(primary, second)| ((primary.chrom, primary.start, primary.end), second))
+            .collect();
+        SecondReference {
+            fasta: second_fasta.to_string(),
+            loci,
+        }
+    }
+
+    /// The locus in the second reference paired to a locus of the primary catalog
+    pub fn paired_locus(&self, repeat: &RepeatInterval) -> Option<&RepeatInterval> {
+        self.loci.get(&(repeat.chrom.clone(), repeat.start, repeat.end))
+    }
+}
+
+/// The call of a locus against the second reference, reported along the call against the primary reference
+/// discrepant: the full allele lengths differ between the references
+pub struct SecondCall {
+    pub locus: String,
+    pub full_length: (String, String),
+    pub discrepant: bool,
+}
+
+impl SecondCall {
+    pub fn new(record: &VCFRecord, second: &VCFRecord, locus: &RepeatInterval) -> SecondCall {
+        let differs = |primary: &str, second: &str| {
+            match (primary.parse::<i64>(), second.parse::<i64>()) {
+                (Ok(primary), Ok(second)) => (primary - second).abs() > MAX_LENGTH_DIFFERENCE,
+                // an allele called against only one of the references is also a discrepancy
+                (Ok(_), Err(_)) | (Err(_), Ok(_)) => true,
+                (Err(_), Err(_)) => false,
+            }
+        };
+        let discrepant = differs(&record.full_length.0, &second.full_length.0)
+            || (!record.haploid && differs(&record.full_length.1, &second.full_length.1));
+        SecondCall {
+            locus: locus.to_string(),
+            full_length: second.full_length.clone(),
+            discrepant,
+        }
+    }
+
+    pub fn to_json(&self, haploid: bool) -> Value {
+        let full_length = if haploid {
+            json!([crate::vcf::missing_to_null(&self.full_length.0)])
+        } else {
+            json!([
+                crate::vcf::missing_to_null(&self.full_length.0),
+                crate::vcf::missing_to_null(&self.full_length.1)
+            ])
+        };
+        json!({
+            "locus": self.locus,
+            "full_length": full_length,
+            "discrepant": self.discrepant,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_call() {
+        let repeat = RepeatInterval::new("chr7", 100, 106);
+        let locus = RepeatInterval::new("chr7", 200, 209);
+        let mut record = crate::test_utils::cag_record(&repeat, "0");
+        record.full_length = ("30".to_string(), "60".to_string());
+        let mut second = VCFRecord::missing_genotype(&locus, "CAGCAGCAG", "0".to_string());
+        second.full_length = ("30".to_string(), "63".to_string());
+        let call = SecondCall::new(&record, &second, &locus);
+        assert_eq!(call.locus, "chr7:200-209");
+        assert!(!call.discrepant);
+        second.full_length = ("30".to_string(), "90".to_string());
+        assert!(SecondCall::new(&record, &second, &locus).discrepant);
+        second.full_length = ("30".to_string(), ".".to_string());
+        assert!(SecondCall::new(&record, &second, &locus).discrepant);
+    }
+}
@@ -1,23 +1,80 @@
-use log::debug;
+use crate::locus_log::{log_locus, Stage};
+use log::{debug, warn, Level};
 use rust_htslib::bam;
 use rust_htslib::bam::ext::BamRecordExtensions;
-use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::record::{Aux, Cigar};
 use rust_htslib::bam::Read;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap};
 use url::Url;
 
+#[derive(Clone)]
 pub struct Reads {
     // could consider not to use a hashmap here and use an attribute per phase
     pub seqs: HashMap<u8, Vec<Vec<u8>>>,
     pub ps: Option<u32>,
+    pub depth: usize,    // number of reads overlapping the repeat
+    pub spanning: usize, // number of reads spanning the repeat
+    pub anchored: usize, // number of spanning reads of which a single alignment is anchored in both flanks
+    pub clipped: usize,  // number of reads soft-clipped within the repeat, e.g. shorter than the allele
+    pub low_mapq: usize, // number of reads overlapping the repeat with a mapping quality below LOW_MAPQ
+    // the phase set of every read in seqs, used to only keep reads from a single phase block
+    phase_sets: HashMap<u8, Vec<Option<u32>>>,
+    // the alignments of the reads in phase 0, only kept for phasing using SNVs
+    pub records: Vec<bam::Record>,
+    keep_records: bool,
+    pub snv_phased: bool, // true if the reads in phase 1 and 2 were phased using SNVs
+    // the fraction of methylated CpGs in the repeat for every read in seqs, only for FMR1
+    pub methylation: HashMap<u8, Vec<Option<f32>>>,
+    // the strand and base quality in the repeat of every read in seqs
+    pub qc: HashMap<u8, Vec<crate::read_qc::ReadQc>>,
+    // the number of spanning reads used per read group, only with a read group filter
+    pub read_groups: HashMap<String, usize>,
+    // with --max-reads-per-read-group, the spanning reads per read group with the hash of their name and their order,
+    // of which a sample is used once all reads are collected
+    sampled: HashMap<String, Vec<(u64, usize, bam::Record)>>,
+    // the number of spanning reads excluded for a low identity and for a short length
+    pub excluded: (usize, usize),
+    read_filter: ReadFilter,
 }
 
+/// The reads to use, with the read group filter and the thresholds on the read identity and length
+/// read_qc: collect the base quality in the repeat of every read, only for --read-qc
+#[derive(Clone, Debug, Default)]
+pub struct ReadFilter {
+    pub read_groups: ReadGroupFilter,
+    pub min_identity: Option<f32>,
+    pub min_length: Option<usize>,
+    pub read_qc: bool,
+}
+
+impl ReadFilter {
+    /// True if reads are excluded by their identity or length
+    pub fn excludes_reads(&self) -> bool {
+        self.min_identity.is_some() || self.min_length.is_some()
+    }
+}
+
+/// The read groups to use, with --read-group and --max-reads-per-read-group
+/// keep: only use the reads of these read groups
+/// max_reads: use at most this number of spanning reads per read group
+#[derive(Clone, Debug, Default)]
+pub struct ReadGroupFilter {
+    pub keep: Option<Vec<String>>,
+    pub max_reads: Option<usize>,
+}
+
+impl ReadGroupFilter {
+    pub fn is_active(&self) -> bool {
+        self.keep.is_some() || self.max_reads.is_some()
+    }
+}
+
+// reads with a mapping quality below this threshold suggest a poorly mappable flank
+pub const LOW_MAPQ: u8 = 20;
+
 pub fn create_bam_reader(bamf: &str, fasta: &str) -> bam::IndexedReader {
-    let mut bam = if bamf.starts_with("s3") || bamf.starts_with("https://") {
-        if env::var("CURL_CA_BUNDLE").is_err() {
-            env::set_var("CURL_CA_BUNDLE", "/etc/ssl/certs/ca-certificates.crt");
-        }
+    // the CA certificates for remote files are set once at startup, see utils::set_ca_bundle
+    let mut bam = if crate::utils::is_remote(bamf) {
         bam::IndexedReader::from_url(&Url::parse(bamf).expect("Failed to parse URL"))
             .unwrap_or_else(|err| panic!("Error opening remote BAM: {err}"))
     } else {
@@ -38,34 +95,285 @@ pub fn create_bam_reader(bamf: &str, fasta: &str) -> bam::IndexedReader {
     bam
 }
 
+/// A reader of all records of a bam/cram file without an index, local or remote
+pub fn create_sequential_reader(bamf: &str, fasta: &str) -> bam::Reader {
+    let mut bam = if crate::utils::is_remote(bamf) {
+        bam::Reader::from_url(&Url::parse(bamf).expect("Failed to parse URL"))
+            .unwrap_or_else(|err| panic!("Error opening remote BAM: {err}"))
+    } else {
+        bam::Reader::from_path(bamf).unwrap_or_else(|err| panic!("Error opening local BAM: {err}"))
+    };
+    if bamf.ends_with(".cram") {
+        bam.set_reference(fasta)
+            .expect("Failed setting reference for CRAM file");
+    }
+    bam
+}
+
 pub fn get_overlapping_reads(
     bam: &mut bam::IndexedReader,
     repeat: &crate::repeats::RepeatInterval,
     unphased: bool,
+    keep_records: bool,
+    read_filter: &ReadFilter,
 ) -> Option<Reads> {
-    let tid = bam
-        .header()
-        .tid(repeat.chrom.as_bytes())
-        .unwrap_or_else(|| panic!("Invalid chromosome {}", repeat.chrom));
+    let header = bam.header();
+    let tid = crate::repeats::reconcile_contig(&repeat.chrom, |name| {
+        header.tid(name.as_bytes()).is_some()
+    })
+    .and_then(|chrom| header.tid(chrom.as_bytes()))
+    .unwrap_or_else(|| panic!("Invalid chromosome {}", repeat.chrom));
     bam.fetch((tid, repeat.start, repeat.end))
         .unwrap_or_else(|err| panic!("Failure to extract reads from bam for {repeat}:\n{err}"));
-    // Per haplotype the read sequences are kept in a dictionary
-    let mut seqs = HashMap::from([(0, Vec::new()), (1, Vec::new()), (2, Vec::new())]);
-    let mut ps = None;
+    let mut reads = Reads::new(keep_records, read_filter.clone());
     // extract sequences spanning the repeat locus
     for r in bam.rc_records() {
+        // with --timeout, the remaining reads are skipped once the locus has taken too long
+        if crate::genotype::deadline_passed() {
+            break;
+        }
         let r = r.unwrap_or_else(|err| panic!("Error reading BAM file in region {repeat}:\n{err}"));
+        reads.add_record(&r, repeat, unphased);
+    }
+    reads.finalize(repeat, unphased)
+}
+
+/// Check that all contigs of the catalog are in the header of the bam file, possibly in another naming convention,
+/// failing before genotyping with a summary of the missing contigs rather than at the first locus
+pub fn check_contigs(bamf: &str, fasta: &str, repeats: &crate::repeats::RepeatIntervalIterator) {
+    let header = if has_index(bamf) {
+        create_bam_reader(bamf, fasta).header().clone()
+    } else {
+        create_sequential_reader(bamf, fasta).header().clone()
+    };
+    if let Err(missing) = contigs_in_header(&header, repeats) {
+        panic!("Loci on contigs that are not in the header of {bamf}: {missing}");
+    }
+}
+
+/// Check that all contigs of the loci are in a bam header, with a summary of the missing contigs otherwise
+pub fn contigs_in_header(
+    header: &bam::HeaderView,
+    repeats: &crate::repeats::RepeatIntervalIterator,
+) -> Result<(), String> {
+    let missing = repeats
+        .contig_loci()
+        .into_iter()
+        .filter(|(contig, _)| {
+            crate::repeats::reconcile_contig(contig, |name| header.tid(name.as_bytes()).is_some())
+                .is_none()
+        })
+        .collect::<BTreeMap<String, usize>>();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(crate::repeats::missing_contigs_summary(&missing))
+    }
+}
+
+/// Check if an index (.bai, .csi or .crai) exists next to a local bam/cram file
+/// Remote files are assumed to be indexed
+pub fn has_index(bamf: &str) -> bool {
+    if crate::utils::is_remote(bamf) {
+        return true;
+    }
+    let without_extension = bamf.trim_end_matches(".bam").trim_end_matches(".cram");
+    [
+        format!("{bamf}.bai"),
+        format!("{bamf}.csi"),
+        format!("{bamf}.crai"),
+        format!("{without_extension}.bai"),
+        format!("{without_extension}.crai"),
+    ]
+    .iter()
+    .any(|index| std::path::Path::new(index).is_file())
+}
+
+/// Collect the reads for all repeats in a single sequential pass over the bam file
+/// This does not require an index, but keeps the reads of all repeats in memory
+/// and is therefore only suitable for a small number of repeats
+/// The repeats overlapping a read are found with a binary search in the repeats of its chromosome
+pub fn scan_overlapping_reads(
+    bamf: &str,
+    fasta: &str,
+    repeats: &[crate::repeats::RepeatInterval],
+    unphased: &dyn Fn(&crate::repeats::RepeatInterval) -> bool,
+    keep_records: bool,
+    read_filter: &ReadFilter,
+) -> Vec<Option<Reads>> {
+    let mut bam = create_sequential_reader(bamf, fasta);
+    let header = bam.header().clone();
+    // the repeats are indexed per chromosome as it is called in the bam file, by their index in the repeats slice
+    let index = crate::utils::IntervalIndex::new(repeats.iter().enumerate().map(|(index, repeat)| {
+        let chrom = crate::repeats::reconcile_contig(&repeat.chrom, |name| {
+            header.tid(name.as_bytes()).is_some()
+        })
+        .unwrap_or_else(|| repeat.chrom.clone());
+        (chrom, repeat.start.into(), repeat.end.into(), index)
+    }));
+    let mut reads = repeats
+        .iter()
+        .map(|_| Reads::new(keep_records, read_filter.clone()))
+        .collect::<Vec<Reads>>();
+    for r in bam.records() {
+        let r = r.unwrap_or_else(|err| panic!("Error reading BAM file {bamf}:\n{err}"));
+        if r.tid() < 0 {
+            continue;
+        }
+        let chrom = std::str::from_utf8(header.tid2name(r.tid() as u32))
+            .expect("Failed parsing chromosome name from bam header");
+        // only reads overlapping the repeat are considered, as with fetch()
+        let (start, end) = (r.reference_start().max(0) as u64, r.reference_end().max(0) as u64);
+        for (_, _, index) in index.overlapping(chrom, start, end) {
+            let repeat = &repeats[*index];
+            reads[*index].add_record(&r, repeat, unphased(repeat));
+        }
+    }
+    reads
+        .into_iter()
+        .zip(repeats.iter())
+        .map(|(r, repeat)| r.finalize(repeat, unphased(repeat)))
+        .collect()
+}
+
+impl Reads {
+    fn new(keep_records: bool, read_filter: ReadFilter) -> Reads {
+        Reads {
+            // Per haplotype the read sequences are kept in a dictionary
+            seqs: HashMap::from([(0, Vec::new()), (1, Vec::new()), (2, Vec::new())]),
+            ps: None,
+            depth: 0,
+            spanning: 0,
+            anchored: 0,
+            clipped: 0,
+            low_mapq: 0,
+            phase_sets: HashMap::from([(1, Vec::new()), (2, Vec::new())]),
+            records: Vec::new(),
+            keep_records,
+            snv_phased: false,
+            methylation: HashMap::from([(0, Vec::new()), (1, Vec::new()), (2, Vec::new())]),
+            qc: HashMap::from([(0, Vec::new()), (1, Vec::new()), (2, Vec::new())]),
+            read_groups: HashMap::new(),
+            sampled: HashMap::new(),
+            excluded: (0, 0),
+            read_filter,
+        }
+    }
+
+    /// Reads from sequences per phase rather than from a bam file, e.g. simulated reads
+    pub fn from_sequences(seqs: HashMap<u8, Vec<Vec<u8>>>) -> Reads {
+        let mut reads = Reads::new(false, ReadFilter::default());
+        for (phase, seqs) in seqs {
+            for seq in seqs {
+                reads.depth += 1;
+                reads.spanning += 1;
+                reads.anchored += 1;
+                reads.seqs.get_mut(&phase).expect("Invalid phase").push(seq);
+                reads.methylation.get_mut(&phase).unwrap().push(None);
+                reads.qc.get_mut(&phase).unwrap().push(crate::read_qc::ReadQc {
+                    reverse: false,
+                    quality: None,
+                });
+                if let Some(phase_sets) = reads.phase_sets.get_mut(&phase) {
+                    phase_sets.push(None);
+                }
+            }
+        }
+        reads
+    }
+
+    fn add_record(
+        &mut self,
+        r: &bam::Record,
+        repeat: &crate::repeats::RepeatInterval,
+        unphased: bool,
+    ) {
+        let read_group = get_read_group(r);
+        // reads of other read groups are ignored entirely, also for the depth
+        if let Some(keep) = &self.read_filter.read_groups.keep {
+            if !keep.contains(&read_group) {
+                return;
+            }
+        }
+        self.depth += 1;
+        if r.mapq() < LOW_MAPQ {
+            self.low_mapq += 1;
+        }
         // skip reads with mapq 0 or reads that do not span the repeat locus
-        if r.mapq() == 0
-            || r.reference_start() > repeat.start.into()
-            || r.reference_end() < repeat.end.into()
-        {
+        // a large expansion can split the alignment of a read in the left and right flank,
+        // in which case the primary alignment with the full read sequence is used
+        let spanning =
+            r.reference_start() <= repeat.start.into() && r.reference_end() >= repeat.end.into();
+        if r.mapq() > 0 && !spanning && clipped_in_repeat(r, repeat) {
+            self.clipped += 1;
+        }
+        if r.mapq() == 0 || !(spanning || spans_with_supplementary(r, repeat)) {
             debug!(
                 "Skipping read {}",
                 std::str::from_utf8(r.qname()).expect("Could get read identifier")
             );
-            continue;
+            return;
         }
+        // chimeric and low-identity reads can create spurious allele clusters
+        if self
+            .read_filter
+            .min_identity
+            .is_some_and(|min| gap_compressed_identity(r).is_some_and(|identity| identity < min))
+        {
+            debug!(
+                "Skipping read {}: low identity",
+                std::str::from_utf8(r.qname()).expect("Could get read identifier")
+            );
+            self.excluded.0 += 1;
+            return;
+        }
+        if self.read_filter.min_length.is_some_and(|min| r.seq_len() < min) {
+            debug!(
+                "Skipping read {}: short read",
+                std::str::from_utf8(r.qname()).expect("Could get read identifier")
+            );
+            self.excluded.1 += 1;
+            return;
+        }
+        if self.read_filter.read_groups.max_reads.is_some() {
+            // the reads of a read group are sampled once all reads are collected, see sample_read_groups
+            let hash = crate::utils::fnv1a(r.qname());
+            self.sampled
+                .entry(read_group)
+                .or_default()
+                .push((hash, self.depth, r.clone()));
+            return;
+        }
+        if self.read_filter.read_groups.is_active() {
+            *self.read_groups.entry(read_group).or_default() += 1;
+        }
+        self.use_spanning(r, repeat, unphased);
+    }
+
+    // use a spanning read that passed all filters for genotyping
+    fn use_spanning(
+        &mut self,
+        r: &bam::Record,
+        repeat: &crate::repeats::RepeatInterval,
+        unphased: bool,
+    ) {
+        self.spanning += 1;
+        if r.reference_start() <= repeat.start.into() && r.reference_end() >= repeat.end.into() {
+            self.anchored += 1;
+        }
+        let methylation = if crate::fmr1::is_fmr1(repeat) {
+            crate::fmr1::read_methylation(r, repeat)
+        } else {
+            None
+        };
+        let qc = if self.read_filter.read_qc {
+            crate::read_qc::ReadQc::new(r, repeat)
+        } else {
+            crate::read_qc::ReadQc {
+                reverse: r.is_reverse(),
+                quality: None,
+            }
+        };
         if unphased {
             // for ([read_start, read_stop], [genome_start, genome_stop]) in r.aligned_block_pairs() {
             //     if repeat.start - genome_start as u32 > 2000 {
@@ -84,32 +392,296 @@ pub fn get_overlapping_reads(
             //     };
             // }
             // if unphased put reads in phase 0
-            seqs.get_mut(&0).unwrap().push(r.seq().as_bytes());
+            self.push_unphased(r, methylation, qc);
         } else {
-            let phase = get_phase(&r);
+            let phase = get_phase(r);
             if phase > 0 {
                 let seq = r.seq().as_bytes();
-                seqs.get_mut(&phase).unwrap().push(seq);
-                ps = get_phase_set(&r);
+                self.seqs.get_mut(&phase).unwrap().push(seq);
+                self.phase_sets
+                    .get_mut(&phase)
+                    .unwrap()
+                    .push(get_phase_set(r));
+                self.methylation.get_mut(&phase).unwrap().push(methylation);
+                self.qc.get_mut(&phase).unwrap().push(qc);
                 // writing fasta to stdout
                 // println!(">read_{}\n{}", phase, std::str::from_utf8(&seq).unwrap());
+            } else {
+                // reads without a haplotype tag are kept in phase 0,
+                // such that genotyping can fall back to clustering if no read in this locus is phased
+                self.push_unphased(r, methylation, qc);
             }
         }
     }
-    if seqs.is_empty() {
-        // error/warning message depends on whether we are looking for phased reads or not
-        if unphased {
-            eprintln!("Cannot genotype {repeat}: no reads found");
+
+    fn push_unphased(
+        &mut self,
+        r: &bam::Record,
+        methylation: Option<f32>,
+        qc: crate::read_qc::ReadQc,
+    ) {
+        self.seqs.get_mut(&0).unwrap().push(r.seq().as_bytes());
+        self.methylation.get_mut(&0).unwrap().push(methylation);
+        self.qc.get_mut(&0).unwrap().push(qc);
+        if self.keep_records {
+            self.records.push(r.clone());
+        }
+    }
+
+    /// Move the reads in phase 0 to phase 1 and 2, using the read indices from phasing with SNVs
+    /// Reads that could not be phased are dropped
+    pub fn assign_phases(&mut self, hap1: Vec<usize>, hap2: Vec<usize>) {
+        let unphased = self.seqs.insert(0, Vec::new()).unwrap();
+        let methylation = self.methylation.insert(0, Vec::new()).unwrap();
+        let qc = self.qc.insert(0, Vec::new()).unwrap();
+        for (phase, indices) in [(1, hap1), (2, hap2)] {
+            let seqs = self.seqs.get_mut(&phase).unwrap();
+            let phase_methylation = self.methylation.get_mut(&phase).unwrap();
+            let phase_qc = self.qc.get_mut(&phase).unwrap();
+            for index in indices {
+                seqs.push(unphased[index].clone());
+                phase_methylation.push(methylation[index]);
+                phase_qc.push(qc[index]);
+            }
+        }
+        self.records.clear();
+        self.snv_phased = true;
+    }
+
+    /// True if none of the reads spanning the repeat has a haplotype tag
+    pub fn is_untagged(&self) -> bool {
+        self.seqs[&1].is_empty() && self.seqs[&2].is_empty() && !self.seqs[&0].is_empty()
+    }
+
+    /// True if the reads can be phased using SNVs in the flanks,
+    /// i.e. their alignments are kept and none of the reads has a haplotype tag
+    /// The haplotypes from SNVs are numbered independently of the haplotype tags, so are not mixed with them
+    pub fn snv_phasable(&self) -> bool {
+        !self.records.is_empty() && self.is_untagged()
+    }
+
+    /// Only keep the phased reads from the phase set with most reads
+    /// Reads from different phase sets are not phased relative to each other,
+    /// so haplotype 1 of one phase block is not necessarily haplotype 1 of another block
+    fn restrict_to_phase_set(&mut self, repeat: &crate::repeats::RepeatInterval) {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for ps in self.phase_sets.values().flatten().flatten() {
+            *counts.entry(*ps).or_default() += 1;
+        }
+        // ties are broken by the lowest phase set identifier to keep the output deterministic
+        let dominant = counts
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(ps, _)| *ps);
+        if counts.len() > 1 {
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Phasing,
+                format_args!(
+                    "Reads from {} phase sets, keeping phase set {:?}",
+                    counts.len(),
+                    dominant
+                ),
+            );
+            for phase in [1, 2] {
+                let phase_sets = self.phase_sets.get(&phase).unwrap();
+                let seqs = self.seqs.remove(&phase).unwrap();
+                let kept = seqs
+                    .into_iter()
+                    .zip(phase_sets.iter())
+                    .filter(|(_, ps)| **ps == dominant)
+                    .map(|(seq, _)| seq)
+                    .collect::<Vec<Vec<u8>>>();
+                self.seqs.insert(phase, kept);
+                let methylation = self.methylation.remove(&phase).unwrap();
+                let kept = methylation
+                    .into_iter()
+                    .zip(phase_sets.iter())
+                    .filter(|(_, ps)| **ps == dominant)
+                    .map(|(methylation, _)| methylation)
+                    .collect::<Vec<Option<f32>>>();
+                self.methylation.insert(phase, kept);
+                let qc = self.qc.remove(&phase).unwrap();
+                let kept = qc
+                    .into_iter()
+                    .zip(phase_sets.iter())
+                    .filter(|(_, ps)| **ps == dominant)
+                    .map(|(qc, _)| qc)
+                    .collect::<Vec<crate::read_qc::ReadQc>>();
+                self.qc.insert(phase, kept);
+            }
+        }
+        self.ps = dominant;
+    }
+
+    /// Use at most max_reads spanning reads per read group, such that one run cannot dominate the consensus
+    /// The reads with the smallest hash of their name are used, rather than the first reads in the bam file,
+    /// such that the same reads are used with an index and with a sequential scan
+    fn sample_read_groups(&mut self, repeat: &crate::repeats::RepeatInterval, unphased: bool) {
+        let max_reads = match self.read_filter.read_groups.max_reads {
+            Some(max_reads) => max_reads,
+            None => return,
+        };
+        let mut kept = vec![];
+        for (read_group, mut reads) in std::mem::take(&mut self.sampled) {
+            if reads.len() > max_reads {
+                log_locus(
+                    Level::Debug,
+                    repeat,
+                    Stage::Reading,
+                    format_args!(
+                        "Using {max_reads} of {} reads of read group {read_group}",
+                        reads.len()
+                    ),
+                );
+            }
+            reads.sort_by_key(|(hash, order, _)| (*hash, *order));
+            reads.truncate(max_reads);
+            self.read_groups.insert(read_group, reads.len());
+            kept.extend(reads.into_iter().map(|(_, order, r)| (order, r)));
+        }
+        // the sampled reads are used in the order of the bam file
+        kept.sort_by_key(|(order, _)| *order);
+        for (_, r) in kept {
+            self.use_spanning(&r, repeat, unphased);
+        }
+    }
+
+    fn finalize(mut self, repeat: &crate::repeats::RepeatInterval, unphased: bool) -> Option<Reads> {
+        self.sample_read_groups(repeat, unphased);
+        if !unphased {
+            self.restrict_to_phase_set(repeat);
+        }
+        if self.seqs.is_empty() {
+            // error/warning message depends on whether we are looking for phased reads or not
+            let reason = if unphased {
+                "no reads found"
+            } else {
+                "no phased reads found"
+            };
+            log_locus(Level::Warn, repeat, Stage::Reading, reason);
+            None
         } else {
-            eprintln!("Cannot genotype {repeat}: no phased reads found");
+            Some(self)
+        }
+    }
+}
+
+/// The gap-compressed identity of an alignment, counting a gap of any length as a single difference
+/// From the de tag of minimap2 if present, else from the NM tag and the CIGAR
+/// None if the alignment has neither tag
+pub fn gap_compressed_identity(r: &bam::Record) -> Option<f32> {
+    if let Ok(Aux::Float(divergence)) = r.aux(b"de") {
+        return Some(1.0 - divergence);
+    }
+    let edit_distance = match r.aux(b"NM") {
+        Ok(Aux::U8(nm)) => nm as usize,
+        Ok(Aux::U16(nm)) => nm as usize,
+        Ok(Aux::U32(nm)) => nm as usize,
+        Ok(Aux::I8(nm)) => nm.max(0) as usize,
+        Ok(Aux::I16(nm)) => nm.max(0) as usize,
+        Ok(Aux::I32(nm)) => nm.max(0) as usize,
+        _ => return None,
+    };
+    let (mut aligned, mut gaps, mut gap_bases) = (0, 0, 0);
+    for op in r.cigar().iter() {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => aligned += *len as usize,
+            Cigar::Ins(len) | Cigar::Del(len) => {
+                gaps += 1;
+                gap_bases += *len as usize;
+            }
+            _ => (),
         }
-        None
-    } else {
-        Some(Reads { seqs, ps })
     }
+    let mismatches = edit_distance.saturating_sub(gap_bases);
+    if aligned + gaps == 0 {
+        return None;
+    }
+    Some(1.0 - (mismatches + gaps) as f32 / (aligned + gaps) as f32)
+}
+
+/// An alignment of a read in the SA tag, with the 0-based reference start and end
+#[derive(Debug, PartialEq)]
+struct SupplementaryAlignment {
+    chrom: String,
+    start: i64,
+    end: i64,
+    reverse: bool,
+}
+
+// parse the SA tag, formatted as chrom,pos,strand,CIGAR,mapQ,NM; with a 1-based pos
+fn parse_sa_tag(sa: &str) -> Vec<SupplementaryAlignment> {
+    sa.split(';')
+        .filter(|alignment| !alignment.is_empty())
+        .filter_map(|alignment| {
+            let fields = alignment.split(',').collect::<Vec<&str>>();
+            if fields.len() < 4 {
+                warn!("Skipping invalid SA tag entry {alignment}");
+                return None;
+            }
+            let start = fields[1].parse::<i64>().ok()? - 1;
+            // the reference length of the alignment follows from the M, D, N, = and X operations
+            let mut length = 0;
+            let mut number = 0;
+            for c in fields[3].chars() {
+                match c.to_digit(10) {
+                    Some(digit) => number = number * 10 + digit as i64,
+                    None => {
+                        if "MDN=X".contains(c) {
+                            length += number;
+                        }
+                        number = 0;
+                    }
+                }
+            }
+            Some(SupplementaryAlignment {
+                chrom: fields[0].to_string(),
+                start,
+                end: start + length,
+                reverse: fields[2] == "-",
+            })
+        })
+        .collect()
 }
 
-fn get_phase(record: &bam::Record) -> u8 {
+/// True if the primary alignment of a read does not span the repeat by itself,
+/// but the read does together with a supplementary alignment on the other side of the repeat
+/// The primary alignment is required to keep the full read sequence, as supplementary alignments can be hard clipped
+fn spans_with_supplementary(r: &bam::Record, repeat: &crate::repeats::RepeatInterval) -> bool {
+    if r.is_supplementary() || r.is_secondary() {
+        return false;
+    }
+    let sa = match r.aux(b"SA") {
+        Ok(Aux::String(sa)) => sa,
+        _ => return false,
+    };
+    let (start, end) = (repeat.start as i64, repeat.end as i64);
+    parse_sa_tag(sa).iter().any(|alignment| {
+        alignment.chrom == repeat.chrom
+            && alignment.reverse == r.is_reverse()
+            // a supplementary alignment spanning the repeat by itself is already a spanning read
+            && !(alignment.start <= start && alignment.end >= end)
+            && ((r.reference_start() <= start && alignment.end >= end)
+                || (alignment.start <= start && r.reference_end() >= end))
+    })
+}
+
+/// True if the alignment of a read ends in the repeat with a soft clip,
+/// such that the rest of the read is not aligned, e.g. because the allele is longer than the read
+fn clipped_in_repeat(r: &bam::Record, repeat: &crate::repeats::RepeatInterval) -> bool {
+    if r.is_supplementary() || r.is_secondary() {
+        return false;
+    }
+    let (start, end) = (repeat.start as i64, repeat.end as i64);
+    let cigar = r.cigar();
+    let within = |pos: i64| pos >= start && pos <= end;
+    (cigar.leading_softclips() > 0 && within(r.reference_start()))
+        || (cigar.trailing_softclips() > 0 && within(r.reference_end()))
+}
+
+pub fn get_phase(record: &bam::Record) -> u8 {
     match record.aux(b"HP") {
         Ok(value) => {
             if let Aux::U8(v) = value {
@@ -122,6 +694,14 @@ fn get_phase(record: &bam::Record) -> u8 {
     }
 }
 
+// the RG tag of a read, "." for reads without read group
+fn get_read_group(record: &bam::Record) -> String {
+    match record.aux(b"RG") {
+        Ok(Aux::String(rg)) => rg.to_string(),
+        _ => ".".to_string(),
+    }
+}
+
 fn get_phase_set(record: &bam::Record) -> Option<u32> {
     match record.aux(b"PS") {
         Ok(value) => {
@@ -143,10 +723,101 @@ fn test_get_overlapping_reads() {
         chrom: String::from("chr7"),
         start: 154654404,
         end: 154654432,
+        motif: None,
+        annotation: None,
+        structure: None,
     };
     let unphased = false;
     let mut bam = create_bam_reader(&bam, &fasta);
-    let _reads = get_overlapping_reads(&mut bam, &repeat, unphased);
+    let _reads =
+        get_overlapping_reads(&mut bam, &repeat, unphased, false, &ReadFilter::default());
+}
+
+#[test]
+fn test_scan_overlapping_reads() {
+    let bam = String::from("test_data/small-test-phased.bam");
+    let fasta = String::from("test_data/chr7.fa.gz");
+    let repeat = |start: u32, end: u32| crate::repeats::RepeatInterval {
+        chrom: String::from("chr7"),
+        start,
+        end,
+        motif: None,
+        annotation: None,
+        structure: None,
+    };
+    // the repeats are not sorted, and overlap each other
+    let repeats = vec![
+        repeat(154654404, 154654432),
+        repeat(1000, 1100),
+        repeat(154654300, 154654500),
+    ];
+    let scanned = scan_overlapping_reads(
+        &bam,
+        &fasta,
+        &repeats,
+        &|_| false,
+        false,
+        &ReadFilter::default(),
+    );
+    assert_eq!(scanned.len(), repeats.len());
+    // the same reads as fetched with the index
+    let mut indexed = create_bam_reader(&bam, &fasta);
+    for (repeat, scanned) in repeats.iter().zip(&scanned) {
+        let fetched =
+            get_overlapping_reads(&mut indexed, repeat, false, false, &ReadFilter::default());
+        assert_eq!(
+            scanned.as_ref().map(|reads| (reads.depth, reads.spanning)),
+            fetched.as_ref().map(|reads| (reads.depth, reads.spanning))
+        );
+    }
+}
+
+#[test]
+fn test_sample_read_groups() {
+    use rust_htslib::bam::record::CigarString;
+    let repeat = crate::repeats::RepeatInterval::new("chr4", 1000, 1100);
+    let read = |name: &str, read_group: &str| {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(500)]);
+        record.set(name.as_bytes(), Some(&cigar), &[b'A'; 500], &[30; 500]);
+        record.set_pos(800);
+        record.set_mapq(60);
+        record
+            .push_aux(b"RG", Aux::String(read_group))
+            .expect("Failed adding RG tag");
+        record
+    };
+    let filter = ReadFilter {
+        read_groups: ReadGroupFilter {
+            keep: None,
+            max_reads: Some(2),
+        },
+        ..ReadFilter::default()
+    };
+    let records = ["r1", "r2", "r3", "r4"]
+        .iter()
+        .map(|name| read(name, "a"))
+        .chain(std::iter::once(read("r5", "b")))
+        .collect::<Vec<bam::Record>>();
+    let sample = |records: &mut dyn Iterator<Item = &bam::Record>| {
+        let mut reads = Reads::new(true, filter.clone());
+        for r in records {
+            reads.add_record(r, &repeat, true);
+        }
+        let reads = reads.finalize(&repeat, true).expect("No reads");
+        let mut names = reads
+            .records
+            .iter()
+            .map(|r| String::from_utf8_lossy(r.qname()).to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        (reads.spanning, reads.read_groups, names)
+    };
+    let (spanning, read_groups, names) = sample(&mut records.iter());
+    assert_eq!(spanning, 3);
+    assert_eq!(read_groups, HashMap::from([("a".to_string(), 2), ("b".to_string(), 1)]));
+    // the same reads are used regardless of their order in the bam file
+    assert_eq!(sample(&mut records.iter().rev()).2, names);
 }
 
 #[test]
@@ -157,10 +828,14 @@ fn test_get_overlapping_reads_url1() {
         chrom: String::from("chr20"),
         start: 154654404,
         end: 154654432,
+        motif: None,
+        annotation: None,
+        structure: None,
     };
     let unphased = false;
     let mut bam = create_bam_reader(&bam, &fasta);
-    let _reads = get_overlapping_reads(&mut bam, &repeat, unphased);
+    let _reads =
+        get_overlapping_reads(&mut bam, &repeat, unphased, false, &ReadFilter::default());
 }
 
 #[test]
@@ -171,10 +846,14 @@ fn test_get_overlapping_reads_url2() {
         chrom: String::from("chr20"),
         start: 154654404,
         end: 154654432,
+        motif: None,
+        annotation: None,
+        structure: None,
     };
     let unphased = false;
     let mut bam = create_bam_reader(&bam, &fasta);
-    let _reads = get_overlapping_reads(&mut bam, &repeat, unphased);
+    let _reads =
+        get_overlapping_reads(&mut bam, &repeat, unphased, false, &ReadFilter::default());
 }
 
 #[test]
@@ -185,10 +864,14 @@ fn test_get_overlapping_reads_url3() {
         chrom: String::from("chr20"),
         start: 154654404,
         end: 154654432,
+        motif: None,
+        annotation: None,
+        structure: None,
     };
     let unphased = false;
     let mut bam = create_bam_reader(&bam, &fasta);
-    let _reads = get_overlapping_reads(&mut bam, &repeat, unphased);
+    let _reads =
+        get_overlapping_reads(&mut bam, &repeat, unphased, false, &ReadFilter::default());
 }
 
 #[test]
@@ -199,10 +882,101 @@ fn test_get_overlapping_reads_url4() {
         chrom: String::from("chr20"),
         start: 154654404,
         end: 154654432,
+        motif: None,
+        annotation: None,
+        structure: None,
     };
     let unphased = false;
     let mut bam = create_bam_reader(&bam, &fasta);
-    let _reads = get_overlapping_reads(&mut bam, &repeat, unphased);
+    let _reads =
+        get_overlapping_reads(&mut bam, &repeat, unphased, false, &ReadFilter::default());
+}
+
+#[test]
+fn test_parse_sa_tag() {
+    let alignments =
+        parse_sa_tag("chr4,3074801,+,5000S80M2D20M,60,3;chr4,3080001,-,30H50M4I,12,0;");
+    assert_eq!(
+        alignments,
+        vec![
+            SupplementaryAlignment {
+                chrom: "chr4".to_string(),
+                start: 3074800,
+                end: 3074902,
+                reverse: false,
+            },
+            SupplementaryAlignment {
+                chrom: "chr4".to_string(),
+                start: 3080000,
+                end: 3080050,
+                reverse: true,
+            },
+        ]
+    );
+    assert!(parse_sa_tag("").is_empty());
+}
+
+#[test]
+fn test_clipped_in_repeat() {
+    use rust_htslib::bam::record::CigarString;
+    let repeat = crate::repeats::RepeatInterval::new("chr4", 1000, 1100);
+    let read = |pos: i64, cigar: Vec<Cigar>| {
+        let mut record = bam::Record::new();
+        let length = cigar
+            .iter()
+            .map(|op| match op {
+                Cigar::Match(len) | Cigar::SoftClip(len) => *len as usize,
+                _ => 0,
+            })
+            .sum::<usize>();
+        record.set(
+            b"read",
+            Some(&CigarString(cigar)),
+            &vec![b'A'; length],
+            &vec![30; length],
+        );
+        record.set_pos(pos);
+        record
+    };
+    let clipped_end = vec![Cigar::Match(150), Cigar::SoftClip(500)];
+    let clipped_start = vec![Cigar::SoftClip(500), Cigar::Match(150)];
+    // ends in the repeat with a soft clip
+    assert!(clipped_in_repeat(&read(900, clipped_end.clone()), &repeat));
+    assert!(clipped_in_repeat(&read(1080, clipped_start), &repeat));
+    // soft-clipped in the flank, or ending in the repeat without a soft clip
+    assert!(!clipped_in_repeat(&read(500, clipped_end), &repeat));
+    assert!(!clipped_in_repeat(&read(900, vec![Cigar::Match(150)]), &repeat));
+}
+
+#[test]
+fn test_snv_phasable() {
+    use rust_htslib::bam::record::CigarString;
+    let repeat = crate::repeats::RepeatInterval::new("chr4", 1000, 1100);
+    let read = |name: &str, phase: Option<u8>| {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(500)]);
+        record.set(name.as_bytes(), Some(&cigar), &[b'A'; 500], &[30; 500]);
+        record.set_pos(800);
+        record.set_mapq(60);
+        if let Some(phase) = phase {
+            record.push_aux(b"HP", Aux::U8(phase)).expect("Failed adding HP tag");
+        }
+        record
+    };
+    let reads = |records: &[bam::Record], unphased: bool| {
+        let mut reads = Reads::new(true, ReadFilter::default());
+        for r in records {
+            reads.add_record(r, &repeat, unphased);
+        }
+        reads.finalize(&repeat, unphased).expect("No reads")
+    };
+    let untagged = vec![read("r1", None), read("r2", None), read("r3", None)];
+    assert!(reads(&untagged, false).snv_phasable());
+    // the reads without a tag are not phased using SNVs if other reads have a haplotype tag
+    let mixed = vec![read("r1", Some(1)), read("r2", Some(2)), read("r3", None)];
+    assert!(!reads(&mixed, false).snv_phasable());
+    // unless all reads are used unphased
+    assert!(reads(&mixed, true).snv_phasable());
 }
 
 #[test]
@@ -216,3 +990,19 @@ fn test_get_phase() {
     let phase = get_phase(&record.unwrap());
     assert_eq!(phase, 2);
 }
+
+#[test]
+fn test_gap_compressed_identity() {
+    use rust_htslib::bam::record::CigarString;
+    let mut record = bam::Record::new();
+    let cigar = vec![Cigar::Match(100), Cigar::Ins(1), Cigar::Match(50), Cigar::Del(2)];
+    record.set(b"read", Some(&CigarString(cigar)), &[b'A'; 151], &[30; 151]);
+    assert_eq!(gap_compressed_identity(&record), None);
+    // two mismatches, and two gaps of three bases in total
+    record.push_aux(b"NM", Aux::U8(5)).expect("Failed adding NM tag");
+    let identity = gap_compressed_identity(&record).expect("No identity");
+    assert!((identity - (1.0 - 4.0 / 152.0)).abs() < 1e-6);
+    record.push_aux(b"de", Aux::Float(0.1)).expect("Failed adding de tag");
+    let identity = gap_compressed_identity(&record).expect("No identity");
+    assert!((identity - 0.9).abs() < 1e-6);
+}
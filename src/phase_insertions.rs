@@ -1,8 +1,13 @@
-use kodama::{linkage, Method};
-use levenshtein::levenshtein;
-use log::{debug, error, log_enabled, Level};
+use crate::locus_log::{log_locus, Stage};
+use crate::utils::edit_distance;
+use kodama::{linkage, Dendrogram, Method};
+use log::{debug, log_enabled, Level};
+use std::cell::RefCell;
 use std::{cmp::max, collections::HashMap};
 
+// the maximal number of iterations to update the medoids
+const MAX_MEDOID_ITERATIONS: usize = 10;
+
 pub struct SplitSequences {
     pub hap1: Vec<String>,
     pub hap2: Option<Vec<String>>,
@@ -10,11 +15,65 @@ pub struct SplitSequences {
     pub outliers: Option<Vec<String>>,
 }
 
+/// The algorithm, distance and threshold to cluster the insertions of unphased reads
+#[derive(Clone, Copy, Debug)]
+pub struct ClusterOptions {
+    pub method: crate::ClusterMethod,
+    pub metric: crate::ClusterMetric,
+    pub split_threshold: f32,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        ClusterOptions {
+            method: crate::ClusterMethod::Hierarchical,
+            metric: crate::ClusterMetric::Edit,
+            split_threshold: 5.0,
+        }
+    }
+}
+
+fn distance(a: &str, b: &str, metric: crate::ClusterMetric) -> f32 {
+    match metric {
+        crate::ClusterMetric::Edit => edit_distance(a, b) as f32,
+        crate::ClusterMetric::Length => a.len().abs_diff(b.len()) as f32,
+    }
+}
+
+thread_local! {
+    // the condensed distance matrix of the insertions, reused across loci rather than allocated for every locus
+    static CONDENSED: RefCell<Vec<f32>> = RefCell::new(vec![]);
+}
+
+// the dendrogram of the hierarchical clustering of at least two insertions with Ward linkage
+// returns None if the locus is abandoned at the timeout
+fn dendrogram(insertions: &[String], metric: crate::ClusterMetric) -> Option<Dendrogram<f32>> {
+    CONDENSED.with(|condensed| {
+        let mut condensed = condensed.borrow_mut();
+        condensed.clear();
+        // the upper triangle of the distance matrix
+        for row in 0..insertions.len() - 1 {
+            // with --timeout, the locus is abandoned before clustering
+            if crate::genotype::deadline_passed() {
+                return None;
+            }
+            for col in row + 1..insertions.len() {
+                condensed.push(distance(&insertions[row], &insertions[col], metric));
+            }
+        }
+        Some(linkage(condensed.as_mut_slice(), insertions.len(), Method::Ward))
+    })
+}
+
 pub fn split(
     insertions: &Vec<String>,
     repeat: &crate::repeats::RepeatInterval,
     check_outliers: bool,
+    options: &ClusterOptions,
 ) -> SplitSequences {
+    if options.method != crate::ClusterMethod::Hierarchical {
+        return split_partitioned(insertions, repeat, check_outliers, options);
+    }
     // the insertions are from an unphased experiment
     // and should be split in one (if homozygous) or two haplotypes
     // this is based on the length of the insertion
@@ -23,14 +82,10 @@ pub fn split(
     // and will use the levenshtein distance
     // this is inspired by the TRGT paper
 
-    // Create a condensed (upper triangle) distance matrix
-    let mut condensed = vec![];
-    for row in 0..insertions.len() - 1 {
-        for col in row + 1..insertions.len() {
-            condensed.push(levenshtein(&insertions[row], &insertions[col]) as f32);
-        }
-    }
-    let dend = linkage(&mut condensed, insertions.len(), Method::Ward);
+    let dend = match dendrogram(insertions, options.metric) {
+        Some(dend) => dend,
+        None => return unsplit(insertions),
+    };
 
     // maybe these hashmaps could be replaced by some tree-like structure
     // create a hashmap to store labels of the clusters and their subclusters
@@ -47,7 +102,12 @@ pub fn split(
     let mut haplotype_clusters = vec![];
     // clusters have to represent at least 20% of the reads
     let min_cluster_size = (insertions.len() as f32 / 10.0) as usize;
-    debug!("{repeat}: Minimum cluster size: {}", min_cluster_size);
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!("Minimum cluster size: {}", min_cluster_size),
+    );
 
     for (index, step) in dend.steps().iter().enumerate() {
         // insert the new label with the clusters it contains
@@ -83,11 +143,16 @@ pub fn split(
                 "".to_string()
             };
 
-            debug!(
-                "{repeat}: Node {cluster} with dissimilarity {} and children {} [{seq1}] and {} [{seq2}]",
-                clusters_to_dissimilarity.get(cluster).unwrap(),
-                subclusters.0,
-                subclusters.1
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Phasing,
+                format_args!(
+                    "Node {cluster} with dissimilarity {} and children {} [{seq1}] and {} [{seq2}]",
+                    clusters_to_dissimilarity.get(cluster).unwrap(),
+                    subclusters.0,
+                    subclusters.1
+                ),
             );
         }
     }
@@ -98,8 +163,14 @@ pub fn split(
         &clusters_to_size,
         &clusters_to_dissimilarity,
         &min_cluster_size,
+        options.split_threshold,
+    );
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!("Roots for this tree: {:?}", roots),
     );
-    debug!("{repeat}: Roots for this tree: {:?}", roots);
 
     // if a parent cluster has been seen we will ignore all children thereof
     let mut large_cluster_seen = vec![];
@@ -120,9 +191,11 @@ pub fn split(
             // as such we only get sufficiently large independent clusters
             if !large_cluster_seen.contains(parent) {
                 haplotype_clusters.push(*cluster);
-                debug!(
-                    "{repeat}: Adding cluster {} to candidate haplotype clusters",
-                    cluster
+                log_locus(
+                    Level::Debug,
+                    repeat,
+                    Stage::Phasing,
+                    format_args!("Adding cluster {} to candidate haplotype clusters", cluster),
                 );
             }
             large_cluster_seen.push(*cluster);
@@ -130,7 +203,12 @@ pub fn split(
     }
     match haplotype_clusters.len() {
         0 => {
-            debug!("{repeat}: No haplotype clusters found! Treating this as homozygous, but here could be dragons");
+            log_locus(
+                Level::Debug,
+                repeat,
+                Stage::Phasing,
+                "No haplotype clusters found! Treating this as homozygous, but here could be dragons",
+            );
             SplitSequences {
                 hap1: insertions.clone(),
                 hap2: None,
@@ -149,7 +227,7 @@ pub fn split(
             // this ignores roots and noise insertions, but identifying those is rather problematic in the homozygous case
             // and the end result is often that we lose too many reads as false-positive roots
             // I assume the poa consensus will deal with noise insertions
-            debug!("{repeat}: Only one haplotype cluster found");
+            log_locus(Level::Debug, repeat, Stage::Phasing, "Only one haplotype cluster found");
             SplitSequences {
                 hap1: insertions.clone(),
                 hap2: None,
@@ -162,7 +240,7 @@ pub fn split(
             }
         }
         2 => {
-            debug!("{repeat}: Found two haplotype clusters");
+            log_locus(Level::Debug, repeat, Stage::Phasing, "Found two haplotype clusters");
             let hap1 =
                 find_cluster_members(&haplotype_clusters[0], &cluster_to_subclusters, insertions);
             let hap2 =
@@ -186,18 +264,319 @@ pub fn split(
             }
         }
         _ => {
-            error!("{repeat}: Found more than two haplotype clusters. This shouldn't happen.");
+            log_locus(
+                Level::Error,
+                repeat,
+                Stage::Phasing,
+                "found more than two haplotype clusters, this shouldn't happen",
+            );
             panic!();
         }
     }
 }
 
+// split the insertions in up to two alleles with the medoids or density clustering
+// as with the hierarchical clustering, clusters have to be larger than 10% of the insertions
+fn split_partitioned(
+    insertions: &Vec<String>,
+    repeat: &crate::repeats::RepeatInterval,
+    check_outliers: bool,
+    options: &ClusterOptions,
+) -> SplitSequences {
+    let mut distances = vec![];
+    for a in insertions {
+        // with --timeout, the locus is abandoned before clustering
+        if crate::genotype::deadline_passed() {
+            return unsplit(insertions);
+        }
+        distances.push(
+            insertions
+                .iter()
+                .map(|b| distance(a, b, options.metric))
+                .collect::<Vec<f32>>(),
+        );
+    }
+    let min_cluster_size = (insertions.len() as f32 / 10.0) as usize;
+    let mut clusters = match options.method {
+        crate::ClusterMethod::Density => {
+            density_clusters(&distances, options.split_threshold, min_cluster_size.max(1))
+        }
+        _ => medoid_clusters(&distances, options.split_threshold),
+    };
+    clusters.retain(|cluster| cluster.len() > min_cluster_size);
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.len()));
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!("Found {} {:?} clusters of sufficient size", clusters.len(), options.method),
+    );
+    let members = |cluster: &Vec<usize>| {
+        cluster
+            .iter()
+            .map(|i| insertions[*i].clone())
+            .collect::<Vec<String>>()
+    };
+    let outliers = |larger_median: Option<usize>| {
+        if check_outliers {
+            find_outliers(insertions, larger_median)
+        } else {
+            None
+        }
+    };
+    match clusters.len() {
+        0 | 1 => SplitSequences {
+            // all insertions are returned as the first haplotype, as in the homozygous hierarchical case
+            hap1: insertions.clone(),
+            hap2: None,
+            flag: clusters.is_empty().then(|| "CLUSTERFAILURE".to_string()),
+            outliers: outliers(None),
+        },
+        _ => {
+            // clusters beyond the two largest are dropped as noise
+            let hap1 = members(&clusters[0]);
+            let hap2 = members(&clusters[1]);
+            let larger_median = max(find_median(&hap1), find_median(&hap2));
+            SplitSequences {
+                hap1,
+                hap2: Some(hap2),
+                flag: None,
+                outliers: outliers(Some(larger_median)),
+            }
+        }
+    }
+}
+
+// all insertions as a single haplotype, for a locus that is abandoned at the timeout
+fn unsplit(insertions: &[String]) -> SplitSequences {
+    SplitSequences {
+        hap1: insertions.to_vec(),
+        hap2: None,
+        flag: None,
+        outliers: None,
+    }
+}
+
+// two clusters around medoids, starting from the medoid of all insertions and the insertion farthest from it
+// a single cluster is returned if the medoids are closer than the split threshold
+fn medoid_clusters(distances: &[Vec<f32>], split_threshold: f32) -> Vec<Vec<usize>> {
+    let all = (0..distances.len()).collect::<Vec<usize>>();
+    let medoid = |members: &[usize]| {
+        members
+            .iter()
+            .map(|i| (*i, members.iter().map(|j| distances[*i][*j]).sum::<f32>()))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+            .expect("Empty cluster")
+    };
+    let first = medoid(&all);
+    let farthest = all
+        .iter()
+        .copied()
+        .max_by(|a, b| distances[first][*a].total_cmp(&distances[first][*b]))
+        .expect("No insertions to cluster");
+    let mut medoids = (first, farthest);
+    let mut clusters = vec![all.clone()];
+    for _ in 0..MAX_MEDOID_ITERATIONS {
+        if distances[medoids.0][medoids.1] < split_threshold {
+            return vec![all];
+        }
+        // in case of a tie the insertion goes to the first medoid
+        let (cluster1, cluster2): (Vec<usize>, Vec<usize>) = all
+            .iter()
+            .copied()
+            .partition(|i| distances[*i][medoids.0] <= distances[*i][medoids.1]);
+        if cluster2.is_empty() {
+            return vec![all];
+        }
+        let updated = (medoid(&cluster1), medoid(&cluster2));
+        clusters = vec![cluster1, cluster2];
+        if updated == medoids {
+            break;
+        }
+        medoids = updated;
+    }
+    clusters
+}
+
+// clusters of insertions connected by distances up to max_distance, starting from insertions with
+// at least min_neighbours neighbours (including itself), insertions without such a neighbour are noise
+fn density_clusters(
+    distances: &[Vec<f32>],
+    max_distance: f32,
+    min_neighbours: usize,
+) -> Vec<Vec<usize>> {
+    let neighbours = |i: usize| {
+        (0..distances.len())
+            .filter(|j| distances[i][*j] <= max_distance)
+            .collect::<Vec<usize>>()
+    };
+    let mut visited = vec![false; distances.len()];
+    let mut clusters = vec![];
+    for start in 0..distances.len() {
+        if visited[start] || neighbours(start).len() < min_neighbours {
+            continue;
+        }
+        visited[start] = true;
+        let mut cluster = vec![];
+        let mut queue = vec![start];
+        while let Some(i) = queue.pop() {
+            cluster.push(i);
+            let close = neighbours(i);
+            // only dense insertions extend the cluster, the others are its border
+            if close.len() >= min_neighbours {
+                for j in close {
+                    if !visited[j] {
+                        visited[j] = true;
+                        queue.push(j);
+                    }
+                }
+            }
+        }
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+pub struct RefinedHaplotypes {
+    pub hap1: Vec<String>,
+    pub hap2: Vec<String>,
+    pub consensus1: crate::consensus::Consensus,
+    pub consensus2: crate::consensus::Consensus,
+}
+
+pub fn refine(
+    hap1: Vec<String>,
+    hap2: Vec<String>,
+    support: usize,
+    repeat: &crate::repeats::RepeatInterval,
+    max_iterations: usize,
+    method: crate::ConsensusMethod,
+) -> RefinedHaplotypes {
+    // expectation-maximization style refinement of the two haplotype clusters
+    // a consensus is built per cluster, after which every insertion is reassigned to the closest consensus
+    // this is repeated until no insertion changes cluster, or max_iterations is reached
+    // this helps for alleles that differ little in length, but have distinct interruptions
+    let mut hap1 = hap1;
+    let mut hap2 = hap2;
+    let mut consensus1 = crate::consensus::consensus(&hap1, support, repeat, method);
+    let mut consensus2 = crate::consensus::consensus(&hap2, support, repeat, method);
+    for iteration in 0..max_iterations {
+        if crate::genotype::deadline_passed() {
+            break;
+        }
+        // refinement is only possible if both clusters have a consensus
+        let (seq1, seq2) = match (&consensus1.seq, &consensus2.seq) {
+            (Some(seq1), Some(seq2)) => (seq1.clone(), seq2.clone()),
+            _ => break,
+        };
+        let mut new_hap1 = vec![];
+        let mut new_hap2 = vec![];
+        let mut changed = 0;
+        for (insertion, in_hap1) in hap1
+            .iter()
+            .map(|i| (i, true))
+            .chain(hap2.iter().map(|i| (i, false)))
+        {
+            let dist1 = edit_distance(insertion, &seq1);
+            let dist2 = edit_distance(insertion, &seq2);
+            // in case of a tie the insertion stays in its current cluster
+            let to_hap1 = dist1 < dist2 || (dist1 == dist2 && in_hap1);
+            if to_hap1 != in_hap1 {
+                changed += 1;
+            }
+            if to_hap1 {
+                new_hap1.push(insertion.clone());
+            } else {
+                new_hap2.push(insertion.clone());
+            }
+        }
+        log_locus(
+            Level::Debug,
+            repeat,
+            Stage::Phasing,
+            format_args!("Refinement iteration {iteration}: {changed} insertions reassigned"),
+        );
+        // stop if converged, or if a cluster would become empty
+        if changed == 0 || new_hap1.is_empty() || new_hap2.is_empty() {
+            break;
+        }
+        hap1 = new_hap1;
+        hap2 = new_hap2;
+        consensus1 = crate::consensus::consensus(&hap1, support, repeat, method);
+        consensus2 = crate::consensus::consensus(&hap2, support, repeat, method);
+    }
+    RefinedHaplotypes {
+        hap1,
+        hap2,
+        consensus1,
+        consensus2,
+    }
+}
+
+/// Split the insertions of a locus with more than two expected alleles in up to <ploidy> clusters,
+/// by cutting the dendrogram of the levenshtein distances between the insertions
+/// clusters with less than 10% of the insertions are dropped, the remaining are sorted by size
+pub fn split_clusters(
+    insertions: &[String],
+    ploidy: usize,
+    repeat: &crate::repeats::RepeatInterval,
+) -> Vec<Vec<String>> {
+    if insertions.len() < 2 {
+        return vec![insertions.to_vec()];
+    }
+    let dend = match dendrogram(insertions, crate::ClusterMetric::Edit) {
+        Some(dend) => dend,
+        None => return vec![insertions.to_vec()],
+    };
+    // every observation starts as its own cluster, after n - ploidy merges there are ploidy clusters left
+    let mut members: HashMap<usize, Vec<usize>> =
+        (0..insertions.len()).map(|i| (i, vec![i])).collect();
+    for (index, step) in dend
+        .steps()
+        .iter()
+        .enumerate()
+        .take(insertions.len().saturating_sub(ploidy))
+    {
+        let mut merged = members.remove(&step.cluster1).unwrap_or_default();
+        merged.extend(members.remove(&step.cluster2).unwrap_or_default());
+        members.insert(index + insertions.len(), merged);
+    }
+    let min_cluster_size = ((insertions.len() as f32 / 10.0) as usize).max(1);
+    let mut clusters = members
+        .into_values()
+        .filter(|cluster| cluster.len() >= min_cluster_size)
+        .map(|cluster| {
+            cluster
+                .iter()
+                .map(|i| insertions[*i].clone())
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+    if clusters.is_empty() {
+        clusters.push(insertions.to_vec());
+    }
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.len()));
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!(
+            "Split {} insertions in {} clusters for ploidy {ploidy}",
+            insertions.len(),
+            clusters.len()
+        ),
+    );
+    clusters
+}
+
 fn find_roots(
     top_root: usize,
     cluster_to_subclusters: &HashMap<usize, (usize, usize)>,
     clusters_to_size: &HashMap<usize, usize>,
     clusters_to_dissimilarity: &HashMap<usize, f32>,
     min_cluster_size: &usize,
+    split_threshold: f32,
 ) -> Vec<usize> {
     // find nodes that qualify as roots
     // this includes the top most node, for which the size is equal to the number of sequences
@@ -213,7 +592,7 @@ fn find_roots(
     if size1 > min_cluster_size && size2 > min_cluster_size {
         // if both clusters are sufficiently large we are done finding roots
         return roots;
-    } else if clusters_to_dissimilarity.get(&top_root).unwrap() < &5.0 {
+    } else if clusters_to_dissimilarity.get(&top_root).unwrap() < &split_threshold {
         // if one node is too small, but the difference is not large enough, there is no root that has to be ignored
         return vec![];
     } else {
@@ -226,6 +605,7 @@ fn find_roots(
                 clusters_to_size,
                 clusters_to_dissimilarity,
                 min_cluster_size,
+                split_threshold,
             ));
         } else {
             roots.extend(find_roots(
@@ -234,6 +614,7 @@ fn find_roots(
                 clusters_to_size,
                 clusters_to_dissimilarity,
                 min_cluster_size,
+                split_threshold,
             ));
         }
     }
@@ -332,8 +713,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         assert!(splitseqs.hap1.len() == splitseqs.hap2.unwrap().len());
         // check that all sequences in hap1 are the same length
@@ -372,8 +757,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         let mut hap1 = splitseqs.hap1;
         let mut hap2 = splitseqs.hap2.unwrap();
@@ -411,8 +800,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         assert!(splitseqs.hap1.len() + splitseqs.hap2.unwrap().len() == insertions.len());
     }
@@ -453,8 +846,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         assert!(splitseqs.hap2.is_none());
         println!("hap1: {:?}", splitseqs.hap1);
@@ -493,8 +890,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         let mut hap1 = splitseqs.hap1;
         let mut hap2 = splitseqs.hap2.unwrap();
@@ -564,8 +965,12 @@ mod tests {
                 chrom: "chr7".to_string(),
                 start: 154654404,
                 end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
             },
             false,
+            &ClusterOptions::default(),
         );
         let mut hap1 = splitseqs.hap1;
         let mut hap2 = splitseqs.hap2.unwrap();
@@ -576,4 +981,85 @@ mod tests {
         expected_haplotype.sort();
         assert!(hap1 == expected_haplotype || hap2 == expected_haplotype);
     }
+
+    #[test]
+    fn test_split_medoids_and_density() {
+        let mut insertions = vec![];
+        for copies in [10, 20] {
+            for _ in 0..6 {
+                insertions.push("CAG".repeat(copies));
+            }
+        }
+        let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+        for method in [crate::ClusterMethod::Medoids, crate::ClusterMethod::Density] {
+            let options = ClusterOptions {
+                method,
+                metric: crate::ClusterMetric::Length,
+                split_threshold: 5.0,
+            };
+            let splitseqs = split(&insertions, &repeat, false, &options);
+            let hap2 = splitseqs.hap2.expect("Expected two alleles");
+            assert_eq!(splitseqs.hap1.len(), 6);
+            assert!(hap2.iter().all(|i| i == &hap2[0]));
+            // a threshold above the difference in length keeps the insertions together
+            let options = ClusterOptions {
+                split_threshold: 50.0,
+                ..options
+            };
+            assert!(split(&insertions, &repeat, false, &options).hap2.is_none());
+        }
+    }
+
+    #[test]
+    fn test_split_clusters() {
+        let mut insertions = vec![];
+        for copies in [10, 20, 30] {
+            for _ in 0..4 {
+                insertions.push("CAG".repeat(copies));
+            }
+        }
+        let clusters = split_clusters(
+            &insertions,
+            3,
+            &crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432),
+        );
+        assert_eq!(clusters.len(), 3);
+        // every cluster has the insertions of a single length
+        assert!(clusters
+            .iter()
+            .all(|cluster| cluster.len() == 4 && cluster.iter().all(|i| i == &cluster[0])));
+    }
+
+    #[test]
+    fn test_refine_reassigns_insertion() {
+        // the last sequence of hap1 is identical to the sequences of hap2 and should be moved
+        let hap1 = vec![
+            "CAGCAGCAGCAGCAGCAGCAGCAGCAGCAG".to_string(),
+            "CAGCAGCAGCAGCAGCAGCAGCAGCAGCAG".to_string(),
+            "CAGCAGCAGCAGCAGCAGCAGCAGCAGCAG".to_string(),
+            "CAGCAACAGCAACAGCAACAGCAACAGCAA".to_string(),
+        ];
+        let hap2 = vec![
+            "CAGCAACAGCAACAGCAACAGCAACAGCAA".to_string(),
+            "CAGCAACAGCAACAGCAACAGCAACAGCAA".to_string(),
+            "CAGCAACAGCAACAGCAACAGCAACAGCAA".to_string(),
+        ];
+        let refined = refine(
+            hap1,
+            hap2,
+            1,
+            &crate::repeats::RepeatInterval {
+                chrom: "chr7".to_string(),
+                start: 154654404,
+                end: 154654432,
+                motif: None,
+                annotation: None,
+                structure: None,
+            },
+            5,
+            crate::ConsensusMethod::Poa,
+        );
+        assert_eq!(refined.hap1.len(), 3);
+        assert_eq!(refined.hap2.len(), 4);
+    }
 }
@@ -0,0 +1,145 @@
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use std::path::Path;
+
+// the size of a base in the plot, the width is reduced for long insertions
+const ROW_HEIGHT: usize = 6;
+const MAX_BASE_WIDTH: f32 = 4.0;
+const MAX_PLOT_WIDTH: f32 = 1200.0;
+const MARGIN: usize = 20;
+
+/// Write a waterfall plot of the insertions per haplotype as SVG to <dir>/<chrom>_<start>_<end>.svg
+/// Every read is a row of bases colored by nucleotide, sorted by length,
+/// with lines for the length of the reference repeat and the consensus of the haplotype
+pub fn write_locus_svg(record: &VCFRecord, repeat: &RepeatInterval, dir: &str) {
+    let insertions = match &record.plot_insertions {
+        Some(insertions) => insertions,
+        None => return,
+    };
+    let consenses = [record.consensus.0.as_str(), record.consensus.1.as_str()];
+    let haplotypes = insertions
+        .iter()
+        .zip(consenses.iter())
+        .map(|(reads, consensus)| {
+            let reads = reads
+                .split(':')
+                .filter(|r| !r.is_empty())
+                .collect::<Vec<&str>>();
+            (*consensus, reads)
+        })
+        .collect::<Vec<(&str, Vec<&str>)>>();
+    let svg = locus_svg(repeat, record.ref_seq.len(), &haplotypes);
+    let name = format!("{}_{}_{}.svg", repeat.chrom, repeat.start, repeat.end);
+    let path = Path::new(dir).join(name);
+    std::fs::write(&path, svg)
+        .unwrap_or_else(|err| panic!("Failed writing plot to {}: {err}", path.display()));
+}
+
+// haplotypes are (consensus sequence, insertions) pairs, with "." for a missing consensus
+fn locus_svg(repeat: &RepeatInterval, ref_len: usize, haplotypes: &[(&str, Vec<&str>)]) -> String {
+    let longest = haplotypes
+        .iter()
+        .flat_map(|(consensus, reads)| reads.iter().map(|r| r.len()).chain([consensus.len()]))
+        .chain([ref_len])
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let base_width = (MAX_PLOT_WIDTH / longest as f32).min(MAX_BASE_WIDTH);
+    let width = (longest as f32 * base_width) as usize + 2 * MARGIN;
+    let mut body = String::new();
+    let mut y = MARGIN;
+    for (index, (consensus, reads)) in haplotypes.iter().enumerate() {
+        body.push_str(&format!(
+            "<text x=\"{MARGIN}\" y=\"{}\" font-size=\"12\" font-family=\"sans-serif\">haplotype {} ({} reads)</text>\n",
+            y + 12,
+            index + 1,
+            reads.len()
+        ));
+        y += 2 * MARGIN;
+        let top = y;
+        let mut reads = reads.clone();
+        reads.sort_by_key(|r| r.len());
+        for read in reads {
+            body.push_str(&read_row(read, y, base_width));
+            y += ROW_HEIGHT;
+        }
+        let bottom = y.max(top + ROW_HEIGHT);
+        body.push_str(&vertical_line(ref_len, top, bottom, base_width, "black"));
+        if *consensus != "." {
+            body.push_str(&vertical_line(consensus.len(), top, bottom, base_width, "red"));
+        }
+        y = bottom + MARGIN;
+    }
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{}\">\n<title>{repeat}</title>\n{body}</svg>\n",
+        y + MARGIN
+    )
+}
+
+// a read as a row of rectangles, merging consecutive identical bases
+fn read_row(read: &str, y: usize, base_width: f32) -> String {
+    let mut row = String::new();
+    let bases = read.as_bytes();
+    let mut start = 0;
+    while start < bases.len() {
+        let end = start
+            + bases[start..]
+                .iter()
+                .take_while(|base| **base == bases[start])
+                .count();
+        row.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{y}\" width=\"{:.1}\" height=\"{ROW_HEIGHT}\" fill=\"{}\"/>\n",
+            MARGIN as f32 + start as f32 * base_width,
+            (end - start) as f32 * base_width,
+            base_color(bases[start])
+        ));
+        start = end;
+    }
+    row
+}
+
+fn vertical_line(length: usize, top: usize, bottom: usize, base_width: f32, color: &str) -> String {
+    let x = MARGIN as f32 + length as f32 * base_width;
+    format!("<line x1=\"{x:.1}\" y1=\"{top}\" x2=\"{x:.1}\" y2=\"{bottom}\" stroke=\"{color}\"/>\n")
+}
+
+fn base_color(base: u8) -> &'static str {
+    match base.to_ascii_uppercase() {
+        b'A' => "#2ca02c",
+        b'C' => "#1f77b4",
+        b'G' => "#ff7f0e",
+        b'T' => "#d62728",
+        _ => "#7f7f7f",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_row() {
+        // consecutive identical bases are a single rectangle
+        assert_eq!(read_row("CAAG", 0, 1.0).matches("<rect").count(), 3);
+        assert_eq!(read_row("", 0, 1.0), "");
+    }
+
+    #[test]
+    fn test_locus_svg() {
+        let repeat = RepeatInterval::new("chr7", 100, 106);
+        let svg = locus_svg(
+            &repeat,
+            6,
+            &[
+                ("CAGCAGCAG", vec!["CAGCAGCAG", "CAGCAGCAGCAG"]),
+                (".", vec![]),
+            ],
+        );
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("<title>chr7:100-106</title>"));
+        assert!(svg.contains("haplotype 1 (2 reads)"));
+        assert!(svg.contains("haplotype 2 (0 reads)"));
+        // a reference line per haplotype and a consensus line for the first haplotype
+        assert_eq!(svg.matches("<line").count(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::repeats::RepeatIntervalIterator;
+use crate::{AlleleResult, Cli, LocusResult};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict};
+
+/// Genotype the repeats in a region string (chrom:start-end) or in a bed file
+/// options: the options of the call subcommand, e.g. {"unphased": True, "support": 5}
+/// Returns a dict per locus, with the genotype and the length, sequence and support per allele
+/// Raises ValueError for invalid options, regions or input files, and RuntimeError if genotyping fails
+/// The GIL is released while genotyping, such that other Python threads keep running
+#[pyfunction]
+#[pyo3(signature = (bam, fasta, region, options = None))]
+fn genotype_region(
+    py: Python<'_>,
+    bam: &str,
+    fasta: &str,
+    region: &str,
+    options: Option<&PyDict>,
+) -> PyResult<Vec<PyObject>> {
+    let args = match options {
+        Some(options) => option_args(options)?,
+        None => vec![],
+    };
+    let args = args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>();
+    let options =
+        Cli::from_args(fasta, bam, &args).map_err(|err| PyValueError::new_err(err.to_string()))?;
+    // a malformed region or a contig that is not in the fasta file panics while parsing
+    let repeats = crate::utils::catch_panic(
+        || {
+            if std::path::Path::new(region).is_file() {
+                RepeatIntervalIterator::from_bed(&region.to_string(), fasta)
+            } else {
+                RepeatIntervalIterator::from_string(region, fasta)
+            }
+        },
+        &format!("Invalid region {region}"),
+    )
+    .map_err(PyValueError::new_err)?;
+    let results = py.allow_threads(|| {
+        // as for the region, a missing or unreadable input file panics when it is opened
+        let (inputs, mut reader) = crate::utils::catch_panic(
+            || {
+                (
+                    crate::genotype::RunInputs::from_args(&options),
+                    crate::parse_bam::create_bam_reader(bam, fasta),
+                )
+            },
+            &format!("Failed opening {bam}"),
+        )
+        .map_err(PyValueError::new_err)?;
+        repeats
+            .map(|repeat| {
+                crate::genotype_repeat(&repeat, &options, &mut reader, &inputs)
+                    .map_err(|err| format!("Failed genotyping {repeat}: {err}"))
+            })
+            .collect::<Result<Vec<LocusResult>, String>>()
+            .map_err(PyRuntimeError::new_err)
+    })?;
+    results
+        .iter()
+        .map(|result| -> PyResult<PyObject> { Ok(locus_dict(py, result)?.into()) })
+        .collect()
+}
+
+// the options as command line arguments, a flag is only added if True
+fn option_args(options: &PyDict) -> PyResult<Vec<String>> {
+    let mut args = vec![];
+    for (key, value) in options {
+        let flag = format!("--{}", key.extract::<String>()?.replace('_', "-"));
+        if value.is_instance_of::<PyBool>() {
+            if value.extract::<bool>()? {
+                args.push(flag);
+            }
+        } else {
+            args.push(flag);
+            args.push(value.str()?.to_string());
+        }
+    }
+    Ok(args)
+}
+
+fn locus_dict<'py>(py: Python<'py>, result: &LocusResult) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("chrom", &result.chrom)?;
+    dict.set_item("start", result.start)?;
+    dict.set_item("end", result.end)?;
+    dict.set_item("ref", &result.ref_seq)?;
+    dict.set_item("alt", &result.alt_seqs)?;
+    dict.set_item("genotype", &result.genotype)?;
+    let alleles = result
+        .alleles
+        .iter()
+        .map(|allele| allele_dict(py, allele))
+        .collect::<PyResult<Vec<&PyDict>>>()?;
+    dict.set_item("alleles", alleles)?;
+    dict.set_item("phase_set", result.phase_set)?;
+    dict.set_item("filters", &result.filters)?;
+    Ok(dict)
+}
+
+fn allele_dict<'py>(py: Python<'py>, allele: &AlleleResult) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("length", allele.length)?;
+    dict.set_item("full_length", allele.full_length)?;
+    dict.set_item("sequence", &allele.sequence)?;
+    dict.set_item("support", allele.support)?;
+    dict.set_item("std_dev", allele.std_dev)?;
+    dict.set_item("score", allele.score)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn strdust(_py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    crate::utils::set_ca_bundle();
+    module.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    module.add_function(wrap_pyfunction!(genotype_region, module)?)?;
+    Ok(())
+}
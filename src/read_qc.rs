@@ -0,0 +1,124 @@
+use crate::repeats::RepeatInterval;
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use serde_json::{json, Value};
+
+// an allele with at least this number of reads is checked for strand bias
+const STRAND_BIAS_MIN_READS: usize = 10;
+// an allele is strand biased if less than this fraction of its reads is on the minor strand
+const STRAND_BIAS_FRACTION: f32 = 0.1;
+
+/// The strand of a read spanning a repeat, and the mean base quality of the read sequence in the repeat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadQc {
+    pub reverse: bool,
+    pub quality: Option<f32>,
+}
+
+impl ReadQc {
+    pub fn new(record: &bam::Record, repeat: &RepeatInterval) -> ReadQc {
+        ReadQc {
+            reverse: record.is_reverse(),
+            quality: repeat_quality(record, repeat),
+        }
+    }
+}
+
+// the mean base quality between the query positions aligned to the start and end of the repeat,
+// including the inserted bases, None if the read has no base qualities
+fn repeat_quality(record: &bam::Record, repeat: &RepeatInterval) -> Option<f32> {
+    let qual = record.qual();
+    if qual.first().map_or(true, |q| *q == 255) {
+        return None;
+    }
+    let mut positions = record
+        .aligned_pairs()
+        .filter(|[_, rpos]| *rpos >= repeat.start as i64 && *rpos < repeat.end as i64)
+        .map(|[qpos, _]| qpos as usize);
+    let first = positions.next()?;
+    let last = positions.last().unwrap_or(first);
+    let quals = &qual[first..=last.min(qual.len() - 1)];
+    Some(quals.iter().map(|q| *q as f32).sum::<f32>() / quals.len() as f32)
+}
+
+/// The read QC of a genotyped repeat, per allele
+/// forward and reverse: number of supporting reads on either strand
+/// quality: mean base quality of the repeat in the supporting reads
+/// strand_bias: an allele with enough reads has almost all reads on the same strand
+#[derive(Clone)]
+pub struct ReadQcAnnotation {
+    pub forward: (String, String),
+    pub reverse: (String, String),
+    pub quality: (String, String),
+    pub strand_bias: bool,
+}
+
+impl ReadQcAnnotation {
+    pub fn new(allele1: &[ReadQc], allele2: &[ReadQc]) -> ReadQcAnnotation {
+        let reverse = |reads: &[ReadQc]| reads.iter().filter(|read| read.reverse).count();
+        let quality = |reads: &[ReadQc]| {
+            let quals = reads.iter().filter_map(|read| read.quality).collect::<Vec<f32>>();
+            if quals.is_empty() {
+                ".".to_string()
+            } else {
+                format!("{:.1}", quals.iter().sum::<f32>() / quals.len() as f32)
+            }
+        };
+        let biased = |reads: &[ReadQc]| {
+            let minor = reverse(reads).min(reads.len() - reverse(reads));
+            reads.len() >= STRAND_BIAS_MIN_READS
+                && (minor as f32) < STRAND_BIAS_FRACTION * reads.len() as f32
+        };
+        ReadQcAnnotation {
+            forward: (
+                (allele1.len() - reverse(allele1)).to_string(),
+                (allele2.len() - reverse(allele2)).to_string(),
+            ),
+            reverse: (reverse(allele1).to_string(), reverse(allele2).to_string()),
+            quality: (quality(allele1), quality(allele2)),
+            strand_bias: biased(allele1) || biased(allele2),
+        }
+    }
+
+    pub fn to_json(&self, haploid: bool) -> Value {
+        let values = |values: &(String, String)| {
+            let first = crate::vcf::missing_to_null(&values.0);
+            if haploid {
+                json!([first])
+            } else {
+                json!([first, crate::vcf::missing_to_null(&values.1)])
+            }
+        };
+        json!({
+            "forward": values(&self.forward),
+            "reverse": values(&self.reverse),
+            "quality": values(&self.quality),
+            "strand_bias": self.strand_bias,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_qc_annotation() {
+        let read = |reverse: bool| ReadQc {
+            reverse,
+            quality: Some(20.0),
+        };
+        let balanced = [vec![read(false); 6], vec![read(true); 6]].concat();
+        let biased = [vec![read(false); 11], vec![read(true)]].concat();
+        let annotation = ReadQcAnnotation::new(&balanced, &balanced);
+        assert_eq!(annotation.forward, ("6".to_string(), "6".to_string()));
+        assert_eq!(annotation.quality.0, "20.0");
+        assert!(!annotation.strand_bias);
+        let annotation = ReadQcAnnotation::new(&balanced, &biased);
+        assert_eq!(annotation.reverse.1, "1");
+        assert!(annotation.strand_bias);
+        // too few reads to call a strand bias
+        assert!(!ReadQcAnnotation::new(&balanced, &biased[..5]).strand_bias);
+        assert_eq!(ReadQcAnnotation::new(&[], &[]).quality.0, ".");
+    }
+}
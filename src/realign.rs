@@ -0,0 +1,140 @@
+use crate::genotype::cs_regex;
+use minimap2::Aligner;
+
+// the length of reference flanking sequence around the called allele in the personalized reference
+pub const REALIGN_FLANKING: u32 = 1000;
+
+/// Realign reads to a personalized reference with the called alleles, as a self-consistency check
+/// Returns the mean fraction of edited bases in the allele per allele, or "." if this can't be determined
+/// With a single set of reads, every read is assigned to the allele it fits best
+/// with two sets, the reads of each set are realigned to the respective allele
+pub fn residual_errors(
+    read_sets: &[&[Vec<u8>]],
+    alleles: [&str; 2],
+    left: &str,
+    right: &str,
+) -> [String; 2] {
+    let residuals = alleles.map(|allele| {
+        if allele == "." {
+            return None;
+        }
+        let per_set = read_sets
+            .iter()
+            .map(|reads| read_residuals(reads, left, right, allele))
+            .collect::<Vec<Vec<Option<f32>>>>();
+        Some(per_set)
+    });
+    let mut assigned: [Vec<f32>; 2] = [vec![], vec![]];
+    if read_sets.len() == 2 {
+        for (index, residual) in residuals.iter().enumerate() {
+            if let Some(per_set) = residual {
+                assigned[index].extend(per_set[index].iter().flatten());
+            }
+        }
+    } else {
+        // every read is assigned to the allele with the lowest residual error
+        let empty = vec![None; read_sets.first().map_or(0, |reads| reads.len())];
+        let first = residuals[0].as_ref().map_or(&empty, |r| &r[0]);
+        let second = residuals[1].as_ref().map_or(&empty, |r| &r[0]);
+        for (residual1, residual2) in first.iter().zip(second.iter()) {
+            match (residual1, residual2) {
+                (Some(r1), Some(r2)) if r1 == r2 => {
+                    // the alleles are identical for this read, e.g. homozygous
+                    assigned[0].push(*r1);
+                    assigned[1].push(*r2);
+                }
+                (Some(r1), Some(r2)) if r1 < r2 => assigned[0].push(*r1),
+                (Some(_), Some(r2)) => assigned[1].push(*r2),
+                (Some(r1), None) => assigned[0].push(*r1),
+                (None, Some(r2)) => assigned[1].push(*r2),
+                (None, None) => (),
+            }
+        }
+    }
+    assigned.map(|errors| {
+        if errors.is_empty() {
+            ".".to_string()
+        } else {
+            format!("{:.3}", errors.iter().sum::<f32>() / errors.len() as f32)
+        }
+    })
+}
+
+// the fraction of edited bases in the allele for every read
+// or None if the read does not align across the full allele
+fn read_residuals(reads: &[Vec<u8>], left: &str, right: &str, allele: &str) -> Vec<Option<f32>> {
+    let reference = format!("{left}{allele}{right}");
+    let aligner = Aligner::builder()
+        .map_ont()
+        .with_cigar()
+        .with_seq(reference.as_bytes())
+        .unwrap_or_else(|err| panic!("Unable to build index for realignment:\n{err}"));
+    let start = left.len() as i32;
+    let end = (left.len() + allele.len()) as i32;
+    reads
+        .iter()
+        .map(|read| {
+            // with --timeout, the remaining reads are skipped as the locus is abandoned
+            if crate::genotype::deadline_passed() {
+                return None;
+            }
+            let mappings = aligner.map(read, true, false, None, None).ok()?;
+            let mapping = mappings
+                .iter()
+                .find(|m| m.target_start <= start && m.target_end >= end)?;
+            let cs = mapping.alignment.as_ref()?.cs.as_ref()?;
+            let edits = edits_in_span(cs, mapping.target_start, start, end);
+            Some(edits as f32 / allele.len().max(1) as f32)
+        })
+        .collect()
+}
+
+// count the edited reference bases between start and end, based on the cs tag
+// insertions directly adjacent to the span are counted as well
+fn edits_in_span(cs: &str, mut ref_pos: i32, start: i32, end: i32) -> usize {
+    let mut edits = 0;
+    for cap in cs_regex().captures_iter(cs) {
+        let op = &cap[0];
+        let len = op.len() as i32 - 1;
+        match op.as_bytes()[0] {
+            b':' => {
+                ref_pos += op[1..]
+                    .parse::<i32>()
+                    .expect("Unable to parse length from CS':' operation")
+            }
+            b'*' => {
+                if ref_pos >= start && ref_pos < end {
+                    edits += 1;
+                }
+                ref_pos += len / 2;
+            }
+            b'+' => {
+                if ref_pos >= start && ref_pos <= end {
+                    edits += len as usize;
+                }
+            }
+            b'-' => {
+                edits += (ref_pos..ref_pos + len)
+                    .filter(|pos| *pos >= start && *pos < end)
+                    .count();
+                ref_pos += len;
+            }
+            _ => (),
+        }
+    }
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edits_in_span() {
+        // a mismatch and an insertion in the span, and a deletion overlapping the end of the span
+        let cs = ":10*ag:5+cag:4-ggtt:20";
+        assert_eq!(edits_in_span(cs, 0, 8, 21), 1 + 3 + 1);
+        // edits outside of the span are not counted
+        assert_eq!(edits_in_span(cs, 0, 30, 40), 0);
+    }
+}
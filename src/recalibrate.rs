@@ -0,0 +1,224 @@
+use crate::likelihood;
+use log::info;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+// loci are classed by the length of their motif up to this length, longer motifs form a single class
+const MAX_CLASS_MOTIF: usize = 6;
+
+/// Recalibrate the genotype qualities of a STRdust VCF with the genotypes of the whole run,
+/// using the fraction of heterozygous calls per motif class as the prior of the genotypes
+/// The recalibrated GQ replaces the GQ, the raw GQ is kept in the GQRAW FORMAT field
+/// The VCF is written to stdout
+#[derive(clap::Args, Debug)]
+pub struct RecalibrateArgs {
+    /// vcf file produced by STRdust
+    vcf: String,
+
+    /// minimal number of calls of a motif class to use its own heterozygous fraction, rather than that of all calls
+    #[clap(long, value_parser, default_value_t = 100)]
+    min_calls: usize,
+}
+
+// the fields of a diploid or haploid call with a genotype quality
+struct Call {
+    class: String,
+    alleles: usize,
+    genotype: Vec<usize>,
+    pl: Vec<u32>,
+    gq: u32,
+    gq_index: usize,
+}
+
+// the fraction of heterozygous calls per motif class, and of all calls for the classes with few calls
+struct HetFractions {
+    classes: HashMap<String, f64>,
+    overall: f64,
+}
+
+impl HetFractions {
+    fn from_vcf(vcf: &str, min_calls: usize) -> HetFractions {
+        let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+        for line in crate::utils::reader(vcf).lines() {
+            let line = line.unwrap_or_else(|err| panic!("Failed reading {vcf}: {err}"));
+            if line.starts_with('#') {
+                continue;
+            }
+            let columns = line.split('\t').collect::<Vec<&str>>();
+            if let Some(call) = parse_call(&columns).filter(|call| call.genotype.len() == 2) {
+                let count = counts.entry(call.class).or_default();
+                count.0 += (call.genotype[0] != call.genotype[1]) as usize;
+                count.1 += 1;
+            }
+        }
+        // a pseudocount of a heterozygous and a homozygous call keeps the fractions away from zero and one
+        let fraction = |(het, calls): (usize, usize)| (het + 1) as f64 / (calls + 2) as f64;
+        let overall = fraction(
+            counts
+                .values()
+                .fold((0, 0), |total, count| (total.0 + count.0, total.1 + count.1)),
+        );
+        let classes = counts
+            .into_iter()
+            .filter(|(_, (_, calls))| *calls >= min_calls)
+            .map(|(class, count)| {
+                let (calls, het_fraction) = (count.1, fraction(count));
+                info!("Motif class {class}: {calls} calls, {het_fraction:.3} heterozygous");
+                (class, fraction(count))
+            })
+            .collect();
+        info!("All calls: {overall:.3} heterozygous");
+        HetFractions { classes, overall }
+    }
+
+    fn get(&self, class: &str) -> f64 {
+        self.classes.get(class).copied().unwrap_or(self.overall)
+    }
+}
+
+pub fn run(args: RecalibrateArgs) {
+    let fractions = HetFractions::from_vcf(&args.vcf, args.min_calls);
+    for line in crate::utils::reader(&args.vcf).lines() {
+        let line = line.unwrap_or_else(|err| panic!("Failed reading {}: {err}", args.vcf));
+        if line.starts_with("#CHROM") {
+            println!(
+                r#"##FORMAT=<ID=GQRAW,Number=1,Type=Integer,Description="Genotype quality before the recalibration by STRdust recalibrate">"#
+            );
+            println!("{line}");
+        } else if line.starts_with('#') {
+            println!("{line}");
+        } else {
+            println!("{}", recalibrate_record(&line, &fractions));
+        }
+    }
+}
+
+// a record with the recalibrated GQ and the raw GQ in GQRAW, unchanged without a GQ
+fn recalibrate_record(line: &str, fractions: &HetFractions) -> String {
+    let columns = line.split('\t').collect::<Vec<&str>>();
+    let call = match parse_call(&columns) {
+        Some(call) => call,
+        None => return line.to_string(),
+    };
+    // haploid calls have no heterozygous genotypes, so keep their quality
+    let gq = if call.genotype.len() == 2 {
+        let prior = genotype_prior(call.alleles, fractions.get(&call.class));
+        likelihood::genotype_quality_with_prior(
+            &call.pl,
+            likelihood::genotype_index(&call.genotype),
+            &prior,
+        )
+    } else {
+        call.gq
+    };
+    let mut values = columns[9].split(':').collect::<Vec<&str>>();
+    let gq = gq.to_string();
+    values[call.gq_index] = &gq;
+    format!(
+        "{}\t{}:GQRAW\t{}:{}",
+        columns[..8].join("\t"),
+        columns[8],
+        values.join(":"),
+        call.gq
+    )
+}
+
+// the call of a single-sample record with a GT, GQ and PL, None for a missing allele
+fn parse_call(columns: &[&str]) -> Option<Call> {
+    if columns.len() != 10 {
+        return None;
+    }
+    let keys = columns[8].split(':').collect::<Vec<&str>>();
+    let values = columns[9].split(':').collect::<Vec<&str>>();
+    let value = |key: &str| {
+        keys.iter()
+            .position(|k| *k == key)
+            .and_then(|index| values.get(index))
+    };
+    let alleles = 1 + columns[4].split(',').filter(|alt| *alt != ".").count();
+    let genotype = value("GT")?
+        .split(['|', '/'])
+        .map(|allele| allele.parse::<usize>().ok().filter(|allele| *allele < alleles))
+        .collect::<Option<Vec<usize>>>()?;
+    let pl = value("PL")?
+        .split(',')
+        .map(|pl| pl.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    if pl.len() != likelihood::genotypes(alleles, genotype.len()).len() {
+        return None;
+    }
+    Some(Call {
+        class: motif_class(columns),
+        alleles,
+        genotype,
+        pl,
+        gq: value("GQ")?.parse().ok()?,
+        gq_index: keys.iter().position(|k| *k == "GQ")?,
+    })
+}
+
+// the motif length of the longest sequence allele of a record, as 1 to MAX_CLASS_MOTIF or longer
+fn motif_class(columns: &[&str]) -> String {
+    let longest = std::iter::once(columns[3])
+        .chain(columns[4].split(','))
+        .filter(|allele| *allele != "." && !allele.starts_with('<'))
+        .max_by_key(|allele| allele.len())
+        .unwrap_or_default();
+    match crate::motif::detect_motif(longest, MAX_CLASS_MOTIF) {
+        Some(motif) => motif.len().to_string(),
+        None => "longer".to_string(),
+    }
+}
+
+// the prior of every diploid genotype in the order of the PL field, of which the heterozygous genotypes
+// share the heterozygous fraction and the homozygous genotypes the rest
+fn genotype_prior(alleles: usize, het_fraction: f64) -> Vec<f64> {
+    let genotypes = likelihood::genotypes(alleles, 2);
+    let hets = genotypes.iter().filter(|genotype| genotype[0] != genotype[1]).count();
+    let homs = genotypes.len() - hets;
+    genotypes
+        .iter()
+        .map(|genotype| {
+            if genotype[0] != genotype[1] {
+                het_fraction / hets as f64
+            } else {
+                (1.0 - het_fraction) / homs as f64
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recalibrate_record() {
+        let line = "chr7\t100\t.\tCAGCAGCAGCAG\tCAGCAGCAGCAGCAG\t.\t.\tEND=112\tGT:RB:GQ:PL\t0|1:0,3:30:30,0,40";
+        let fractions = |fraction: f64| HetFractions {
+            classes: HashMap::from([("3".to_string(), fraction)]),
+            overall: 0.5,
+        };
+        // heterozygous calls are rare for this motif class, so the quality of a heterozygous call drops
+        let recalibrated = recalibrate_record(line, &fractions(0.01));
+        let columns = recalibrated.split('\t').collect::<Vec<&str>>();
+        assert_eq!(columns[8], "GT:RB:GQ:PL:GQRAW");
+        let values = columns[9].split(':').collect::<Vec<&str>>();
+        assert!(values[2].parse::<u32>().unwrap() < 30);
+        assert_eq!(values[4], "30");
+        // with a third of the calls heterozygous, the prior is flat and the quality is unchanged
+        let recalibrated = recalibrate_record(line, &fractions(1.0 / 3.0));
+        assert!(recalibrated.ends_with("\t0|1:0,3:30:30,0,40:30"));
+        // a record without a quality is unchanged
+        let missing = "chr7\t100\t.\tCAGCAG\t.\t.\t.\tEND=106\tGT:RB\t.|.:.,.";
+        assert_eq!(recalibrate_record(missing, &fractions(0.01)), missing);
+    }
+
+    #[test]
+    fn test_genotype_prior() {
+        let prior = genotype_prior(3, 0.3);
+        assert_eq!(prior.len(), 6);
+        assert!((prior.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!((prior[1] - 0.1).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::vcf::VCFRecord;
+use log::error;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, Write};
+use std::sync::Mutex;
+
+/// A tsv file with every allele observed in this and previous runs, keyed by a stable allele id
+/// Alleles that are already in the file are not added again
+pub struct AlleleRegistry {
+    path: String,
+    registry: Mutex<(HashSet<String>, BufWriter<File>)>,
+}
+
+impl AlleleRegistry {
+    pub fn open(path: &str) -> AlleleRegistry {
+        let exists = std::path::Path::new(path).is_file();
+        let known = if exists {
+            crate::utils::reader(path)
+                .lines()
+                .map(|line| line.expect("Failed reading allele registry"))
+                .filter(|line| !line.starts_with('#'))
+                .filter_map(|line| line.split('\t').next().map(|id| id.to_string()))
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|err| panic!("Failed opening allele registry {path}: {err}"));
+        let mut writer = BufWriter::new(file);
+        if !exists {
+            writeln!(writer, "#allele_id\tchrom\tstart\tend\tlength\tsequence")
+                .unwrap_or_else(|err| panic!("Failed writing allele registry {path}: {err}"));
+        }
+        AlleleRegistry {
+            path: path.to_string(),
+            registry: Mutex::new((known, writer)),
+        }
+    }
+
+    /// Add the ALT alleles of a record that are not yet in the registry
+    pub fn add(&self, record: &VCFRecord) {
+        let mut registry = self.registry.lock().expect("Unable to lock allele registry");
+        let (known, writer) = &mut *registry;
+        for (id, seq) in allele_ids(record).into_iter().zip(alt_alleles(record)) {
+            if known.insert(id.clone()) {
+                writeln!(
+                    writer,
+                    "{id}\t{}\t{}\t{}\t{}\t{seq}",
+                    record.chrom,
+                    record.start,
+                    record.end,
+                    seq.len() as i64 - record.ref_seq.len() as i64
+                )
+                .unwrap_or_else(|err| panic!("Failed writing allele registry {}: {err}", self.path));
+            }
+        }
+    }
+}
+
+impl Drop for AlleleRegistry {
+    fn drop(&mut self) {
+        if let Ok(mut registry) = self.registry.lock() {
+            if let Err(err) = registry.1.flush() {
+                error!("Failed writing allele registry {}: {err}", self.path);
+            }
+        }
+    }
+}
+
+fn alt_alleles(record: &VCFRecord) -> Vec<&str> {
+    match record.alt_seq.as_deref() {
+        Some(".") | None => vec![],
+        Some(alts) => alts.split(',').collect(),
+    }
+}
+
+/// The stable id of every ALT allele of a record
+pub fn allele_ids(record: &VCFRecord) -> Vec<String> {
+    alt_alleles(record)
+        .into_iter()
+        .map(|seq| crate::utils::allele_id(&record.chrom, record.start, record.end, seq))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allele_registry() {
+        let path = crate::test_utils::temp_path("allele-registry.tsv");
+        let path = path.as_str();
+        let repeat = crate::repeats::RepeatInterval::new("chr7", 100, 106);
+        let mut record = crate::test_utils::cag_record(&repeat, "0");
+        record.alt_seq = Some("CAGCAGCAG,CAGCAGCAGCAG".to_string());
+        AlleleRegistry::open(path).add(&record);
+        // alleles from a previous run are not added again
+        AlleleRegistry::open(path).add(&record);
+        let content = std::fs::read_to_string(path).expect("Failed reading registry");
+        assert_eq!(content.lines().count(), 3);
+        let id = &allele_ids(&record)[0];
+        assert!(content.contains(&format!("{id}\tchr7\t100\t106\t3\tCAGCAGCAG")));
+        std::fs::remove_file(path).expect("Failed removing registry");
+    }
+}
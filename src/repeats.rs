@@ -1,7 +1,82 @@
+use crate::motif::LocusStructure;
+use crate::utils::IntervalIndex;
 use bio::io::bed;
+use human_sort::compare as human_compare;
+use log::{debug, info, warn};
 use rust_htslib::faidx;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// contig names of the bed file are matched to the fasta and bam file with and without a chr prefix,
+// unless disabled with --no-contig-reconciliation
+static RECONCILE_CONTIGS: AtomicBool = AtomicBool::new(true);
+
+thread_local! {
+    // every thread keeps the fasta reader open across repeats, with the path it was opened for
+    static FASTA_READER: RefCell<Option<(String, faidx::Reader)>> = RefCell::new(None);
+}
+
+// run a function with the fasta reader of this thread, opening it if required
+fn with_fasta_reader<T>(fasta: &str, f: impl FnOnce(&faidx::Reader) -> T) -> T {
+    FASTA_READER.with(|reader| {
+        let mut reader = reader.borrow_mut();
+        if !matches!(&*reader, Some((path, _)) if path == fasta) {
+            *reader = Some((fasta.to_string(), open_fasta(fasta)));
+        }
+        let (_, fas) = reader.as_ref().expect("Fasta reader was just opened");
+        f(fas)
+    })
+}
+
+/// Open a fasta file with its index, which can be bgzipped (with a .gzi index) or remote
+/// A missing index of a local fasta file is created by htslib
+pub fn open_fasta(fasta: &str) -> faidx::Reader {
+    faidx::Reader::from_path(fasta)
+        .unwrap_or_else(|err| panic!("Failed opening fasta {fasta}: {err}"))
+}
+
+/// The name and length of every sequence in the index of a fasta file
+pub fn fasta_contigs(fasta: &str) -> Vec<(String, u64)> {
+    with_fasta_reader(fasta, |fas| {
+        (0..fas.n_seqs())
+            .map(|i| {
+                let name = fas
+                    .seq_name(i as i32)
+                    .unwrap_or_else(|err| panic!("Failed reading the index of {fasta}: {err}"));
+                let length = fas.fetch_seq_len(&name);
+                (name, length)
+            })
+            .collect()
+    })
+}
+
+/// The reference sequence between the 1-based positions start and end, None outside the chromosome
+pub fn reference_sequence(fasta: &str, chrom: &str, start: u32, end: u32) -> Option<String> {
+    if start == 0
+        || start > end
+        || contig_length(fasta, chrom).map_or(true, |length| end as u64 > length)
+    {
+        return None;
+    }
+    with_fasta_reader(fasta, |fas| {
+        fas.fetch_seq_string(chrom, start as usize - 1, end as usize - 1).ok()
+    })
+}
+
+/// The reference base at a 1-based position, None outside the chromosome
+pub fn reference_base(fasta: &str, chrom: &str, pos: u32) -> Option<char> {
+    reference_sequence(fasta, chrom, pos, pos).and_then(|base| base.chars().next())
+}
+
+// the length of a sequence in the fasta index, None if the fasta has no such sequence
+fn contig_length(fasta: &str, chrom: &str) -> Option<u64> {
+    // htslib returns a length of -1 for a missing sequence
+    let length = with_fasta_reader(fasta, |fas| fas.fetch_seq_len(chrom)) as i64;
+    (length >= 0).then_some(length as u64)
+}
 
 #[derive(Debug)]
 pub struct RepeatIntervalIterator {
@@ -21,8 +96,8 @@ impl RepeatIntervalIterator {
         let end: u32 = interval.split('-').collect::<Vec<&str>>()[1]
             .parse()
             .unwrap();
-        let repeat = RepeatInterval::new_interval(chrom, start, end, fasta)
-            .expect("Failed to create repeat interval");
+        let repeat = RepeatInterval::new_interval(chrom.clone(), start, end, fasta)
+            .unwrap_or_else(|| panic!("Chromosome {chrom} is not in the fasta file {fasta}"));
         RepeatIntervalIterator {
             current_index: 0,
             data: vec![repeat],
@@ -31,19 +106,7 @@ impl RepeatIntervalIterator {
     }
     pub fn from_bed(region_file: &String, fasta: &str) -> Self {
         let mut reader = bed::Reader::from_file(region_file).expect("Problem reading bed file!");
-        let mut data = Vec::new();
-        for record in reader.records() {
-            let rec = record.expect("Error reading bed record.");
-            let repeat = RepeatInterval::from_bed(&rec, fasta);
-            if let Some(repeat) = repeat {
-                data.push(repeat);
-            }
-        }
-        RepeatIntervalIterator {
-            current_index: 0,
-            data: data.clone(),
-            num_intervals: data.len(),
-        }
+        Self::from_bed_reader(&mut reader, fasta, region_file)
     }
 
     pub fn pathogenic(fasta: &str) -> Self {
@@ -51,21 +114,162 @@ impl RepeatIntervalIterator {
         let resp = reqwest::blocking::get(url).expect("request to STRchive failed");
         let body = resp.text().expect("body invalid");
         let mut reader = io::BufReader::new(body.as_bytes());
-        let mut data = Vec::new();
         let mut reader = bed::Reader::new(&mut reader);
+        Self::from_bed_reader(&mut reader, fasta, "STRchive")
+    }
+
+    // fails with a summary of the contigs that are not in the fasta file, rather than at the first locus
+    fn from_bed_reader<R: io::Read>(reader: &mut bed::Reader<R>, fasta: &str, source: &str) -> Self {
+        let mut data = Vec::new();
+        let mut missing: BTreeMap<String, usize> = BTreeMap::new();
         for record in reader.records() {
             let rec = record.expect("Error reading bed record.");
-            let repeat = RepeatInterval::from_bed(&rec, fasta);
-            if let Some(repeat) = repeat {
-                data.push(repeat);
+            match RepeatInterval::from_bed(&rec, fasta) {
+                Some(repeat) => data.push(repeat),
+                None => *missing.entry(rec.chrom().to_string()).or_default() += 1,
             }
         }
+        if !missing.is_empty() {
+            panic!(
+                "Loci of {source} on contigs that are not in the fasta file {fasta}: {}",
+                missing_contigs_summary(&missing)
+            );
+        }
         RepeatIntervalIterator {
             current_index: 0,
             data: data.clone(),
             num_intervals: data.len(),
         }
     }
+
+    /// An iterator over the given intervals, e.g. a selection of the loci of a catalog
+    pub fn from_intervals(data: Vec<RepeatInterval>) -> Self {
+        RepeatIntervalIterator {
+            current_index: 0,
+            num_intervals: data.len(),
+            data,
+        }
+    }
+
+    /// The number of remaining intervals per contig
+    pub fn contig_loci(&self) -> BTreeMap<String, usize> {
+        let mut loci: BTreeMap<String, usize> = BTreeMap::new();
+        for repeat in &self.data[self.current_index..] {
+            *loci.entry(repeat.chrom.clone()).or_default() += 1;
+        }
+        loci
+    }
+}
+
+/// The number of loci per missing contig, with a hint on the naming of the contigs
+pub fn missing_contigs_summary(missing: &BTreeMap<String, usize>) -> String {
+    let contigs = missing
+        .iter()
+        .map(|(contig, loci)| format!("{contig} ({loci} loci)"))
+        .collect::<Vec<String>>()
+        .join(", ");
+    if RECONCILE_CONTIGS.load(Ordering::Relaxed) {
+        format!("{contigs}. Contig names were also tried with and without a chr prefix")
+    } else {
+        format!("{contigs}. Contig names with and without a chr prefix are only matched without --no-contig-reconciliation")
+    }
+}
+
+/// Match contig names with and without a chr prefix, e.g. chr1 in a bed file to 1 in the fasta file, for all threads
+pub fn set_contig_reconciliation(enabled: bool) {
+    RECONCILE_CONTIGS.store(enabled, Ordering::Relaxed);
+}
+
+/// The name of a contig as it is called in a fasta or bam file with has_contig,
+/// if reconciliation is enabled also with or without a chr prefix, and MT for chrM
+/// None if the file has no such contig
+pub fn reconcile_contig(chrom: &str, has_contig: impl Fn(&str) -> bool) -> Option<String> {
+    if has_contig(chrom) {
+        return Some(chrom.to_string());
+    }
+    if !RECONCILE_CONTIGS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let alias = contig_aliases(chrom).into_iter().find(|alias| has_contig(alias));
+    if let Some(alias) = &alias {
+        debug!("Contig {chrom} is called {alias}");
+    }
+    alias
+}
+
+// the names of a contig in the other naming convention
+fn contig_aliases(chrom: &str) -> Vec<String> {
+    match chrom.strip_prefix("chr") {
+        Some("M") => vec!["MT".to_string(), "M".to_string()],
+        Some(name) => vec![name.to_string()],
+        None if chrom == "MT" => vec!["chrM".to_string(), "chrMT".to_string()],
+        None => vec![format!("chr{chrom}")],
+    }
+}
+
+impl RepeatIntervalIterator {
+    /// Remove duplicated intervals, keeping the order of the first occurrence
+    /// With max_gap, overlapping intervals and intervals closer than max_gap bases are merged,
+    /// which sorts the intervals by chromosome and start position
+    pub fn merge_intervals(mut self, max_gap: Option<u32>) -> Self {
+        let num_intervals = self.data.len();
+        let mut seen = std::collections::HashSet::new();
+        self.data
+            .retain(|repeat| seen.insert((repeat.chrom.clone(), repeat.start, repeat.end)));
+        if let Some(max_gap) = max_gap {
+            self.data
+                .sort_by(|a, b| human_compare(&a.chrom, &b.chrom).then(a.start.cmp(&b.start)));
+            let mut merged: Vec<RepeatInterval> = Vec::with_capacity(self.data.len());
+            for repeat in self.data.drain(..) {
+                match merged.last_mut() {
+                    Some(last)
+                        if last.chrom == repeat.chrom
+                            && repeat.start <= last.end.saturating_add(max_gap) =>
+                    {
+                        last.end = last.end.max(repeat.end);
+                        // the structure of a compound locus no longer describes the merged interval
+                        last.structure = None;
+                        if last.motif != repeat.motif {
+                            last.motif = None;
+                        }
+                        if last.annotation != repeat.annotation {
+                            last.annotation = None;
+                        }
+                    }
+                    _ => merged.push(repeat),
+                }
+            }
+            self.data = merged;
+        }
+        if self.data.len() < num_intervals {
+            info!(
+                "Merged {num_intervals} repeat intervals into {}",
+                self.data.len()
+            );
+        }
+        self.num_intervals = self.data.len();
+        self
+    }
+
+    /// Sort the remaining intervals by chromosome and start position and split them in chunks
+    /// of at most chunk_size consecutive intervals on the same chromosome,
+    /// such that the reads of a chunk are fetched with short forward seeks in the bam file
+    pub fn into_chunks(mut self, chunk_size: usize) -> Vec<Vec<RepeatInterval>> {
+        let mut data = self.data.split_off(self.current_index);
+        data.sort_by(|a, b| human_compare(&a.chrom, &b.chrom).then(a.start.cmp(&b.start)));
+        let mut chunks: Vec<Vec<RepeatInterval>> = vec![];
+        for repeat in data {
+            match chunks.last_mut() {
+                Some(chunk)
+                    if chunk.len() < chunk_size.max(1) && chunk[0].chrom == repeat.chrom =>
+                {
+                    chunk.push(repeat)
+                }
+                _ => chunks.push(vec![repeat]),
+            }
+        }
+        chunks
+    }
 }
 
 impl Clone for RepeatInterval {
@@ -74,6 +278,9 @@ impl Clone for RepeatInterval {
             chrom: self.chrom.clone(),
             start: self.start,
             end: self.end,
+            structure: self.structure.clone(),
+            annotation: self.annotation.clone(),
+            motif: self.motif.clone(),
         }
     }
 }
@@ -99,6 +306,58 @@ pub struct RepeatInterval {
     pub chrom: String,
     pub start: u32,
     pub end: u32,
+    // the structure of compound loci, from the STRUC field of the bed file
+    pub structure: Option<LocusStructure>,
+    // the locus identifier, gene and disease from the annotation columns of the bed file
+    pub annotation: Option<LocusAnnotation>,
+    // the motif of the repeat, from the MOTIFS or STRUC field of the bed file
+    pub motif: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocusAnnotation {
+    pub id: Option<String>,
+    pub gene: Option<String>,
+    pub disease: Option<String>,
+}
+
+impl LocusAnnotation {
+    /// Parse the columns after the end coordinate of a bed file
+    /// either as TRGT-style ID=..;GENE=..;DISEASE=.. in the fourth column,
+    /// or as the locus identifier, gene and disease in the fourth, fifth and sixth column
+    pub fn parse(columns: &[&str]) -> Option<LocusAnnotation> {
+        let value = |value: Option<&str>| {
+            value
+                .filter(|v| !v.is_empty() && *v != ".")
+                .map(|v| v.to_string())
+        };
+        let first = columns.first()?;
+        let annotation = if first.contains('=') {
+            let field = |key: &str| {
+                value(
+                    first
+                        .split(';')
+                        .find_map(|field| field.strip_prefix(key)?.strip_prefix('=')),
+                )
+            };
+            LocusAnnotation {
+                id: field("ID"),
+                gene: field("GENE"),
+                disease: field("DISEASE"),
+            }
+        } else {
+            LocusAnnotation {
+                id: value(Some(first)),
+                gene: value(columns.get(1).copied()),
+                disease: value(columns.get(2).copied()),
+            }
+        };
+        if annotation.id.is_none() && annotation.gene.is_none() && annotation.disease.is_none() {
+            None
+        } else {
+            Some(annotation)
+        }
+    }
 }
 
 impl fmt::Display for RepeatInterval {
@@ -117,7 +376,25 @@ impl RepeatInterval {
         let chrom = rec.chrom().to_string();
         let start = rec.start().try_into().unwrap();
         let end = rec.end().try_into().unwrap();
-        RepeatInterval::new_interval(chrom, start, end, fasta)
+        let mut repeat = RepeatInterval::new_interval(chrom, start, end, fasta)?;
+        // the fourth column can hold TRGT-style annotations such as ID=HTT;MOTIFS=CAG,CCG;STRUC=(CAG)nCAACAG(CCG)n
+        if let Some(name) = rec.name() {
+            repeat.structure = name
+                .split(';')
+                .find_map(|field| field.strip_prefix("STRUC="))
+                .and_then(LocusStructure::parse);
+            // the first motif of a compound locus is used for the length in repeat units
+            repeat.motif = name
+                .split(';')
+                .find_map(|field| field.strip_prefix("MOTIFS="))
+                .and_then(|motifs| motifs.split(',').next())
+                .filter(|motif| !motif.is_empty())
+                .map(|motif| motif.to_string())
+                .or_else(|| repeat.structure.as_ref().and_then(|s| s.first_motif()));
+        }
+        let columns = (3..).map_while(|i| rec.aux(i)).collect::<Vec<&str>>();
+        repeat.annotation = LocusAnnotation::parse(&columns);
+        Some(repeat)
     }
 
     fn new_interval(chrom: String, start: u32, end: u32, fasta: &str) -> Option<Self> {
@@ -125,37 +402,47 @@ impl RepeatInterval {
             panic!("End coordinate is smaller than start coordinate for {chrom}:{start}-{end}")
         }
 
-        let fai = format!("{}.fai", fasta);
-        // check if the chromosome exists in the fai file
+        // check if the chromosome exists in the fasta index, possibly in another naming convention,
         // and if the end coordinate is within the chromosome length
-        for line in std::fs::read_to_string(fai)
-            .expect("Failed to read fai file")
-            .lines()
-        {
-            if line.split('\t').collect::<Vec<&str>>()[0] == chrom
-                && line.split('\t').collect::<Vec<&str>>()[1]
-                    .parse::<u32>()
-                    .expect("Failed parsing chromosome length from fai file")
-                    > end
-            {
-                return Some(Self { chrom, start, end });
-            }
+        let chrom = reconcile_contig(&chrom, |name| contig_length(fasta, name).is_some())?;
+        if contig_length(fasta, &chrom).is_some_and(|length| length > end as u64) {
+            return Some(Self {
+                chrom,
+                start,
+                end,
+                structure: None,
+                annotation: None,
+                motif: None,
+            });
         }
-        // if the chromosome is not in the fai file or the end does not fit the interval, return None
-        panic!(
-            "Chromosome {chrom} is not in the fasta file or the end coordinate is out of bounds"
-        );
+        panic!("The end coordinate of {chrom}:{start}-{end} is beyond the end of the chromosome");
     }
     pub fn new(chrom: &str, start: u32, end: u32) -> Self {
         Self {
             chrom: chrom.to_string(),
             start,
             end,
+            structure: None,
+            annotation: None,
+            motif: None,
+        }
+    }
+
+    /// The interval extended by padding bases on either side, to collect the reads with insertions
+    /// placed just outside the interval
+    pub fn padded(&self, padding: u32) -> Self {
+        RepeatInterval {
+            start: self.start.saturating_sub(padding),
+            end: self.end + padding,
+            ..self.clone()
         }
     }
 
     pub fn make_repeat_compressed_sequence(&self, fasta: &String, flanking: u32) -> Vec<u8> {
-        let fas = faidx::Reader::from_path(fasta).expect("Failed to read fasta");
+        with_fasta_reader(fasta, |fas| self.repeat_compressed_sequence(fas, flanking))
+    }
+
+    fn repeat_compressed_sequence(&self, fas: &faidx::Reader, flanking: u32) -> Vec<u8> {
         let fas_left = fas
             .fetch_seq(
                 &self.chrom,
@@ -177,8 +464,32 @@ impl RepeatInterval {
         newref
     }
 
+    /// The reference sequence of <flanking> bases up and downstream of the repeat
+    pub fn flanking_sequences(&self, fasta: &str, flanking: u32) -> (String, String) {
+        with_fasta_reader(fasta, |fas| {
+            let left = fas
+                .fetch_seq_string(
+                    &self.chrom,
+                    self.start.saturating_sub(flanking + 1) as usize,
+                    self.start as usize - 2,
+                )
+                .expect("Failed to extract left flanking sequence from fasta");
+            let right = fas
+                .fetch_seq_string(
+                    &self.chrom,
+                    self.end as usize + 1,
+                    (self.end + flanking) as usize,
+                )
+                .expect("Failed to extract right flanking sequence from fasta");
+            (left, right)
+        })
+    }
+
     pub fn reference_repeat_sequence(&self, fasta: &String) -> Option<String> {
-        let fas = faidx::Reader::from_path(fasta).expect("Failed to read fasta");
+        with_fasta_reader(fasta, |fas| self.repeat_sequence(fas))
+    }
+
+    fn repeat_sequence(&self, fas: &faidx::Reader) -> Option<String> {
         let repeat_ref_sequence = std::str::from_utf8(
             fas.fetch_seq(&self.chrom, self.start as usize - 1, self.end as usize)
                 .expect("Failed to extract repeat sequence from fasta for {chrom}:{start}-{end}"),
@@ -188,7 +499,7 @@ impl RepeatInterval {
 
         // If the repeat sequence is out of bounds, None is returned
         if repeat_ref_sequence == "N" {
-            eprintln!(
+            warn!(
                 "Cannot genotype repeat at {self} because it is out of bounds for the fasta file",
             );
             return None;
@@ -197,6 +508,71 @@ impl RepeatInterval {
     }
 }
 
+/// Regions in which genotypes are less reliable, such as segmental duplications or low-complexity flanks
+/// Repeats overlapping these regions get the LOWCONF_FLANK filter
+pub struct LowConfidenceRegions {
+    regions: IntervalIndex<()>,
+}
+
+impl LowConfidenceRegions {
+    pub fn from_bed(bed_file: &str) -> Self {
+        let mut reader = bed::Reader::from_file(bed_file).expect("Problem reading bed file!");
+        let regions = reader.records().map(|record| {
+            let rec = record.expect("Error reading bed record.");
+            (rec.chrom().to_string(), rec.start(), rec.end(), ())
+        });
+        LowConfidenceRegions {
+            regions: IntervalIndex::new(regions),
+        }
+    }
+
+    pub fn overlaps(&self, repeat: &RepeatInterval) -> bool {
+        self.regions
+            .overlapping(&repeat.chrom, repeat.start.into(), repeat.end.into())
+            .next()
+            .is_some()
+    }
+}
+
+/// Regions with an expected number of alleles other than two, e.g. amplified regions in tumors
+/// from a bed file with the ploidy in the fourth column
+pub struct PloidyRegions {
+    regions: IntervalIndex<usize>,
+}
+
+impl PloidyRegions {
+    pub fn from_bed(bed_file: &str) -> Self {
+        let mut reader = bed::Reader::from_file(bed_file).expect("Problem reading bed file!");
+        let regions = reader.records().map(|record| {
+            let rec = record.expect("Error reading bed record.");
+            let ploidy = rec
+                .name()
+                .and_then(|name| name.parse::<usize>().ok())
+                .filter(|ploidy| *ploidy > 0)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Invalid ploidy in {bed_file} for {}:{}-{}, expected a positive integer in the fourth column",
+                        rec.chrom(),
+                        rec.start(),
+                        rec.end()
+                    )
+                });
+            (rec.chrom().to_string(), rec.start(), rec.end(), ploidy)
+        });
+        PloidyRegions {
+            regions: IntervalIndex::new(regions),
+        }
+    }
+
+    /// The ploidy of the overlapping region that starts first, or is first in the bed file for the same start
+    pub fn ploidy(&self, repeat: &RepeatInterval) -> Option<usize> {
+        self.regions
+            .overlapping(&repeat.chrom, repeat.start.into(), repeat.end.into())
+            .next()
+            .map(|(_, _, ploidy)| *ploidy)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,6 +595,20 @@ mod tests {
         let _ = RepeatIntervalIterator::from_string("chr7:1154654404-1154654432", &fasta);
     }
 
+    // the contig is called chr7 in the fasta file
+    #[test]
+    fn test_reconcile_contig() {
+        let fasta = String::from("test_data/chr7.fa.gz");
+        let mut repeat = RepeatIntervalIterator::from_string("7:154654404-154654432", &fasta);
+        assert_eq!(repeat.contig_loci(), BTreeMap::from([("chr7".to_string(), 1)]));
+        assert_eq!(repeat.next().unwrap().chrom, "chr7");
+        let has_contig = |name: &str| ["1", "MT", "chrX"].contains(&name);
+        assert_eq!(reconcile_contig("chr1", has_contig), Some("1".to_string()));
+        assert_eq!(reconcile_contig("chrM", has_contig), Some("MT".to_string()));
+        assert_eq!(reconcile_contig("X", has_contig), Some("chrX".to_string()));
+        assert_eq!(reconcile_contig("chr2", has_contig), None);
+    }
+
     // capture the case where the newref extended by <flanking> bases is out of bounds (<0) for the fasta file
     // and thus a shorter sequence is returned
     #[test]
@@ -254,6 +644,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_merge_intervals() {
+        let iterator = |intervals: &[(&str, u32, u32)]| RepeatIntervalIterator {
+            current_index: 0,
+            data: intervals
+                .iter()
+                .map(|(chrom, start, end)| RepeatInterval::new(chrom, *start, *end))
+                .collect(),
+            num_intervals: intervals.len(),
+        };
+        let intervals = [
+            ("chr7", 200, 250),
+            ("chr7", 100, 150),
+            ("chr7", 100, 150),
+            ("chr7", 140, 160),
+            ("chr8", 100, 150),
+        ];
+        // without a gap, only identical intervals are removed
+        let deduplicated = iterator(&intervals).merge_intervals(None);
+        assert_eq!(deduplicated.num_intervals, 4);
+        assert_eq!(deduplicated.data[0].start, 200);
+        let merged = iterator(&intervals)
+            .merge_intervals(Some(0))
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(merged, ["chr7:100-160", "chr7:200-250", "chr8:100-150"]);
+        let merged = iterator(&intervals).merge_intervals(Some(50));
+        assert_eq!(merged.num_intervals, 2);
+        // a gap beyond the end of the chromosome merges all intervals of a chromosome
+        let merged = iterator(&intervals).merge_intervals(Some(u32::MAX));
+        assert_eq!(merged.num_intervals, 2);
+        let chunks = iterator(&intervals)
+            .into_chunks(2)
+            .iter()
+            .map(|chunk| chunk.iter().map(|r| r.to_string()).collect::<Vec<String>>())
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(
+            chunks,
+            [
+                vec!["chr7:100-150", "chr7:100-150"],
+                vec!["chr7:140-160", "chr7:200-250"],
+                vec!["chr8:100-150"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_low_confidence_regions_overlap() {
+        let lowconf = LowConfidenceRegions {
+            regions: IntervalIndex::new([("chr7".to_string(), 154654000, 154654410, ())]),
+        };
+        assert!(lowconf.overlaps(&RepeatInterval::new("chr7", 154654404, 154654432)));
+        assert!(!lowconf.overlaps(&RepeatInterval::new("chr7", 154654410, 154654432)));
+        assert!(!lowconf.overlaps(&RepeatInterval::new("chr1", 154654404, 154654432)));
+    }
+
     // this test is ignored as it uses a file outside the test_data directory
     #[test]
     #[ignore]
@@ -261,4 +707,32 @@ mod tests {
         let fasta = String::from("/home/wdecoster/reference/GRCh38.fa");
         let _ = crate::repeats::RepeatIntervalIterator::pathogenic(&fasta);
     }
+
+    #[test]
+    fn test_ploidy_regions() {
+        let ploidy = PloidyRegions {
+            regions: IntervalIndex::new([
+                ("chr7".to_string(), 154654000, 154655000, 4),
+                ("chr7".to_string(), 154654420, 154660000, 3),
+            ]),
+        };
+        assert_eq!(ploidy.ploidy(&RepeatInterval::new("chr7", 154655500, 154655600)), Some(3));
+        assert_eq!(ploidy.ploidy(&RepeatInterval::new("chr7", 154654404, 154654432)), Some(4));
+        assert_eq!(ploidy.ploidy(&RepeatInterval::new("chr1", 154654404, 154654432)), None);
+    }
+
+    #[test]
+    fn test_locus_annotation() {
+        let trgt =
+            LocusAnnotation::parse(&["ID=HTT;GENE=HTT;DISEASE=Huntington disease;MOTIFS=CAG"])
+                .expect("Failed parsing annotation");
+        assert_eq!(trgt.id.as_deref(), Some("HTT"));
+        assert_eq!(trgt.disease.as_deref(), Some("Huntington disease"));
+        let columns =
+            LocusAnnotation::parse(&["FMR1_CGG", "FMR1", "."]).expect("Failed parsing annotation");
+        assert_eq!(columns.gene.as_deref(), Some("FMR1"));
+        assert_eq!(columns.disease, None);
+        assert_eq!(LocusAnnotation::parse(&["MOTIFS=CAG"]), None);
+        assert_eq!(LocusAnnotation::parse(&[]), None);
+    }
 }
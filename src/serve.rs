@@ -0,0 +1,379 @@
+use crate::repeats::{LowConfidenceRegions, RepeatInterval, RepeatIntervalIterator};
+use crate::utils::{Anonymizer, IntervalIndex};
+use crate::{call, genotype, parse_bam, Cli, OutputFormat};
+use log::{error, info, warn};
+use rust_htslib::bam;
+use rust_htslib::bam::Read as BamRead;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, SystemTime};
+
+// a client that does not send its request in time does not block the server
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// the maximal size of the request line and headers
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Genotype loci on demand over HTTP, keeping the bam and fasta readers open between requests
+/// GET /genotype?region=chr4:3074876-3074939 returns the genotype as JSON, as with --format json
+/// GET /health returns {"status":"ok"} once the readers are open
+/// With --catalog, the catalog loci in the region are genotyped, and the catalog is reloaded when it changes
+/// Requests are handled one at a time
+/// Options that select the loci or write other outputs than the JSON records are not supported
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// reference genome used for alignment
+    #[clap(long, value_parser)]
+    fasta: String,
+
+    /// bam/cram file to genotype (local path or URL), requires an index
+    #[clap(long, value_parser)]
+    bam: String,
+
+    /// address to listen on
+    #[clap(long, value_parser, default_value = "127.0.0.1")]
+    host: String,
+
+    /// port to listen on
+    #[clap(long, value_parser, default_value_t = 8080)]
+    port: u16,
+
+    /// options of the call subcommand used for every locus, e.g. --options="--unphased --support 5"
+    #[clap(long, value_parser, default_value = "")]
+    options: String,
+
+    /// bed file of loci to genotype in the requested regions, reloaded when the file changes
+    #[clap(long, value_parser)]
+    catalog: Option<String>,
+}
+
+pub fn run(args: ServeArgs) {
+    let options = args.options.split_whitespace().collect::<Vec<&str>>();
+    let mut cli =
+        Cli::from_args(&args.fasta, &args.bam, &options).unwrap_or_else(|err| err.exit());
+    let unsupported = unsupported_options(&cli);
+    if !unsupported.is_empty() {
+        error!("Options not supported by serve: {}", unsupported.join(", "));
+        std::process::exit(1);
+    }
+    cli.format = OutputFormat::Json;
+    if !parse_bam::has_index(&args.bam) {
+        panic!("No index found for {}, index the bam file to serve it", args.bam);
+    }
+    crate::repeats::set_contig_reconciliation(!cli.no_contig_reconciliation);
+    let catalog = args.catalog.as_ref().map(|path| {
+        let catalog = Catalog::load(path, &args.fasta).unwrap_or_else(|err| panic!("{err}"));
+        info!("Loaded catalog {path} version {}", catalog.version);
+        catalog
+    });
+    let mut state = ServeState {
+        inputs: genotype::RunInputs::from_args(&cli),
+        lowconf: cli.lowconf_regions.as_ref().map(|bed| LowConfidenceRegions::from_bed(bed)),
+        // the same key is used for all requests, such that a read group keeps its hash
+        anonymizer: cli.anonymizer(),
+        cli,
+        catalog,
+    };
+    let mut reader = parse_bam::create_bam_reader(&args.bam, &args.fasta);
+    let listener = TcpListener::bind((args.host.as_str(), args.port))
+        .unwrap_or_else(|err| panic!("Failed listening on {}:{}: {err}", args.host, args.port));
+    info!("Genotyping {} on http://{}:{}/genotype", args.bam, args.host, args.port);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => handle_connection(&mut stream, &mut state, &mut reader),
+            Err(err) => warn!("Failed accepting a connection: {err}"),
+        }
+    }
+}
+
+// the options and inputs used for every locus, loaded once at startup
+struct ServeState {
+    cli: Cli,
+    inputs: genotype::RunInputs,
+    lowconf: Option<LowConfidenceRegions>,
+    anonymizer: Option<Anonymizer>,
+    catalog: Option<Catalog>,
+}
+
+// the loci of --catalog, reloaded when the file is modified
+struct Catalog {
+    path: String,
+    modified: Option<SystemTime>,
+    // the first 16 hexadecimal digits of the SHA-256 of the file, reported with every response
+    version: String,
+    loci: IntervalIndex<RepeatInterval>,
+}
+
+impl Catalog {
+    fn load(path: &str, fasta: &str) -> Result<Catalog, String> {
+        let modified = std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let contents =
+            std::fs::read(path).map_err(|err| format!("Failed reading catalog {path}: {err}"))?;
+        let version = Sha256::digest(&contents)[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        // an invalid catalog, e.g. one that is being written, is reported rather than stopping the server
+        let loci = std::panic::catch_unwind(|| {
+            IntervalIndex::new(
+                RepeatIntervalIterator::from_bed(&path.to_string(), fasta).map(|locus| {
+                    (locus.chrom.clone(), locus.start.into(), locus.end.into(), locus)
+                }),
+            )
+        })
+        .map_err(|_| format!("Failed loading catalog {path}"))?;
+        Ok(Catalog {
+            path: path.to_string(),
+            modified,
+            version,
+            loci,
+        })
+    }
+
+    // the current loci are kept if the modified catalog can not be loaded
+    fn reload_if_modified(&mut self, fasta: &str) {
+        let modified = std::fs::metadata(&self.path).and_then(|meta| meta.modified()).ok();
+        if modified == self.modified {
+            return;
+        }
+        match Catalog::load(&self.path, fasta) {
+            Ok(catalog) => {
+                info!("Reloaded catalog {} version {}", self.path, catalog.version);
+                *self = catalog;
+            }
+            Err(err) => warn!("{err}, keeping catalog version {}", self.version),
+        }
+    }
+
+    // the loci of the catalog that overlap a region, sorted by start
+    fn overlapping(&self, region: &RepeatInterval) -> Vec<RepeatInterval> {
+        self.loci
+            .overlapping(&region.chrom, region.start.into(), region.end.into())
+            .map(|(_, _, locus)| locus.clone())
+            .collect()
+    }
+}
+
+// the options that select the loci or write files next to the records
+fn unsupported_options(cli: &Cli) -> Vec<&'static str> {
+    [
+        ("--region", cli.region.is_some()),
+        ("--region-file", cli.region_file.is_some()),
+        ("--pathogenic", cli.pathogenic),
+        ("--checkpoint", cli.checkpoint.is_some()),
+        ("--allele-registry", cli.allele_registry.is_some()),
+        ("--consensus-fasta", cli.consensus_fasta.is_some()),
+        ("--gfa", cli.gfa.is_some()),
+        ("--plot", cli.plot.is_some()),
+        ("--dotplot", cli.dotplot.is_some()),
+        ("--stats", cli.stats.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, given)| *given)
+    .map(|(option, _)| option)
+    .collect()
+}
+
+fn handle_connection(
+    stream: &mut TcpStream,
+    state: &mut ServeState,
+    reader: &mut bam::IndexedReader,
+) {
+    if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        warn!("Failed setting the read timeout: {err}");
+        return;
+    }
+    let connection = stream.try_clone().expect("Failed cloning the connection");
+    // a request line that does not fit is cut off, and rejected as a bad request
+    let mut lines = BufReader::new(connection.take(MAX_REQUEST_BYTES)).lines();
+    let request = lines.next().and_then(|line| line.ok()).unwrap_or_default();
+    // the headers are not used, but are read such that the client gets a response
+    for line in lines.by_ref() {
+        if line.map_or(true, |line| line.is_empty()) {
+            break;
+        }
+    }
+    if let Some(catalog) = &mut state.catalog {
+        catalog.reload_if_modified(&state.cli.fasta);
+    }
+    let state = &*state;
+    let (status, body) = match parse_request(&request) {
+        Some((path, _)) if path == "/health" => {
+            let mut health = json!({"status": "ok"});
+            if let Some(catalog) = &state.catalog {
+                health["catalog_version"] = json!(catalog.version);
+                health["catalog_loci"] = json!(catalog.loci.len());
+            }
+            ("200 OK", health.to_string())
+        }
+        Some((path, params)) if path == "/genotype" => match params.get("region") {
+            Some(region) => {
+                info!("Genotyping {region}");
+                // a failure in a single locus does not stop the server
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    genotype_region(region, state, reader)
+                }));
+                match result {
+                    Ok(Ok(records)) => ("200 OK", records),
+                    Ok(Err(err)) => ("500 Internal Server Error", error(&err)),
+                    Err(_) => {
+                        let message = format!("Failed genotyping {region}");
+                        ("500 Internal Server Error", error(&message))
+                    }
+                }
+            }
+            None => ("400 Bad Request", error("Missing region parameter")),
+        },
+        Some(_) => ("404 Not Found", error("Unknown path, use /genotype?region=chr:start-end")),
+        None => ("400 Bad Request", error("Only GET requests are supported")),
+    };
+    // the version of the catalog with which the response was genotyped
+    let version = state.catalog.as_ref().map_or(String::new(), |catalog| {
+        format!("X-Catalog-Version: {}\r\n", catalog.version)
+    });
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{version}Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        warn!("Failed writing the response: {err}");
+    }
+}
+
+fn error(message: &str) -> String {
+    json!({ "error": message }).to_string()
+}
+
+// the JSON of the genotyped region, one object per line as with --format json
+// the records are finished as in the call subcommand, e.g. with --normalize or --split-nested
+fn genotype_region(
+    region: &str,
+    state: &ServeState,
+    reader: &mut bam::IndexedReader,
+) -> Result<String, String> {
+    let cli = &state.cli;
+    let mut repeats = RepeatIntervalIterator::from_string(region, &cli.fasta);
+    if let Some(catalog) = &state.catalog {
+        let region = repeats.next().expect("A region string has a single interval");
+        repeats = RepeatIntervalIterator::from_intervals(catalog.overlapping(&region));
+    }
+    parse_bam::contigs_in_header(reader.header(), &repeats)
+        .map_err(|missing| format!("Locus on a contig that is not in the bam header: {missing}"))?;
+    let mut records = vec![];
+    for repeat in repeats {
+        let mut record =
+            genotype::genotype_repeat_singlethreaded(&repeat, cli, reader, &state.inputs)?;
+        let line = call::finish_record(
+            &mut record,
+            &repeat,
+            cli,
+            &state.lowconf,
+            &None,
+            &None,
+            &None,
+            &state.anonymizer,
+        );
+        records.push(line);
+    }
+    Ok(records.join("\n"))
+}
+
+// the path and the decoded query parameters of a GET request line, e.g. GET /genotype?region=chr1%3A100-200 HTTP/1.1
+fn parse_request(line: &str) -> Option<(String, HashMap<String, String>)> {
+    let mut fields = line.split_whitespace();
+    if fields.next()? != "GET" {
+        return None;
+    }
+    let target = fields.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect();
+    Some((path.to_string(), params))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let (path, params) = parse_request("GET /genotype?region=chr4%3A3074876-3074939 HTTP/1.1")
+            .expect("Failed parsing request");
+        assert_eq!(path, "/genotype");
+        assert_eq!(params["region"], "chr4:3074876-3074939");
+        let (path, params) = parse_request("GET /health HTTP/1.1").expect("Failed parsing request");
+        assert_eq!(path, "/health");
+        assert!(params.is_empty());
+        assert!(parse_request("POST /genotype HTTP/1.1").is_none());
+        assert_eq!(percent_decode("a+b%2"), "a b%2");
+    }
+
+    #[test]
+    fn test_catalog_reload() {
+        let fasta = "test_data/chr7.fa.gz";
+        let path = crate::test_utils::temp_path("catalog.bed");
+        std::fs::write(&path, "chr7\t154654404\t154654432\n").expect("Failed writing catalog");
+        let mut catalog = Catalog::load(&path, fasta).expect("Failed loading catalog");
+        assert_eq!(catalog.version.len(), 16);
+        let region = RepeatInterval::new("chr7", 154654000, 154655000);
+        assert_eq!(catalog.overlapping(&region).len(), 1);
+        // a modified catalog is reloaded with another version
+        let version = catalog.version.clone();
+        std::fs::write(&path, "chr7\t154654404\t154654432\nchr7\t154654500\t154654530\n")
+            .expect("Failed writing catalog");
+        catalog.modified = None;
+        catalog.reload_if_modified(fasta);
+        assert_ne!(catalog.version, version);
+        assert_eq!(catalog.overlapping(&region).len(), 2);
+        // an invalid catalog keeps the loci of the previous version
+        std::fs::write(&path, "chrUnknown\t100\t200\n").expect("Failed writing catalog");
+        catalog.modified = None;
+        catalog.reload_if_modified(fasta);
+        assert_eq!(catalog.overlapping(&region).len(), 2);
+        std::fs::remove_file(&path).expect("Failed removing catalog");
+    }
+
+    #[test]
+    fn test_unsupported_options() {
+        let bam = "test_data/small-test-phased.bam";
+        let cli = Cli::from_args("test_data/chr7.fa.gz", bam, &["--normalize", "--allele-ids"])
+            .expect("Invalid options");
+        assert!(unsupported_options(&cli).is_empty());
+        let cli = Cli::from_args("test_data/chr7.fa.gz", bam, &["--gfa", "gfa", "--stats", "s"])
+            .expect("Invalid options");
+        assert_eq!(unsupported_options(&cli), vec!["--gfa", "--stats"]);
+    }
+}
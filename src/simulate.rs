@@ -0,0 +1,134 @@
+//! Synthetic long reads around a repeat, to test the accuracy of genotyping across allele sizes
+//! The reads consist of the reference flanks of the repeat with a chosen allele in between,
+//! with substitutions, insertions and deletions at a chosen error rate
+use crate::genotype::{genotype_repeat_from_reads, RunInputs};
+use crate::parse_bam::Reads;
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use crate::Cli;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+// the length of the reference flanks on either side of the repeat in every read
+const READ_FLANK: u32 = 2000;
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// The reads to simulate per allele, the error rate per base, and the seed to make the reads reproducible
+pub struct Simulation {
+    pub reads_per_allele: usize,
+    pub error_rate: f64,
+    pub seed: u64,
+}
+
+/// Substitutions, insertions and deletions in equal parts, at error_rate per base
+pub fn add_errors(seq: &[u8], error_rate: f64, rng: &mut StdRng) -> Vec<u8> {
+    let mut read = Vec::with_capacity(seq.len());
+    for base in seq {
+        if rng.gen::<f64>() >= error_rate {
+            read.push(*base);
+            continue;
+        }
+        match rng.gen_range(0..3) {
+            0 => read.push(BASES[rng.gen_range(0..4)]),
+            1 => read.extend([*base, BASES[rng.gen_range(0..4)]]),
+            _ => (),
+        }
+    }
+    read
+}
+
+/// Reads of two alleles of a repeat, in phase 1 and 2 if phased or all in phase 0
+pub fn simulate_reads(
+    repeat: &RepeatInterval,
+    fasta: &str,
+    alleles: [&str; 2],
+    simulation: &Simulation,
+    phased: bool,
+) -> Reads {
+    let (left, right) = repeat.flanking_sequences(fasta, READ_FLANK);
+    let mut rng = StdRng::seed_from_u64(simulation.seed);
+    let mut seqs: HashMap<u8, Vec<Vec<u8>>> = HashMap::new();
+    for (index, allele) in alleles.iter().enumerate() {
+        let haplotype = format!("{left}{allele}{right}").to_uppercase();
+        let phase = if phased { index as u8 + 1 } else { 0 };
+        for _ in 0..simulation.reads_per_allele {
+            let read = add_errors(haplotype.as_bytes(), simulation.error_rate, &mut rng);
+            seqs.entry(phase).or_default().push(read);
+        }
+    }
+    Reads::from_sequences(seqs)
+}
+
+/// Genotype the simulated reads of two alleles with the options of the call subcommand
+pub fn genotype_simulated(
+    repeat: &RepeatInterval,
+    alleles: [&str; 2],
+    simulation: &Simulation,
+    options: &[&str],
+) -> VCFRecord {
+    let fasta = "test_data/chr7.fa.gz";
+    let args = Cli::from_args(fasta, "test_data/small-test-phased.bam", options)
+        .expect("Invalid options");
+    let reads = simulate_reads(repeat, fasta, alleles, simulation, !args.unphased);
+    genotype_repeat_from_reads(repeat, &args, Some(reads), &RunInputs::default())
+        .expect("Failed genotyping simulated reads")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the called full lengths, sorted
+    fn full_lengths(record: &VCFRecord) -> Vec<i64> {
+        let mut lengths = [&record.full_length.0, &record.full_length.1]
+            .iter()
+            .map(|length| length.parse().expect("Missing allele length"))
+            .collect::<Vec<i64>>();
+        lengths.sort_unstable();
+        lengths
+    }
+
+    #[test]
+    fn test_add_errors() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let seq = "CAG".repeat(100).into_bytes();
+        assert_eq!(add_errors(&seq, 0.0, &mut rng), seq);
+        let read = add_errors(&seq, 0.1, &mut rng);
+        assert_ne!(read, seq);
+        assert!(read.len().abs_diff(seq.len()) < 30);
+    }
+
+    #[test]
+    fn test_simulated_allele_sizes() {
+        let repeat = RepeatInterval::new("chr7", 154654404, 154654432);
+        let short = "CAG".repeat(10);
+        for copies in [5, 20, 60, 150] {
+            let long = "CAG".repeat(copies);
+            for (error_rate, options) in [
+                (0.0, vec![]),
+                (0.02, vec![]),
+                (0.02, vec!["--unphased"]),
+            ] {
+                let simulation = Simulation {
+                    reads_per_allele: 10,
+                    error_rate,
+                    seed: copies as u64,
+                };
+                let record = genotype_simulated(&repeat, [&short, &long], &simulation, &options);
+                let mut expected = vec![short.len() as i64, long.len() as i64];
+                expected.sort_unstable();
+                // the consensus of reads with errors can be a few bases off
+                let tolerance = 3 + (expected[1] as f64 * error_rate) as i64;
+                let called = full_lengths(&record);
+                assert!(
+                    called
+                        .iter()
+                        .zip(&expected)
+                        .all(|(called, expected)| (called - expected).abs() <= tolerance),
+                    "{copies} copies with error rate {error_rate} {options:?}: called {called:?}, expected {expected:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::locus_log::{log_locus, Stage};
+use log::Level;
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use std::collections::HashMap;
+
+// minimal number of reads covering a position to consider it as a heterozygous SNV
+const MIN_SNV_DEPTH: u32 = 6;
+// minimal fraction of reads with the minor allele
+const MIN_MINOR_FRACTION: f32 = 0.25;
+// minimal fraction of reads with one of the two major alleles, to avoid noisy positions
+const MIN_BIALLELIC_FRACTION: f32 = 0.8;
+const MAX_ITERATIONS: usize = 10;
+
+/// Split the reads of an unphased locus in two haplotypes using heterozygous SNVs in the flanking sequence
+/// returns the indices of the reads assigned to each haplotype, or None if no informative SNVs are found
+/// reads that do not overlap an informative SNV are not assigned to a haplotype
+pub fn phase_reads(
+    records: &[bam::Record],
+    repeat: &crate::repeats::RepeatInterval,
+    flank: i64,
+) -> Option<(Vec<usize>, Vec<usize>)> {
+    let start = repeat.start as i64;
+    let end = repeat.end as i64;
+    let in_flank =
+        |pos: i64| (pos >= start - flank && pos < start) || (pos > end && pos <= end + flank);
+
+    // first pass: count the nucleotides per position in the flanking sequence
+    let mut counts: HashMap<i64, [u32; 4]> = HashMap::new();
+    for record in records {
+        // with --timeout, the locus is abandoned and the reads are not phased
+        if crate::genotype::deadline_passed() {
+            return None;
+        }
+        let seq = record.seq().as_bytes();
+        for [qpos, rpos] in record.aligned_pairs() {
+            if in_flank(rpos) {
+                if let Some(base) = base_index(seq[qpos as usize]) {
+                    counts.entry(rpos).or_insert([0; 4])[base] += 1;
+                }
+            }
+        }
+    }
+
+    // identify heterozygous sites, as the positions with two frequent alleles
+    let mut sites = HashMap::new();
+    for (pos, count) in counts.iter() {
+        let depth = count.iter().sum::<u32>();
+        if depth < MIN_SNV_DEPTH {
+            continue;
+        }
+        let mut ranked = [0, 1, 2, 3];
+        ranked.sort_by_key(|b| std::cmp::Reverse(count[*b]));
+        let (major, minor) = (ranked[0], ranked[1]);
+        if count[minor] as f32 / depth as f32 >= MIN_MINOR_FRACTION
+            && (count[major] + count[minor]) as f32 / depth as f32 >= MIN_BIALLELIC_FRACTION
+        {
+            sites.insert(*pos, (major, minor));
+        }
+    }
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!("Found {} heterozygous SNVs in the flanks", sites.len()),
+    );
+    if sites.is_empty() {
+        return None;
+    }
+    let mut positions = sites.keys().copied().collect::<Vec<i64>>();
+    positions.sort_unstable();
+    let site_index = positions
+        .iter()
+        .enumerate()
+        .map(|(i, pos)| (*pos, i))
+        .collect::<HashMap<i64, usize>>();
+
+    // second pass: per read, the allele at every heterozygous site
+    // encoded as 1 for the major and -1 for the minor allele, 0 if not covered or another allele
+    let mut alleles = vec![vec![0i8; positions.len()]; records.len()];
+    for (read, record) in records.iter().enumerate() {
+        let seq = record.seq().as_bytes();
+        for [qpos, rpos] in record.aligned_pairs() {
+            if let (Some(site), Some((major, minor))) =
+                (site_index.get(&rpos), sites.get(&rpos))
+            {
+                match base_index(seq[qpos as usize]) {
+                    Some(base) if base == *major => alleles[read][*site] = 1,
+                    Some(base) if base == *minor => alleles[read][*site] = -1,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // the haplotype is initialized with the read that covers most heterozygous sites
+    let seed = alleles
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, a)| a.iter().filter(|x| **x != 0).count())
+        .map(|(i, _)| i)?;
+    let mut haplotype = alleles[seed]
+        .iter()
+        .map(|x| if *x == 0 { 1 } else { *x })
+        .collect::<Vec<i8>>();
+
+    // iteratively assign reads to the haplotype or its complement, and update the haplotype
+    let mut assignment = vec![0i32; records.len()];
+    for _ in 0..MAX_ITERATIONS {
+        if crate::genotype::deadline_passed() {
+            return None;
+        }
+        let new_assignment = alleles
+            .iter()
+            .map(|a| {
+                a.iter()
+                    .zip(haplotype.iter())
+                    .map(|(x, h)| (*x as i32) * (*h as i32))
+                    .sum::<i32>()
+                    .signum()
+            })
+            .collect::<Vec<i32>>();
+        if new_assignment == assignment {
+            break;
+        }
+        assignment = new_assignment;
+        for (site, h) in haplotype.iter_mut().enumerate() {
+            // reads of the complement haplotype vote with the opposite sign
+            let vote = alleles
+                .iter()
+                .zip(assignment.iter())
+                .map(|(a, s)| (a[site] as i32) * s)
+                .sum::<i32>();
+            if vote != 0 {
+                *h = vote.signum() as i8;
+            }
+        }
+    }
+    let hap1 = (0..records.len())
+        .filter(|i| assignment[*i] > 0)
+        .collect::<Vec<usize>>();
+    let hap2 = (0..records.len())
+        .filter(|i| assignment[*i] < 0)
+        .collect::<Vec<usize>>();
+    log_locus(
+        Level::Debug,
+        repeat,
+        Stage::Phasing,
+        format_args!(
+            "SNV phasing assigned {} and {} of {} reads",
+            hap1.len(),
+            hap2.len(),
+            records.len()
+        ),
+    );
+    if hap1.is_empty() || hap2.is_empty() {
+        None
+    } else {
+        Some((hap1, hap2))
+    }
+}
+
+fn base_index(base: u8) -> Option<usize> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    fn make_record(seq: &[u8]) -> bam::Record {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(seq.len() as u32)]);
+        record.set(b"read", Some(&cigar), seq, &vec![30; seq.len()]);
+        record.set_pos(0);
+        record
+    }
+
+    #[test]
+    fn test_phase_reads() {
+        // two heterozygous SNVs, one in each flank, with even reads carrying C and odd reads G
+        let mut records = vec![];
+        for read in 0..8 {
+            let mut seq = vec![b'A'; 200];
+            let allele = if read % 2 == 0 { b'C' } else { b'G' };
+            seq[50] = allele;
+            seq[150] = allele;
+            records.push(make_record(&seq));
+        }
+        let repeat = crate::repeats::RepeatInterval::new("chr1", 100, 110);
+        let (hap1, hap2) = phase_reads(&records, &repeat, 60).expect("Failed phasing reads");
+        assert_eq!(hap1.len(), 4);
+        assert_eq!(hap2.len(), 4);
+        assert!(hap1.iter().all(|i| i % 2 == hap1[0] % 2));
+    }
+
+    #[test]
+    fn test_phase_reads_homozygous() {
+        let records = (0..8)
+            .map(|_| make_record(&[b'A'; 200]))
+            .collect::<Vec<bam::Record>>();
+        let repeat = crate::repeats::RepeatInterval::new("chr1", 100, 110);
+        assert!(phase_reads(&records, &repeat, 60).is_none());
+    }
+}
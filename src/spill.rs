@@ -0,0 +1,291 @@
+use human_sort::compare as human_compare;
+use log::debug;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Mutex;
+
+// the memory used per record besides the formatted line, roughly
+const RECORD_OVERHEAD: usize = 64;
+// the number of temporary files that are kept before they are merged into a single file
+const MAX_SPILLED_FILES: usize = 64;
+// the number of temporary files created by this process, for unique file names
+static SPILLED_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Formatted records collected in arbitrary order, e.g. from multiple threads, and written sorted by chrom and start
+/// With max_bytes, the records are sorted and spilled to a temporary file once they take more memory,
+/// after which the spilled files are merged when writing
+/// Records are spilled without blocking other threads from pushing, and the temporary files are removed when dropped
+pub struct SortedRecords {
+    max_bytes: Option<usize>,
+    // the records in memory and the memory they take
+    records: Mutex<(Vec<(String, u32, String)>, usize)>,
+    spilled: Mutex<Vec<SpillFile>>,
+}
+
+// a temporary file of sorted records, a line per record line as chrom\tstart\tline, removed when dropped
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            debug!("Failed removing temporary file {}: {err}", self.path.display());
+        }
+    }
+}
+
+// the next record of a spilled file, ordered such that the binary heap returns the smallest chrom and start first
+struct Head {
+    chrom: String,
+    start: u32,
+    line: String,
+    run: usize,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        human_compare(&other.chrom, &self.chrom)
+            .then(other.start.cmp(&self.start))
+            .then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl SortedRecords {
+    pub fn new(max_bytes: Option<usize>) -> SortedRecords {
+        SortedRecords {
+            max_bytes,
+            records: Mutex::new((vec![], 0)),
+            spilled: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn push(&self, chrom: String, start: u32, line: String) {
+        let full = {
+            let mut records = self.records.lock().expect("Unable to lock records mutex");
+            records.1 += chrom.len() + line.len() + RECORD_OVERHEAD;
+            records.0.push((chrom, start, line));
+            if self.max_bytes.is_some_and(|max| records.1 > max) {
+                records.1 = 0;
+                Some(std::mem::take(&mut records.0))
+            } else {
+                None
+            }
+        };
+        // the records are sorted and written after releasing the lock
+        if let Some(records) = full {
+            self.spill(records);
+        }
+    }
+
+    fn spill(&self, mut records: Vec<(String, u32, String)>) {
+        sort(&mut records);
+        debug!("Spilling {} records", records.len());
+        let file = write_spill_file(record_lines(records));
+        let merge = {
+            let mut spilled = self.spilled.lock().expect("Unable to lock spilled mutex");
+            spilled.push(file);
+            if spilled.len() > MAX_SPILLED_FILES {
+                Some(std::mem::take(&mut *spilled))
+            } else {
+                None
+            }
+        };
+        // too many files are merged into one, such that the number of open files stays bounded
+        if let Some(files) = merge {
+            debug!("Merging {} temporary files", files.len());
+            let merged = write_spill_file(Merge::new(&files));
+            self.spilled
+                .lock()
+                .expect("Unable to lock spilled mutex")
+                .push(merged);
+        }
+    }
+
+    /// Write all records sorted by chrom and start, merging the spilled files if any
+    pub fn write_sorted(self, out: &mut impl Write) {
+        let (mut records, _) = self.records.into_inner().expect("Unable to lock records mutex");
+        let mut spilled = self.spilled.into_inner().expect("Unable to lock spilled mutex");
+        sort(&mut records);
+        if spilled.is_empty() {
+            for (_, _, line) in &records {
+                writeln!(out, "{line}").expect("Failed writing the result.");
+            }
+            return;
+        }
+        if !records.is_empty() {
+            spilled.push(write_spill_file(record_lines(records)));
+        }
+        for (_, _, line) in Merge::new(&spilled) {
+            writeln!(out, "{line}").expect("Failed writing the result.");
+        }
+    }
+}
+
+// a locus reported as multiple records has multiple lines, which are spilled as separate lines
+fn record_lines(
+    records: Vec<(String, u32, String)>,
+) -> impl Iterator<Item = (String, u32, String)> {
+    records.into_iter().flat_map(|(chrom, start, lines)| {
+        lines
+            .lines()
+            .map(|line| (chrom.clone(), start, line.to_string()))
+            .collect::<Vec<_>>()
+    })
+}
+
+fn sort(records: &mut [(String, u32, String)]) {
+    records.sort_unstable_by(|a, b| human_compare(&a.0, &b.0).then(a.1.cmp(&b.1)));
+}
+
+// write sorted records to a new temporary file
+fn write_spill_file(records: impl IntoIterator<Item = (String, u32, String)>) -> SpillFile {
+    let path = std::env::temp_dir().join(format!(
+        "strdust-{}-{}.tsv",
+        std::process::id(),
+        SPILLED_FILES.fetch_add(1, atomic::Ordering::Relaxed)
+    ));
+    // the guard removes the file if writing fails
+    let spill_file = SpillFile { path };
+    let file = File::create(&spill_file.path).unwrap_or_else(|err| {
+        panic!("Failed creating temporary file {}: {err}", spill_file.path.display())
+    });
+    let mut writer = BufWriter::new(file);
+    for (chrom, start, line) in records {
+        writeln!(writer, "{chrom}\t{start}\t{line}")
+            .unwrap_or_else(|err| panic!("Failed writing temporary file: {err}"));
+    }
+    writer
+        .flush()
+        .unwrap_or_else(|err| panic!("Failed writing temporary file: {err}"));
+    spill_file
+}
+
+// the sorted records of spilled files, merged with a binary heap of the next record of every file
+struct Merge {
+    runs: Vec<Lines<BufReader<File>>>,
+    heap: BinaryHeap<Head>,
+}
+
+impl Merge {
+    fn new(files: &[SpillFile]) -> Merge {
+        let mut runs = files
+            .iter()
+            .map(|file| {
+                let handle = File::open(&file.path).unwrap_or_else(|err| {
+                    panic!("Failed opening temporary file {}: {err}", file.path.display())
+                });
+                BufReader::new(handle).lines()
+            })
+            .collect::<Vec<_>>();
+        let heap = runs
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(run, lines)| next_entry(lines, run))
+            .collect();
+        Merge { runs, heap }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = (String, u32, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let head = self.heap.pop()?;
+        if let Some(next) = next_entry(&mut self.runs[head.run], head.run) {
+            self.heap.push(next);
+        }
+        Some((head.chrom, head.start, head.line))
+    }
+}
+
+fn next_entry(lines: &mut Lines<BufReader<File>>, run: usize) -> Option<Head> {
+    let line = lines
+        .next()?
+        .unwrap_or_else(|err| panic!("Failed reading temporary file: {err}"));
+    let mut fields = line.splitn(3, '\t');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(chrom), Some(start), Some(line)) => Some(Head {
+            chrom: chrom.to_string(),
+            start: start.parse().expect("Invalid start in temporary file"),
+            line: line.to_string(),
+            run,
+        }),
+        _ => panic!("Invalid line in temporary file: {line}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spilled_records_are_sorted() {
+        let records = SortedRecords::new(Some(200));
+        let loci = [("chr2", 50), ("chr10", 5), ("chr1", 300), ("chr2", 10), ("chr1", 20)];
+        for (chrom, start) in loci {
+            records.push(chrom.to_string(), start, format!("{chrom}:{start}"));
+        }
+        let spilled = records
+            .spilled
+            .lock()
+            .expect("Unable to lock spilled mutex")
+            .iter()
+            .map(|file| file.path.clone())
+            .collect::<Vec<PathBuf>>();
+        assert!(!spilled.is_empty());
+        let mut out = vec![];
+        records.write_sorted(&mut out);
+        assert_eq!(
+            String::from_utf8(out).expect("Invalid output"),
+            "chr1:20\nchr1:300\nchr2:10\nchr2:50\nchr10:5\n"
+        );
+        assert!(spilled.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn test_spilled_files_are_merged() {
+        // every record is spilled to its own file, until the files are merged into one
+        let records = SortedRecords::new(Some(1));
+        for start in (0..MAX_SPILLED_FILES as u32 + 10).rev() {
+            records.push("chr1".to_string(), start, format!("chr1:{start}"));
+        }
+        let spilled = records.spilled.lock().expect("Unable to lock spilled mutex").len();
+        assert!(spilled <= MAX_SPILLED_FILES);
+        let mut out = vec![];
+        records.write_sorted(&mut out);
+        let out = String::from_utf8(out).expect("Invalid output");
+        let starts = out
+            .lines()
+            .map(|line| line.trim_start_matches("chr1:").parse::<u32>().unwrap())
+            .collect::<Vec<u32>>();
+        assert_eq!(starts, (0..MAX_SPILLED_FILES as u32 + 10).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_spill_files_are_removed_when_dropped() {
+        let file = write_spill_file([("chr1".to_string(), 1, "line".to_string())]);
+        let path = file.path.clone();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::vcf::VCFRecord;
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Counters of a genotyping run, shared by all threads
+pub struct RunStats {
+    attempted: AtomicUsize,
+    genotyped: AtomicUsize,
+    low_support: AtomicUsize, // no allele could be called, as too few reads support the alleles
+    no_consensus: AtomicUsize, // no allele could be called, although enough reads support an allele
+    no_coverage: AtomicUsize, // no allele could be called, as no read spans the repeat
+    timed_out: AtomicUsize, // no allele could be called within --timeout
+    no_reference: AtomicUsize, // no allele could be called, as the repeat is not in the fasta file
+    failed: AtomicUsize,
+    from_checkpoint: AtomicUsize,
+    depth: AtomicUsize, // sum of the reads overlapping the genotyped repeats
+    with_reads: AtomicUsize, // number of repeats with overlapping reads
+    reading_nanos: AtomicU64, // time spent collecting reads from the bam file, summed over threads
+    genotyping_nanos: AtomicU64, // time spent genotyping the collected reads, summed over threads
+}
+
+pub static STATS: RunStats = RunStats::new();
+
+impl RunStats {
+    const fn new() -> RunStats {
+        RunStats {
+            attempted: AtomicUsize::new(0),
+            genotyped: AtomicUsize::new(0),
+            low_support: AtomicUsize::new(0),
+            no_consensus: AtomicUsize::new(0),
+            no_coverage: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+            no_reference: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            from_checkpoint: AtomicUsize::new(0),
+            depth: AtomicUsize::new(0),
+            with_reads: AtomicUsize::new(0),
+            reading_nanos: AtomicU64::new(0),
+            genotyping_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a record as genotyped, or by the reason that no allele could be called,
+    /// from its filters and the number of reads supporting its alleles
+    pub fn add_record(&self, record: &VCFRecord, min_support: usize) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        if record.allele.0 != "." || record.allele.1 != "." {
+            self.genotyped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let filtered = |filter: &str| record.filters.iter().any(|f| f == filter);
+        let support = [&record.support.0, &record.support.1]
+            .iter()
+            .filter_map(|support| support.parse::<usize>().ok())
+            .max();
+        let counter = if filtered("TIMEOUT") {
+            &self.timed_out
+        } else if filtered("NOCOV") {
+            &self.no_coverage
+        } else {
+            match support {
+                // the support is only missing if the repeat is not in the fasta file
+                None => &self.no_reference,
+                Some(0) => &self.no_coverage,
+                Some(support) if support < min_support => &self.low_support,
+                Some(_) => &self.no_consensus,
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_failure(&self) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_checkpointed(&self) {
+        self.from_checkpoint.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_depth(&self, depth: usize) {
+        self.depth.fetch_add(depth, Ordering::Relaxed);
+        self.with_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_reading_time(&self, duration: Duration) {
+        self.reading_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_genotyping_time(&self, duration: Duration) {
+        self.genotyping_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// The statistics as JSON, with the wall time of every stage of the run in seconds
+    pub fn to_json(&self, stages: &[(&str, Duration)]) -> String {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+        let seconds = |nanos: &AtomicU64| nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let with_reads = load(&self.with_reads);
+        let mean_depth = if with_reads == 0 {
+            0.0
+        } else {
+            load(&self.depth) as f64 / with_reads as f64
+        };
+        let mut wall_time = serde_json::Map::new();
+        for (stage, duration) in stages {
+            wall_time.insert(stage.to_string(), json!(duration.as_secs_f64()));
+        }
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "loci_attempted": load(&self.attempted),
+            "loci_genotyped": load(&self.genotyped),
+            "loci_low_support": load(&self.low_support),
+            "loci_no_consensus": load(&self.no_consensus),
+            "loci_no_coverage": load(&self.no_coverage),
+            "loci_timed_out": load(&self.timed_out),
+            "loci_no_reference": load(&self.no_reference),
+            "loci_failed": load(&self.failed),
+            "loci_from_checkpoint": load(&self.from_checkpoint),
+            "mean_depth": mean_depth,
+            "wall_time": wall_time,
+            "thread_time": {
+                "reading": seconds(&self.reading_nanos),
+                "genotyping": seconds(&self.genotyping_nanos),
+            },
+        })
+        .to_string()
+    }
+}
+
+/// Write the statistics of the run to a file, or to stderr with "-"
+pub fn write_stats(path: &str, stages: &[(&str, Duration)]) {
+    let stats = STATS.to_json(stages);
+    if path == "-" {
+        eprintln!("{stats}");
+    } else {
+        std::fs::write(path, stats + "\n")
+            .unwrap_or_else(|err| panic!("Failed writing statistics to {path}: {err}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let stats = RunStats::new();
+        let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+        stats.add_record(&crate::test_utils::cag_record(&repeat, "2"), 3);
+        stats.add_record(&crate::test_utils::cag_record(&repeat, "5"), 3);
+        stats.add_record(&crate::test_utils::cag_record(&repeat, "0"), 3);
+        let mut timed_out = crate::vcf::VCFRecord::missing_genotype(&repeat, "CAGCAG", ".".into());
+        timed_out.filters = vec!["TIMEOUT".to_string()];
+        stats.add_record(&timed_out, 3);
+        stats.add_record(&crate::vcf::VCFRecord::missing_genotype(&repeat, "N", ".".into()), 3);
+        stats.add_failure();
+        stats.add_depth(10);
+        stats.add_depth(20);
+        let parsed: serde_json::Value =
+            serde_json::from_str(&stats.to_json(&[("genotyping", Duration::from_secs(2))]))
+                .expect("Invalid JSON");
+        assert_eq!(parsed["loci_attempted"], 6);
+        assert_eq!(parsed["loci_genotyped"], 0);
+        assert_eq!(parsed["loci_low_support"], 1);
+        assert_eq!(parsed["loci_no_consensus"], 1);
+        assert_eq!(parsed["loci_no_coverage"], 1);
+        assert_eq!(parsed["loci_timed_out"], 1);
+        assert_eq!(parsed["loci_no_reference"], 1);
+        assert_eq!(parsed["loci_failed"], 1);
+        assert_eq!(parsed["mean_depth"], 15.0);
+        assert_eq!(parsed["wall_time"]["genotyping"], 2.0);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::repeats::RepeatIntervalIterator;
+use human_sort::compare as human_compare;
+use log::info;
+use std::collections::HashMap;
+
+/// Write the target regions of a catalog for adaptive sampling or targeted capture as bed to stdout,
+/// with every locus padded by the flank size, merged and sorted
+#[derive(clap::Args, Debug)]
+pub struct TargetsArgs {
+    /// reference genome
+    fasta: String,
+
+    /// Bed file with the repeat catalog
+    region_file: String,
+
+    /// number of bases to add on either side of every locus, reads have to span the locus and its flanks
+    #[clap(long, value_parser, default_value_t = 50000)]
+    flank: u32,
+
+    /// maximal gap between padded loci that are merged into a single target region
+    #[clap(long, value_parser, default_value_t = 0)]
+    merge_distance: u32,
+}
+
+pub fn run(args: TargetsArgs) {
+    let lengths = crate::repeats::fasta_contigs(&args.fasta)
+        .into_iter()
+        .collect::<HashMap<String, u64>>();
+    // the padded loci are clipped to the bounds of the chromosome
+    let padded = RepeatIntervalIterator::from_bed(&args.region_file, &args.fasta)
+        .map(|repeat| {
+            let padded = repeat.padded(args.flank);
+            let length = lengths.get(&padded.chrom).map_or(u32::MAX, |length| *length as u32);
+            (padded.chrom, padded.start, padded.end.min(length))
+        })
+        .collect::<Vec<(String, u32, u32)>>();
+    let loci = padded.len();
+    let targets = merge_regions(padded, args.merge_distance);
+    for (chrom, start, end) in &targets {
+        println!("{chrom}\t{start}\t{end}");
+    }
+    let size = targets.iter().map(|(_, start, end)| (end - start) as u64).sum::<u64>();
+    let genome = lengths.values().sum::<u64>().max(1);
+    info!(
+        "Wrote {} target regions for {loci} loci, {size} bases or {:.2}% of the genome",
+        targets.len(),
+        100.0 * size as f64 / genome as f64
+    );
+}
+
+// sort the regions by chromosome and start, and merge regions that overlap or are at most max_gap apart
+fn merge_regions(mut regions: Vec<(String, u32, u32)>, max_gap: u32) -> Vec<(String, u32, u32)> {
+    regions.sort_by(|a, b| human_compare(&a.0, &b.0).then(a.1.cmp(&b.1)));
+    let mut merged: Vec<(String, u32, u32)> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.0 == region.0 && region.1 <= last.2.saturating_add(max_gap) => {
+                last.2 = last.2.max(region.2);
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_regions() {
+        let region = |chrom: &str, start: u32, end: u32| (chrom.to_string(), start, end);
+        let regions = vec![
+            region("chr10", 100, 200),
+            region("chr2", 500, 600),
+            region("chr2", 100, 300),
+            region("chr2", 250, 400),
+            region("chr10", 150, 180),
+        ];
+        assert_eq!(
+            merge_regions(regions.clone(), 0),
+            vec![region("chr2", 100, 400), region("chr2", 500, 600), region("chr10", 100, 200)]
+        );
+        assert_eq!(merge_regions(regions, 100)[0], region("chr2", 100, 600));
+    }
+}
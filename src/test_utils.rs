@@ -0,0 +1,25 @@
+//! Fixtures shared by the tests of multiple modules
+use crate::repeats::RepeatInterval;
+use crate::vcf::VCFRecord;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// the number of temporary paths handed out by this process, such that every test gets its own file
+static TEMP_PATHS: AtomicUsize = AtomicUsize::new(0);
+
+/// A path in the temporary directory that is unique to this process and call,
+/// such that tests running in parallel or in concurrent test runs do not share a file
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!(
+            "strdust-test-{}-{}-{name}",
+            std::process::id(),
+            TEMP_PATHS.fetch_add(1, Ordering::Relaxed)
+        ))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// A record without a genotype of a CAGCAG reference allele, for tests to fill in
+pub fn cag_record(repeat: &RepeatInterval, support: &str) -> VCFRecord {
+    VCFRecord::missing_genotype(repeat, "CAGCAG", support.to_string())
+}
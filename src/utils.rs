@@ -1,4 +1,8 @@
 use flate2::read;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -6,6 +10,7 @@ use std::path::Path;
 
 /// Read normal or compressed files seamlessly
 /// Uses the presence of a `.gz` extension to decide
+/// bgzip compressed files consist of multiple gzip members, which are all read
 pub fn reader(filename: &str) -> Box<dyn BufRead> {
     let path = Path::new(filename);
     let file = match File::open(path) {
@@ -16,9 +21,254 @@ pub fn reader(filename: &str) -> Box<dyn BufRead> {
     if path.extension() == Some(OsStr::new("gz")) {
         Box::new(BufReader::with_capacity(
             128 * 1024,
-            read::GzDecoder::new(file),
+            read::MultiGzDecoder::new(file),
         ))
     } else {
         Box::new(BufReader::with_capacity(128 * 1024, file))
     }
 }
+
+/// True for a remote file on S3 or a web server, which htslib reads directly
+pub fn is_remote(path: &str) -> bool {
+    path.starts_with("s3://") || path.starts_with("https://") || path.starts_with("http://")
+}
+
+/// Point htslib to the CA certificates of the system for remote files, unless CURL_CA_BUNDLE is set
+/// This changes the environment, so it is called once at startup before any threads are started
+pub fn set_ca_bundle() {
+    if std::env::var("CURL_CA_BUNDLE").is_err() {
+        std::env::set_var("CURL_CA_BUNDLE", "/etc/ssl/certs/ca-certificates.crt");
+    }
+}
+
+/// Run f, with a panic returned as an error with the message of the panic, or fallback if it has none
+pub fn catch_panic<T>(f: impl FnOnce() -> T, fallback: &str) -> Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|panic| {
+        panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+            .unwrap_or_else(|| fallback.to_string())
+    })
+}
+
+/// 64-bit FNV-1a hash, which unlike the std hasher is stable across builds and platforms
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Replaces identifiers, such as sample names and file paths, by a keyed hash (HMAC-SHA256) for --anonymize
+/// Without the key, an identifier can't be recovered by hashing candidate names
+pub struct Anonymizer {
+    key: Vec<u8>,
+}
+
+impl Anonymizer {
+    /// With a key, an identifier gets the same hash in every run with that key,
+    /// without a key a random key is drawn, such that the hashes of different runs can't be linked
+    pub fn new(key: Option<&str>) -> Anonymizer {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        Anonymizer { key }
+    }
+
+    pub fn anonymize(&self, identifier: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(identifier.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex = digest[..8].iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        format!("anon-{hex}")
+    }
+}
+
+thread_local! {
+    // the row of the edit distance matrix, reused across calls as the distances of all pairs of reads are computed
+    static EDIT_ROW: RefCell<Vec<usize>> = RefCell::new(vec![]);
+}
+
+/// The levenshtein distance between two ASCII sequences, without allocating a matrix for every pair
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    EDIT_ROW.with(|row| {
+        let mut row = row.borrow_mut();
+        row.clear();
+        row.extend(0..=b.len());
+        for (i, base_a) in a.iter().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, base_b) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if base_a == base_b {
+                    diagonal
+                } else {
+                    1 + diagonal.min(above).min(row[j])
+                };
+                diagonal = above;
+            }
+        }
+        row[b.len()]
+    })
+}
+
+/// The rotations of a sequence, starting with the sequence itself
+pub fn rotations(seq: &str) -> Vec<String> {
+    (0..seq.len())
+        .map(|i| format!("{}{}", &seq[i..], &seq[..i]))
+        .collect()
+}
+
+/// The lexicographically smallest rotation of a sequence
+/// such that the same repeat with a different start in the motif gets the same key
+pub fn canonical_rotation(seq: &str) -> String {
+    rotations(seq).into_iter().min().unwrap_or_default()
+}
+
+/// A stable identifier of an allele, from the locus and the uppercased allele sequence
+/// At a fixed locus a rotation of the sequence is another allele, and gets another identifier
+pub fn allele_id(chrom: &str, start: u32, end: u32, seq: &str) -> String {
+    let key = format!("{chrom}:{start}-{end}:{}", seq.trim().to_uppercase());
+    format!("STRdust-{:016x}", fnv1a(key.as_bytes()))
+}
+
+/// Intervals per chromosome sorted by start, to find the intervals overlapping a region with a binary search
+pub struct IntervalIndex<T> {
+    // the length of the longest interval and the intervals of every chromosome
+    chroms: HashMap<String, (u64, Vec<(u64, u64, T)>)>,
+}
+
+impl<T> IntervalIndex<T> {
+    /// Index chromosome, start, end and value tuples, intervals with the same start keep their order
+    pub fn new(intervals: impl IntoIterator<Item = (String, u64, u64, T)>) -> Self {
+        let mut chroms: HashMap<String, (u64, Vec<(u64, u64, T)>)> = HashMap::new();
+        for (chrom, start, end, value) in intervals {
+            let (max_length, intervals) = chroms.entry(chrom).or_default();
+            *max_length = (*max_length).max(end.saturating_sub(start));
+            intervals.push((start, end, value));
+        }
+        for (_, intervals) in chroms.values_mut() {
+            intervals.sort_by_key(|(start, _, _)| *start);
+        }
+        IntervalIndex { chroms }
+    }
+
+    /// The intervals on a chromosome that overlap start to end, sorted by start
+    pub fn overlapping<'a>(
+        &'a self,
+        chrom: &str,
+        start: u64,
+        end: u64,
+    ) -> impl Iterator<Item = &'a (u64, u64, T)> + 'a {
+        let (max_length, intervals) = match self.chroms.get(chrom) {
+            Some((max_length, intervals)) => (*max_length, intervals.as_slice()),
+            None => (0, &[][..]),
+        };
+        // no interval that starts before min_start reaches the region
+        let min_start = start.saturating_sub(max_length);
+        let first = intervals.partition_point(|(interval_start, _, _)| *interval_start < min_start);
+        intervals[first..]
+            .iter()
+            .take_while(move |(interval_start, _, _)| *interval_start < end)
+            .filter(move |(_, interval_end, _)| *interval_end > start)
+    }
+
+    /// The number of intervals
+    pub fn len(&self) -> usize {
+        self.chroms.values().map(|(_, intervals)| intervals.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_index() {
+        let index = IntervalIndex::new([
+            ("chr1".to_string(), 500, 2000, 'a'),
+            ("chr1".to_string(), 100, 200, 'b'),
+            ("chr2".to_string(), 150, 160, 'c'),
+            ("chr1".to_string(), 2100, 2200, 'd'),
+        ]);
+        let values = |chrom: &str, start, end| {
+            index
+                .overlapping(chrom, start, end)
+                .map(|(_, _, value)| *value)
+                .collect::<String>()
+        };
+        assert_eq!(values("chr1", 150, 160), "b");
+        // a long interval that starts well before the region
+        assert_eq!(values("chr1", 1900, 2150), "ad");
+        assert_eq!(values("chr1", 200, 500), "");
+        assert_eq!(values("chr3", 0, 1000), "");
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_is_remote() {
+        assert!(is_remote("s3://bucket/sample.bam"));
+        assert!(is_remote("https://example.org/sample.cram"));
+        assert!(!is_remote("s3_sample.bam"));
+        assert!(!is_remote("test_data/small-test-phased.bam"));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "CAG"), 3);
+        assert_eq!(edit_distance("CAGCAG", ""), 6);
+        assert_eq!(edit_distance("CAGCAGCAG", "CAGCTGCAG"), 1);
+        assert_eq!(edit_distance("CAGCAG", "CAGCAG"), 0);
+    }
+
+    #[test]
+    fn test_anonymize() {
+        // test case 2 of RFC 4231
+        let anonymizer = Anonymizer::new(Some("Jefe"));
+        assert_eq!(anonymizer.anonymize("what do ya want for nothing?"), "anon-5bdcc146bf60754e");
+        assert_ne!(anonymizer.anonymize("sample1"), anonymizer.anonymize("sample2"));
+        // a random key gives other hashes
+        let random = Anonymizer::new(None);
+        assert_ne!(random.anonymize("sample1"), anonymizer.anonymize("sample1"));
+        assert_eq!(random.anonymize("sample1"), random.anonymize("sample1"));
+    }
+
+    #[test]
+    fn test_canonical_rotation() {
+        assert_eq!(canonical_rotation("CAGCAGCAG"), canonical_rotation("AGCAGCAGC"));
+        assert_ne!(canonical_rotation("CAGCAGCAG"), canonical_rotation("CAGCAG"));
+        assert_eq!(canonical_rotation(""), "");
+        assert_eq!(rotations("CAG"), vec!["CAG", "AGC", "GCA"]);
+    }
+
+    #[test]
+    fn test_allele_id() {
+        // the id does not depend on the case of the allele
+        let id = allele_id("chr7", 100, 106, "CAGCAGCAG");
+        assert_eq!(id, allele_id("chr7", 100, 106, "cagcagcag"));
+        // but a rotation is another allele
+        assert_ne!(
+            allele_id("chr7", 100, 106, "CAGCAGCTG"),
+            allele_id("chr7", 100, 106, "CTGCAGCAG")
+        );
+        assert_ne!(id, allele_id("chr7", 200, 206, "CAGCAGCAG"));
+        assert!(id.starts_with("STRdust-"));
+    }
+}
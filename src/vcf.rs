@@ -2,10 +2,9 @@ use crate::consensus::Consensus;
 use distance::levenshtein;
 use human_sort::compare as human_compare;
 use log::debug;
-use rust_htslib::faidx;
+use serde_json::{json, Value};
 use std::cmp::Ordering;
 use std::fmt;
-use std::io::Read;
 
 pub struct Allele {
     pub length: String, // length of the consensus sequence minus the length of the repeat sequence
@@ -14,10 +13,20 @@ pub struct Allele {
     pub std_dev: String, // standard deviation of the repeat length
     pub score: String,  // consensus score in the poa graph
     pub seq: String,    // consensus sequence
+    pub ci: String,     // 5th and 95th percentile of the length relative to the reference, as lower,upper
+    pub trimmed: String, // number of reads trimmed as length outlier
 }
 
 impl Allele {
     pub fn from_consensus(consensus: Consensus, start: u32, end: u32) -> Allele {
+        let ci = match consensus.length_ci {
+            Some((lower, upper)) => format!(
+                "{},{}",
+                lower as i32 - (end - start) as i32,
+                upper as i32 - (end - start) as i32
+            ),
+            None => ".".to_string(),
+        };
         match consensus.seq {
             Some(seq) => Allele {
                 length: (seq.len() as i32 - ((end - start) as i32)).to_string(),
@@ -26,6 +35,8 @@ impl Allele {
                 std_dev: consensus.std_dev.to_string(),
                 score: consensus.score.to_string(),
                 seq,
+                ci,
+                trimmed: consensus.trimmed.to_string(),
             },
             None => Allele {
                 length: ".".to_string(),
@@ -34,6 +45,8 @@ impl Allele {
                 std_dev: ".".to_string(),
                 score: ".".to_string(),
                 seq: ".".to_string(),
+                ci,
+                trimmed: consensus.trimmed.to_string(),
             },
         }
     }
@@ -50,11 +63,38 @@ pub struct VCFRecord {
     pub support: (String, String),
     pub std_dev: (String, String),
     pub score: (String, String),
-    pub somatic_info_field: String,
-    pub outliers: String,
+    pub ci: (String, String), // 5th to 95th percentile range of the repeat length per allele
+    pub trimmed: (String, String), // reads trimmed as length outlier per allele
+    pub somatic_insertions: Option<Vec<String>>, // per haplotype, with ':' separated insertions
+    pub outliers: Option<Vec<String>>,
     pub ps: Option<u32>, // phase set identifier
+    pub gq: Option<u32>, // genotype quality, only for a ploidy of one or two
+    pub pl: Option<Vec<u32>>, // phred-scaled genotype likelihoods, only for a ploidy of one or two
     pub flags: String,
     pub allele: (String, String),
+    pub consensus: (String, String), // consensus sequence per haplotype
+    pub coverage: Option<(usize, usize)>, // depth and spanning reads, only with --emit-all-sites
+    pub clipping: Option<(usize, usize)>, // spanning reads anchored in both flanks and reads soft-clipped in the repeat
+    pub read_groups: Option<Vec<(String, usize)>>, // spanning reads used per read group, only with --read-group or --max-reads-per-read-group
+    pub excluded: Option<(usize, usize)>, // spanning reads excluded for a low identity and a short length, only with --min-read-identity or --min-read-length
+    pub haploid: bool, // haploid loci are reported with a single allele
+    pub filters: Vec<String>, // reported in the FILTER column, e.g. LOWCONF_FLANK
+    pub motif_counts: Option<(String, String)>, // copies per motif segment, only for compound loci
+    pub motifs: Option<(String, String)>, // repeated motifs per allele separated by '_', only with --nested-motifs
+    pub residual_error: Option<(String, String)>, // error of reads realigned to the alleles, only with --realign-qc
+    pub purity: Option<(String, String)>, // fraction of the allele in perfect copies of the motif, only with --purity
+    pub motif_distance: Option<(String, String)>, // edit distance to a perfect repeat of the same length, only with --purity
+    pub expansion_index: Option<(String, String)>, // mean repeat units of the reads above the modal count per allele, only with --somatic
+    pub contraction_index: Option<(String, String)>, // mean repeat units of the reads below the modal count per allele, only with --somatic
+    pub read_qc: Option<crate::read_qc::ReadQcAnnotation>, // strand and base quality per allele, only with --read-qc
+    pub plot_insertions: Option<Vec<String>>, // per haplotype, with ':' separated insertions, only with --plot
+    pub fmr1: Option<crate::fmr1::Fmr1Annotation>, // repeat units, interruptions, category and methylation, only for FMR1
+    pub allele_ids: Option<Vec<String>>, // stable id per ALT allele, only with --allele-ids
+    pub second_reference: Option<crate::multiref::SecondCall>, // only with --second-fasta
+    pub annotation: Option<crate::repeats::LocusAnnotation>, // locus identifier, gene and disease from the bed file
+    pub extra_alleles: Vec<(String, Allele)>, // genotype and allele beyond the first two, only for a ploidy above two
+    pub locus: Option<(u32, u32)>, // start and end of the catalog locus, only with --normalize
+    pub low_support: Option<Vec<bool>>, // per allele, missing for too few supporting reads, only with --keep-low-support
 }
 
 impl VCFRecord {
@@ -87,55 +127,8 @@ impl VCFRecord {
             "Genotyping {repeat}:{repeat_ref_sequence} with {} and {}",
             allele1.seq, allele2.seq,
         );
-        // if the consensus is very similar to the reference the variant is considered ref
-        // for this I use a threshold of 5% of the length of the repeat sequence in the reference
-        // e.g. if the repeat is 300bp in the reference this will allow an edit distance of 15
-        // not sure if these numbers require further tuning
-        // note that is an integer division, i.e. floor division
-        // the next_alt variable dictates which genotype code the genotype2 can be in the case it is not the same as genotype1
-        // this avoids a 0/2 genotype
-        let (genotype1, next_alt) = if allele1.seq == "." {
-            (".", "1")
-        } else if levenshtein(&allele1.seq, &repeat_ref_sequence) < repeat_ref_sequence.len() / 20 {
-            ("0", "1")
-        } else {
-            ("1", "2")
-        };
-
-        let genotype2 = if allele2.seq == "." {
-            "."
-        } else if levenshtein(&allele2.seq, &repeat_ref_sequence) < repeat_ref_sequence.len() / 20 {
-            "0"
-        } else if levenshtein(&allele2.seq, &allele1.seq) < allele1.seq.len() / 20 {
-            genotype1
-        } else {
-            next_alt
-        };
-
-        let alts = match (genotype1, genotype2) {
-            ("1", "0") | ("1", ".") | ("1", "1") => allele1.seq, // if both alleles are the same, only report one
-            ("0", "1") | (".", "1") => allele2.seq,
-            ("1", "2") => allele1.seq + "," + &allele2.seq,
-            _ => ".".to_string(), // includes ./. and 0/0
-        };
-
-        let somatic_info_field = match all_insertions {
-            Some(somatic_insertions) => {
-                format!(";SEQS={}", somatic_insertions.join(","))
-            }
-            None => "".to_string(),
-        };
-
-        let outliers = match outlier_insertions {
-            Some(outlier_insertions) => {
-                if outlier_insertions.is_empty() {
-                    "".to_string()
-                } else {
-                    format!(";OUTLIERS={}", outlier_insertions.join(","))
-                }
-            }
-            None => "".to_string(),
-        };
+        let (genotype1, genotype2, alts) =
+            genotype_alleles(&allele1.seq, &allele2.seq, &repeat_ref_sequence, None);
 
         let flags = if flag.is_empty() {
             "".to_string()
@@ -153,11 +146,38 @@ impl VCFRecord {
             support: (allele1.support, allele2.support),
             std_dev: (allele1.std_dev, allele2.std_dev),
             score: (allele1.score, allele2.score),
-            somatic_info_field,
-            outliers,
+            ci: (allele1.ci, allele2.ci),
+            trimmed: (allele1.trimmed, allele2.trimmed),
+            somatic_insertions: all_insertions,
+            outliers: outlier_insertions,
             ps,
+            gq: None,
+            pl: None,
             flags,
-            allele: (genotype1.to_string(), genotype2.to_string()),
+            allele: (genotype1, genotype2),
+            consensus: (allele1.seq, allele2.seq),
+            coverage: None,
+            clipping: None,
+            read_groups: None,
+            excluded: None,
+            haploid: false,
+            filters: vec![],
+            motif_counts: None,
+            motifs: None,
+            residual_error: None,
+            purity: None,
+            motif_distance: None,
+            expansion_index: None,
+            contraction_index: None,
+            read_qc: None,
+            plot_insertions: None,
+            fmr1: None,
+            allele_ids: None,
+            second_reference: None,
+            annotation: repeat.annotation.clone(),
+            extra_alleles: vec![],
+            locus: None,
+            low_support: None,
         }
     }
 
@@ -177,61 +197,927 @@ impl VCFRecord {
             support: (support, ".".to_string()),
             std_dev: (".".to_string(), ".".to_string()),
             score: (".".to_string(), ".".to_string()),
-            somatic_info_field: "".to_string(),
-            outliers: "".to_string(),
+            ci: (".".to_string(), ".".to_string()),
+            trimmed: (".".to_string(), ".".to_string()),
+            somatic_insertions: None,
+            outliers: None,
             ps: None,
+            gq: None,
+            pl: None,
             flags: "".to_string(),
             allele: (".".to_string(), ".".to_string()),
+            consensus: (".".to_string(), ".".to_string()),
+            coverage: None,
+            clipping: None,
+            read_groups: None,
+            excluded: None,
+            haploid: false,
+            filters: vec![],
+            motif_counts: None,
+            motifs: None,
+            residual_error: None,
+            purity: None,
+            motif_distance: None,
+            expansion_index: None,
+            contraction_index: None,
+            read_qc: None,
+            plot_insertions: None,
+            fmr1: None,
+            allele_ids: None,
+            second_reference: None,
+            annotation: repeat.annotation.clone(),
+            extra_alleles: vec![],
+            locus: None,
+            low_support: None,
+        }
+    }
+}
+
+impl VCFRecord {
+    /// A no-call for a locus without spanning reads, with the NOCOV filter and the depth of the locus
+    pub fn no_coverage(
+        repeat: &crate::repeats::RepeatInterval,
+        repeat_ref_seq: &str,
+        depth: usize,
+    ) -> VCFRecord {
+        let mut record = VCFRecord::missing_genotype(repeat, repeat_ref_seq, 0.to_string());
+        record.coverage = Some((depth, 0));
+        record.filters.push("NOCOV".to_string());
+        record
+    }
+
+    /// Report alleles at least min_contraction bases shorter than the reference as ALT,
+    /// even if they are within the edit distance that is otherwise considered reference
+    pub fn call_contractions(&mut self, min_contraction: usize) {
+        if self.alt_seq.is_none() {
+            return;
+        }
+        let (genotype1, genotype2, alts) = genotype_alleles(
+            &self.consensus.0,
+            &self.consensus.1,
+            &self.ref_seq,
+            Some(min_contraction),
+        );
+        self.allele = (genotype1, genotype2);
+        self.alt_seq = Some(alts);
+    }
+
+    /// Add the alleles beyond the first two of a locus with a ploidy above two,
+    /// alleles that are not similar to the reference or an ALT allele are added as ALT
+    pub fn add_alleles(
+        &mut self,
+        consenses: Vec<Consensus>,
+        repeat: &crate::repeats::RepeatInterval,
+        min_contraction: Option<usize>,
+    ) {
+        let mut alts = match self.alt_seq.as_deref() {
+            Some(".") | None => vec![],
+            Some(alts) => alts.split(',').map(|alt| alt.to_string()).collect(),
+        };
+        for consensus in consenses {
+            let allele = Allele::from_consensus(consensus, repeat.start, repeat.end);
+            let genotype = if allele.seq == "." {
+                ".".to_string()
+            } else if is_reference(&allele.seq, &self.ref_seq, min_contraction) {
+                "0".to_string()
+            } else if let Some(index) = alts
+                .iter()
+                .position(|alt| levenshtein(&allele.seq, alt) < alt.len() / 20)
+            {
+                (index + 1).to_string()
+            } else {
+                alts.push(allele.seq.clone());
+                alts.len().to_string()
+            };
+            self.extra_alleles.push((genotype, allele));
+        }
+        if !alts.is_empty() {
+            self.alt_seq = Some(alts.join(","));
         }
     }
 }
 
+impl VCFRecord {
+    /// Replace the read group identifiers by their hash for --anonymize,
+    /// as these often contain the sample name or the flow cell and run of the reads
+    pub fn anonymize_read_groups(&mut self, anonymizer: &crate::utils::Anonymizer) {
+        if let Some(read_groups) = self.read_groups.as_mut() {
+            for (read_group, _) in read_groups.iter_mut() {
+                *read_group = anonymizer.anonymize(read_group);
+            }
+        }
+    }
+
+    /// Report the ALT alleles as symbolic alleles of their length in bases, e.g. <STR42>, for --no-sequence
+    /// The sequences are those of single reads, so are dropped, and ALT alleles of the same length are merged
+    pub fn set_symbolic_alleles(&mut self) {
+        let alts = match self.alt_seq.as_deref() {
+            Some(".") | None => return,
+            Some(alts) => alts.split(',').map(|alt| format!("<STR{}>", alt.len())),
+        };
+        let mut symbolic: Vec<String> = vec![];
+        // the genotype code of every ALT allele among the symbolic alleles
+        let codes = alts
+            .map(|alt| match symbolic.iter().position(|s| *s == alt) {
+                Some(index) => index + 1,
+                None => {
+                    symbolic.push(alt);
+                    symbolic.len()
+                }
+            })
+            .collect::<Vec<usize>>();
+        let recode = |genotype: &mut String| {
+            if let Some(code) = genotype.parse::<usize>().ok().filter(|code| *code > 0) {
+                *genotype = codes[code - 1].to_string();
+            }
+        };
+        recode(&mut self.allele.0);
+        recode(&mut self.allele.1);
+        for (genotype, allele) in self.extra_alleles.iter_mut() {
+            recode(genotype);
+            allele.seq = ".".to_string();
+        }
+        self.alt_seq = Some(symbolic.join(","));
+        self.consensus = (".".to_string(), ".".to_string());
+    }
+
+    /// Decompose the alleles of a compound locus in the number of copies of every repeated motif
+    pub fn set_motif_counts(
+        &mut self,
+        structure: &crate::motif::LocusStructure,
+        mismatches: usize,
+    ) {
+        self.motif_counts = Some((
+            structure.motif_counts(&self.consensus.0, mismatches),
+            structure.motif_counts(&self.consensus.1, mismatches),
+        ));
+    }
+
+    /// The repeated motifs of at most max_length bases of every allele
+    pub fn set_motifs(&mut self, max_length: usize, mismatches: usize) {
+        let motifs = |seq: &str| {
+            let motifs = crate::motif::nested_motifs(seq, max_length, mismatches);
+            if seq == "." || motifs.is_empty() {
+                ".".to_string()
+            } else {
+                motifs.join("_")
+            }
+        };
+        self.motifs = Some((motifs(&self.consensus.0), motifs(&self.consensus.1)));
+    }
+
+    /// Set the purity of every allele and its edit distance to a perfect repeat of the motif
+    pub fn set_purity(&mut self, motif: Option<&str>) {
+        let purity = |seq: &str| match motif {
+            Some(motif) if seq != "." => {
+                let (fraction, distance) = crate::motif::purity(seq, motif);
+                (
+                    format!("{fraction:.2}"),
+                    distance.map_or(".".to_string(), |distance| distance.to_string()),
+                )
+            }
+            _ => (".".to_string(), ".".to_string()),
+        };
+        let (purity1, distance1) = purity(&self.consensus.0);
+        let (purity2, distance2) = purity(&self.consensus.1);
+        self.purity = Some((purity1, purity2));
+        self.motif_distance = Some((distance1, distance2));
+    }
+
+    /// Set the expansion and contraction index of the reads of every allele, in repeat units of the motif,
+    /// from the insertions per haplotype collected with --somatic
+    /// Not set for more than two alleles, as the insertions are then collected per cluster
+    pub fn set_instability_index(&mut self, motif: Option<&str>, mismatches: usize) {
+        let haplotypes = match &self.somatic_insertions {
+            Some(haplotypes) if !haplotypes.is_empty() && haplotypes.len() <= 2 => haplotypes,
+            _ => return,
+        };
+        let index = |insertions: &str| {
+            let counts = match motif {
+                Some(motif) => insertions
+                    .split(':')
+                    .filter(|insertion| !insertion.is_empty())
+                    .map(|insertion| crate::motif::repeat_units(insertion, motif, mismatches))
+                    .collect::<Vec<usize>>(),
+                None => vec![],
+            };
+            match crate::instability::expansion_contraction_index(&counts) {
+                Some((expansion, contraction)) => {
+                    (format!("{expansion:.2}"), format!("{contraction:.2}"))
+                }
+                None => (".".to_string(), ".".to_string()),
+            }
+        };
+        // a homozygous or haploid locus has the insertions of a single haplotype
+        let (expansion1, contraction1) = index(&haplotypes[0]);
+        let (expansion2, contraction2) = index(haplotypes.last().expect("No haplotypes"));
+        self.expansion_index = Some((expansion1, expansion2));
+        self.contraction_index = Some((contraction1, contraction2));
+    }
+
+    /// Set the genotype likelihoods from the repeat length of the reads, and the quality of the called genotype
+    /// A genotype with a missing allele has no likelihoods
+    /// Neither has a genotype with alleles of the same length, e.g. an ALT with a substitution,
+    /// as the read lengths can not tell such alleles apart
+    pub fn set_likelihoods(&mut self, read_lengths: &[usize]) {
+        let alt_lengths = match self.alt_seq.as_deref() {
+            Some(".") | None => vec![],
+            Some(alts) => alts.split(',').map(|alt| alt.len()).collect(),
+        };
+        let allele_lengths = std::iter::once(self.ref_seq.len())
+            .chain(alt_lengths)
+            .collect::<Vec<usize>>();
+        let mut distinct = allele_lengths.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() < allele_lengths.len() {
+            return;
+        }
+        let called = if self.haploid {
+            vec![&self.allele.0]
+        } else {
+            vec![&self.allele.0, &self.allele.1]
+        };
+        let called = match called
+            .iter()
+            .map(|allele| allele.parse::<usize>().ok())
+            .collect::<Option<Vec<usize>>>()
+        {
+            Some(called) => called,
+            None => return,
+        };
+        let pl = crate::likelihood::phred_likelihoods(read_lengths, &allele_lengths, called.len());
+        self.gq = Some(crate::likelihood::genotype_quality(
+            &pl,
+            crate::likelihood::genotype_index(&called),
+        ));
+        self.pl = Some(pl);
+    }
+
+    /// Flag every allele that is missing for too few supporting reads, e.g. the second allele of 1|.
+    /// The support of a flagged allele is kept in SUP, the other alleles of the genotype are unaffected
+    pub fn flag_low_support(&mut self) {
+        let genotypes = if self.haploid {
+            vec![&self.allele.0]
+        } else {
+            vec![&self.allele.0, &self.allele.1]
+        };
+        let low_support = genotypes
+            .into_iter()
+            .chain(self.extra_alleles.iter().map(|(genotype, _)| genotype))
+            .map(|genotype| genotype == ".")
+            .collect();
+        self.low_support = Some(low_support);
+    }
+
+    /// Split a locus of which the reference consists of two adjacent repeats with a different motif
+    /// in a record per repeat, with the sub-interval coordinates in the reference
+    /// Every allele is split at the first run of the second motif, if any
+    /// Returns None if the reference has a single motif, or for a ploidy above two
+    pub fn split_nested(
+        &self,
+        max_length: usize,
+        mismatches: usize,
+    ) -> Option<(VCFRecord, VCFRecord)> {
+        if self.alt_seq.is_none() || !self.extra_alleles.is_empty() {
+            return None;
+        }
+        let runs = crate::motif::nested_runs(&self.ref_seq, max_length, mismatches);
+        let first = runs.first()?;
+        let other_motif = |run: &&crate::motif::MotifRun| {
+            !crate::motif::same_motif(&run.motif, &first.motif)
+        };
+        // the second motif is taken from the alleles if the reference only has the first,
+        // in which case the second record is anchored at the last base of the reference
+        let (second_motif, boundary) = match runs.iter().find(other_motif) {
+            Some(second) => (second.motif.clone(), Some(second.start)),
+            None => {
+                let seqs = [self.consensus.0.as_str(), self.consensus.1.as_str()];
+                let second = seqs
+                    .iter()
+                    .filter(|seq| **seq != ".")
+                    .flat_map(|seq| crate::motif::nested_runs(seq, max_length, mismatches))
+                    .find(|run| other_motif(&run))?;
+                (second.motif, None)
+            }
+        };
+        let anchor = &self.ref_seq[self.ref_seq.len().saturating_sub(1)..];
+        let split = |seq: &str| {
+            crate::motif::nested_runs(seq, max_length, mismatches)
+                .iter()
+                .find(|run| crate::motif::same_motif(&run.motif, &second_motif))
+                .map_or(seq.len(), |run| run.start)
+        };
+        let consensus = |seq: &str, support: &str, left: bool| {
+            let support = support.parse().unwrap_or_default();
+            if seq == "." {
+                return Consensus {
+                    support,
+                    ..Default::default()
+                };
+            }
+            let at = split(seq);
+            let part = match (left, boundary) {
+                (true, _) => seq[..at].to_string(),
+                (false, Some(_)) => seq[at..].to_string(),
+                (false, None) => format!("{anchor}{}", &seq[at..]),
+            };
+            Consensus {
+                seq: Some(part),
+                support,
+                ..Default::default()
+            }
+        };
+        let sub_record = |left: bool| {
+            let (start, end, ref_seq) = match (left, boundary) {
+                (true, Some(boundary)) => {
+                    (self.start, self.start + boundary as u32, &self.ref_seq[..boundary])
+                }
+                (false, Some(boundary)) => {
+                    (self.start + boundary as u32, self.end, &self.ref_seq[boundary..])
+                }
+                (true, None) => (self.start, self.end, self.ref_seq.as_str()),
+                (false, None) => (self.end - anchor.len() as u32, self.end, anchor),
+            };
+            let interval = crate::repeats::RepeatInterval::new(&self.chrom, start, end);
+            let mut record = VCFRecord::new(
+                vec![
+                    consensus(&self.consensus.0, &self.support.0, left),
+                    consensus(&self.consensus.1, &self.support.1, left),
+                ],
+                ref_seq.to_string(),
+                None,
+                None,
+                &interval,
+                self.ps,
+                vec![],
+            );
+            // the values that are measured on the reads of an allele are those of the whole allele
+            record.std_dev = self.std_dev.clone();
+            record.score = self.score.clone();
+            record.ci = self.ci.clone();
+            record.trimmed = self.trimmed.clone();
+            record.read_qc = self.read_qc.clone();
+            record.fmr1 = self.fmr1.clone();
+            record.low_support = self.low_support.clone();
+            record.flags = self.flags.clone();
+            record.haploid = self.haploid;
+            record.filters = self.filters.clone();
+            record.coverage = self.coverage;
+            record.clipping = self.clipping;
+            record.read_groups = self.read_groups.clone();
+            record.excluded = self.excluded;
+            // the identifier of the locus gets the number of the record as suffix, such that both are unique
+            record.annotation = self.annotation.clone().map(|mut annotation| {
+                annotation.id = annotation
+                    .id
+                    .map(|id| format!("{id}_{}", if left { 1 } else { 2 }));
+                annotation
+            });
+            if self.motifs.is_some() {
+                record.set_motifs(max_length, mismatches);
+            }
+            record
+        };
+        Some((sub_record(true), sub_record(false)))
+    }
+}
+
+impl VCFRecord {
+    /// Left-align the REF and ALT alleles against the reference and trim them to a single anchor base,
+    /// and move POS and END to the normalized REF allele
+    pub fn normalize(&mut self, fasta: &str) {
+        let alts = match self.alt_seq.as_deref() {
+            Some(alts) if alts != "." => alts.split(',').map(|alt| alt.to_string()),
+            _ => return,
+        };
+        let mut alleles =
+            std::iter::once(self.ref_seq.clone()).chain(alts).collect::<Vec<String>>();
+        let pos = match normalize_alleles(self.start, &mut alleles, |pos| {
+            crate::repeats::reference_base(fasta, &self.chrom, pos)
+        }) {
+            Some(pos) => pos,
+            None => return,
+        };
+        // the catalog locus is kept in the LOCUS INFO field, such that merge can match the records of a locus
+        self.locus = Some((self.start, self.end));
+        self.start = pos;
+        self.end = pos + alleles[0].len() as u32 - 1;
+        self.ref_seq = alleles.remove(0);
+        self.alt_seq = Some(alleles.join(","));
+    }
+}
+
+/// Left-align the REF and ALT alleles at the 1-based position pos, and trim their common prefix and suffix to a single anchor base
+/// Returns the new position, reference_base gives the base at a 1-based position and None before the chromosome
+/// None if an allele is symbolic or an ALT allele is the REF allele, which are left as they are
+pub fn normalize_alleles(
+    mut pos: u32,
+    alleles: &mut [String],
+    reference_base: impl Fn(u32) -> Option<char>,
+) -> Option<u32> {
+    let is_sequence = |allele: &String| {
+        !allele.is_empty() && allele.chars().all(|base| "ACGTNacgtn".contains(base))
+    };
+    if !alleles.iter().all(is_sequence)
+        || alleles[1..].iter().any(|alt| alt.eq_ignore_ascii_case(&alleles[0]))
+    {
+        return None;
+    }
+    let last = |allele: &String| allele.chars().last().map(|base| base.to_ascii_uppercase());
+    let first = |allele: &String| allele.chars().next().map(|base| base.to_ascii_uppercase());
+    // a common last base is trimmed, after extending all alleles with the base before them if one would be empty
+    while alleles.iter().all(|allele| last(allele) == last(&alleles[0])) {
+        if alleles.iter().any(|allele| allele.len() == 1) {
+            match reference_base(pos - 1) {
+                Some(base) => alleles.iter_mut().for_each(|allele| allele.insert(0, base)),
+                None => break,
+            }
+            pos -= 1;
+        }
+        alleles.iter_mut().for_each(|allele| {
+            allele.pop();
+        });
+    }
+    while alleles.iter().all(|allele| allele.len() >= 2 && first(allele) == first(&alleles[0])) {
+        alleles.iter_mut().for_each(|allele| {
+            allele.remove(0);
+        });
+        pos += 1;
+    }
+    Some(pos)
+}
+
+fn is_reference(seq: &str, ref_seq: &str, min_contraction: Option<usize>) -> bool {
+    levenshtein(seq, ref_seq) < ref_seq.len() / 20
+        && !min_contraction.is_some_and(|min| seq.len() + min <= ref_seq.len())
+}
+
+// the genotype of both alleles and the ALT sequences
+// if the consensus is very similar to the reference the variant is considered ref
+// for this I use a threshold of 5% of the length of the repeat sequence in the reference
+// e.g. if the repeat is 300bp in the reference this will allow an edit distance of 15
+// not sure if these numbers require further tuning
+// note that is an integer division, i.e. floor division
+// with min_contraction, alleles that are at least this much shorter than the reference are never ref
+fn genotype_alleles(
+    seq1: &str,
+    seq2: &str,
+    ref_seq: &str,
+    min_contraction: Option<usize>,
+) -> (String, String, String) {
+    let is_ref = |seq: &str| is_reference(seq, ref_seq, min_contraction);
+    // the next_alt variable dictates which genotype code the genotype2 can be in the case it is not the same as genotype1
+    // this avoids a 0/2 genotype
+    let (genotype1, next_alt) = if seq1 == "." {
+        (".", "1")
+    } else if is_ref(seq1) {
+        ("0", "1")
+    } else {
+        ("1", "2")
+    };
+
+    let genotype2 = if seq2 == "." {
+        "."
+    } else if is_ref(seq2) {
+        "0"
+    } else if levenshtein(seq2, seq1) < seq1.len() / 20 {
+        genotype1
+    } else {
+        next_alt
+    };
+
+    let alts = match (genotype1, genotype2) {
+        ("1", "0") | ("1", ".") | ("1", "1") => seq1.to_string(), // if both alleles are the same, only report one
+        ("0", "1") | (".", "1") => seq2.to_string(),
+        ("1", "2") => seq1.to_string() + "," + seq2,
+        _ => ".".to_string(), // includes ./. and 0/0
+    };
+    (genotype1.to_string(), genotype2.to_string(), alts)
+}
+
+impl VCFRecord {
+    /// Format the record as a single-line JSON object, used for --format json
+    pub fn to_json(&self) -> String {
+        let alts = match &self.alt_seq {
+            Some(alts) if alts != "." => alts.split(',').collect::<Vec<&str>>(),
+            _ => vec![],
+        };
+        // the somatic insertions are stored per haplotype as a ':' separated string
+        let somatic = self.somatic_insertions.as_ref().map(|haplotypes| {
+            haplotypes
+                .iter()
+                .map(|hap| {
+                    hap.split(':')
+                        .filter(|s| !s.is_empty())
+                        .collect::<Vec<&str>>()
+                })
+                .collect::<Vec<Vec<&str>>>()
+        });
+        let flags = self
+            .flags
+            .split(';')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>();
+        let mut record = json!({
+            "chrom": self.chrom,
+            "start": self.start,
+            "end": self.end,
+            "ref": self.ref_seq,
+            "alt": alts,
+            "genotype": [missing_to_null(&self.allele.0), missing_to_null(&self.allele.1)],
+            "length": [missing_to_null(&self.length.0), missing_to_null(&self.length.1)],
+            "full_length": [missing_to_null(&self.full_length.0), missing_to_null(&self.full_length.1)],
+            "support": [missing_to_null(&self.support.0), missing_to_null(&self.support.1)],
+            "std_dev": [missing_to_null(&self.std_dev.0), missing_to_null(&self.std_dev.1)],
+            "score": [missing_to_null(&self.score.0), missing_to_null(&self.score.1)],
+            "ci": [range_to_json(&self.ci.0), range_to_json(&self.ci.1)],
+            "trimmed": [missing_to_null(&self.trimmed.0), missing_to_null(&self.trimmed.1)],
+            "consensus": [missing_to_null(&self.consensus.0), missing_to_null(&self.consensus.1)],
+            "ps": self.ps,
+            "flags": flags,
+            "filters": self.filters,
+            "somatic": somatic,
+            "outliers": self.outliers,
+        });
+        for (genotype, allele) in &self.extra_alleles {
+            for (field, value) in [
+                ("genotype", genotype),
+                ("length", &allele.length),
+                ("full_length", &allele.full_length),
+                ("support", &allele.support),
+                ("std_dev", &allele.std_dev),
+                ("score", &allele.score),
+                ("trimmed", &allele.trimmed),
+                ("consensus", &allele.seq),
+            ] {
+                if let Some(values) = record[field].as_array_mut() {
+                    values.push(missing_to_null(value));
+                }
+            }
+            if let Some(values) = record["ci"].as_array_mut() {
+                values.push(range_to_json(&allele.ci));
+            }
+        }
+        if let (Some(gq), Some(pl)) = (self.gq, &self.pl) {
+            record["gq"] = json!(gq);
+            record["pl"] = json!(pl);
+        }
+        if let Some(motif_counts) = &self.motif_counts {
+            record["motif_counts"] = json!([motif_counts.0, motif_counts.1]);
+        }
+        if let Some(motifs) = &self.motifs {
+            record["motifs"] = json!([motifs.0, motifs.1]);
+        }
+        if let Some(residual_error) = &self.residual_error {
+            record["residual_error"] = json!([
+                missing_to_null(&residual_error.0),
+                missing_to_null(&residual_error.1)
+            ]);
+        }
+        if let (Some(purity), Some(distance)) = (&self.purity, &self.motif_distance) {
+            record["purity"] = json!([missing_to_null(&purity.0), missing_to_null(&purity.1)]);
+            record["motif_distance"] =
+                json!([missing_to_null(&distance.0), missing_to_null(&distance.1)]);
+        }
+        if let (Some(expansion), Some(contraction)) =
+            (&self.expansion_index, &self.contraction_index)
+        {
+            record["expansion_index"] =
+                json!([missing_to_null(&expansion.0), missing_to_null(&expansion.1)]);
+            record["contraction_index"] =
+                json!([missing_to_null(&contraction.0), missing_to_null(&contraction.1)]);
+        }
+        if let Some(read_qc) = &self.read_qc {
+            record["read_qc"] = read_qc.to_json(self.haploid);
+        }
+        if let Some(low_support) = &self.low_support {
+            record["low_support"] = json!(low_support);
+        }
+        if self.haploid {
+            // only the first allele is reported for haploid loci
+            for field in [
+                "genotype",
+                "length",
+                "full_length",
+                "support",
+                "std_dev",
+                "score",
+                "ci",
+                "trimmed",
+                "consensus",
+                "motif_counts",
+                "motifs",
+                "residual_error",
+                "purity",
+                "motif_distance",
+                "expansion_index",
+                "contraction_index",
+            ] {
+                if record.get(field).is_some() {
+                    record[field] = json!([record[field][0]]);
+                }
+            }
+        }
+        if let Some((start, end)) = self.locus {
+            record["locus"] = json!(format!("{}:{start}-{end}", self.chrom));
+        }
+        if let Some(allele_ids) = &self.allele_ids {
+            record["allele_ids"] = json!(allele_ids);
+        }
+        if let Some(fmr1) = &self.fmr1 {
+            record["fmr1"] = fmr1.to_json(self.haploid);
+        }
+        if let Some(second) = &self.second_reference {
+            record["second_reference"] = second.to_json(self.haploid);
+        }
+        if let Some(annotation) = &self.annotation {
+            record["id"] = json!(annotation.id);
+            record["gene"] = json!(annotation.gene);
+            record["disease"] = json!(annotation.disease);
+        }
+        if let Some((depth, spanning)) = self.coverage {
+            record["depth"] = json!(depth);
+            record["spanning"] = json!(spanning);
+        }
+        if let Some((anchored, clipped)) = self.clipping {
+            record["anchored"] = json!(anchored);
+            record["clipped"] = json!(clipped);
+        }
+        if let Some((low_identity, short)) = self.excluded {
+            record["excluded_low_identity"] = json!(low_identity);
+            record["excluded_short"] = json!(short);
+        }
+        if let Some(read_groups) = &self.read_groups {
+            record["read_groups"] = read_groups
+                .iter()
+                .map(|(rg, reads)| (rg.clone(), json!(reads)))
+                .collect();
+        }
+        record.to_string()
+    }
+}
+
+// spaces, semicolons, equals signs and commas are not allowed in the ID and INFO values of a VCF
+fn vcf_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_whitespace() || ";=,".contains(c) { '_' } else { c })
+        .collect()
+}
+
+/// Convert the "." used for missing values in the VCF to null, and numbers to integers or floats
+pub fn missing_to_null(value: &str) -> Value {
+    if value == "." {
+        Value::Null
+    } else if let Ok(number) = value.parse::<i64>() {
+        json!(number)
+    } else if value.chars().all(|c| c.is_ascii_digit() || c == '.' || c == '-') {
+        // only parse decimal numbers, as f64 parsing also accepts sequences like NAN
+        value.parse::<f64>().map_or(json!(value), |number| json!(number))
+    } else {
+        json!(value)
+    }
+}
+
+// a lower,upper range as a pair of numbers, or null if missing
+fn range_to_json(range: &str) -> Value {
+    match range.split_once(',') {
+        Some((lower, upper)) => json!([missing_to_null(lower), missing_to_null(upper)]),
+        None => Value::Null,
+    }
+}
+
 impl fmt::Display for VCFRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let somatic = match &self.somatic_insertions {
+            Some(somatic_insertions) => format!(";SEQS={}", somatic_insertions.join(",")),
+            None => "".to_string(),
+        };
+        let outliers = match &self.outliers {
+            Some(outlier_insertions) if !outlier_insertions.is_empty() => {
+                format!(";OUTLIERS={}", outlier_insertions.join(","))
+            }
+            _ => "".to_string(),
+        };
+        let read_groups = match &self.read_groups {
+            Some(read_groups) if !read_groups.is_empty() => format!(
+                ";RGSPAN={}",
+                read_groups
+                    .iter()
+                    .map(|(rg, reads)| format!("{}:{reads}", vcf_value(rg)))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+            _ => "".to_string(),
+        };
+        let allele_ids = match &self.allele_ids {
+            Some(ids) if !ids.is_empty() => format!(";AID={}", ids.join(",")),
+            _ => "".to_string(),
+        };
+        let second_reference = match &self.second_reference {
+            Some(second) => format!(
+                ";REF2={};REF2FRB={}{}",
+                second.locus,
+                if self.haploid {
+                    second.full_length.0.clone()
+                } else {
+                    format!("{},{}", second.full_length.0, second.full_length.1)
+                },
+                if second.discrepant { ";REFDIFF" } else { "" }
+            ),
+            None => "".to_string(),
+        };
+        // the locus identifier goes in the ID column, the gene and disease in the INFO field
+        let id = self
+            .annotation
+            .as_ref()
+            .and_then(|annotation| annotation.id.as_deref())
+            .map_or(".".to_string(), vcf_value);
+        let annotation = match &self.annotation {
+            Some(annotation) => {
+                let gene = annotation
+                    .gene
+                    .as_deref()
+                    .map(|gene| format!(";GENE={}", vcf_value(gene)));
+                let disease = annotation
+                    .disease
+                    .as_deref()
+                    .map(|disease| format!(";DISEASE={}", vcf_value(disease)));
+                gene.unwrap_or_default() + &disease.unwrap_or_default()
+            }
+            None => "".to_string(),
+        };
+        let locus = self
+            .locus
+            .map_or("".to_string(), |(start, end)| format!(";LOCUS={}:{start}-{end}", self.chrom));
+        // haploid loci have a single allele, and a single value for every per allele field
+        let per_allele = |values: &(String, String)| {
+            if self.haploid {
+                values.0.clone()
+            } else {
+                format!("{},{}", values.0, values.1)
+            }
+        };
+        let filter = if self.filters.is_empty() {
+            ".".to_string()
+        } else {
+            self.filters.join(";")
+        };
+        let genotype = if self.haploid {
+            self.allele.0.clone()
+        } else if self.filters.iter().any(|filter| filter == "NOCOV") {
+            // if there are no reads there is no phase
+            format!("{}/{}", self.allele.0, self.allele.1)
+        } else if !self.extra_alleles.is_empty() {
+            // the clusters of a locus with a ploidy above two are not phased
+            [&self.allele.0, &self.allele.1]
+                .into_iter()
+                .chain(self.extra_alleles.iter().map(|(genotype, _)| genotype))
+                .map(|genotype| genotype.as_str())
+                .collect::<Vec<&str>>()
+                .join("/")
+        } else {
+            format!("{}|{}", self.allele.0, self.allele.1)
+        };
+        // the lower and upper value of a range, with both missing if the range is missing
+        let range = |range: &str| {
+            if range == "." {
+                ".,.".to_string()
+            } else {
+                range.to_string()
+            }
+        };
+        // the values of the alleles beyond the first two
+        let extra = |field: &dyn Fn(&Allele) -> String| {
+            self.extra_alleles
+                .iter()
+                .map(|(_, allele)| format!(",{}", field(allele)))
+                .collect::<String>()
+        };
         match &self.alt_seq {
             Some(alts) => {
                 let (FORMAT, ps) = match self.ps {
-                    Some(ps) => ("GT:RB:FRB:SUP:SC:PS", format!(":{}", ps)),
-                    None => ("GT:RB:FRB:SUP:SC", "".to_string()),
+                    Some(ps) => ("GT:RB:FRB:SUP:SC:CI:TRIM:PS", format!(":{}", ps)),
+                    None => ("GT:RB:FRB:SUP:SC:CI:TRIM", "".to_string()),
+                };
+                let (FORMAT_GQ, likelihoods) = match (self.gq, &self.pl) {
+                    (Some(gq), Some(pl)) => (
+                        ":GQ:PL",
+                        format!(
+                            ":{gq}:{}",
+                            pl.iter().map(|pl| pl.to_string()).collect::<Vec<String>>().join(",")
+                        ),
+                    ),
+                    _ => ("", "".to_string()),
+                };
+                let (FORMAT_MC, motif_counts) = match &self.motif_counts {
+                    Some(motif_counts) => (":MC", format!(":{}", per_allele(motif_counts))),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_MO, motifs) = match &self.motifs {
+                    Some(motifs) => (":MO", format!(":{}", per_allele(motifs))),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_RE, residual_error) = match &self.residual_error {
+                    Some(residual_error) => (":RE", format!(":{}", per_allele(residual_error))),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_PUR, purity) = match (&self.purity, &self.motif_distance) {
+                    (Some(purity), Some(distance)) => (
+                        ":PUR:MED",
+                        format!(":{}:{}", per_allele(purity), per_allele(distance)),
+                    ),
+                    _ => ("", "".to_string()),
+                };
+                let (FORMAT_EXI, instability) =
+                    match (&self.expansion_index, &self.contraction_index) {
+                        (Some(expansion), Some(contraction)) => (
+                            ":EXI:CXI",
+                            format!(":{}:{}", per_allele(expansion), per_allele(contraction)),
+                        ),
+                        _ => ("", "".to_string()),
+                    };
+                let (FORMAT_QC, read_qc) = match &self.read_qc {
+                    Some(read_qc) => (
+                        ":FWD:REV:BQ",
+                        format!(
+                            ":{}:{}:{}",
+                            per_allele(&read_qc.forward),
+                            per_allele(&read_qc.reverse),
+                            per_allele(&read_qc.quality)
+                        ),
+                    ),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_FMR1, fmr1) = match &self.fmr1 {
+                    Some(fmr1) => (
+                        ":FXCN:FXAGG:FXCAT:FXMOS:FXMETH",
+                        format!(
+                            ":{}:{}:{}:{}:{}",
+                            per_allele(&fmr1.copies),
+                            per_allele(&fmr1.interruptions),
+                            per_allele(&fmr1.category),
+                            per_allele(&fmr1.mosaic),
+                            per_allele(&fmr1.methylation)
+                        ),
+                    ),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_COV, coverage) = match self.coverage {
+                    Some((depth, spanning)) => (":DP:SPAN", format!(":{depth}:{spanning}")),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_CLIP, clipping) = match self.clipping {
+                    Some((anchored, clipped)) => (":ANCH:CLIP", format!(":{anchored}:{clipped}")),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_EXCL, excluded) = match self.excluded {
+                    Some((low_identity, short)) => (":XID:XLEN", format!(":{low_identity}:{short}")),
+                    None => ("", "".to_string()),
+                };
+                let (FORMAT_LS, low_support) = match &self.low_support {
+                    Some(low_support) => (
+                        ":LS",
+                        format!(
+                            ":{}",
+                            low_support
+                                .iter()
+                                .map(|low| if *low { "1" } else { "0" })
+                                .collect::<Vec<&str>>()
+                                .join(",")
+                        ),
+                    ),
+                    None => ("", "".to_string()),
                 };
                 write!(
                     f,
-                    "{chrom}\t{start}\t.\t{ref}\t{alt}\t.\t.\t{flags}END={end};STDEV={sd1},{sd2}{somatic}{outliers}\t{FORMAT}\t{genotype1}|{genotype2}:{l1},{l2}:{fl1},{fl2}:{sup1},{sup2}:{score1},{score2}{ps}",
+                    "{chrom}\t{start}\t{id}\t{ref}\t{alt}\t.\t{filter}\t{flags}END={end};STDEV={sd}{annotation}{locus}{allele_ids}{second_reference}{read_groups}{somatic}{outliers}\t{FORMAT}{FORMAT_GQ}{FORMAT_MC}{FORMAT_MO}{FORMAT_RE}{FORMAT_PUR}{FORMAT_EXI}{FORMAT_QC}{FORMAT_FMR1}{FORMAT_COV}{FORMAT_CLIP}{FORMAT_EXCL}{FORMAT_LS}\t{genotype}:{l}:{fl}:{sup}:{score}:{ci}:{trimmed}{ps}{likelihoods}{motif_counts}{motifs}{residual_error}{purity}{instability}{read_qc}{fmr1}{coverage}{clipping}{excluded}{low_support}",
                     chrom = self.chrom,
                     start = self.start,
                     flags = self.flags,
                     end = self.end,
                     ref = self.ref_seq,
                     alt = alts,
-                    l1 = self.length.0,
-                    l2 = self.length.1,
-                    fl1 = self.full_length.0,
-                    fl2 = self.full_length.1,
-                    sd1 = self.std_dev.0,
-                    sd2 = self.std_dev.1,
-                    somatic = self.somatic_info_field,
-                    outliers = self.outliers,
-                    genotype1 = self.allele.0,
-                    genotype2 = self.allele.1,
-                    sup1 = self.support.0,
-                    sup2 = self.support.1,
-                    score1 = self.score.0,
-                    score2 = self.score.1,
+                    l = per_allele(&self.length) + &extra(&|a: &Allele| a.length.clone()),
+                    fl = per_allele(&self.full_length) + &extra(&|a: &Allele| a.full_length.clone()),
+                    sd = per_allele(&self.std_dev) + &extra(&|a: &Allele| a.std_dev.clone()),
+                    sup = per_allele(&self.support) + &extra(&|a: &Allele| a.support.clone()),
+                    score = per_allele(&self.score) + &extra(&|a: &Allele| a.score.clone()),
+                    ci = per_allele(&(range(&self.ci.0), range(&self.ci.1)))
+                        + &extra(&|a: &Allele| range(&a.ci)),
+                    trimmed = per_allele(&self.trimmed) + &extra(&|a: &Allele| a.trimmed.clone()),
                 )
             }
             None => {
                 write!(
                     f,
-                    "{chrom}\t{start}\t.\t{ref}\t.\t.\t.\tEND={end};{somatic}\tGT:SUP\t{genotype1}|{genotype2}:{sup1},{sup2}",
+                    "{chrom}\t{start}\t{id}\t{ref}\t.\t.\t{filter}\tEND={end}{annotation};{somatic}\tGT:SUP\t{genotype}:{sup}",
                     chrom = self.chrom,
                     start = self.start,
                     end = self.end,
                     ref = self.ref_seq,
-                    somatic = self.somatic_info_field,
-                    genotype1 = self.allele.0,
-                    genotype2 = self.allele.1,
-                    sup1 = self.support.0,
-                    sup2 = self.support.1,
+                    sup = per_allele(&self.support),
                 )
             }
         }
@@ -258,39 +1144,65 @@ impl PartialEq for VCFRecord {
 
 impl Eq for VCFRecord {}
 
-pub fn write_vcf_header(fasta: &str, bam: &str, sample: &Option<String>) {
+pub fn write_vcf_header(
+    fasta: &str,
+    bam: &str,
+    sample: &Option<String>,
+    anonymizer: Option<&crate::utils::Anonymizer>,
+) {
     println!(r#"##fileformat=VCFv4.2"#);
-    // get absolute path to fasta file
-    let path = std::fs::canonicalize(fasta)
-        .unwrap_or_else(|err| panic!("Failed getting absolute path to fasta: {err}"));
-    println!(
-        r#"##reference={}"#,
-        path.to_str().expect("Failed converting path to string")
-    );
+    // get absolute path to a local fasta file
+    let path = if crate::utils::is_remote(fasta) {
+        fasta.to_string()
+    } else {
+        std::fs::canonicalize(fasta)
+            .unwrap_or_else(|err| panic!("Failed getting absolute path to fasta: {err}"))
+            .to_str()
+            .expect("Failed converting path to string")
+            .to_string()
+    };
+    // with --anonymize, file paths and sample names are replaced by a hash
+    if let Some(anonymizer) = anonymizer {
+        println!(r#"##reference={}"#, anonymizer.anonymize(&path));
+    } else {
+        println!(r#"##reference={}"#, path);
+    }
     // get the version of this crate
     let version = env!("CARGO_PKG_VERSION");
     println!(r#"##source=STRdust v{}"#, version);
-    // call faidx to make sure the fasta index exists, we'll need this anyway when genotyping
-    let _ =
-        faidx::Reader::from_path(fasta).unwrap_or_else(|err| panic!("Failed opening fasta: {err}"));
-
-    let mut fai_file = std::fs::File::open(format!("{fasta}.fai")).expect("Can't open .fai file");
-    // parse the fasta index file
-    let mut buf = String::new();
-    fai_file
-        .read_to_string(&mut buf)
-        .expect("Can't read fai file");
-    for contig in buf.lines() {
-        let mut contig = contig.split_whitespace();
-        let name = contig.next().unwrap();
-        let length = contig.next().unwrap().parse::<usize>().unwrap();
+    // the contigs from the fasta index, which is created if it does not exist yet
+    for (name, length) in crate::repeats::fasta_contigs(fasta) {
         println!(r#"##contig=<ID={},length={}>"#, name, length);
     }
     println!(
         r#"##INFO=<ID=END,Number=1,Type=Integer,Description="End position of the repeat interval">"#
     );
     println!(
-        r#"##INFO=<ID=STDEV,Number=2,Type=Integer,Description="Standard deviation of the repeat length">"#
+        r#"##INFO=<ID=LOCUS,Number=1,Type=String,Description="Catalog locus of a record of which the alleles were normalized, only with --normalize">"#
+    );
+    println!(
+        r#"##INFO=<ID=STDEV,Number=.,Type=Integer,Description="Standard deviation of the repeat length">"#
+    );
+    println!(
+        r#"##INFO=<ID=GENE,Number=1,Type=String,Description="Gene of the locus, from the bed file">"#
+    );
+    println!(
+        r#"##INFO=<ID=DISEASE,Number=1,Type=String,Description="Disease associated with the locus, from the bed file">"#
+    );
+    println!(
+        r#"##INFO=<ID=AID,Number=A,Type=String,Description="Stable identifier of every ALT allele, from the locus and the allele sequence">"#
+    );
+    println!(
+        r#"##INFO=<ID=REF2,Number=1,Type=String,Description="Locus in the second reference, with --second-fasta">"#
+    );
+    println!(
+        r#"##INFO=<ID=REF2FRB,Number=.,Type=Integer,Description="Full repeat length of the alleles in bases called against the second reference">"#
+    );
+    println!(
+        r#"##INFO=<ID=REFDIFF,Number=0,Type=Flag,Description="Allele lengths differ between the primary and the second reference">"#
+    );
+    println!(
+        r#"##INFO=<ID=RGSPAN,Number=.,Type=String,Description="Spanning reads used per read group as RG:reads, with --read-group or --max-reads-per-read-group">"#
     );
     println!(
         r#"##INFO=<ID=SEQS,Number=1,Type=String,Description="Sequences supporting the two alleles">"#
@@ -301,16 +1213,119 @@ pub fn write_vcf_header(fasta: &str, bam: &str, sample: &Option<String>) {
     println!(
         r#"##INFO=<ID=CLUSTERFAILURE,Number=0,Type=Flag,Description="If unphased input failed to cluster in two haplotype">"#
     );
+    println!(
+        r#"##ALT=<ID=STR,Description="Short tandem repeat allele with the length in bases in the ID, e.g. <STR42>, only with --no-sequence">"#
+    );
+    println!(
+        r#"##FILTER=<ID=LOWCONF_FLANK,Description="Repeat in a low confidence region or with poorly mappable flanks">"#
+    );
+    println!(
+        r#"##FILTER=<ID=TIMEOUT,Description="Genotyping of the repeat was stopped after the time set by --timeout">"#
+    );
+    println!(
+        r#"##FILTER=<ID=NOCOV,Description="No reads span the repeat, only with --no-coverage-calls">"#
+    );
+    println!(
+        r#"##FILTER=<ID=STRAND_BIAS,Description="Almost all reads of an allele with at least 10 reads are on the same strand">"#
+    );
     println!(r#"##FORMAT=<ID=GT,Number=1,Type=String,Description="Genotype">"#);
     println!(
-        r#"##FORMAT=<ID=RB,Number=2,Type=Integer,Description="Repeat length of the two alleles in bases relative to reference">"#
+        r#"##FORMAT=<ID=RB,Number=.,Type=Integer,Description="Repeat length of the two alleles in bases relative to reference">"#
     );
     println!(
-        r#"##FORMAT=<ID=FRB,Number=2,Type=Integer,Description="Full repeat length of the two alleles in bases">"#
+        r#"##FORMAT=<ID=FRB,Number=.,Type=Integer,Description="Full repeat length of the two alleles in bases">"#
     );
     println!(r#"##FORMAT=<ID=PS,Number=1,Type=Integer,Description="Phase set identifier">"#);
-    println!(r#"##FORMAT=<ID=SUP,Number=2,Type=Integer,Description="Read support per allele">"#);
-    println!(r#"##FORMAT=<ID=SC,Number=2,Type=Integer,Description="Consensus score per allele">"#);
+    println!(
+        r#"##FORMAT=<ID=GQ,Number=1,Type=Integer,Description="Genotype quality, from the likelihoods of the repeat length of the reads">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=PL,Number=G,Type=Integer,Description="Phred-scaled genotype likelihoods, from the repeat length of the reads">"#
+    );
+    println!(r#"##FORMAT=<ID=SUP,Number=.,Type=Integer,Description="Read support per allele">"#);
+    println!(r#"##FORMAT=<ID=SC,Number=.,Type=Integer,Description="Consensus score per allele">"#);
+    println!(
+        r#"##FORMAT=<ID=CI,Number=.,Type=Integer,Description="Range of the repeat length relative to reference per allele, as the 5th and the 95th percentile of supporting reads, two values per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=TRIM,Number=.,Type=Integer,Description="Reads trimmed per allele for a length beyond 3 median absolute deviations from the median">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=MC,Number=.,Type=String,Description="Copies of every repeated motif per allele for compound loci, separated by _">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=MO,Number=.,Type=String,Description="Repeated motifs per allele, separated by _">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=RE,Number=.,Type=Float,Description="Residual error rate of the reads realigned to a reference with the called allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FWD,Number=.,Type=Integer,Description="Supporting reads on the forward strand per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=REV,Number=.,Type=Integer,Description="Supporting reads on the reverse strand per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=PUR,Number=.,Type=Float,Description="Fraction of the allele in runs of perfect copies of the motif">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=MED,Number=.,Type=Integer,Description="Edit distance of the allele to the closest perfect repeat of the motif with the same length">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=EXI,Number=.,Type=Float,Description="Expansion index per allele: mean number of repeat units that the reads are longer than the modal repeat count">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=CXI,Number=.,Type=Float,Description="Contraction index per allele: mean number of repeat units that the reads are shorter than the modal repeat count">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=BQ,Number=.,Type=Float,Description="Mean base quality of the repeat in the supporting reads per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FXCN,Number=.,Type=Integer,Description="FMR1: number of CGG repeat units per allele, including interruptions">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FXAGG,Number=.,Type=Integer,Description="FMR1: number of AGG interruptions per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FXCAT,Number=.,Type=String,Description="FMR1: normal, intermediate, premutation or full_mutation per allele">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FXMOS,Number=.,Type=Integer,Description="FMR1: 1 if the supporting reads of the allele span multiple categories, indicating size mosaicism">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=FXMETH,Number=.,Type=Float,Description="FMR1: fraction of methylated CpGs in the repeat per allele, from the MM/ML tags of phased reads">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=DP,Number=1,Type=Integer,Description="Number of reads overlapping the repeat">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=SPAN,Number=1,Type=Integer,Description="Number of reads spanning the repeat">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=ANCH,Number=1,Type=Integer,Description="Number of reads spanning the repeat with a single alignment anchored in both flanks">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=CLIP,Number=1,Type=Integer,Description="Number of reads soft-clipped within the repeat, many suggest an allele longer than the reads">"#
+    );
+    let name = sample_name(bam, sample, anonymizer);
+    println!(
+        r#"##FORMAT=<ID=XID,Number=1,Type=Integer,Description="Number of spanning reads excluded for an identity below --min-read-identity">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=XLEN,Number=1,Type=Integer,Description="Number of spanning reads excluded for a length below --min-read-length">"#
+    );
+    println!(
+        r#"##FORMAT=<ID=LS,Number=.,Type=Integer,Description="1 for an allele with fewer supporting reads than --support, which is missing from the genotype, 0 otherwise, only with --keep-low-support">"#
+    );
+    println!("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{name}",);
+}
+
+/// The sample name from --sample, or the file name of the bam file without extension
+pub fn sample_name(
+    bam: &str,
+    sample: &Option<String>,
+    anonymizer: Option<&crate::utils::Anonymizer>,
+) -> String {
     let name = match sample {
         Some(name) => name,
         None => {
@@ -323,7 +1338,10 @@ pub fn write_vcf_header(fasta: &str, bam: &str, sample: &Option<String>) {
             name
         }
     };
-    println!("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{name}",);
+    match anonymizer {
+        Some(anonymizer) => anonymizer.anonymize(name),
+        None => name.to_string(),
+    }
 }
 
 #[cfg(test)]
@@ -333,6 +1351,7 @@ fn test_write_vcf_header_from_bam() {
         "test_data/chr7.fa.gz",
         "test_data/small-test-phased.bam",
         &None,
+        None,
     );
 }
 
@@ -342,5 +1361,187 @@ fn test_write_vcf_header_from_name() {
         "test_data/chr7.fa.gz",
         "test_data/small-test-phased.bam",
         &Some("test_sample".to_string()),
+        None,
     );
 }
+
+#[test]
+fn test_anonymize_read_groups() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let mut record = crate::test_utils::cag_record(&repeat, "0");
+    record.read_groups = Some(vec![("HG002_flowcell1".to_string(), 3)]);
+    let anonymizer = crate::utils::Anonymizer::new(Some("key"));
+    record.anonymize_read_groups(&anonymizer);
+    let hash = anonymizer.anonymize("HG002_flowcell1");
+    assert!(record.to_string().contains(&format!(";RGSPAN={hash}:3")));
+    let parsed: Value = serde_json::from_str(&record.to_json()).expect("Invalid JSON");
+    assert_eq!(parsed["read_groups"][&hash], 3);
+    assert!(!record.to_json().contains("HG002"));
+}
+
+#[test]
+fn test_to_json() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let record = crate::test_utils::cag_record(&repeat, "0");
+    let parsed: Value = serde_json::from_str(&record.to_json()).expect("Invalid JSON");
+    assert_eq!(parsed["chrom"], "chr7");
+    assert_eq!(parsed["support"][0], 0);
+    assert!(parsed["genotype"][0].is_null());
+}
+
+#[test]
+fn test_ci() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let mut record = crate::test_utils::cag_record(&repeat, "0");
+    record.ci = ("-3,-1".to_string(), ".".to_string());
+    // a lower and upper value per allele, both missing for a missing range
+    assert!(record.to_string().contains(":-3,-1,.,.:"));
+    let parsed: Value = serde_json::from_str(&record.to_json()).expect("Invalid JSON");
+    assert_eq!(parsed["ci"][0], json!([-3, -1]));
+    assert!(parsed["ci"][1].is_null());
+}
+
+#[test]
+fn test_genotype_alleles() {
+    let ref_seq = "CAG".repeat(40);
+    let contracted = "CAG".repeat(39);
+    // a small contraction is within the edit distance that is considered reference
+    let (genotype1, genotype2, alts) = genotype_alleles(&contracted, &ref_seq, &ref_seq, None);
+    assert_eq!((genotype1.as_str(), genotype2.as_str(), alts.as_str()), ("0", "0", "."));
+    // unless it is at least min_contraction bases shorter than the reference
+    let (genotype1, genotype2, alts) = genotype_alleles(&contracted, &ref_seq, &ref_seq, Some(3));
+    assert_eq!((genotype1.as_str(), genotype2.as_str()), ("1", "0"));
+    assert_eq!(alts, contracted);
+}
+
+#[test]
+fn test_split_nested() {
+    let vntr = "GCCCCGCCTG".repeat(4);
+    let ref_seq = "CAG".repeat(15) + "TT" + &vntr;
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 100, 187);
+    let mut record = VCFRecord::missing_genotype(&repeat, &ref_seq, "10".to_string());
+    record.alt_seq = Some(".".to_string());
+    record.consensus = (ref_seq.clone(), "CAG".repeat(30) + "TT" + &vntr);
+    record.support = ("10".to_string(), "12".to_string());
+    let (first, second) = record.split_nested(20, 1).expect("Failed splitting record");
+    assert_eq!((first.start, first.end, second.start), (100, 147, 147));
+    // only the CAG repeat is expanded
+    assert_eq!((first.allele.0.as_str(), first.allele.1.as_str()), ("0", "1"));
+    assert_eq!((second.allele.0.as_str(), second.allele.1.as_str()), ("0", "0"));
+    assert_eq!(first.support.1, "12");
+    // the fields of the whole allele are kept, and the identifier gets a suffix
+    record.std_dev = ("1".to_string(), "2".to_string());
+    record.ci = ("-1,1".to_string(), "40,50".to_string());
+    record.low_support = Some(vec![false, true]);
+    record.annotation = Some(crate::repeats::LocusAnnotation {
+        id: Some("HTT".to_string()),
+        gene: None,
+        disease: None,
+    });
+    let (first, second) = record.split_nested(20, 1).expect("Failed splitting record");
+    assert_eq!(second.std_dev, record.std_dev);
+    assert_eq!(second.ci, record.ci);
+    assert_eq!(first.low_support, record.low_support);
+    assert!(first.to_string().contains("\tHTT_1\t"));
+    assert!(second.to_string().contains("\tHTT_2\t"));
+    // a second motif that is only in an allele is split off in a record anchored at the last reference base
+    let cag = "CAG".repeat(15);
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 100, 145);
+    let mut record = VCFRecord::missing_genotype(&repeat, &cag, "10".to_string());
+    record.alt_seq = Some(".".to_string());
+    record.consensus = (cag.clone(), cag.clone() + "TT" + &vntr);
+    let (first, second) = record.split_nested(20, 1).expect("Failed splitting record");
+    assert_eq!((first.start, first.end), (100, 145));
+    assert_eq!((second.start, second.end, second.ref_seq.as_str()), (144, 145, "G"));
+    assert_eq!((first.allele.0.as_str(), first.allele.1.as_str()), ("0", "1"));
+    assert_eq!((second.allele.0.as_str(), second.allele.1.as_str()), ("0", "1"));
+    assert_eq!(second.consensus.1, "G".to_string() + &vntr);
+    // a locus with a single motif is not split
+    let record = VCFRecord::missing_genotype(&repeat, &"CAG".repeat(30), "10".to_string());
+    assert!(record.split_nested(20, 1).is_none());
+}
+
+#[test]
+fn test_set_symbolic_alleles() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let mut record = crate::test_utils::cag_record(&repeat, "10");
+    record.allele = ("1".to_string(), "2".to_string());
+    record.alt_seq = Some("CAGCAGCAG,CAACAGCAG".to_string());
+    record.consensus = ("CAGCAGCAG".to_string(), "CAACAGCAG".to_string());
+    record.set_symbolic_alleles();
+    // the two ALT alleles have the same length, so are merged
+    assert_eq!(record.alt_seq.as_deref(), Some("<STR9>"));
+    assert_eq!((record.allele.0.as_str(), record.allele.1.as_str()), ("1", "1"));
+    assert_eq!(record.consensus.0, ".");
+}
+
+#[test]
+fn test_set_likelihoods() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let mut record = crate::test_utils::cag_record(&repeat, "10");
+    record.allele = ("0".to_string(), "1".to_string());
+    record.alt_seq = Some("CAGCAGCAG".to_string());
+    record.set_likelihoods(&[6, 6, 6, 9, 9, 9]);
+    assert!(record.gq.is_some());
+    assert_eq!(record.pl.as_ref().map(|pl| pl.len()), Some(3));
+    // an ALT allele of the reference length can not be told apart by the read lengths
+    let mut record = crate::test_utils::cag_record(&repeat, "10");
+    record.allele = ("0".to_string(), "1".to_string());
+    record.alt_seq = Some("CAACAG".to_string());
+    record.set_likelihoods(&[6, 6, 6, 6, 6, 6]);
+    assert!(record.gq.is_none() && record.pl.is_none());
+}
+
+#[test]
+fn test_flag_low_support() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let mut record = crate::test_utils::cag_record(&repeat, "2");
+    record.allele = ("1".to_string(), "0".to_string());
+    record.flag_low_support();
+    assert_eq!(record.low_support, Some(vec![false, false]));
+    // a partial genotype, of which only the second allele misses the support
+    record.allele.1 = ".".to_string();
+    record.flag_low_support();
+    assert_eq!(record.low_support, Some(vec![false, true]));
+    assert!(record.filters.is_empty());
+    assert!(record.to_string().ends_with(":0,1"));
+    // only the first allele is reported for haploid loci
+    let mut haploid = crate::test_utils::cag_record(&repeat, "2");
+    haploid.allele.0 = "1".to_string();
+    haploid.haploid = true;
+    haploid.flag_low_support();
+    assert_eq!(haploid.low_support, Some(vec![false]));
+}
+
+#[test]
+fn test_normalize_alleles() {
+    let reference = "GCACACATTG";
+    let reference_base = |pos: u32| reference.chars().nth(pos as usize - 1);
+    // an insertion of CA is shifted to the start of the repeat, after the G
+    let mut alleles = vec!["CACACAT".to_string(), "CACACACAT".to_string()];
+    assert_eq!(normalize_alleles(2, &mut alleles, reference_base), Some(1));
+    assert_eq!(alleles, vec!["G".to_string(), "GCA".to_string()]);
+    // a substitution at the end keeps only the differing base
+    let mut alleles = vec!["CACACAT".to_string(), "CACACAG".to_string()];
+    assert_eq!(normalize_alleles(2, &mut alleles, reference_base), Some(8));
+    assert_eq!(alleles, vec!["T".to_string(), "G".to_string()]);
+    // a deletion and an insertion in the same record
+    let mut alleles = vec!["ACACATT".to_string(), "ACATT".to_string(), "ACACACATT".to_string()];
+    assert_eq!(normalize_alleles(3, &mut alleles, reference_base), Some(1));
+    assert_eq!(alleles, vec!["GCA".to_string(), "G".to_string(), "GCACA".to_string()]);
+    // symbolic alleles are not normalized
+    let mut alleles = vec!["CACACAT".to_string(), "<STR>".to_string()];
+    assert_eq!(normalize_alleles(2, &mut alleles, reference_base), None);
+}
+
+#[test]
+fn test_no_coverage() {
+    let repeat = crate::repeats::RepeatInterval::new("chr7", 154654404, 154654432);
+    let record = VCFRecord::no_coverage(&repeat, "CAGCAG", 12);
+    let line = record.to_string();
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    assert_eq!(fields[6], "NOCOV");
+    assert!(fields[8].ends_with(":DP:SPAN"));
+    assert!(fields[9].starts_with("./.:"));
+    assert!(fields[9].ends_with(":12:0"));
+}